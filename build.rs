@@ -1,7 +1,48 @@
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(usync_tsan_enabled)");
+    println!("cargo:rustc-check-cfg=cfg(usync_track_owner)");
+    println!("cargo:rustc-check-cfg=cfg(usync_rmw_fast_paths)");
+    println!("cargo:rustc-check-cfg=cfg(usync_cas_backoff)");
+
     let santizer_list = std::env::var("CARGO_CFG_SANITIZE").unwrap_or_default();
     if santizer_list.contains("thread") {
         println!("cargo:rustc-cfg=usync_tsan_enabled");
     }
+
+    // The `track_owner` feature records the owning thread of a locked Mutex to catch recursive locking.
+    // This is disabled with `send_guard` as guards are then allowed to be unlocked from other threads.
+    let track_owner = std::env::var_os("CARGO_FEATURE_TRACK_OWNER").is_some();
+    let send_guard = std::env::var_os("CARGO_FEATURE_SEND_GUARD").is_some();
+    if track_owner && !send_guard {
+        println!("cargo:rustc-cfg=usync_track_owner");
+    }
+
+    // The RwLock fast paths use fetch_or/fetch_sub where those are single wait-free instructions
+    // (`lock bts`/`lock xadd` on x86, `amoor`/`amoadd` on RISC-V with the A extension, and
+    // `ldsetal`/`ldaddl` on AArch64 with LSE) instead of `compare_exchange` loops, which would
+    // retry whenever another thread updates the queue bits. AArch64 targets without LSE
+    // enabled at compile time use outline atomics, which pick the LSE instructions at runtime
+    // when the CPU has them and otherwise fall back to an `ldxr`/`stxr` loop that only retries
+    // on actual conflicts, so they get the fast paths as well.
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let features = std::env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    let has_feature = |feature: &str| features.split(',').any(|f| f == feature);
+    let rmw_fast_paths = match arch.as_str() {
+        "x86" | "x86_64" => true,
+        "riscv32" | "riscv64" => has_feature("a"),
+        "aarch64" => true,
+        _ => false,
+    };
+    let portable = std::env::var_os("CARGO_FEATURE_PORTABLE").is_some();
+    if rmw_fast_paths && !portable {
+        println!("cargo:rustc-cfg=usync_rmw_fast_paths");
+    }
+
+    // The remaining `compare_exchange` loops back off between retries on RISC-V, where they're
+    // `lr`/`sc` loops failing on any write to the cache line, unless Zacas turns them into `amocas`.
+    let is_riscv = matches!(arch.as_str(), "riscv32" | "riscv64");
+    if is_riscv && !has_feature("zacas") {
+        println!("cargo:rustc-cfg=usync_cas_backoff");
+    }
 }
@@ -0,0 +1,370 @@
+use super::{shared::SpinWait, RawMutex};
+use lock_api::RawMutex as _RawMutex;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+#[cfg(feature = "std")]
+use {
+    crate::clock,
+    std::time::{Duration, Instant},
+};
+
+// The spin limit for a contended lock() is derived from the running average as
+// `spins * 2`, capped at MAX_SPINS. The initial average roughly matches the
+// default spin count of 100 used by the other locks.
+const MAX_SPINS: u32 = 1000;
+const INITIAL_SPINS: u32 = 45;
+
+// The average is kept in the low bits as a fixed point number with SCALE as 1 spin,
+// so that it can decay all the way to 0. The high bits count the contended locks
+// which parked right away since then. Every PROBE_INTERVAL of them, one spins for
+// PROBE_SPINS instead to find out whether spinning pays off again.
+const SCALE: u32 = 16;
+const AVERAGE_MASK: u32 = 0xffff;
+const SKIPPED_SHIFT: u32 = 16;
+const PROBE_INTERVAL: u32 = 16;
+const PROBE_SPINS: u32 = 10;
+
+/// Raw mutex type which adapts how long it spins to the lock's recent contention.
+///
+/// Every contended lock records how many spins it took to acquire the mutex into a
+/// running average kept on the lock. Spinning which ended in the thread having to
+/// block counts as zero, so the average tracks how long the lock is usually held
+/// for compared to the cost of parking. Once it drops to zero, contended threads
+/// park right away, apart from an occasional one spinning to probe the lock again.
+pub struct RawAdaptiveMutex {
+    mutex: RawMutex,
+    spins: AtomicU32,
+}
+
+impl Default for RawAdaptiveMutex {
+    fn default() -> Self {
+        <Self as lock_api::RawMutex>::INIT
+    }
+}
+
+impl fmt::Debug for RawAdaptiveMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RawAdaptiveMutex { .. }")
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawAdaptiveMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self {
+        mutex: RawMutex::INIT,
+        spins: AtomicU32::new(INITIAL_SPINS * SCALE),
+    };
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.mutex.is_locked()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        if !self.mutex.try_lock() {
+            self.lock_slow();
+        }
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.mutex.try_lock()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.mutex.unlock()
+    }
+}
+
+unsafe impl lock_api::RawMutexFair for RawAdaptiveMutex {
+    #[inline]
+    unsafe fn unlock_fair(&self) {
+        self.mutex.unlock_fair()
+    }
+
+    #[inline]
+    unsafe fn bump(&self) {
+        self.mutex.bump()
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl lock_api::RawMutexTimed for RawAdaptiveMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => {
+                self.lock();
+                true
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.mutex.try_lock() || self.try_lock_until_slow(deadline)
+    }
+}
+
+impl RawAdaptiveMutex {
+    #[cold]
+    fn lock_slow(&self) {
+        if !self.spin() {
+            self.mutex.lock_without_spinning();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[cold]
+    fn try_lock_until_slow(&self, deadline: Instant) -> bool {
+        self.spin() || self.mutex.try_lock_until_without_spinning(deadline)
+    }
+
+    /// Spins on the contended mutex for as long as the running average suggests,
+    /// returning whether it was acquired meanwhile.
+    fn spin(&self) -> bool {
+        // Spin for up to twice as long as it usually takes to acquire the lock.
+        // This spins the whole time for short critical sections and parks
+        // immediately when spinning hasn't been acquiring the lock recently.
+        let state = self.spins.load(Ordering::Relaxed);
+        let average = state & AVERAGE_MASK;
+        let mut limit = (average * 2 / SCALE).min(MAX_SPINS);
+        if limit == 0 {
+            let skipped = state >> SKIPPED_SHIFT;
+            if skipped < PROBE_INTERVAL {
+                let new_state = average | ((skipped + 1) << SKIPPED_SHIFT);
+                self.spins.store(new_state, Ordering::Relaxed);
+                return false;
+            }
+            limit = PROBE_SPINS;
+        }
+
+        let mut spin = SpinWait::with_limit(limit as usize);
+        let mut spun = 0;
+        let acquired = loop {
+            if !self.mutex.is_locked() && self.mutex.try_lock() {
+                break true;
+            }
+
+            if !spin.try_yield_now() {
+                break false;
+            }
+
+            spun += 1;
+        };
+
+        // Update the running average with the spins it took (or 0 if spinning didn't help),
+        // rounding down so that it reaches 0. This also restarts the probe interval.
+        // Racing updates from other threads may get lost but that only makes it less precise.
+        let observed = if acquired { spun } else { 0 };
+        let new_average = (average * 7 + observed * SCALE) / 8;
+        let new_average = new_average.min(MAX_SPINS * SCALE);
+        self.spins.store(new_average, Ordering::Relaxed);
+
+        acquired
+    }
+}
+
+/// A mutual exclusion primitive which adapts its spinning to the lock's contention
+///
+/// This is the same as [`Mutex`](type.Mutex.html) except that the amount of spinning
+/// done before blocking on a contended lock isn't fixed. Each `AdaptiveMutex` keeps a running
+/// average of how long contended threads had to spin for before acquiring it, and spins for
+/// up to twice that next time, bounded to 1000 spins:
+///
+/// - Locks with very short critical sections are usually acquired while spinning, and only
+///   block the thread when the holder takes longer than the bound.
+/// - Locks with moderate critical sections spin for a while then block, like `Mutex`.
+/// - Locks held for longer than spinning is worth block the thread immediately. Every so
+///   often, a contended thread still spins briefly to notice when that changes.
+///
+/// The average follows changes in how the lock is used, which helps workloads where the
+/// critical section length varies widely between locks (or over time) and no fixed spin
+/// count works well for all of them. The mutex is 1 word larger than a `Mutex` for the average.
+///
+/// # Examples
+///
+/// ```
+/// use usync::AdaptiveMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let counter = Arc::new(AdaptiveMutex::new(0));
+///
+/// let threads: Vec<_> = (0..10)
+///     .map(|_| {
+///         let counter = Arc::clone(&counter);
+///         thread::spawn(move || *counter.lock() += 1)
+///     })
+///     .collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// assert_eq!(*counter.lock(), 10);
+/// ```
+pub type AdaptiveMutex<T> = lock_api::Mutex<RawAdaptiveMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of an adaptive mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type AdaptiveMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawAdaptiveMutex, T>;
+
+/// An RAII mutex guard returned by `AdaptiveMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedAdaptiveMutexGuard` and `AdaptiveMutexGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedAdaptiveMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawAdaptiveMutex, T>;
+
+/// Creates a new adaptive mutex in an unlocked state ready for use.
+///
+/// This allows creating a mutex in a constant context on stable Rust.
+pub const fn const_adaptive_mutex<T>(value: T) -> AdaptiveMutex<T> {
+    AdaptiveMutex::const_new(<RawAdaptiveMutex as lock_api::RawMutex>::INIT, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawAdaptiveMutex, AVERAGE_MASK, INITIAL_SPINS, SCALE, SKIPPED_SHIFT};
+    use crate::AdaptiveMutex;
+    use std::{
+        sync::{atomic::Ordering, mpsc::channel, Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let m = AdaptiveMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let m = Arc::new(AdaptiveMutex::new(()));
+        let guard = m.lock();
+
+        let m2 = m.clone();
+        let timed_out = thread::spawn(move || m2.try_lock_for(Duration::from_millis(50)).is_none());
+        assert!(timed_out.join().unwrap());
+
+        // The timed out attempt spun first, which didn't help.
+        assert!(average(unsafe { m.raw() }) < INITIAL_SPINS * SCALE);
+
+        drop(guard);
+        assert!(m.try_lock_for(Duration::from_millis(50)).is_some());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(AdaptiveMutex::new(0));
+
+        fn inc(m: &AdaptiveMutex<u32>) {
+            for _ in 0..J {
+                *m.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..K {
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+        }
+
+        drop(tx);
+        for _ in 0..2 * K {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = AdaptiveMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_adaptive_spins_decay() {
+        let m = Arc::new(AdaptiveMutex::new(()));
+        let barrier = Arc::new(Barrier::new(2));
+
+        // Contend on the lock while it's held for much longer than spinning takes.
+        for _ in 0..10 {
+            let guard = m.lock();
+            let t = {
+                let m = m.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    drop(m.lock());
+                })
+            };
+
+            barrier.wait();
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+            t.join().unwrap();
+        }
+
+        assert!(average(unsafe { m.raw() }) < INITIAL_SPINS * SCALE);
+    }
+
+    #[test]
+    fn test_adaptive_parks_immediately() {
+        let m = Arc::new(AdaptiveMutex::new(()));
+        let raw = unsafe { m.raw() };
+        raw.spins.store(0, Ordering::Relaxed);
+
+        // Without spinning paying off, the contended lock parks right away.
+        let guard = m.lock();
+        let t = {
+            let m = m.clone();
+            thread::spawn(move || drop(m.lock()))
+        };
+        while raw.spins.load(Ordering::Relaxed) >> SKIPPED_SHIFT == 0 {
+            thread::yield_now();
+        }
+
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(raw.spins.load(Ordering::Relaxed), 1 << SKIPPED_SHIFT);
+    }
+
+    fn average(raw: &RawAdaptiveMutex) -> u32 {
+        raw.spins.load(Ordering::Relaxed) & AVERAGE_MASK
+    }
+}
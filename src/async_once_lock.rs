@@ -0,0 +1,310 @@
+use super::{const_mutex, Mutex, OnceLock};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// A cell which can be written to only once, initialized asynchronously.
+///
+/// This is the async equivalent of [`OnceLock`](struct.OnceLock.html): the first task
+/// calling `get_or_init` runs the initialization future, while other tasks calling it
+/// concurrently wait for that future to complete without blocking their thread.
+///
+/// If the initialization future panics, returns an error or is dropped before completing
+/// (e.g. because the task running it got cancelled), the cell is left uninitialized and
+/// one of the waiting tasks runs its own initialization future instead.
+///
+/// This type is only available with the `async` feature.
+///
+/// # Examples
+///
+/// ```
+/// use usync::AsyncOnceLock;
+///
+/// static POOL: AsyncOnceLock<Vec<u32>> = AsyncOnceLock::new();
+///
+/// async fn connection_pool() -> &'static Vec<u32> {
+///     POOL.get_or_init(|| async { vec![1, 2, 3] }).await
+/// }
+/// ```
+pub struct AsyncOnceLock<T> {
+    value: OnceLock<T>,
+    state: Mutex<InitState>,
+}
+
+struct InitState {
+    running: bool,
+    wakers: Vec<Waker>,
+}
+
+impl<T> AsyncOnceLock<T> {
+    /// Creates a new empty cell.
+    pub const fn new() -> Self {
+        Self {
+            value: OnceLock::new(),
+            state: const_mutex(InitState {
+                running: false,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Gets the reference to the underlying value.
+    ///
+    /// Returns `None` if the cell is empty, or being initialized.
+    pub fn get(&self) -> Option<&T> {
+        self.value.get()
+    }
+
+    /// Gets the contents of the cell, initializing it with the future returned by `f`
+    /// if the cell was empty.
+    ///
+    /// Many tasks may call `get_or_init` concurrently with different initializing
+    /// functions, but only one of their futures is run at a time and the others
+    /// wait for it to complete.
+    pub async fn get_or_init<F, Fut>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        enum Never {}
+        match self
+            .get_or_try_init(|| async { Ok::<T, Never>(f().await) })
+            .await
+        {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Gets the contents of the cell, initializing it with the future returned by `f`
+    /// if the cell was empty. If the cell was empty and the future failed, an error is returned.
+    ///
+    /// When the future returns an error, the cell is left uninitialized and the tasks
+    /// waiting for it to be initialized are woken up to try their own function.
+    pub async fn get_or_try_init<F, Fut, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut f = Some(f);
+        loop {
+            if let Some(value) = self.get() {
+                return Ok(value);
+            }
+
+            if !self.try_start_init() {
+                WaitForInit { cell: self }.await;
+                continue;
+            }
+
+            // Make sure that waiting tasks are woken up even if the future panics or is dropped.
+            let _guard = InitGuard { cell: self };
+            let init = f.take().expect("AsyncOnceLock initializer ran twice");
+            let value = init().await?;
+
+            // Nothing else can set the value while we're the one running.
+            assert!(self.value.set(value).is_ok());
+            return Ok(self.get().unwrap());
+        }
+    }
+
+    /// Consumes the cell, returning the wrapped value.
+    ///
+    /// Returns `None` if the cell was empty.
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+
+    fn try_start_init(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.running || self.value.get().is_some() {
+            return false;
+        }
+
+        state.running = true;
+        true
+    }
+}
+
+impl<T> Default for AsyncOnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AsyncOnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("AsyncOnceLock").field(value).finish(),
+            None => f.write_str("AsyncOnceLock(<uninit>)"),
+        }
+    }
+}
+
+/// Ends the initialization started by a task and wakes up the tasks waiting for it.
+struct InitGuard<'a, T> {
+    cell: &'a AsyncOnceLock<T>,
+}
+
+impl<'a, T> Drop for InitGuard<'a, T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut state = self.cell.state.lock();
+            state.running = false;
+            std::mem::take(&mut state.wakers)
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Resolves once no task is running an initialization future for the cell.
+struct WaitForInit<'a, T> {
+    cell: &'a AsyncOnceLock<T>,
+}
+
+impl<'a, T> Future for WaitForInit<'a, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.cell.state.lock();
+        if !state.running {
+            return Poll::Ready(());
+        }
+
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AsyncOnceLock;
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// Returns Pending once before completing, to let other tasks observe the running initializer.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let cell = AsyncOnceLock::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(block_on(cell.get_or_init(|| async { 92 })), &92);
+        assert_eq!(block_on(cell.get_or_init(|| async { 62 })), &92);
+        assert_eq!(cell.into_inner(), Some(92));
+    }
+
+    #[test]
+    fn stampede() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static CELL: AsyncOnceLock<usize> = AsyncOnceLock::new();
+
+        let threads: Vec<_> = (0..10)
+            .map(|i| {
+                thread::spawn(move || {
+                    *block_on(CELL.get_or_init(|| async move {
+                        CALLS.fetch_add(1, Ordering::Relaxed);
+                        YieldNow(false).await;
+                        i
+                    }))
+                })
+            })
+            .collect();
+
+        let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(values.iter().all(|&value| value == values[0]));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn get_or_try_init_error() {
+        let cell = AsyncOnceLock::new();
+        let result = block_on(cell.get_or_try_init(|| async {
+            YieldNow(false).await;
+            Err("failed")
+        }));
+        assert_eq!(result, Err("failed"));
+        assert_eq!(cell.get(), None);
+
+        let result = block_on(cell.get_or_try_init(|| async { Ok::<_, ()>(1) }));
+        assert_eq!(result, Ok(&1));
+    }
+
+    #[test]
+    fn cancelled_init() {
+        let cell = AsyncOnceLock::new();
+
+        // Start initializing and drop the future while it's running.
+        let mut init = Box::pin(cell.get_or_init(|| async {
+            YieldNow(false).await;
+            1
+        }));
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        assert!(init
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        drop(init);
+
+        assert_eq!(block_on(cell.get_or_init(|| async { 2 })), &2);
+    }
+
+    #[test]
+    fn test_async_once_lock_debug() {
+        let cell = AsyncOnceLock::new();
+        assert_eq!(format!("{:?}", cell), "AsyncOnceLock(<uninit>)");
+        block_on(cell.get_or_init(|| async { 5 }));
+        assert_eq!(format!("{:?}", cell), "AsyncOnceLock(5)");
+    }
+}
@@ -0,0 +1,226 @@
+use super::{
+    const_mutex,
+    shared::{Epoch, Primitive},
+    Mutex,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Arc,
+    },
+};
+
+/// An atomically replaceable [`Arc`], which can be loaded without taking any lock.
+///
+/// [`load`](AtomicArc::load) returns a new `Arc` to the current value in a bounded amount
+/// of steps, whatever writers are doing, and the returned snapshot stays valid for as long
+/// as the caller keeps it. Writers swap in a new `Arc` and only wait for the readers which
+/// are in the middle of a `load`, never for the snapshots they hold.
+///
+/// This fits publishing a pointer to shared data, like configuration, which hot paths read
+/// far more often than it's replaced, without the read contention of a
+/// [`RwLock`](type.RwLock.html)`<Arc<T>>`.
+///
+/// # Examples
+///
+/// ```
+/// use usync::AtomicArc;
+/// use std::sync::Arc;
+///
+/// let config = AtomicArc::new(Arc::new(String::from("v1")));
+/// let snapshot = config.load();
+///
+/// config.store(Arc::new(String::from("v2")));
+///
+/// // Snapshots aren't affected by later stores.
+/// assert_eq!(*snapshot, "v1");
+/// assert_eq!(*config.load(), "v2");
+/// ```
+pub struct AtomicArc<T> {
+    value: AtomicPtr<T>,
+    epoch: Epoch,
+    writer: Mutex<()>,
+    _marker: PhantomData<Arc<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for AtomicArc<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicArc<T> {}
+
+impl<T: Default> Default for AtomicArc<T> {
+    fn default() -> Self {
+        Self::new(Arc::default())
+    }
+}
+
+impl<T> From<Arc<T>> for AtomicArc<T> {
+    fn from(value: Arc<T>) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T> From<T> for AtomicArc<T> {
+    fn from(value: T) -> Self {
+        Self::new(Arc::new(value))
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AtomicArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtomicArc")
+            .field("data", &self.load())
+            .finish()
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Arc::from_raw(*self.value.get_mut()) });
+    }
+}
+
+impl<T> AtomicArc<T> {
+    /// Creates a new atomic `Arc` holding the given value.
+    pub fn new(value: Arc<T>) -> Self {
+        Self {
+            value: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            epoch: Epoch::new(),
+            writer: const_mutex(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Consumes the atomic `Arc`, returning the current value.
+    pub fn into_inner(mut self) -> Arc<T> {
+        let value = unsafe { Arc::from_raw(*self.value.get_mut()) };
+        std::mem::forget(self);
+        value
+    }
+
+    /// Returns a new `Arc` to the current value.
+    ///
+    /// This never blocks nor retries, and the returned `Arc` isn't affected by later stores.
+    pub fn load(&self) -> Arc<T> {
+        // Register as a reader before loading the value, so writers replacing it
+        // don't drop their reference before we took ours.
+        let parity = self.epoch.enter();
+
+        let value = self.value.load(Ordering::SeqCst);
+        let snapshot = unsafe {
+            Arc::increment_strong_count(value);
+            Arc::from_raw(value)
+        };
+
+        self.epoch.leave(parity);
+        snapshot
+    }
+
+    /// Replaces the value, dropping the previous `Arc`.
+    pub fn store(&self, value: Arc<T>) {
+        drop(self.swap(value));
+    }
+
+    /// Replaces the value, returning the previous `Arc`.
+    ///
+    /// This waits for the threads currently in the middle of a [`load`](AtomicArc::load)
+    /// to take their reference, but not for the snapshots they hold to be dropped.
+    pub fn swap(&self, value: Arc<T>) -> Arc<T> {
+        let _writer = self.writer.lock();
+        let old_value = self
+            .value
+            .swap(Arc::into_raw(value) as *mut T, Ordering::SeqCst);
+
+        // Wait for the loads which could have seen the old value to take their reference.
+        self.epoch.synchronize(Primitive::new("AtomicArc", self));
+        unsafe { Arc::from_raw(old_value) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AtomicArc;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let value = Arc::new(1);
+        let atomic = AtomicArc::new(value.clone());
+        assert!(Arc::ptr_eq(&atomic.load(), &value));
+        assert_eq!(Arc::strong_count(&value), 2);
+
+        let old = atomic.swap(Arc::new(2));
+        assert!(Arc::ptr_eq(&old, &value));
+        drop(old);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        atomic.store(Arc::new(3));
+        assert_eq!(format!("{:?}", atomic), "AtomicArc { data: 3 }");
+        assert_eq!(*atomic.into_inner(), 3);
+    }
+
+    #[test]
+    fn snapshot_outlives_store() {
+        let atomic = AtomicArc::from(String::from("old"));
+        let snapshot = atomic.load();
+        atomic.store(Arc::new(String::from("new")));
+        assert_eq!(*snapshot, "old");
+        assert_eq!(Arc::strong_count(&snapshot), 1);
+        assert_eq!(*atomic.load(), "new");
+    }
+
+    #[test]
+    fn concurrent_stores() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        struct Value(usize, usize);
+        impl Drop for Value {
+            fn drop(&mut self) {
+                assert_eq!(self.0, self.1);
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let atomic = Arc::new(AtomicArc::from(Value(0, 0)));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let atomic = atomic.clone();
+                thread::spawn(move || {
+                    while !DONE.load(Ordering::Relaxed) {
+                        let value = atomic.load();
+                        assert_eq!(value.0, value.1);
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let atomic = atomic.clone();
+                thread::spawn(move || {
+                    for i in 1..=1000 {
+                        atomic.store(Arc::new(Value(i, i)));
+                    }
+                })
+            })
+            .collect();
+
+        for t in writers {
+            t.join().unwrap();
+        }
+        DONE.store(true, Ordering::Relaxed);
+        for t in readers {
+            t.join().unwrap();
+        }
+
+        assert_eq!(atomic.load().0, 1000);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 2000);
+    }
+}
@@ -0,0 +1,335 @@
+use super::RawSpinLock;
+use lock_api::RawMutex as _RawMutex;
+use std::{
+    cell::UnsafeCell,
+    fmt, mem, ptr,
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+
+/// A thread-safe mutable memory location for small `Copy` values.
+///
+/// When `T` has the size and alignment of a native atomic integer, every operation is a
+/// single atomic instruction on it. Otherwise, operations are serialized by a spin lock
+/// picked from a global table according to the cell's address, which keeps the cell
+/// exactly as large as `T`. [`is_lock_free`](AtomicCell::is_lock_free) tells which
+/// implementation a given `T` uses.
+///
+/// All operations use sequentially consistent ordering.
+///
+/// # Examples
+///
+/// ```
+/// use usync::AtomicCell;
+///
+/// let cell = AtomicCell::new(7u32);
+/// assert!(AtomicCell::<u32>::is_lock_free());
+///
+/// assert_eq!(cell.swap(8), 7);
+/// assert_eq!(cell.compare_exchange(8, 9), Ok(8));
+/// assert_eq!(cell.compare_exchange(8, 10), Err(9));
+/// assert_eq!(cell.load(), 9);
+/// ```
+#[repr(transparent)]
+pub struct AtomicCell<T> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for AtomicCell<T> {}
+unsafe impl<T: Send> Sync for AtomicCell<T> {}
+
+impl<T: Copy + Default> Default for AtomicCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for AtomicCell<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for AtomicCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtomicCell")
+            .field("value", &self.load())
+            .finish()
+    }
+}
+
+/// An atomic integer which values of the same size and at least the same alignment
+/// can be reinterpreted as.
+///
+/// The methods are unsafe as `T` must have the size of the integer.
+trait Native {
+    unsafe fn load_as<T>(&self) -> T;
+    unsafe fn store_as<T>(&self, value: T);
+    unsafe fn swap_as<T>(&self, value: T) -> T;
+    unsafe fn compare_exchange_as<T>(&self, current: T, new: T) -> Result<T, T>;
+}
+
+macro_rules! impl_native {
+    ($($atomic:ty => $bits:ty),*) => {$(
+        impl Native for $atomic {
+            unsafe fn load_as<T>(&self) -> T {
+                mem::transmute_copy(&self.load(Ordering::SeqCst))
+            }
+
+            unsafe fn store_as<T>(&self, value: T) {
+                self.store(mem::transmute_copy(&value), Ordering::SeqCst)
+            }
+
+            unsafe fn swap_as<T>(&self, value: T) -> T {
+                let bits: $bits = self.swap(mem::transmute_copy(&value), Ordering::SeqCst);
+                mem::transmute_copy(&bits)
+            }
+
+            unsafe fn compare_exchange_as<T>(&self, current: T, new: T) -> Result<T, T> {
+                match self.compare_exchange(
+                    mem::transmute_copy(&current),
+                    mem::transmute_copy(&new),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(bits) => Ok(mem::transmute_copy(&bits)),
+                    Err(bits) => Err(mem::transmute_copy(&bits)),
+                }
+            }
+        }
+    )*};
+}
+
+impl_native!(AtomicU8 => u8, AtomicU16 => u16, AtomicU32 => u32, AtomicUsize => usize);
+
+/// Runs `$native` with `$atomic` bound to the cell reinterpreted as the first fitting
+/// native atomic, or `$fallback` if there is none.
+macro_rules! dispatch {
+    ($cell:expr, |$atomic:ident| $native:expr, || $fallback:expr) => {
+        if let Some($atomic) = $cell.native::<AtomicU8>() {
+            $native
+        } else if let Some($atomic) = $cell.native::<AtomicU16>() {
+            $native
+        } else if let Some($atomic) = $cell.native::<AtomicU32>() {
+            $native
+        } else if let Some($atomic) = $cell.native::<AtomicUsize>() {
+            $native
+        } else {
+            $fallback
+        }
+    };
+}
+
+/// Returns true if values of `T` can be reinterpreted as the atomic `A`.
+const fn fits<T, A>() -> bool {
+    mem::size_of::<T>() == mem::size_of::<A>() && mem::align_of::<T>() >= mem::align_of::<A>()
+}
+
+impl<T> AtomicCell<T> {
+    /// Creates a new atomic cell holding the given value.
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the cell, returning the value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Returns a mutable reference to the value.
+    ///
+    /// Since this call borrows the cell mutably, no atomic operations need to take place.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Returns true if operations on the cell are native atomic instructions
+    /// instead of going through a lock.
+    pub const fn is_lock_free() -> bool {
+        fits::<T, AtomicU8>()
+            || fits::<T, AtomicU16>()
+            || fits::<T, AtomicU32>()
+            || fits::<T, AtomicUsize>()
+    }
+
+    /// Returns the native atomic the cell can be reinterpreted as, if `A` fits `T`.
+    fn native<A: Native>(&self) -> Option<&A> {
+        match fits::<T, A>() {
+            true => Some(unsafe { &*(self.value.get() as *const A) }),
+            false => None,
+        }
+    }
+
+    /// Locks the spin lock protecting the cell when it isn't lock free.
+    fn lock(&self) -> LockGuard {
+        const LOCKS: usize = 64;
+        #[allow(clippy::declare_interior_mutable_const)]
+        const UNLOCKED: RawSpinLock = RawSpinLock::INIT;
+        static TABLE: [RawSpinLock; LOCKS] = [UNLOCKED; LOCKS];
+
+        // Fibonacci hashing spreads neighbouring addresses over the locks.
+        let hash = (self.value.get() as usize).wrapping_mul(0x9E3779B97F4A7C15u64 as usize);
+        let lock = &TABLE[hash >> (usize::BITS - LOCKS.trailing_zeros())];
+        lock.lock();
+        LockGuard(lock)
+    }
+}
+
+impl<T: Copy> AtomicCell<T> {
+    /// Stores a value into the cell.
+    pub fn store(&self, value: T) {
+        dispatch!(self, |atomic| unsafe { atomic.store_as(value) }, || {
+            let _guard = self.lock();
+            unsafe { ptr::write(self.value.get(), value) };
+        })
+    }
+
+    /// Stores a value into the cell, returning the previous value.
+    pub fn swap(&self, value: T) -> T {
+        dispatch!(self, |atomic| unsafe { atomic.swap_as(value) }, || {
+            let _guard = self.lock();
+            unsafe { ptr::replace(self.value.get(), value) }
+        })
+    }
+
+    /// Loads the value from the cell.
+    pub fn load(&self) -> T {
+        dispatch!(self, |atomic| unsafe { atomic.load_as() }, || {
+            let _guard = self.lock();
+            unsafe { ptr::read(self.value.get()) }
+        })
+    }
+
+    /// Stores `new` into the cell if it currently holds `current`.
+    ///
+    /// Returns the previous value, wrapped in `Ok` if it was replaced and in `Err` otherwise.
+    /// Values are compared by their bytes rather than with `PartialEq`, so types with padding
+    /// bytes can fail to compare equal.
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        dispatch!(
+            self,
+            |atomic| unsafe { atomic.compare_exchange_as(current, new) },
+            || {
+                let _guard = self.lock();
+                let previous = unsafe { ptr::read(self.value.get()) };
+                match bytes_eq(&previous, &current) {
+                    true => {
+                        unsafe { ptr::write(self.value.get(), new) };
+                        Ok(previous)
+                    }
+                    false => Err(previous),
+                }
+            }
+        )
+    }
+
+    /// Replaces the value with the one returned by `f`, retrying if another thread
+    /// modified it concurrently.
+    ///
+    /// Returns the previous value if `f` returned `Some`, or `Err` with the current value
+    /// if it returned `None`.
+    pub fn fetch_update(&self, mut f: impl FnMut(T) -> Option<T>) -> Result<T, T> {
+        let mut previous = self.load();
+        while let Some(new) = f(previous) {
+            match self.compare_exchange(previous, new) {
+                Ok(previous) => return Ok(previous),
+                Err(current) => previous = current,
+            }
+        }
+        Err(previous)
+    }
+}
+
+impl<T: Copy + Default> AtomicCell<T> {
+    /// Takes the value of the cell, leaving `T::default()` in its place.
+    pub fn take(&self) -> T {
+        self.swap(T::default())
+    }
+}
+
+fn bytes_eq<T>(a: &T, b: &T) -> bool {
+    let size = mem::size_of::<T>();
+    unsafe {
+        let a = std::slice::from_raw_parts(a as *const T as *const u8, size);
+        let b = std::slice::from_raw_parts(b as *const T as *const u8, size);
+        a == b
+    }
+}
+
+struct LockGuard(&'static RawSpinLock);
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        unsafe { self.0.unlock() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AtomicCell;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn is_lock_free() {
+        assert!(AtomicCell::<u8>::is_lock_free());
+        assert!(AtomicCell::<u32>::is_lock_free());
+        assert!(AtomicCell::<usize>::is_lock_free());
+        assert!(AtomicCell::<Option<&u8>>::is_lock_free());
+        assert!(!AtomicCell::<[u8; 3]>::is_lock_free());
+        assert!(!AtomicCell::<[usize; 4]>::is_lock_free());
+    }
+
+    #[test]
+    fn native() {
+        let mut cell = AtomicCell::new(1u16);
+        assert_eq!(cell.load(), 1);
+        cell.store(2);
+        assert_eq!(cell.swap(3), 2);
+        assert_eq!(cell.compare_exchange(3, 4), Ok(3));
+        assert_eq!(cell.compare_exchange(3, 5), Err(4));
+        assert_eq!(cell.fetch_update(|v| Some(v * 2)), Ok(4));
+        assert_eq!(cell.take(), 8);
+        *cell.get_mut() += 1;
+        assert_eq!(format!("{:?}", cell), "AtomicCell { value: 1 }");
+        assert_eq!(cell.into_inner(), 1);
+    }
+
+    #[test]
+    fn locked() {
+        let cell = AtomicCell::new([1u64; 4]);
+        assert_eq!(cell.load(), [1; 4]);
+        cell.store([2; 4]);
+        assert_eq!(cell.swap([3; 4]), [2; 4]);
+        assert_eq!(cell.compare_exchange([3; 4], [4; 4]), Ok([3; 4]));
+        assert_eq!(cell.compare_exchange([3; 4], [5; 4]), Err([4; 4]));
+        assert_eq!(cell.take(), [4; 4]);
+        assert_eq!(cell.into_inner(), [0; 4]);
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        const THREADS: u64 = 4;
+        const ITERS: u64 = 1000;
+
+        let native = Arc::new(AtomicCell::new(0u32));
+        let locked = Arc::new(AtomicCell::new((0u64, 0u64)));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (native, locked) = (native.clone(), locked.clone());
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        native.fetch_update(|v| Some(v + 1)).unwrap();
+                        locked.fetch_update(|(a, b)| Some((a + 1, b + 2))).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(native.load(), (THREADS * ITERS) as u32);
+        assert_eq!(locked.load(), (THREADS * ITERS, THREADS * ITERS * 2));
+    }
+}
@@ -0,0 +1,247 @@
+//! Futex-style waiting and waking on atomic integers.
+//!
+//! These are the building blocks for custom synchronization primitives: a thread
+//! [`wait`]s while an atomic still holds an expected value, and another thread changes
+//! the value then calls [`wake_one`] or [`wake_all`] on it.
+//!
+//! On Linux and Android this uses the `futex` system call directly. On other platforms,
+//! waiting threads are [parked](crate::parking) using the atomic's address as the key.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::atomic_wait;
+//! use std::sync::atomic::{AtomicU32, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! let ready = Arc::new(AtomicU32::new(0));
+//!
+//! let t = {
+//!     let ready = ready.clone();
+//!     thread::spawn(move || {
+//!         // Wait may return spuriously, so re-check the value in a loop.
+//!         while ready.load(Ordering::Acquire) == 0 {
+//!             atomic_wait::wait(&ready, 0);
+//!         }
+//!     })
+//! };
+//!
+//! ready.store(1, Ordering::Release);
+//! atomic_wait::wake_all(&ready);
+//! t.join().unwrap();
+//! ```
+
+use std::{sync::atomic::AtomicU32, time::Duration};
+
+/// Blocks the current thread while `atomic` holds the `expected` value,
+/// until another thread calls [`wake_one`] or [`wake_all`] on it.
+///
+/// The value is compared atomically with going to sleep, so a wake up after changing
+/// the value can't be missed. This may return spuriously, without a wake up or the
+/// value changing, so it should be called in a loop which re-checks the value.
+#[inline]
+pub fn wait(atomic: &AtomicU32, expected: u32) {
+    sys::wait(atomic, expected, None);
+}
+
+/// Blocks the current thread like [`wait`], but for at most `timeout`.
+///
+/// Returns false if the timeout elapsed before the thread was woken up.
+/// Like `wait`, this may return true spuriously.
+#[inline]
+pub fn wait_timeout(atomic: &AtomicU32, expected: u32, timeout: Duration) -> bool {
+    sys::wait(atomic, expected, Some(timeout))
+}
+
+/// Wakes up one of the threads blocked in [`wait`] on `atomic`, if any.
+#[inline]
+pub fn wake_one(atomic: &AtomicU32) {
+    sys::wake(atomic, false);
+}
+
+/// Wakes up all the threads blocked in [`wait`] on `atomic`.
+#[inline]
+pub fn wake_all(atomic: &AtomicU32) {
+    sys::wake(atomic, true);
+}
+
+/// Like [`wait`], but also woken up by threads of other processes sharing the memory.
+pub(crate) fn wait_shared(atomic: &AtomicU32, expected: u32) {
+    sys::wait_shared(atomic, expected);
+}
+
+/// Like [`wake_one`] and [`wake_all`], but also wakes up threads of other processes.
+///
+/// Returns true if a thread may have been woken up.
+pub(crate) fn wake_shared(atomic: &AtomicU32, all: bool) -> bool {
+    sys::wake_shared(atomic, all)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::{io, ptr, sync::atomic::AtomicU32, time::Duration};
+
+    pub(super) fn wait(atomic: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+        futex_wait(
+            atomic,
+            expected,
+            timeout,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+        )
+    }
+
+    pub(super) fn wake(atomic: &AtomicU32, all: bool) {
+        futex_wake(atomic, all, libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG);
+    }
+
+    // Without FUTEX_PRIVATE_FLAG, the kernel keys waiters by the physical page of the atomic,
+    // so threads mapping the same memory in different processes wait on the same futex.
+    pub(super) fn wait_shared(atomic: &AtomicU32, expected: u32) {
+        futex_wait(atomic, expected, None, libc::FUTEX_WAIT);
+    }
+
+    pub(super) fn wake_shared(atomic: &AtomicU32, all: bool) -> bool {
+        futex_wake(atomic, all, libc::FUTEX_WAKE) != 0
+    }
+
+    fn futex_wait(
+        atomic: &AtomicU32,
+        expected: u32,
+        timeout: Option<Duration>,
+        op: libc::c_int,
+    ) -> bool {
+        // Timeouts too large for a timespec are treated as waiting forever.
+        let timeout = timeout.and_then(|timeout| {
+            Some(libc::timespec {
+                tv_sec: timeout.as_secs().try_into().ok()?,
+                tv_nsec: timeout.subsec_nanos() as _,
+            })
+        });
+
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                atomic as *const AtomicU32,
+                op,
+                expected,
+                timeout.as_ref().map_or(ptr::null(), |ts| ts as *const _),
+            )
+        };
+
+        // EAGAIN (value mismatch) and EINTR are reported as (spurious) wake ups.
+        rc >= 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ETIMEDOUT)
+    }
+
+    /// Returns the amount of threads woken up.
+    fn futex_wake(atomic: &AtomicU32, all: bool, op: libc::c_int) -> libc::c_long {
+        let count = if all { i32::MAX } else { 1 };
+        unsafe { libc::syscall(libc::SYS_futex, atomic as *const AtomicU32, op, count) }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use crate::{
+        parking::{self, ParkResult},
+        shared::Primitive,
+    };
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    /// How long threads waiting on memory shared with other processes sleep between polls.
+    const SHARED_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+    pub(super) fn wait(atomic: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+        // Wakers change the value before unparking, so checking it with the queue locked
+        // ensures we can't miss their wake up.
+        let key = atomic as *const AtomicU32 as usize;
+        let validate = || atomic.load(Ordering::Relaxed) == expected;
+        let primitive = Primitive::at("atomic_wait", key);
+        parking::park_on(primitive, key, validate, timeout) != ParkResult::TimedOut
+    }
+
+    pub(super) fn wake(atomic: &AtomicU32, all: bool) {
+        let key = atomic as *const AtomicU32 as usize;
+        if all {
+            parking::unpark_all(key);
+        } else {
+            parking::unpark_one(key);
+        }
+    }
+
+    // Threads of other processes can't be unparked, so waiters poll the value instead.
+    pub(super) fn wait_shared(atomic: &AtomicU32, expected: u32) {
+        if atomic.load(Ordering::Relaxed) == expected {
+            thread::sleep(SHARED_POLL_INTERVAL);
+        }
+    }
+
+    pub(super) fn wake_shared(_atomic: &AtomicU32, _all: bool) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wait, wait_timeout, wake_all, wake_one};
+    use std::{
+        sync::{
+            atomic::{AtomicU32, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn value_mismatch() {
+        let atomic = AtomicU32::new(1);
+        wait(&atomic, 0);
+        assert!(wait_timeout(&atomic, 0, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn timeout() {
+        let atomic = AtomicU32::new(0);
+        let timeout = Duration::from_millis(50);
+        let started = Instant::now();
+        while wait_timeout(&atomic, 0, timeout) {
+            assert!(started.elapsed() < Duration::from_secs(60));
+        }
+        assert!(started.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn wake_threads() {
+        const THREADS: usize = 4;
+        let atomic = Arc::new(AtomicU32::new(0));
+        let woken = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (atomic, woken) = (atomic.clone(), woken.clone());
+                thread::spawn(move || {
+                    while atomic.load(Ordering::Acquire) == 0 {
+                        wait(&atomic, 0);
+                    }
+                    woken.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        wake_one(&atomic);
+        assert_eq!(woken.load(Ordering::Relaxed), 0);
+
+        atomic.store(1, Ordering::Release);
+        wake_all(&atomic);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(woken.load(Ordering::Relaxed), THREADS);
+    }
+}
@@ -1,14 +1,25 @@
-use crate::shared::{fence_acquire, invalid_mut, StrictProvenance, Waiter};
+use crate::shared::{
+    self, chaos, fence_acquire, invalid_mut, Primitive, SpinWait, StrictProvenance, Waiter,
+};
 use std::{
     fmt,
+    pin::Pin,
     ptr::{self, NonNull},
     sync::atomic::{AtomicPtr, Ordering},
+    time::Duration,
 };
 
 const QUEUED: usize = 1;
 const QUEUE_LOCKED: usize = 2;
 const COMPLETED: usize = 0;
-const COUNT_SHIFT: u32 = QUEUED.trailing_zeros();
+const COUNT_SHIFT: u32 = (QUEUED | QUEUE_LOCKED).count_ones();
+
+// The queue bits are stored in the low bits of the queue head's address.
+const _: () = assert!((QUEUED | QUEUE_LOCKED) & Waiter::MASK == 0);
+
+/// Set in the flags of the waiter chosen as leader when the thread completing
+/// the Barrier isn't waiting on it anymore.
+const WAITER_LEADER: usize = 1;
 
 /// A barrier enables multiple threads to synchronize the beginning
 /// of some computation.
@@ -65,7 +76,16 @@ unsafe impl Sync for Barrier {}
 
 impl fmt::Debug for Barrier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Barrier").finish_non_exhaustive()
+        let state = self.state.load(Ordering::Relaxed).address();
+        let mut debug = f.debug_struct("Barrier");
+
+        // The count is moved to the queue's tail once threads are waiting,
+        // and is 0 once the barrier was completed.
+        if state & QUEUED == 0 {
+            debug.field("remaining", &(state >> COUNT_SHIFT));
+        }
+
+        debug.field("has_waiters", &(state & QUEUED != 0)).finish()
     }
 }
 
@@ -75,6 +95,11 @@ impl Barrier {
     /// A barrier will block `n`-1 threads which call [`wait()`] and then wake
     /// up all threads at once when the `n`th thread calls [`wait()`].
     ///
+    /// # Panics
+    ///
+    /// Panics if `n` doesn't fit in the barrier's state, which only holds
+    /// `usize::MAX >> 2` threads.
+    ///
     /// [`wait()`]: Barrier::wait
     ///
     /// # Examples
@@ -86,6 +111,8 @@ impl Barrier {
     /// ```
     #[must_use]
     pub const fn new(n: usize) -> Self {
+        // The count shares the state with the queue bits, which matters on 16-bit targets.
+        assert!(n <= usize::MAX >> COUNT_SHIFT, "barrier count too large");
         let state = invalid_mut(n << COUNT_SHIFT);
         Self {
             state: AtomicPtr::new(state),
@@ -94,8 +121,8 @@ impl Barrier {
 
     /// Blocks the current thread until all threads have rendezvoused here.
     ///
-    /// Barriers are re-usable after all threads have rendezvoused once, and can
-    /// be used continuously.
+    /// Unlike the barrier of the standard library, this one completes only once: after all
+    /// threads have rendezvoused, later calls return right away without being the leader.
     ///
     /// A single (arbitrary) thread will receive a [`BarrierWaitResult`] that
     /// returns `true` from [`BarrierWaitResult::is_leader()`] when returning
@@ -134,24 +161,152 @@ impl Barrier {
         // Acquire barrier to ensure Barrier completions happens before we return.
         let state = self.state.load(Ordering::Acquire);
         if state.address() != COMPLETED {
-            is_leader = self.wait_slow(state);
+            is_leader = self
+                .wait_slow(state, None)
+                .expect("Barrier wait without a timeout timed out");
         }
 
         BarrierWaitResult(is_leader)
     }
 
+    /// Blocks the current thread until all threads have rendezvoused here,
+    /// or until the `timeout` elapses.
+    ///
+    /// Returns `None` if the timeout elapsed before the barrier completed. The timed out
+    /// thread no longer counts towards the barrier, which then needs another thread
+    /// to call [`wait()`](Barrier::wait) to complete in its place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Barrier;
+    /// use std::time::Duration;
+    ///
+    /// let barrier = Barrier::new(2);
+    /// assert!(barrier.wait_timeout(Duration::from_millis(10)).is_none());
+    /// ```
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<BarrierWaitResult> {
+        // Acquire barrier to ensure Barrier completions happens before we return.
+        let state = self.state.load(Ordering::Acquire);
+        if state.address() == COMPLETED {
+            return Some(BarrierWaitResult(false));
+        }
+
+        self.wait_slow(state, Some(timeout)).map(BarrierWaitResult)
+    }
+
+    /// Arrives at the barrier without waiting for it to complete, leaving the participants.
+    ///
+    /// This counts towards the barrier like a call to [`wait()`](Barrier::wait) which would
+    /// return right away, so a participant which stops early doesn't leave the remaining ones
+    /// waiting for it forever. If this completes the barrier, one of the waiting threads
+    /// becomes the leader, if there is any.
+    ///
+    /// Like [`wait()`](Barrier::wait), this returns right away once the barrier completed,
+    /// as there is no later rendezvous to leave. For worker pools which synchronize repeatedly
+    /// and shrink between phases, see
+    /// [`Phaser::arrive_and_deregister`](crate::Phaser::arrive_and_deregister).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Barrier;
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let barrier = Arc::new(Barrier::new(3));
+    /// let handles: Vec<_> = (0..2)
+    ///     .map(|_| {
+    ///         let barrier = barrier.clone();
+    ///         thread::spawn(move || barrier.wait().is_leader())
+    ///     })
+    ///     .collect();
+    ///
+    /// // The third participant has nothing to do, so it shouldn't hold up the others.
+    /// barrier.arrive_and_drop();
+    ///
+    /// let leaders = handles.into_iter().map(|h| h.join().unwrap());
+    /// assert_eq!(leaders.filter(|&is_leader| is_leader).count(), 1);
+    /// ```
+    pub fn arrive_and_drop(&self) {
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            if state.address() == COMPLETED {
+                return;
+            }
+
+            // Without waiters, the count is in the state. Counting the last thread completes it.
+            // Release barrier ensures our writes happen before the waiting threads return.
+            if state.address() & QUEUED == 0 {
+                let count = state.address() >> COUNT_SHIFT;
+                let new_state = state.with_address((count - 1) << COUNT_SHIFT);
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            // The QUEUE_LOCKED bit holder never blocks so wait for it to finish.
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    state = new_state;
+                    break;
+                }
+                Err(e) => state = e,
+            }
+        }
+
+        unsafe {
+            // Account for ourselves along with the discovered waiters like link_queue_or_complete().
+            // Acquire barrier to ensure writes to waiters pushed to the queue happen before we start fixing it.
+            fence_acquire(&self.state);
+            let mut discovered = 0;
+            let (_, tail) = Waiter::get_and_link_queue(state, |_| discovered += 1);
+
+            let counter = tail.as_ref().counter.load(Ordering::Relaxed);
+            let counter = counter.saturating_sub(discovered + 1);
+            tail.as_ref().counter.store(counter, Ordering::Relaxed);
+
+            // Completes the barrier with one of the waiters as leader if the count hit zero.
+            self.link_queue_or_complete(state, false);
+        }
+    }
+
     #[cold]
-    fn wait_slow(&self, mut state: *mut Waiter) -> bool {
-        Waiter::with(|waiter| {
+    fn wait_slow(&self, mut state: *mut Waiter, timeout: Option<Duration>) -> Option<bool> {
+        Waiter::with(Primitive::new("Barrier", self), |waiter| {
             waiter.waiting_on.set(None);
             waiter.prev.set(None);
+            waiter.flags.set(0);
 
             loop {
                 // If the queue became completed, return that we are not the leader.
                 // Acqire barrier to ensure the queue completion happens before we return.
                 if state.address() == COMPLETED {
                     fence_acquire(&self.state);
-                    return false;
+                    return Some(false);
                 }
 
                 // Special case to complete the queue if there's only an n=1.
@@ -165,7 +320,7 @@ impl Barrier {
                         Ordering::Release,
                         Ordering::Relaxed,
                     ) {
-                        Ok(_) => return true,
+                        Ok(_) => return Some(true),
                         Err(e) => state = e,
                     }
                     continue;
@@ -218,25 +373,159 @@ impl Barrier {
                 if (state.address() & QUEUED != 0) && (state.address() & QUEUE_LOCKED == 0) {
                     // If we manage to complete the Barrier, return is_leader=true here.
                     // SAFETY: we hold the QUEUE_LOCKED bit now.
-                    if unsafe { self.link_queue_or_complete(new_state) } {
-                        return true;
+                    if unsafe { self.link_queue_or_complete(new_state, true) } {
+                        return Some(true);
                     }
                 }
 
                 // Wait until we're woken up with the barrier completed.
-                assert!(waiter.parker.park(None));
+                // On timeout, try to leave the queue. This can complete the barrier if other
+                // threads were still being counted, or fail if it was completed meanwhile.
+                if !waiter.parker.park(timeout) {
+                    match unsafe { self.remove_waiter(waiter) } {
+                        None => return None,
+                        Some(true) => return Some(true),
+                        Some(false) => assert!(waiter.parker.park(None)),
+                    }
+                }
 
                 // Ensure that once we're woken up, the barrier was completed.
                 // Acqire barrier to ensure the queue completion happens before we return.
                 state = self.state.load(Ordering::Acquire);
                 assert_eq!(state.address(), COMPLETED);
-                return false;
+                return Some(waiter.flags.get() & WAITER_LEADER != 0);
             }
         })
     }
 
+    /// Removes a timed out waiter from the queue, adding it back to the barrier count.
+    ///
+    /// Returns `None` if the waiter was removed, `Some(true)` if we completed the barrier
+    /// while linking the queue (and are the leader), or `Some(false)` if the barrier was already
+    /// completed by another thread which is going to wake us up.
     #[cold]
-    unsafe fn link_queue_or_complete(&self, mut state: *mut Waiter) -> bool {
+    unsafe fn remove_waiter(&self, waiter: Pin<&Waiter>) -> Option<bool> {
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            // We're still queued unless the Barrier got completed.
+            if state.address() == COMPLETED {
+                return Some(false);
+            }
+
+            // The QUEUE_LOCKED bit holder never blocks so wait for it to finish.
+            assert_ne!(state.address() & QUEUED, 0);
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    state = new_state;
+                    break;
+                }
+                Err(e) => state = e,
+            }
+        }
+
+        let target = NonNull::from(&*waiter);
+        let counter = loop {
+            // Link the queue and account for newly pushed waiters like link_queue_or_complete().
+            // Acquire barrier to ensure writes to waiters pushed to the queue happen before we start fixing it.
+            fence_acquire(&self.state);
+            let mut discovered = 0;
+            let (head, tail) = Waiter::get_and_link_queue(state, |_| discovered += 1);
+
+            let counter = tail.as_ref().counter.load(Ordering::Relaxed);
+            let counter = counter.saturating_sub(discovered);
+            tail.as_ref().counter.store(counter, Ordering::Relaxed);
+
+            // We're still counted towards the barrier, so we can complete it as the leader.
+            if counter == 0 {
+                return Some(self.complete(true));
+            }
+
+            if target == head {
+                // Replace the head of the queue or, if we were the only waiter,
+                // move the counter back into the state (also releasing the QUEUE_LOCKED bit).
+                // This fails if a new waiter was pushed to the head, in which case we retry unlinking.
+                // Release barrier ensures the queue updates happen before the next QUEUE_LOCKED bit holder.
+                let new_state = match waiter.next.get().filter(|_| target != tail) {
+                    Some(new_head) => {
+                        new_head.as_ref().tail.set(Some(tail));
+                        new_head.as_ref().prev.set(None);
+                        new_head
+                            .as_ptr()
+                            .map_address(|addr| addr | QUEUED | QUEUE_LOCKED)
+                    }
+                    None => invalid_mut((counter + 1) << COUNT_SHIFT),
+                };
+
+                if let Err(e) = self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    state = e;
+                    continue;
+                }
+
+                if new_state.address() & QUEUED == 0 {
+                    return None;
+                }
+
+                state = new_state;
+                break counter;
+            }
+
+            if target == tail {
+                // The counter lives at the tail, so move it to the new one.
+                let new_tail = waiter
+                    .prev
+                    .get()
+                    .expect("queue tail without a previous waiter");
+                new_tail.as_ref().next.set(None);
+                head.as_ref().tail.set(Some(new_tail));
+            } else {
+                let prev = waiter
+                    .prev
+                    .get()
+                    .expect("queued waiter without a previous waiter");
+                let next = waiter
+                    .next
+                    .get()
+                    .expect("queued waiter without a next waiter");
+                prev.as_ref().next.set(Some(next));
+                next.as_ref().prev.set(Some(prev));
+            }
+
+            break counter;
+        };
+
+        // Add ourselves back to the count now that we're off the queue.
+        let (_, tail) = Waiter::get_and_link_queue(state, |_| {});
+        tail.as_ref().counter.store(counter + 1, Ordering::Relaxed);
+
+        // Release the QUEUE_LOCKED bit, accounting for threads which were pushed meanwhile.
+        // If they complete the barrier, one of them is chosen as leader since we timed out.
+        self.link_queue_or_complete(state, false);
+        None
+    }
+
+    #[cold]
+    unsafe fn link_queue_or_complete(&self, mut state: *mut Waiter, is_waiting: bool) -> bool {
         loop {
             assert_ne!(state.address() & QUEUED, 0);
             assert_ne!(state.address() & QUEUE_LOCKED, 0);
@@ -257,7 +546,7 @@ impl Barrier {
             // When the count hits zero, complete the barrier.
             tail.as_ref().counter.store(counter, Ordering::Relaxed);
             if counter == 0 {
-                return self.complete();
+                return self.complete(is_waiting);
             }
 
             // The barrier count isnt zero yet.
@@ -275,8 +564,10 @@ impl Barrier {
         }
     }
 
+    /// Completes the barrier and wakes up all the waiters, returning true.
+    /// If we aren't `is_waiting` on the barrier ourselves, one of the waiters is made the leader instead.
     #[cold]
-    unsafe fn complete(&self) -> bool {
+    unsafe fn complete(&self, is_waiting: bool) -> bool {
         // Complete the barrier while also dequeueing all the waiters.
         // AcqRel as Acquire barrier to ensure the writes to the pushed waiters happens before we iterate & wake them below.
         // AcqRel as Release barrier to ensure that the barrier completion happens before the wait() calls return.
@@ -286,19 +577,25 @@ impl Barrier {
         assert_ne!(state.address() & QUEUED, 0);
         assert_ne!(state.address() & QUEUE_LOCKED, 0);
 
-        let mut waiters = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
-        while let Some(waiter) = waiters {
-            waiters = waiter.as_ref().next.get();
-            waiter.as_ref().parker.unpark();
+        let waiters = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
+        if !is_waiting {
+            let leader = waiters.expect("Barrier completed without any waiters");
+            leader.as_ref().flags.set(WAITER_LEADER);
         }
 
-        // Since we completed the barrier, we are the leader.
-        true
+        chaos::wake_list(
+            waiters,
+            |waiter| waiter.next.get(),
+            |waiter| waiter.as_ref().parker.unpark(),
+        );
+
+        // Since we completed the barrier, we are the leader unless we gave it to another waiter.
+        is_waiting
     }
 }
 
-/// A `BarrierWaitResult` is returned by [`Barrier::wait()`] when all threads
-/// in the [`Barrier`] have rendezvoused.
+/// A `BarrierWaitResult` is returned by [`Barrier::wait()`] and [`Barrier::wait_timeout()`]
+/// when all threads in the [`Barrier`] have rendezvoused.
 ///
 /// # Examples
 ///
@@ -339,3 +636,156 @@ impl BarrierWaitResult {
         self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::Barrier;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn test_barrier() {
+        for n in 1..8 {
+            let barrier = Arc::new(Barrier::new(n));
+            let threads: Vec<_> = (0..n)
+                .map(|_| {
+                    let barrier = barrier.clone();
+                    thread::spawn(move || barrier.wait().is_leader())
+                })
+                .collect();
+
+            let leaders = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|&is_leader| is_leader)
+                .count();
+            assert_eq!(leaders, 1);
+            assert!(!barrier.wait().is_leader());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "barrier count too large")]
+    fn count_overflow() {
+        let _ = Barrier::new(usize::MAX);
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let barrier = Barrier::new(2);
+        assert!(barrier.wait_timeout(Duration::from_millis(10)).is_none());
+        assert!(barrier.wait_timeout(Duration::from_millis(10)).is_none());
+
+        // The timed out waiters were removed from the count.
+        let barrier = Arc::new(barrier);
+        let t = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait().is_leader())
+        };
+        let result = barrier.wait_timeout(Duration::from_secs(60)).unwrap();
+        assert_ne!(result.is_leader(), t.join().unwrap());
+    }
+
+    #[test]
+    fn wait_timeout_mixed() {
+        const THREADS: usize = 8;
+
+        // Half the threads time out, and are replaced by the other half waiting again.
+        for _ in 0..50 {
+            let barrier = Arc::new(Barrier::new(THREADS));
+            let threads: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        let timeout = Duration::from_micros((i as u64 % 2) * 100);
+                        match barrier.wait_timeout(timeout) {
+                            Some(result) => (result.is_leader(), false),
+                            None => (barrier.wait().is_leader(), true),
+                        }
+                    })
+                })
+                .collect();
+
+            let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+            let leaders = results.iter().filter(|(is_leader, _)| *is_leader).count();
+            assert_eq!(leaders, 1);
+        }
+    }
+
+    #[test]
+    fn arrive_and_drop() {
+        // Without waiters, arriving only updates the count.
+        let barrier = Barrier::new(2);
+        barrier.arrive_and_drop();
+        assert_eq!(
+            format!("{:?}", barrier),
+            "Barrier { remaining: 1, has_waiters: false }"
+        );
+        barrier.arrive_and_drop();
+        assert!(!barrier.wait().is_leader());
+
+        // The last arrival makes one of the waiters the leader.
+        let barrier = Arc::new(Barrier::new(2));
+        let t = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait().is_leader())
+        };
+        while !format!("{:?}", barrier).contains("has_waiters: true") {
+            thread::yield_now();
+        }
+        barrier.arrive_and_drop();
+        assert!(t.join().unwrap());
+
+        // Arriving before the barrier is complete leaves the waiters waiting.
+        let barrier = Arc::new(Barrier::new(3));
+        let t = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait().is_leader())
+        };
+        while !format!("{:?}", barrier).contains("has_waiters: true") {
+            thread::yield_now();
+        }
+        barrier.arrive_and_drop();
+        let result = barrier.wait_timeout(Duration::from_secs(60)).unwrap();
+        assert_ne!(result.is_leader(), t.join().unwrap());
+    }
+
+    #[test]
+    fn arrive_and_drop_after_completion() {
+        // Arriving completes the barrier, so there is no later rendezvous to wait for.
+        let barrier = Barrier::new(1);
+        barrier.arrive_and_drop();
+        assert!(!barrier.wait().is_leader());
+        assert!(!barrier
+            .wait_timeout(Duration::from_millis(1))
+            .unwrap()
+            .is_leader());
+
+        // Arriving again leaves the completed barrier as it is.
+        barrier.arrive_and_drop();
+        assert_eq!(format!("{:?}", barrier), format!("{:?}", Barrier::new(0)));
+        assert!(!barrier.wait().is_leader());
+    }
+
+    #[test]
+    fn test_debug_barrier() {
+        let barrier = Arc::new(Barrier::new(2));
+        assert_eq!(
+            format!("{:?}", barrier),
+            "Barrier { remaining: 2, has_waiters: false }"
+        );
+
+        let t = {
+            let barrier = barrier.clone();
+            thread::spawn(move || barrier.wait().is_leader())
+        };
+        while !format!("{:?}", barrier).contains("has_waiters: true") {
+            thread::yield_now();
+        }
+        barrier.wait();
+        t.join().unwrap();
+        assert_eq!(
+            format!("{:?}", barrier),
+            "Barrier { remaining: 0, has_waiters: false }"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use super::{
+    parking,
+    shared::{Primitive, SpinWait},
+};
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A lock kept in one or two bits of an `AtomicUsize` owned by the caller.
+///
+/// This is a building block for intrusive data structures which can't afford a separate lock
+/// field, like nodes with spare bits in an aligned pointer or a counter. The `BitLock` only
+/// describes which bits to use, and all of its methods operate on the given atomic, leaving
+/// its other bits untouched. Other threads may keep updating the other bits concurrently,
+/// as long as they do so atomically.
+///
+/// Waiting threads park in the process-global table of the [`parking`](crate::parking) module,
+/// keyed by the address of the atomic, so an atomic must only hold a single lock.
+/// With a second `parked` bit, the lock remembers whether threads may be parked and only looks
+/// them up in the table on contended unlocks. Without it, every unlock has to.
+///
+/// # Examples
+///
+/// ```
+/// use usync::BitLock;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// // A counter in the high bits, with its two lowest bits used for the lock.
+/// const LOCK: BitLock = BitLock::new(0b01, 0b10);
+/// const ONE: usize = 0b100;
+///
+/// let node = AtomicUsize::new(0);
+/// LOCK.lock(&node);
+/// node.fetch_add(ONE, Ordering::Relaxed);
+/// assert!(LOCK.is_locked(&node));
+/// unsafe { LOCK.unlock(&node) };
+///
+/// assert_eq!(node.load(Ordering::Relaxed), ONE);
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct BitLock {
+    locked: usize,
+    parked: usize,
+}
+
+impl fmt::Debug for BitLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BitLock")
+            .field("locked", &format_args!("{:#x}", self.locked))
+            .field("parked", &format_args!("{:#x}", self.parked))
+            .finish()
+    }
+}
+
+impl BitLock {
+    /// Creates a lock using the `locked` bit, and the `parked` bit to track waiting threads
+    /// unless it's 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `locked` or a non-zero `parked` don't have exactly one bit set, or if they're
+    /// the same bit.
+    pub const fn new(locked: usize, parked: usize) -> Self {
+        assert!(
+            locked.is_power_of_two(),
+            "the locked mask must be a single bit"
+        );
+        assert!(
+            parked == 0 || (parked.is_power_of_two() && parked != locked),
+            "the parked mask must be 0 or a single bit other than the locked one"
+        );
+        Self { locked, parked }
+    }
+
+    /// Returns true if the lock is held.
+    #[inline]
+    pub fn is_locked(&self, atomic: &AtomicUsize) -> bool {
+        atomic.load(Ordering::Relaxed) & self.locked != 0
+    }
+
+    /// Attempts to acquire the lock without blocking, returning whether it was acquired.
+    #[inline]
+    pub fn try_lock(&self, atomic: &AtomicUsize) -> bool {
+        atomic.fetch_or(self.locked, Ordering::Acquire) & self.locked == 0
+    }
+
+    /// Acquires the lock, blocking the current thread until it is able to do so.
+    #[inline]
+    pub fn lock(&self, atomic: &AtomicUsize) {
+        if !self.try_lock(atomic) {
+            self.lock_slow(atomic);
+        }
+    }
+
+    /// Releases the lock, waking up a thread waiting for it if there is any.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held, by the current thread or by a thread which handed it over.
+    #[inline]
+    pub unsafe fn unlock(&self, atomic: &AtomicUsize) {
+        let state = atomic.fetch_and(!(self.locked | self.parked), Ordering::Release);
+        if self.parked == 0 || state & self.parked != 0 {
+            self.unlock_slow(atomic);
+        }
+    }
+
+    #[cold]
+    fn lock_slow(&self, atomic: &AtomicUsize) {
+        let key = atomic as *const AtomicUsize as usize;
+        let contended = self.locked | self.parked;
+
+        // Spin for a bit while nobody is parked, in hopes that the lock is released soon.
+        let mut spin = SpinWait::default();
+        while atomic.load(Ordering::Relaxed) & self.parked == 0 && spin.try_yield_now() {
+            if !self.is_locked(atomic) && self.try_lock(atomic) {
+                return;
+            }
+        }
+
+        // Like RawByteMutex, a thread acquires the lock with the parked bit set once it started
+        // parking, as it can't tell whether other threads are still parked.
+        loop {
+            if atomic.fetch_or(contended, Ordering::Acquire) & self.locked == 0 {
+                return;
+            }
+
+            // Unlocking clears the bits before unparking, which our validation would see.
+            let validate = || atomic.load(Ordering::Relaxed) & contended == contended;
+            parking::park_on(Primitive::at("BitLock", key), key, validate, None);
+        }
+    }
+
+    #[cold]
+    fn unlock_slow(&self, atomic: &AtomicUsize) {
+        parking::unpark_one(atomic as *const AtomicUsize as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitLock;
+    use std::{
+        cell::UnsafeCell,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    struct Node {
+        state: AtomicUsize,
+        value: UnsafeCell<usize>,
+    }
+
+    unsafe impl Sync for Node {}
+
+    fn contended(lock: BitLock) {
+        const THREADS: usize = 4;
+        const ITERS: usize = 1000;
+        const OTHER: usize = 1 << 8;
+
+        let node = Arc::new(Node {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(0),
+        });
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let node = node.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        lock.lock(&node.state);
+                        unsafe { *node.value.get() += 1 };
+                        unsafe { lock.unlock(&node.state) };
+
+                        // The other bits can change concurrently without affecting the lock.
+                        node.state.fetch_add(OTHER, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(unsafe { *node.value.get() }, THREADS * ITERS);
+        assert_eq!(node.state.load(Ordering::Relaxed), THREADS * ITERS * OTHER);
+    }
+
+    #[test]
+    fn contended_with_parked_bit() {
+        contended(BitLock::new(1, 2));
+    }
+
+    #[test]
+    fn contended_single_bit() {
+        contended(BitLock::new(1 << 7, 0));
+    }
+
+    #[test]
+    fn try_lock() {
+        let lock = BitLock::new(1 << 3, 0);
+        let state = AtomicUsize::new(0b101);
+        assert!(lock.try_lock(&state));
+        assert!(!lock.try_lock(&state));
+        assert!(lock.is_locked(&state));
+        assert_eq!(state.load(Ordering::Relaxed), 0b1101);
+
+        unsafe { lock.unlock(&state) };
+        assert!(!lock.is_locked(&state));
+        assert_eq!(state.load(Ordering::Relaxed), 0b101);
+    }
+
+    #[test]
+    #[should_panic]
+    fn same_bits() {
+        let _ = BitLock::new(1, 1);
+    }
+}
@@ -0,0 +1,248 @@
+use super::{
+    parking,
+    shared::{Primitive, SpinWait},
+};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const UNLOCKED: u8 = 0;
+const LOCKED: u8 = 1;
+const PARKED: u8 = 2;
+
+/// Raw mutex type which only takes a single byte.
+///
+/// The other mutexes keep their queue of waiting threads in the lock word, which makes
+/// them pointer-sized. This one keeps its waiting threads in the process-global table of
+/// the [`parking`](crate::parking) module instead, keyed by the address of the byte, so
+/// that it can be embedded in every element of large arrays or in every node of a data
+/// structure without growing it much.
+///
+/// Waiting threads aren't woken up in any particular order, and a thread which was woken up
+/// assumes others are still waiting, so its unlock may do a needless lookup in the table.
+pub struct RawByteMutex {
+    state: AtomicU8,
+}
+
+impl Default for RawByteMutex {
+    fn default() -> Self {
+        <Self as lock_api::RawMutex>::INIT
+    }
+}
+
+impl fmt::Debug for RawByteMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.load(Ordering::Relaxed);
+        f.debug_struct("RawByteMutex")
+            .field("locked", &(state & LOCKED != 0))
+            .field("has_waiters", &(state & PARKED != 0))
+            .finish()
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawByteMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self {
+        state: AtomicU8::new(UNLOCKED),
+    };
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & LOCKED != 0
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & LOCKED == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state | LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(e) => state = e,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    fn lock(&self) {
+        if self
+            .state
+            .compare_exchange_weak(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        if self
+            .state
+            .compare_exchange(LOCKED, UNLOCKED, Ordering::Release, Ordering::Relaxed)
+            .is_err()
+        {
+            self.unlock_slow();
+        }
+    }
+}
+
+impl RawByteMutex {
+    fn key(&self) -> usize {
+        &self.state as *const AtomicU8 as usize
+    }
+
+    #[cold]
+    fn lock_slow(&self) {
+        // Spin for a bit while nobody is parked, in hopes that the mutex is released soon.
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & PARKED == 0 {
+            if state & LOCKED == 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            if !spin.try_yield_now() {
+                break;
+            }
+            state = self.state.load(Ordering::Relaxed);
+        }
+
+        // The state doesn't tell whether other threads are still parked after one is woken
+        // up, so once a thread parks, it acquires the mutex with the PARKED bit set.
+        // Its unlock then wakes up a thread, if there is any left, so none can be forgotten.
+        loop {
+            if self.state.swap(LOCKED | PARKED, Ordering::Acquire) & LOCKED == 0 {
+                return;
+            }
+
+            // Unlocking clears the state before unparking, which our validation would see.
+            let validate = || self.state.load(Ordering::Relaxed) == LOCKED | PARKED;
+            let primitive = Primitive::new("ByteMutex", self);
+            parking::park_on(primitive, self.key(), validate, None);
+        }
+    }
+
+    #[cold]
+    fn unlock_slow(&self) {
+        self.state.store(UNLOCKED, Ordering::Release);
+        parking::unpark_one(self.key());
+    }
+}
+
+/// A mutual exclusion primitive which only takes a single byte next to the protected data.
+///
+/// This type is a [`RawByteMutex`] wrapped in a [`lock_api::Mutex`]. It's meant for
+/// embedding one lock per element into large collections, where the word-sized
+/// [`Mutex`](type.Mutex.html) would take too much memory. Otherwise, prefer `Mutex` which
+/// doesn't need a global table of waiting threads.
+///
+/// # Examples
+///
+/// ```
+/// use usync::ByteMutex;
+///
+/// // One lock per slot, each taking a single byte (before padding for the data).
+/// let slots: Vec<ByteMutex<u8>> = (0..1024).map(|_| ByteMutex::new(0)).collect();
+/// assert_eq!(std::mem::size_of::<ByteMutex<u8>>(), 2);
+///
+/// *slots[42].lock() += 1;
+/// assert_eq!(*slots[42].lock(), 1);
+/// ```
+pub type ByteMutex<T> = lock_api::Mutex<RawByteMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a byte mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type ByteMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawByteMutex, T>;
+
+/// An RAII mutex guard returned by `ByteMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedByteMutexGuard` and `ByteMutexGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedByteMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawByteMutex, T>;
+
+/// Creates a new byte mutex in an unlocked state ready for use.
+///
+/// This allows creating a byte mutex in a constant context on stable Rust.
+pub const fn const_byte_mutex<T>(value: T) -> ByteMutex<T> {
+    ByteMutex::const_new(<RawByteMutex as lock_api::RawMutex>::INIT, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawByteMutex;
+    use crate::ByteMutex;
+    use std::{mem, sync::Arc, thread};
+
+    #[test]
+    fn smoke() {
+        let m = ByteMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 6;
+
+        let m = Arc::new(ByteMutex::new(0));
+        let threads: Vec<_> = (0..K)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for _ in 0..J {
+                        *m.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = ByteMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        assert!(m.is_locked());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn test_byte_mutex_size() {
+        assert_eq!(mem::size_of::<RawByteMutex>(), 1);
+        assert_eq!(mem::size_of::<[ByteMutex<u8>; 4]>(), 8);
+        assert_eq!(
+            format!("{:?}", RawByteMutex::default()),
+            "RawByteMutex { locked: false, has_waiters: false }"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use super::{
+    const_mutex,
+    shared::{Primitive, Waiter},
+    Mutex, RawRwLock,
+};
+use alloc::vec::Vec;
+use std::{
+    fmt,
+    pin::Pin,
+    ptr::NonNull,
+    sync::atomic::{fence, AtomicBool, Ordering},
+};
+
+/// A token used to abort blocking lock acquisitions from another thread.
+///
+/// Once [`cancel`](CancellationToken::cancel) is called, every acquisition blocked using
+/// this token (as well as those started afterwards) returns [`Cancelled`] instead of
+/// waiting for the lock.
+///
+/// # Examples
+///
+/// ```
+/// use usync::{CancellationToken, Mutex, MutexExt};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let mutex = Arc::new(Mutex::new(()));
+/// let token = Arc::new(CancellationToken::new());
+///
+/// let guard = mutex.lock();
+/// let t = {
+///     let (mutex, token) = (mutex.clone(), token.clone());
+///     thread::spawn(move || mutex.lock_with_cancel(&token).is_err())
+/// };
+///
+/// token.cancel();
+/// assert!(t.join().unwrap());
+/// drop(guard);
+/// ```
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+    waiters: Mutex<Vec<Registration>>,
+}
+
+struct Registration {
+    lock: NonNull<RawRwLock>,
+    waiter: NonNull<Waiter>,
+}
+
+// SAFETY: Registrations are only accessed while their waiters are blocked on the lock
+// and waiters only return after removing their registration from the token.
+unsafe impl Send for CancellationToken {}
+unsafe impl Sync for CancellationToken {}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl CancellationToken {
+    /// Creates a new token which isn't cancelled.
+    pub const fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            waiters: const_mutex(Vec::new()),
+        }
+    }
+
+    /// Returns true if [`cancel`](CancellationToken::cancel) was called on this token.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Cancels all lock acquisitions which are blocked using this token, and any future ones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+
+        // SeqCst fence pairs with the one in RawRwLock::lock_exclusive_cancellable() to ensure
+        // that either we see a registered waiter in the queue or it sees the cancellation.
+        fence(Ordering::SeqCst);
+
+        // Holding the waiters lock keeps the registered waiters from returning while we wake them.
+        let waiters = self.waiters.lock();
+        for registration in waiters.iter() {
+            unsafe {
+                let waiter = Pin::new_unchecked(registration.waiter.as_ref());
+                let _ = registration.lock.as_ref().cancel_waiter(waiter, true);
+            }
+        }
+    }
+
+    /// Acquires the RwLock exclusively, registering the waiter on the token
+    /// so that it can be woken up when cancelled.
+    pub(super) fn lock_exclusive(
+        &self,
+        lock: &RawRwLock,
+        primitive: Primitive,
+    ) -> Result<(), Cancelled> {
+        Waiter::with(primitive, |waiter| {
+            struct DropGuard<'a> {
+                token: &'a CancellationToken,
+                waiter: NonNull<Waiter>,
+            }
+
+            impl<'a> Drop for DropGuard<'a> {
+                fn drop(&mut self) {
+                    let mut waiters = self.token.waiters.lock();
+                    waiters.retain(|registration| registration.waiter != self.waiter);
+                }
+            }
+
+            let waiter_ptr = NonNull::from(&*waiter);
+            self.waiters.lock().push(Registration {
+                lock: NonNull::from(lock),
+                waiter: waiter_ptr,
+            });
+
+            let _drop_guard = DropGuard {
+                token: self,
+                waiter: waiter_ptr,
+            };
+
+            match lock.lock_exclusive_cancellable(waiter, || self.is_cancelled()) {
+                true => Ok(()),
+                false => Err(Cancelled),
+            }
+        })
+    }
+}
+
+/// An error returned when a lock acquisition was aborted through a [`CancellationToken`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("lock acquisition was cancelled")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CancellationToken, Cancelled, Mutex, MutexExt};
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn test_lock_with_cancel_uncontended() {
+        let m = Mutex::new(1);
+        let token = CancellationToken::new();
+        assert_eq!(*m.lock_with_cancel(&token).unwrap(), 1);
+
+        token.cancel();
+        assert!(m.lock_with_cancel(&token).is_ok());
+
+        let _guard = m.lock();
+        assert_eq!(m.lock_with_cancel(&token).unwrap_err(), Cancelled);
+    }
+
+    #[test]
+    fn test_lock_with_cancel_wakes_waiters() {
+        let m = Arc::new(Mutex::new(()));
+        let token = Arc::new(CancellationToken::new());
+        let guard = m.lock();
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (m, token) = (m.clone(), token.clone());
+                thread::spawn(move || m.lock_with_cancel(&token).is_err())
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        token.cancel();
+        for t in threads {
+            assert!(t.join().unwrap());
+        }
+
+        // The cancelled waiters must be unlinked from the mutex queue.
+        drop(guard);
+        drop(m.lock());
+    }
+
+    #[test]
+    fn test_lock_with_cancel_mixed_waiters() {
+        let m = Arc::new(Mutex::new(0));
+        let token = Arc::new(CancellationToken::new());
+        let guard = m.lock();
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (m, token) = (m.clone(), token.clone());
+                thread::spawn(move || {
+                    if i % 2 == 0 {
+                        *m.lock() += 1;
+                    } else {
+                        let _ = m.lock_with_cancel(&token).map(|mut g| *g += 1);
+                    }
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        token.cancel();
+        drop(guard);
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(*m.lock() >= 4);
+    }
+
+    #[test]
+    fn test_lock_with_cancel_stress() {
+        let m = Arc::new(Mutex::new(0));
+        for _ in 0..100 {
+            let token = Arc::new(CancellationToken::new());
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let (m, token) = (m.clone(), token.clone());
+                    thread::spawn(move || {
+                        while let Ok(mut guard) = m.lock_with_cancel(&token) {
+                            *guard += 1;
+                            if token.is_cancelled() {
+                                break;
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            thread::yield_now();
+            token.cancel();
+            for t in threads {
+                t.join().unwrap();
+            }
+        }
+        drop(m.lock());
+    }
+}
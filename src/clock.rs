@@ -0,0 +1,191 @@
+//! The clock measuring the timeouts of the primitives.
+//!
+//! Timeouts are measured with [`Instant::now`] unless a [`Clock`] was set for the current
+//! thread with [`set_thread_clock`]. A [`MockClock`] only moves forward when it's
+//! [advanced](MockClock::advance), which lets tests of timeout behavior run without
+//! actually waiting for the timeouts to elapse.
+//!
+//! The clock is per thread so that tests running in parallel don't affect each other.
+//! It's only used by the thread which waits: time outs are noticed by polling the clock
+//! every millisecond, so advancing it doesn't need to wake anything up.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::{sync::{mpsc, Arc}, thread, time::Duration};
+//! use usync::{clock::{self, MockClock}, Mutex};
+//!
+//! let clock = Arc::new(MockClock::new());
+//! let mutex = Arc::new(Mutex::new(()));
+//! let guard = mutex.lock();
+//!
+//! let (tx, rx) = mpsc::channel();
+//! thread::spawn({
+//!     let (clock, mutex) = (clock.clone(), mutex.clone());
+//!     move || {
+//!         clock::set_thread_clock(Some(clock));
+//!         tx.send(mutex.try_lock_for(Duration::from_secs(60)).is_none()).unwrap();
+//!     }
+//! });
+//!
+//! // Times out the waiter as soon as it started waiting instead of after a minute.
+//! let timed_out = loop {
+//!     clock.advance(Duration::from_secs(60));
+//!     if let Ok(timed_out) = rx.recv_timeout(Duration::from_millis(10)) {
+//!         break timed_out;
+//!     }
+//! };
+//! assert!(timed_out);
+//! drop(guard);
+//! ```
+
+use std::{
+    cell::RefCell,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// A source of the current time, used to measure timeouts.
+pub trait Clock: Send + Sync {
+    /// Returns the current time, which must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system, used by default.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves forward when it's advanced.
+pub struct MockClock {
+    start: Instant,
+    elapsed_ns: AtomicU64,
+}
+
+impl fmt::Debug for MockClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockClock")
+            .field("elapsed", &self.elapsed())
+            .finish()
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    /// Creates a clock starting at the current time of the system.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let duration_ns = duration.as_nanos().min(u64::MAX as u128) as u64;
+        let _ = self
+            .elapsed_ns
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |elapsed_ns| {
+                Some(elapsed_ns.saturating_add(duration_ns))
+            });
+    }
+
+    /// Returns how far the clock was advanced since it was created.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns.load(Ordering::Relaxed))
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+std::thread_local! {
+    static THREAD_CLOCK: RefCell<Option<Arc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+/// Sets the clock measuring the timeouts of the primitives on the current thread,
+/// or resets it to the [`SystemClock`] with `None`. Returns the previous one.
+pub fn set_thread_clock(clock: Option<Arc<dyn Clock>>) -> Option<Arc<dyn Clock>> {
+    THREAD_CLOCK.with(|thread_clock| thread_clock.replace(clock))
+}
+
+/// Returns the current time of the current thread's clock.
+pub fn now() -> Instant {
+    virtual_now().unwrap_or_else(Instant::now)
+}
+
+/// Returns the current time if a clock was set for the current thread, in which case
+/// the time only moves on with it and waits have to poll it.
+pub(crate) fn virtual_now() -> Option<Instant> {
+    // Thread locals may already be destroyed, in which case the system clock is used.
+    THREAD_CLOCK
+        .try_with(|thread_clock| thread_clock.borrow().as_ref().map(|clock| clock.now()))
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_thread_clock, Clock, MockClock};
+    use crate::{Condvar, Mutex};
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn mock_timeouts() {
+        let clock = Arc::new(MockClock::new());
+        let state = Arc::new((Mutex::new(()), Condvar::new()));
+        let (tx, rx) = channel();
+        thread::spawn({
+            let (clock, state) = (clock.clone(), state.clone());
+            move || {
+                set_thread_clock(Some(clock));
+                let (mutex, condvar) = &*state;
+                let result = condvar.wait_for(&mut mutex.lock(), Duration::from_secs(3600));
+                tx.send(result.timed_out()).unwrap();
+                assert!(set_thread_clock(None).is_some());
+            }
+        });
+
+        // The wait can't time out before the clock is advanced past the deadline.
+        clock.advance(Duration::from_secs(1800));
+        assert!(rx.recv_timeout(Duration::from_millis(20)).is_err());
+
+        let timed_out = loop {
+            clock.advance(Duration::from_secs(1800));
+            if let Ok(timed_out) = rx.recv_timeout(Duration::from_millis(10)) {
+                break timed_out;
+            }
+        };
+        assert!(timed_out);
+    }
+}
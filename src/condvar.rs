@@ -1,21 +1,28 @@
 use super::{
-    shared::{fence_acquire, invalid_mut, StrictProvenance, Waiter},
-    MutexGuard, RawRwLock,
+    shared::{chaos, fence_acquire, invalid_mut, Primitive, StrictProvenance, Waiter},
+    MutexGuard, RawRwLock, ReentrantMutexGuard,
 };
-use lock_api::RawMutex as _RawMutex;
+use lock_api::{RawMutex as _RawMutex, RawRwLock as _RawRwLock};
 use std::{
-    fmt,
+    cell::Cell,
+    fmt, mem,
     pin::Pin,
-    ptr::NonNull,
+    ptr::{self, NonNull},
     sync::atomic::{AtomicPtr, Ordering},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
+#[cfg(feature = "std")]
+use {crate::clock, std::time::Instant};
+
 const EMPTY: usize = 0;
 const SIGNAL: usize = 1;
 const SIGNAL_MASK: usize = 0b111;
 const QUEUE_LOCKED: usize = SIGNAL_MASK + 1;
 
+// The signal and lock bits are stored in the low bits of the queue head's address.
+const _: () = assert!((SIGNAL_MASK | QUEUE_LOCKED) & Waiter::MASK == 0);
+
 /// A Condition Variable
 ///
 /// Condition variables represent the ability to block a thread such that it
@@ -28,6 +35,9 @@ const QUEUE_LOCKED: usize = SIGNAL_MASK + 1;
 /// This is different compared to `parking_lot`'s condvar, which doesn't allow
 /// multiple threads waiting on the same condvar with different mutexes.
 ///
+/// A condvar can also be waited on while holding a `ReentrantMutex` using
+/// `wait_reentrant`, similar to C++'s `condition_variable_any`.
+///
 /// # Differences from the standard library `Condvar`
 ///
 /// - Spurious wake ups are avoided. This means a wait will try to not return early
@@ -74,7 +84,7 @@ const QUEUE_LOCKED: usize = SIGNAL_MASK + 1;
 #[derive(Default)]
 pub struct Condvar {
     /// This atomic integer holds the current state of the Condvar instance.
-    /// The four least significant bits are used to notify_one calls and the state of the Condvar.
+    /// The four least significant bits are used to buffer notifications and the state of the Condvar.
     ///
     /// # State table:
     ///
@@ -91,16 +101,19 @@ pub struct Condvar {
     ///      n>0    |       1       |  *Waiter  | The Remaining bits point to the head Waiter node of the
     ///             |               |           | waiting thread queue. There is also a thread which is
     ///             |               |           | updating the waiting-thread queue and waking from it.
-    ///             |               |           | `n` is the amount of `notify_one` calls that happened since the
-    ///             |               |           | QUEUE_LOCKED bit was taken for waiting-thread queue updating/waking.
-    //              |               |           | If `n == SIGNAL_MASK` then all threads in the queue are woken up.
+    ///             |               |           | `n` is the amount of threads that `notify_*` calls asked to wake up
+    ///             |               |           | since the QUEUE_LOCKED bit was taken for waiting-thread queue updating/waking.
+    ///             |               |           | If `n == SIGNAL_MASK` then all threads in the queue are woken up.
     /// ------------+---------------+-----------+-----------------------------------------------------------------
     state: AtomicPtr<Waiter>,
 }
 
 impl fmt::Debug for Condvar {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.pad("Condvar { .. }")
+        let state = self.state.load(Ordering::Relaxed);
+        f.debug_struct("Condvar")
+            .field("has_waiters", &(state.address() & Waiter::MASK != 0))
+            .finish()
     }
 }
 
@@ -125,7 +138,45 @@ impl Condvar {
     /// candidates to wake this thread up. When this function call returns, the
     /// lock specified will have been re-acquired.
     pub fn wait<T: ?Sized>(&self, mutex_guard: &mut MutexGuard<'_, T>) {
-        let result = self.wait_with(mutex_guard, None);
+        let result = self.wait_with(mutex_guard, 0, None);
+        assert!(!result.timed_out());
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification meant for `token`.
+    ///
+    /// This is the same as `wait()`, except that the thread can also be woken up on its own
+    /// by [`notify_matching`](Self::notify_matching) with a predicate accepting `token`.
+    /// This lets a single condvar serve a table of keyed waits, like one per slot or per
+    /// connection, without waking up every waiting thread on every change. Threads waiting
+    /// with `wait()` have the token 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::{Condvar, Mutex};
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let pair = Arc::new((Mutex::new([false; 4]), Condvar::new()));
+    /// let pair2 = pair.clone();
+    ///
+    /// let waiter = thread::spawn(move || {
+    ///     let &(ref lock, ref cvar) = &*pair2;
+    ///     let mut ready = lock.lock();
+    ///     while !ready[2] {
+    ///         cvar.wait_with_token(&mut ready, 2);
+    ///     }
+    /// });
+    ///
+    /// let &(ref lock, ref cvar) = &*pair;
+    /// lock.lock()[2] = true;
+    /// // Only the threads waiting for slot 2 are woken up.
+    /// cvar.notify_matching(|token| token == 2);
+    /// waiter.join().unwrap();
+    /// ```
+    pub fn wait_with_token<T: ?Sized>(&self, mutex_guard: &mut MutexGuard<'_, T>, token: usize) {
+        let result = self.wait_with(mutex_guard, token, None);
         assert!(!result.timed_out());
     }
 
@@ -147,12 +198,13 @@ impl Condvar {
     ///
     /// Like `wait`, the lock specified will be re-acquired when this function
     /// returns, regardless of whether the timeout elapsed or not.
+    #[cfg(feature = "std")]
     pub fn wait_until<T: ?Sized>(
         &self,
         mutex_guard: &mut MutexGuard<'_, T>,
         timeout: Instant,
     ) -> WaitTimeoutResult {
-        match timeout.checked_duration_since(Instant::now()) {
+        match timeout.checked_duration_since(clock::now()) {
             Some(until_deadline) => self.wait_for(mutex_guard, until_deadline),
             None => WaitTimeoutResult(true),
         }
@@ -181,22 +233,156 @@ impl Condvar {
         mutex_guard: &mut MutexGuard<'_, T>,
         timeout: Duration,
     ) -> WaitTimeoutResult {
-        self.wait_with(mutex_guard, Some(timeout))
+        self.wait_with(mutex_guard, 0, Some(timeout))
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification, while holding a [`ReentrantMutex`](type.ReentrantMutex.html).
+    ///
+    /// This is the same as `wait()` except that all the recursive locks held by the
+    /// current thread on the reentrant mutex are released while blocked, not only the
+    /// one represented by `mutex_guard`. They are all re-acquired before returning.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::{Condvar, ReentrantMutex};
+    /// use std::cell::Cell;
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let pair = Arc::new((ReentrantMutex::new(Cell::new(false)), Condvar::new()));
+    /// let pair2 = pair.clone();
+    ///
+    /// thread::spawn(move || {
+    ///     let &(ref lock, ref cvar) = &*pair2;
+    ///     lock.lock().set(true);
+    ///     cvar.notify_one();
+    /// });
+    ///
+    /// let &(ref lock, ref cvar) = &*pair;
+    /// let outer = lock.lock();
+    /// let mut started = lock.lock();
+    /// while !started.get() {
+    ///     cvar.wait_reentrant(&mut started);
+    /// }
+    /// drop(outer);
+    /// ```
+    pub fn wait_reentrant<T: ?Sized>(&self, mutex_guard: &mut ReentrantMutexGuard<'_, T>) {
+        let result = self.wait_reentrant_with(mutex_guard, None);
+        assert!(!result.timed_out());
+    }
+
+    /// Waits on this condition variable for a notification while holding a
+    /// [`ReentrantMutex`](type.ReentrantMutex.html), timing out after the specified time instant.
+    ///
+    /// The semantics of this function are equivalent to `wait_until()` except that
+    /// all the recursive locks held on the reentrant mutex are released while blocked,
+    /// like `wait_reentrant()`.
+    #[cfg(feature = "std")]
+    pub fn wait_reentrant_until<T: ?Sized>(
+        &self,
+        mutex_guard: &mut ReentrantMutexGuard<'_, T>,
+        timeout: Instant,
+    ) -> WaitTimeoutResult {
+        match timeout.checked_duration_since(clock::now()) {
+            Some(until_deadline) => self.wait_reentrant_for(mutex_guard, until_deadline),
+            None => WaitTimeoutResult(true),
+        }
+    }
+
+    /// Waits on this condition variable for a notification while holding a
+    /// [`ReentrantMutex`](type.ReentrantMutex.html), timing out after a specified duration.
+    ///
+    /// The semantics of this function are equivalent to `wait_for()` except that
+    /// all the recursive locks held on the reentrant mutex are released while blocked,
+    /// like `wait_reentrant()`.
+    pub fn wait_reentrant_for<T: ?Sized>(
+        &self,
+        mutex_guard: &mut ReentrantMutexGuard<'_, T>,
+        timeout: Duration,
+    ) -> WaitTimeoutResult {
+        self.wait_reentrant_with(mutex_guard, Some(timeout))
     }
 
     #[cold]
     fn wait_with<T: ?Sized>(
         &self,
         mutex_guard: &mut MutexGuard<'_, T>,
+        token: usize,
+        timeout: Option<Duration>,
+    ) -> WaitTimeoutResult {
+        let raw_mutex = unsafe { MutexGuard::mutex(mutex_guard).raw() };
+        self.wait_on(
+            raw_mutex,
+            token,
+            timeout,
+            || unsafe { raw_mutex.unlock() },
+            |waiter| raw_mutex.relock(waiter),
+        )
+    }
+
+    #[cold]
+    fn wait_reentrant_with<T: ?Sized>(
+        &self,
+        mutex_guard: &mut ReentrantMutexGuard<'_, T>,
+        timeout: Option<Duration>,
+    ) -> WaitTimeoutResult {
+        let remutex = ReentrantMutexGuard::remutex(mutex_guard);
+        let raw_mutex = unsafe { remutex.raw() };
+        let lock_count = Cell::new(0);
+
+        self.wait_on(
+            raw_mutex,
+            0,
+            timeout,
+            || {
+                // Release every recursive lock held by the current thread.
+                // The inner mutex is only unlocked by the last one.
+                while remutex.is_owned_by_current_thread() {
+                    unsafe { remutex.force_unlock() };
+                    lock_count.set(lock_count.get() + 1);
+                }
+            },
+            |waiter| {
+                // The inner mutex may have been handed off to us while requeued on it,
+                // but the ReentrantMutex wouldn't know that it's owned by this thread.
+                // Release it and re-acquire it through the ReentrantMutex instead.
+                if RawRwLock::is_handed_off(waiter) {
+                    unsafe { raw_mutex.rwlock.unlock_exclusive() };
+                }
+
+                for _ in 0..lock_count.get() {
+                    mem::forget(remutex.lock());
+                }
+            },
+        )
+    }
+
+    /// Waits on the condvar for a thread holding `raw_mutex`, registered with `token`.
+    /// `unlock` releases the mutex once the thread is registered as waiting
+    /// and `relock` re-acquires it when returning (even in the case of a panic).
+    fn wait_on(
+        &self,
+        raw_mutex: &crate::RawMutex,
+        token: usize,
         timeout: Option<Duration>,
+        unlock: impl FnOnce(),
+        relock: impl FnOnce(Pin<&Waiter>),
     ) -> WaitTimeoutResult {
-        Waiter::with(|waiter| unsafe {
-            // MutexGuard acquired the internal RawRwLock as a writer
+        // Chaos mode makes untimed waits return spuriously, without releasing the mutex so
+        // that it isn't noticed by other threads. Timed ones report whether they timed out.
+        if timeout.is_none() && chaos::spurious_wakeup() {
+            return WaitTimeoutResult(false);
+        }
+
+        Waiter::with(Primitive::new("Condvar", self), |waiter| unsafe {
+            // The mutex guard acquired the internal RawRwLock as a writer
             let is_writer = true;
             waiter.flags.set(is_writer as usize);
+            waiter.token.set(token);
 
             // RawMutex is just a wrapper around RawRwLock.
-            let raw_mutex = MutexGuard::mutex(mutex_guard).raw();
             let raw_rwlock = NonNull::from(&raw_mutex.rwlock);
 
             waiter.waiting_on.set(Some(raw_rwlock.cast()));
@@ -204,7 +390,7 @@ impl Condvar {
 
             // Push our waiter to the wait queue and report if we acquired the QUEUE_LOCKED bit
             let mut state = self.state.load(Ordering::Relaxed);
-            let signal_locked = loop {
+            let queue_locked = loop {
                 let head = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
                 waiter.next.set(head);
 
@@ -237,22 +423,31 @@ impl Condvar {
                 }
             };
 
-            struct DropGuard<'a>(&'a crate::RawMutex);
-            impl<'a> Drop for DropGuard<'a> {
+            struct DropGuard<'a, F: FnOnce(Pin<&Waiter>)> {
+                relock: Option<F>,
+                waiter: Pin<&'a Waiter>,
+            }
+
+            impl<'a, F: FnOnce(Pin<&Waiter>)> Drop for DropGuard<'a, F> {
                 fn drop(&mut self) {
-                    self.0.lock();
+                    if let Some(relock) = self.relock.take() {
+                        relock(self.waiter);
+                    }
                 }
             }
 
             // Now that our waiter is registered on the state, unlock the mutex in order to block the thread.
             // Make sure to re-acquire the mutex back when returning (even in the case of a panic).
-            raw_mutex.unlock();
-            let _drop_guard = DropGuard(raw_mutex);
+            unlock();
+            let _drop_guard = DropGuard {
+                relock: Some(relock),
+                waiter,
+            };
 
             // Make sure to link and unset the QUEUE_LOCKED
-            if signal_locked {
+            if queue_locked {
                 state = self.state.load(Ordering::Relaxed);
-                self.link_queue_or_unpark(state);
+                self.link_queue(state);
             }
 
             // Block the thread and wait for a wake up or timeout.
@@ -273,7 +468,7 @@ impl Condvar {
     }
 
     #[cold]
-    unsafe fn link_queue_or_unpark(&self, mut state: *mut Waiter) {
+    unsafe fn link_queue(&self, mut state: *mut Waiter) {
         loop {
             assert_ne!(state.address() & QUEUE_LOCKED, 0);
 
@@ -281,7 +476,8 @@ impl Condvar {
             // then we are now responsible for doing the wake up from the notifications.
             let signals = state.address() & SIGNAL_MASK;
             if signals > 0 {
-                return self.unpark(state, 0);
+                self.unpark(state, 0);
+                return;
             }
 
             // Fix the prev links in the waiter queue now that we hold the QUEUE_LOCKED bit.
@@ -305,12 +501,16 @@ impl Condvar {
 
     /// Wakes up one blocked thread on this condvar.
     ///
-    /// Returns **a hint** as to whether a thread was woken up.
+    /// Returns whether a thread was woken up.
     ///
     /// If there is a blocked thread on this condition variable, then it will
     /// be woken up from its call to `wait` or `wait_timeout`. Calls to
     /// `notify_one` are not buffered in any way *to subsequent waiters*.
     ///
+    /// When another thread is updating the wait queue at the same time, the notification
+    /// is handed off to it instead of waiting for it to finish. That thread then wakes up
+    /// a waiter in our place, so this still returns `true`.
+    ///
     /// To wake up all threads, see `notify_all()`.
     ///
     /// # Examples
@@ -333,129 +533,249 @@ impl Condvar {
             return false;
         }
 
-        self.notify_one_slow(state)
+        self.notify_slow(state, 1) != 0
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    ///
+    /// Returns the number of threads woken up.
+    ///
+    /// This method will ensure that any current waiters on the condition
+    /// variable are awoken. Calls to `notify_all()` are not buffered in any
+    /// way *to subsequent waiters*.
+    ///
+    /// Threads which are requeued to wait on their `Mutex` instead of being
+    /// unparked directly are also counted as woken up. Like `notify_one()`, the
+    /// notification is handed off to a thread updating the wait queue at the same
+    /// time. Only one of the threads it wakes up is counted then, so the count is
+    /// a lower bound.
+    ///
+    /// To wake up only one thread, see `notify_one()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Condvar;
+    ///
+    /// let condvar = Condvar::new();
+    ///
+    /// // do something with condvar, share it with other threads
+    ///
+    /// if condvar.notify_all() == 0 {
+    ///     println!("Nobody was listening for this.");
+    /// }
+    /// ```
+    #[inline]
+    pub fn notify_all(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        if state.address() == EMPTY {
+            return 0;
+        }
+
+        self.notify_all_slow(state)
     }
 
     #[cold]
-    fn notify_one_slow(&self, mut state: *mut Waiter) -> bool {
+    fn notify_all_slow(&self, mut state: *mut Waiter) -> usize {
         loop {
             if state.address() == EMPTY {
-                return false;
+                return 0;
+            }
+
+            // If no thread is currently waking up the waiters, grab all of them to wake up.
+            // Acquire barrier to ensure writes to pushed waiters happen before we start waking them up.
+            if state.address() & QUEUE_LOCKED == 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state.with_address(EMPTY),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return unsafe { self.unpark_waiters(state) },
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            // Tell the QUEUE_LOCKED bit holder to wake up all threads in our place.
+            // The queue isn't empty while the QUEUE_LOCKED bit is held, so at least one is woken up.
+            if self.signal(&mut state, SIGNAL_MASK) {
+                return 1;
+            }
+        }
+    }
+
+    /// Wakes up to `count` blocked threads on this condvar.
+    ///
+    /// Returns the number of threads woken up, which may be less than `count`
+    /// if there weren't enough threads waiting.
+    ///
+    /// Threads are woken up in the order they started waiting. Like `notify_all()`,
+    /// threads requeued to wait on their `Mutex` are counted as woken up, and only one
+    /// of the threads woken up by a thread updating the wait queue in our place is
+    /// counted, making the count a lower bound.
+    ///
+    /// That thread buffers the handed off wake ups in a few bits of the condvar's state.
+    /// Once they add up to 7 or more, together with the ones handed off by other
+    /// notifications, it wakes up all of the waiting threads instead of `count` of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Condvar;
+    ///
+    /// let condvar = Condvar::new();
+    ///
+    /// // do something with condvar, share it with other threads
+    ///
+    /// let woken = condvar.notify_many(4);
+    /// println!("{} threads will pick up the work.", woken);
+    /// ```
+    #[inline]
+    pub fn notify_many(&self, count: usize) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        if state.address() == EMPTY || count == 0 {
+            return 0;
+        }
+
+        self.notify_slow(state, count)
+    }
+
+    #[cold]
+    fn notify_slow(&self, mut state: *mut Waiter, count: usize) -> usize {
+        loop {
+            if state.address() == EMPTY {
+                return 0;
             }
 
             if state.address() & QUEUE_LOCKED == 0 {
                 // Try to acquire the QUEUE_LOCKED bit to wake up the queued threads.
                 let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
-                if let Err(e) = self.state.compare_exchange_weak(
+                match self.state.compare_exchange_weak(
                     state,
                     new_state,
                     Ordering::Relaxed,
                     Ordering::Relaxed,
                 ) {
-                    state = e;
-                    continue;
+                    Ok(_) => return unsafe { self.unpark(new_state, count) },
+                    Err(e) => state = e,
                 }
-
-                unsafe { self.unpark(new_state, 1) };
-                return true;
+                continue;
             }
 
-            // Bail if all threads are going to be woken up eventually.
-            let signals = state.address() & SIGNAL_MASK;
-            if signals == SIGNAL_MASK {
-                return false;
-            }
-
-            // Tell the QUEUE_LOCKED bit holder to wake up the thread in our place.
-            // Release barrier to ensure this notify_one() happens before
-            // the wake ups done by the QUEUE_LOCKED bit holder.
-            match self.state.compare_exchange_weak(
-                state,
-                state.map_address(|addr| addr + SIGNAL),
-                Ordering::Release,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => return true,
-                Err(e) => state = e,
+            // Tell the QUEUE_LOCKED bit holder to wake up the threads in our place.
+            // Asking for as many wake ups as the SIGNAL bits can hold wakes up all of them.
+            // The queue isn't empty while the QUEUE_LOCKED bit is held, so at least one is woken up.
+            let signals = (state.address() & SIGNAL_MASK) + count.min(SIGNAL_MASK) * SIGNAL;
+            if self.signal(&mut state, signals.min(SIGNAL_MASK)) {
+                return 1;
             }
         }
     }
 
-    /// Wakes up all blocked threads on this condvar.
+    /// Wakes up the blocked threads on this condvar whose token is accepted by `predicate`.
     ///
-    /// Returns **a hint** as to whether threads were woken up.
+    /// Returns the number of threads woken up. Tokens are registered by waiting with
+    /// [`wait_with_token`](Self::wait_with_token), and are 0 for the other waits.
     ///
-    /// This method will ensure that any current waiters on the condition
-    /// variable are awoken. Calls to `notify_all()` are not buffered in any
-    /// way *to subsequent waiters*.
+    /// The predicate is called once for each waiting thread, from the oldest to the newest one,
+    /// while the condvar's queue is locked. It should be quick and must not use this condvar.
+    /// If another thread is updating the wait queue at the same time, all the waiting threads
+    /// are woken up by it instead without calling the predicate. None of them is counted then,
+    /// so the count is a lower bound which is 0 in that case.
     ///
-    /// To wake up only one thread, see `notify_one()`.
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Condvar;
+    ///
+    /// let condvar = Condvar::new();
+    ///
+    /// // do something with condvar, share it with other threads
+    ///
+    /// let woken = condvar.notify_matching(|token| token % 2 == 0);
+    /// println!("{} threads waiting on even slots were woken up.", woken);
+    /// ```
     #[inline]
-    pub fn notify_all(&self) -> bool {
+    pub fn notify_matching(&self, predicate: impl FnMut(usize) -> bool) -> usize {
         let state = self.state.load(Ordering::Relaxed);
         if state.address() == EMPTY {
-            return false;
+            return 0;
         }
 
-        self.notify_all_slow(state)
+        self.notify_matching_slow(state, predicate)
     }
 
     #[cold]
-    fn notify_all_slow(&self, mut state: *mut Waiter) -> bool {
+    fn notify_matching_slow(
+        &self,
+        mut state: *mut Waiter,
+        predicate: impl FnMut(usize) -> bool,
+    ) -> usize {
         loop {
             if state.address() == EMPTY {
-                return false;
+                return 0;
             }
 
-            // If no thread is currently waking up the waiters, grab all of them to wake up.
-            // Acquire barrier to ensure writes to pushed waiters happen before we start waking them up.
             if state.address() & QUEUE_LOCKED == 0 {
-                if let Err(e) = self.state.compare_exchange_weak(
+                let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+                match self.state.compare_exchange_weak(
                     state,
-                    state.with_address(EMPTY),
-                    Ordering::Acquire,
+                    new_state,
+                    Ordering::Relaxed,
                     Ordering::Relaxed,
                 ) {
-                    state = e;
-                    continue;
+                    Ok(_) => return unsafe { self.unpark_matching(new_state, predicate) },
+                    Err(e) => state = e,
                 }
-
-                unsafe { self.unpark_waiters(state) };
-                return true;
+                continue;
             }
 
-            let signals = state.address() & SIGNAL_MASK;
-            if signals == SIGNAL_MASK {
-                return false;
+            // The predicate can't be handed off to the QUEUE_LOCKED bit holder,
+            // so have it wake up everything instead which the waiters see as spurious wake ups.
+            if self.signal(&mut state, SIGNAL_MASK) {
+                return 0;
             }
+        }
+    }
 
-            // Tell the QUEUE_LOCKED bit holder to wake up all threads in our place.
-            // Release barrier to ensure this notify_one() happens before
-            // the wake ups done by the QUEUE_LOCKED bit holder.
-            match self.state.compare_exchange_weak(
-                state,
-                state.map_address(|addr| addr | SIGNAL_MASK),
-                Ordering::Release,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => return true,
-                Err(e) => state = e,
+    /// Sets the SIGNAL bits to `signals` while the QUEUE_LOCKED bit is held,
+    /// returning whether the QUEUE_LOCKED bit holder will do the wake ups.
+    fn signal(&self, state: &mut *mut Waiter, signals: usize) -> bool {
+        assert_ne!(state.address() & QUEUE_LOCKED, 0);
+
+        // Bail if all threads are going to be woken up eventually.
+        if state.address() & SIGNAL_MASK == SIGNAL_MASK {
+            return true;
+        }
+
+        // Release barrier to ensure this notification happens before
+        // the wake ups done by the QUEUE_LOCKED bit holder.
+        match self.state.compare_exchange_weak(
+            *state,
+            state.map_address(|addr| (addr & !SIGNAL_MASK) | signals),
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => true,
+            Err(e) => {
+                *state = e;
+                false
             }
         }
     }
 
+    /// Wakes up `also_wake` waiters along with the ones from the buffered signals
+    /// while holding the QUEUE_LOCKED bit, returning how many of the former were woken up.
     #[cold]
-    unsafe fn unpark(&self, mut state: *mut Waiter, also_wake: usize) {
-        let mut scanned = 0;
-        let mut unparked = None;
-
+    unsafe fn unpark(&self, mut state: *mut Waiter, also_wake: usize) -> usize {
         loop {
             assert_ne!(state.address() & QUEUE_LOCKED, 0);
 
             // If enough threads have sent notifications, just wake up all waiters.
             let signals = state.address() & SIGNAL_MASK;
             if signals == SIGNAL_MASK {
-                return self.unpark_all();
+                return self.unpark_all().min(also_wake);
             }
 
             // Fix and get the ends of the wait queue in order to wake up waiters starting from the tail.
@@ -464,27 +784,16 @@ impl Condvar {
             fence_acquire(&self.state);
             let (head, tail) = Waiter::get_and_link_queue(state, |_| {});
 
-            // Get the head of the waiter nodes we plan on waking up.
-            let mut front = unparked.unwrap_or_else(|| {
-                scanned += 1;
-                tail
-            });
-
-            // Bounded scan to collect enough waiter nodes to match current buffered signals.
-            let max_scan = signals + also_wake;
+            // Bounded scan to collect enough waiter nodes to match the buffered signals and our own.
+            let max_scan = signals.saturating_add(also_wake);
+            let mut front = tail;
+            let mut scanned = 1;
             while scanned < max_scan {
-                if let Some(prev) = front.as_ref().prev.get() {
-                    front = prev;
-                    scanned += 1;
-                } else {
-                    break;
+                match front.as_ref().prev.get() {
+                    Some(prev) => front = prev,
+                    None => break,
                 }
-            }
-
-            // If enough threads have been scanned, just wake up all waiters.
-            unparked = Some(front);
-            if scanned >= SIGNAL_MASK {
-                return self.unpark_all();
+                scanned += 1;
             }
 
             // If we're only waking up a portion of the queue,
@@ -493,26 +802,18 @@ impl Condvar {
                 head.as_ref().tail.set(Some(new_tail));
                 new_tail.as_ref().next.set(None);
 
-                // Use saturating sub as we may have scanned to wake up a bit more than whats signaled
-                // if unpark() is being called by `notify_one()` which wakes without adding a SIGNAL.
-                let new_state = state.map_address(|mut addr| {
-                    addr &= !QUEUE_LOCKED;
-                    addr -= signals.saturating_sub(scanned) * SIGNAL;
-                    addr
-                });
-
                 // Release barrier ensures the head/tail access above happen before we release the QUEUE_LOCKED bit before wake up.
                 match self.state.compare_exchange_weak(
                     state,
-                    new_state,
+                    state.map_address(|addr| addr & !(QUEUE_LOCKED | SIGNAL_MASK)),
                     Ordering::Release,
                     Ordering::Relaxed,
                 ) {
-                    Ok(_) => return self.unpark_requeue(front),
+                    Ok(_) => return self.unpark_requeue(front).min(also_wake),
                     Err(e) => state = e,
                 }
 
-                // Reverse wha we did above
+                // Reverse what we did above
                 new_tail.as_ref().next.set(Some(front));
                 head.as_ref().tail.set(Some(tail));
                 continue;
@@ -526,25 +827,123 @@ impl Condvar {
                 Ordering::Release,
                 Ordering::Relaxed,
             ) {
-                Ok(_) => return self.unpark_requeue(head),
+                Ok(_) => return self.unpark_requeue(head).min(also_wake),
                 Err(e) => state = e,
             }
         }
     }
 
     #[cold]
-    unsafe fn unpark_all(&self) {
+    unsafe fn unpark_all(&self) -> usize {
         // Wake all by zeroing everything.
         // Acquire barrier ensures that writes done to pushed waiters happen before we start waking them.
         let state = self.state.swap(invalid_mut(EMPTY), Ordering::Acquire);
         assert_ne!(state.address() & QUEUE_LOCKED, 0);
-        assert_ne!(state.address() & SIGNAL_MASK, 0);
+        assert_eq!(state.address() & SIGNAL_MASK, SIGNAL_MASK);
 
         self.unpark_waiters(state)
     }
 
     #[cold]
-    unsafe fn unpark_waiters(&self, state: *mut Waiter) {
+    unsafe fn unpark_matching(
+        &self,
+        mut state: *mut Waiter,
+        mut predicate: impl FnMut(usize) -> bool,
+    ) -> usize {
+        // Fix and get the ends of the wait queue in order to check waiters starting from the tail.
+        // Acquire barrier ensures that writes to waiters pushed to the queue
+        // happen before we start fixing/getting it.
+        fence_acquire(&self.state);
+        let (head, tail) = Waiter::get_and_link_queue(state, |_| {});
+
+        // Unlink the matching waiters, collecting them into a list linked through their next
+        // fields which ends with the oldest one, like the queue unpark_requeue() expects.
+        let mut woken: Option<NonNull<Waiter>> = None;
+
+        // The tail is cached at the current head of the queue, which needs updating when
+        // unlinking the tail.
+        let unlink = |head: NonNull<Waiter>, waiter: NonNull<Waiter>| {
+            let prev = waiter.as_ref().prev.get();
+            let prev = prev.expect("Condvar unlinking a waiter without prev");
+            let next = waiter.as_ref().next.get();
+            prev.as_ref().next.set(next);
+            match next {
+                Some(next) => next.as_ref().prev.set(Some(prev)),
+                None => head.as_ref().tail.set(Some(prev)),
+            }
+        };
+
+        let mut current = tail;
+        while current != head {
+            let waiter = current;
+            current = waiter
+                .as_ref()
+                .prev
+                .get()
+                .expect("Condvar waiter queue not linked");
+
+            if predicate(waiter.as_ref().token.get()) {
+                unlink(head, waiter);
+                waiter.as_ref().next.set(woken);
+                woken = Some(waiter);
+            }
+        }
+
+        // The head is only removed from the state, but other threads may push new waiters
+        // in front of it in the meantime, after which it's unlinked like the others.
+        if predicate(head.as_ref().token.get()) {
+            loop {
+                let current_head = state.map_address(|addr| addr & Waiter::MASK);
+                if !ptr::eq(current_head, head.as_ptr()) {
+                    // Linking stops at the old head as its tail is set, which fixes its prev.
+                    fence_acquire(&self.state);
+                    let (new_head, _) = Waiter::get_and_link_queue(state, |_| {});
+                    unlink(new_head, head);
+                    break;
+                }
+
+                // Removing the last waiter releases the QUEUE_LOCKED bit with the queue.
+                let new_state = match head.as_ref().next.get() {
+                    Some(next) => {
+                        next.as_ref().prev.set(None);
+                        next.as_ref().tail.set(head.as_ref().tail.get());
+                        next.as_ptr()
+                            .map_address(|addr| addr | (state.address() & !Waiter::MASK))
+                    }
+                    None => state.with_address(EMPTY),
+                };
+
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        state = new_state;
+                        break;
+                    }
+                    Err(e) => state = e,
+                }
+            }
+
+            head.as_ref().next.set(woken);
+            woken = Some(head);
+        }
+
+        // Release the QUEUE_LOCKED bit, if removing the head didn't already do it.
+        if state.address() != EMPTY {
+            self.link_queue(state);
+        }
+
+        match woken {
+            Some(woken) => self.unpark_requeue(woken),
+            None => 0,
+        }
+    }
+
+    #[cold]
+    unsafe fn unpark_waiters(&self, state: *mut Waiter) -> usize {
         // Get the head waiter node from the queue and wake the entire queue.
         let head = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
         let head = head.expect("Condvar waking up waiters with invalid state");
@@ -553,33 +952,75 @@ impl Condvar {
     }
 
     #[cold]
-    unsafe fn unpark_requeue(&self, head: NonNull<Waiter>) {
-        let mut waiters = Some(head);
-        while let Some(waiter) = waiters {
-            waiters = waiter.as_ref().next.get();
-
-            let waiting_on = waiter.as_ref().waiting_on.get();
-            let waiting_on = waiting_on.expect("Condvar waiter not waiting on anything");
-
-            // Storing the lock the waiter is waiting on inside the waiter
-            // allows the Condvar to support waiting on multiples mutexes at once.
-            let raw_rwlock = waiting_on.cast::<RawRwLock>();
-            let waiter = Pin::new_unchecked(waiter.as_ref());
-
-            // Try to requeue the waiter onto the RwLock (really, Mutex) it was waiting on.
-            // Failure to do so means the lock is unlocked and we should unpark directly in
-            // hopes that the waiter will immediately acquire it.
-            if !raw_rwlock.as_ref().try_requeue(waiter) {
-                waiter.parker.unpark();
+    unsafe fn unpark_requeue(&self, head: NonNull<Waiter>) -> usize {
+        // The deterministic feature requeues the waiters starting from the oldest one so that
+        // they also acquire the mutex in the order in which they started waiting. The waiters
+        // are owned by us now, so link them back to front while looking for the oldest one.
+        let (first, head_ptr) = if cfg!(feature = "deterministic") {
+            let mut tail = head;
+            while let Some(next) = tail.as_ref().next.get() {
+                next.as_ref().prev.set(Some(tail));
+                tail = next;
             }
+            (tail, Some(head))
+        } else {
+            (head, None)
+        };
+
+        let mut woken = 0;
+        let mut unparked: Option<(&RawRwLock, Pin<&Waiter>)> = None;
+        chaos::wake_list(
+            Some(first),
+            |waiter| match head_ptr {
+                Some(head) if ptr::eq(waiter, head.as_ptr()) => None,
+                Some(_) => waiter.prev.get(),
+                None => waiter.next.get(),
+            },
+            |waiter| {
+                woken += 1;
+
+                let waiting_on = waiter.as_ref().waiting_on.get();
+                let waiting_on = waiting_on.expect("Condvar waiter not waiting on anything");
+
+                // Storing the lock the waiter is waiting on inside the waiter
+                // allows the Condvar to support waiting on multiples mutexes at once.
+                let raw_rwlock = &*waiting_on.cast::<RawRwLock>().as_ptr();
+                let waiter = Pin::new_unchecked(&*waiter.as_ptr());
+
+                // A waiter of this mutex is going to be unparked to acquire it,
+                // so queue the others behind it even if the mutex is unlocked.
+                // It wakes them up one at a time when unlocking instead of all of them racing for it.
+                if let Some((unparked_on, _)) = unparked {
+                    if ptr::eq(unparked_on, raw_rwlock) {
+                        return raw_rwlock.requeue(waiter);
+                    }
+                }
+
+                // Try to requeue the waiter onto the RwLock (really, Mutex) it was waiting on.
+                // Failure to do so means the lock is unlocked and we should unpark directly in
+                // hopes that the waiter will immediately acquire it. The first of those is only
+                // unparked once the others are requeued, so that it can't release the mutex before.
+                if !raw_rwlock.try_requeue(waiter) {
+                    match unparked {
+                        None => unparked = Some((raw_rwlock, waiter)),
+                        Some(_) => waiter.parker.unpark(),
+                    }
+                }
+            },
+        );
+
+        if let Some((_, waiter)) = unparked {
+            waiter.parker.unpark();
         }
+
+        woken
     }
 }
 
 /// A type indicating whether a timed wait on a condition variable returned
 /// due to a time out or not.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub struct WaitTimeoutResult(bool);
+pub struct WaitTimeoutResult(pub(crate) bool);
 
 impl WaitTimeoutResult {
     /// Returns whether the wait was known to have timed out.
@@ -591,13 +1032,25 @@ impl WaitTimeoutResult {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Condvar, Mutex};
+    use super::{QUEUE_LOCKED, SIGNAL_MASK};
+    use crate::{
+        shared::StrictProvenance, Condvar, Mutex, MutexGuard, ReentrantMutex, ReentrantMutexGuard,
+    };
     use std::{
-        sync::{mpsc::channel, Arc},
+        cell::Cell,
+        sync::{atomic::Ordering, mpsc::channel, Arc},
         thread,
         time::{Duration, Instant},
     };
 
+    // The last waiter links the queue after unlocking the mutex. Notifications racing
+    // with it are handed off to it, and the threads it wakes up aren't counted by them.
+    fn wait_for_linked_queue(cond: &Condvar) {
+        while cond.state.load(Ordering::Relaxed).address() & QUEUE_LOCKED != 0 {
+            thread::yield_now();
+        }
+    }
+
     #[test]
     fn smoke() {
         let c = Condvar::new();
@@ -630,7 +1083,7 @@ mod tests {
             let data = data.clone();
             let tx = tx.clone();
             thread::spawn(move || {
-                let &(ref lock, ref cond) = &*data;
+                let (lock, cond) = &*data;
                 let mut cnt = lock.lock();
                 *cnt += 1;
                 if *cnt == N {
@@ -644,7 +1097,7 @@ mod tests {
         }
         drop(tx);
 
-        let &(ref lock, ref cond) = &*data;
+        let (lock, cond) = &*data;
         rx.recv().unwrap();
         let mut cnt = lock.lock();
         *cnt = 0;
@@ -682,6 +1135,47 @@ mod tests {
         });
     }
 
+    #[test]
+    fn notify_one_return_exact() {
+        const N: usize = 3;
+
+        let data = Arc::new((Mutex::new(0), Condvar::new()));
+        let (tx, rx) = channel();
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let data = data.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let (lock, cond) = &*data;
+                    let mut cnt = lock.lock();
+                    *cnt += 1;
+                    if *cnt == N {
+                        tx.send(()).unwrap();
+                    }
+                    while *cnt != 0 {
+                        cond.wait(&mut cnt);
+                    }
+                })
+            })
+            .collect();
+
+        // Each notification should only report a wake up while there are threads left waiting.
+        let (lock, cond) = &*data;
+        rx.recv().unwrap();
+        let mut cnt = lock.lock();
+        wait_for_linked_queue(cond);
+        *cnt = 0;
+        for _ in 0..N {
+            assert!(cond.notify_one());
+        }
+        assert!(!cond.notify_one());
+        drop(cnt);
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
     #[test]
     fn notify_all_return() {
         const N: usize = 10;
@@ -692,7 +1186,7 @@ mod tests {
             let data = data.clone();
             let tx = tx.clone();
             thread::spawn(move || {
-                let &(ref lock, ref cond) = &*data;
+                let (lock, cond) = &*data;
                 let mut cnt = lock.lock();
                 *cnt += 1;
                 if *cnt == N {
@@ -706,18 +1200,279 @@ mod tests {
         }
         drop(tx);
 
-        let &(ref lock, ref cond) = &*data;
+        let (lock, cond) = &*data;
         rx.recv().unwrap();
         let mut cnt = lock.lock();
+        wait_for_linked_queue(cond);
         *cnt = 0;
-        assert_eq!(cond.notify_all(), true);
+        assert_eq!(cond.notify_all(), N);
         drop(cnt);
 
         for _ in 0..N {
             rx.recv().unwrap();
         }
 
-        assert_eq!(cond.notify_all(), false);
+        assert_eq!(cond.notify_all(), 0);
+    }
+
+    #[test]
+    fn notify_all_requeue_unlocked_mutex() {
+        const N: usize = 5;
+
+        let data = Arc::new((Mutex::new((0, false)), Condvar::new()));
+        let (tx, rx) = channel();
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let data = data.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let (lock, cond) = &*data;
+                    let mut state = lock.lock();
+                    state.0 += 1;
+                    if state.0 == N {
+                        tx.send(()).unwrap();
+                    }
+                    while !state.1 {
+                        cond.wait(&mut state);
+                    }
+                    state.0 -= 1;
+                })
+            })
+            .collect();
+
+        // Wait for all the threads to block on the condvar.
+        let (lock, cond) = &*data;
+        rx.recv().unwrap();
+        lock.lock().1 = true;
+        wait_for_linked_queue(cond);
+
+        // The mutex is unlocked, so one waiter is unparked to acquire it
+        // and the others are requeued onto it without the condvar locking it.
+        assert_eq!(cond.notify_all(), N);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(lock.lock().0, 0);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn notify_many_return() {
+        const N: usize = 10;
+
+        let data = Arc::new((Mutex::new((0, 0)), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let (lock, cond) = &*data;
+                let mut state = lock.lock();
+                state.0 += 1;
+                if state.0 == N {
+                    tx.send(()).unwrap();
+                }
+                while state.1 == 0 {
+                    cond.wait(&mut state);
+                }
+                state.1 -= 1;
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let (lock, cond) = &*data;
+        rx.recv().unwrap();
+        let mut state = lock.lock();
+        wait_for_linked_queue(cond);
+        state.1 = N;
+        assert_eq!(cond.notify_many(0), 0);
+        assert_eq!(cond.notify_many(3), 3);
+        assert_eq!(cond.notify_many(N), N - 3);
+        assert_eq!(cond.notify_many(N), 0);
+        drop(state);
+
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_handoff_return() {
+        const N: usize = 10;
+
+        // Pretends to be a thread updating the wait queue, for the notifications to be handed off to.
+        fn lock_queue(cond: &Condvar) {
+            let state = cond.state.load(Ordering::Relaxed);
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            cond.state
+                .compare_exchange(state, new_state, Ordering::Relaxed, Ordering::Relaxed)
+                .unwrap();
+        }
+
+        // Wakes up the threads the notifications were handed off for, like that thread would.
+        fn unlock_queue(cond: &Condvar) {
+            let state = cond.state.load(Ordering::Acquire);
+            assert_eq!(unsafe { cond.unpark(state, 0) }, 0);
+        }
+
+        let data = Arc::new((Mutex::new((0, 0)), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let (lock, cond) = &*data;
+                let mut state = lock.lock();
+                state.0 += 1;
+                if state.0 == N {
+                    tx.send(()).unwrap();
+                }
+                while state.1 == 0 {
+                    cond.wait(&mut state);
+                }
+                state.1 -= 1;
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let (lock, cond) = &*data;
+        rx.recv().unwrap();
+        let mut state = lock.lock();
+        wait_for_linked_queue(cond);
+        state.1 = N;
+
+        // Handed off notifications count one wake up each.
+        lock_queue(cond);
+        assert!(cond.notify_one());
+        assert_eq!(cond.notify_many(2), 1);
+        assert_eq!(
+            cond.state.load(Ordering::Relaxed).address() & SIGNAL_MASK,
+            3
+        );
+        unlock_queue(cond);
+        drop(state);
+
+        for _ in 0..3 {
+            rx.recv().unwrap();
+        }
+
+        // Handing off 7 or more wake ups in total wakes up all the remaining threads.
+        let state = lock.lock();
+        lock_queue(cond);
+        assert_eq!(cond.notify_many(4), 1);
+        assert_eq!(cond.notify_many(3), 1);
+        assert_eq!(
+            cond.state.load(Ordering::Relaxed).address() & SIGNAL_MASK,
+            SIGNAL_MASK
+        );
+        assert_eq!(cond.notify_all(), 1);
+        unlock_queue(cond);
+        drop(state);
+
+        for _ in 3..N {
+            rx.recv().unwrap();
+        }
+        assert_eq!(cond.notify_all(), 0);
+    }
+
+    #[test]
+    fn notify_matching_return() {
+        const N: usize = 10;
+
+        let data = Arc::new((Mutex::new((0, [false; N])), Condvar::new()));
+        let (tx, rx) = channel();
+        for token in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let (lock, cond) = &*data;
+                let mut state = lock.lock();
+                state.0 += 1;
+                if state.0 == N {
+                    tx.send(N).unwrap();
+                }
+                while !state.1[token] {
+                    cond.wait_with_token(&mut state, token);
+                }
+                tx.send(token).unwrap();
+            });
+        }
+        drop(tx);
+
+        let (lock, cond) = &*data;
+        assert_eq!(rx.recv().unwrap(), N);
+        let mut state = lock.lock();
+        wait_for_linked_queue(cond);
+        state.1 = [true; N];
+        assert_eq!(cond.notify_matching(|token| token % 2 == 0), N / 2);
+        assert_eq!(cond.notify_matching(|token| token % 2 == 0), 0);
+        assert_eq!(cond.notify_matching(|token| token == N - 1), 1);
+        assert_eq!(cond.notify_matching(|_| false), 0);
+        assert_eq!(cond.notify_all(), N / 2 - 1);
+        drop(state);
+
+        let mut woken: Vec<_> = rx.iter().collect();
+        woken.sort_unstable();
+        assert_eq!(woken, (0..N).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn notify_matching_concurrent() {
+        const THREADS: usize = 6;
+        const ITERS: usize = 200;
+
+        // Timed waits and notifications without the mutex held keep the queue changing while
+        // waiters are unlinked from it.
+        let data = Arc::new((Mutex::new(([false; THREADS], 0)), Condvar::new()));
+        let workers: Vec<_> = (0..THREADS)
+            .map(|token| {
+                let data = data.clone();
+                thread::spawn(move || {
+                    let (lock, cond) = &*data;
+                    for _ in 0..ITERS {
+                        let mut state = lock.lock();
+                        state.0[token] = true;
+                        while state.0[token] {
+                            cond.wait_with_token(&mut state, token);
+                        }
+                    }
+                    lock.lock().1 += 1;
+                })
+            })
+            .collect();
+
+        let timed = {
+            let data = data.clone();
+            thread::spawn(move || {
+                let (lock, cond) = &*data;
+                let mut state = lock.lock();
+                while state.1 < THREADS {
+                    cond.wait_for(&mut state, Duration::from_millis(1));
+                }
+            })
+        };
+
+        let (lock, cond) = &*data;
+        for round in 0.. {
+            let parity = round % 2;
+            let mut state = lock.lock();
+            if state.1 == THREADS {
+                break;
+            }
+            for token in (parity..THREADS).step_by(2) {
+                state.0[token] = false;
+            }
+            drop(state);
+            cond.notify_matching(|token| token % 2 == parity);
+        }
+
+        for t in workers {
+            t.join().unwrap();
+        }
+        timed.join().unwrap();
     }
 
     #[test]
@@ -735,7 +1490,7 @@ mod tests {
             let _g = m2.lock();
             c2.notify_one();
         });
-        let timeout_res = c.wait_for(&mut g, Duration::from_secs(u64::max_value()));
+        let timeout_res = c.wait_for(&mut g, Duration::from_secs(u64::MAX));
         assert!(!timeout_res.timed_out());
 
         drop(g);
@@ -757,12 +1512,81 @@ mod tests {
         });
         let timeout_res = c.wait_until(
             &mut g,
-            Instant::now() + Duration::from_millis(u32::max_value() as u64),
+            Instant::now() + Duration::from_millis(u32::MAX as u64),
         );
         assert!(!timeout_res.timed_out());
         drop(g);
     }
 
+    #[test]
+    fn wait_reentrant() {
+        let m = Arc::new(ReentrantMutex::new(Cell::new(false)));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        let outer = m.lock();
+        let mut g = m.lock();
+
+        // The other thread can only lock the mutex if both locks are released while waiting.
+        let t = thread::spawn(move || {
+            let g = m2.lock();
+            g.set(true);
+            c2.notify_one();
+        });
+        while !g.get() {
+            c.wait_reentrant(&mut g);
+        }
+
+        assert!(m.is_owned_by_current_thread());
+        drop(g);
+        assert!(m.is_owned_by_current_thread());
+        drop(outer);
+        assert!(!m.is_locked());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn wait_reentrant_for() {
+        let m = ReentrantMutex::new(());
+        let c = Condvar::new();
+
+        let _outer = m.lock();
+        let mut g = m.lock();
+        let timeout_res = c.wait_reentrant_for(&mut g, Duration::from_millis(1));
+        assert!(timeout_res.timed_out());
+        assert!(m.is_owned_by_current_thread());
+    }
+
+    #[test]
+    fn wait_reentrant_unlock_fair() {
+        let m = Arc::new(ReentrantMutex::new(Cell::new(false)));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+        let (tx, rx) = channel();
+
+        let t = thread::spawn(move || {
+            let _outer = m2.lock();
+            let mut g = m2.lock();
+            tx.send(()).unwrap();
+            while !g.get() {
+                c2.wait_reentrant(&mut g);
+            }
+            assert!(m2.is_owned_by_current_thread());
+        });
+
+        // Requeue the waiter onto the mutex then hand the mutex off to it.
+        rx.recv().unwrap();
+        let g = m.lock();
+        g.set(true);
+        assert_eq!(c.notify_all(), 1);
+        ReentrantMutexGuard::unlock_fair(g);
+
+        t.join().unwrap();
+        assert!(!m.is_locked());
+    }
+
     #[test]
     fn two_mutexes() {
         let m = Arc::new(Mutex::new(()));
@@ -789,7 +1613,7 @@ mod tests {
         drop(g);
         rx.recv().unwrap();
         let _g = m.lock();
-        let _guard = PanicGuard(&*c);
+        let _guard = PanicGuard(&c);
 
         let result = c.wait_for(&mut m3.lock(), Duration::from_millis(100));
         assert!(result.timed_out());
@@ -817,18 +1641,20 @@ mod tests {
     #[test]
     fn test_debug_condvar() {
         let c = Condvar::new();
-        assert_eq!(format!("{:?}", c), "Condvar { .. }");
+        assert_eq!(format!("{:?}", c), "Condvar { has_waiters: false }");
     }
 
     #[test]
     fn test_condvar_requeue() {
-        let m = Arc::new(Mutex::new(()));
+        let m = Arc::new(Mutex::new(false));
         let m2 = m.clone();
         let c = Arc::new(Condvar::new());
         let c2 = c.clone();
         let t = thread::spawn(move || {
             let mut g = m2.lock();
-            c2.wait(&mut g);
+            while !*g {
+                c2.wait(&mut g);
+            }
         });
 
         let mut g = m.lock();
@@ -845,10 +1671,39 @@ mod tests {
             thread::yield_now();
         }
         // The thread should have been requeued to the mutex, which we wake up now.
+        *g = true;
         drop(g);
         t.join().unwrap();
     }
 
+    #[test]
+    fn test_condvar_requeue_unlock_fair() {
+        let m = Arc::new(Mutex::new(0));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+        let t = thread::spawn(move || {
+            let mut g = m2.lock();
+            while *g == 0 {
+                c2.wait(&mut g);
+            }
+            *g += 1;
+        });
+
+        let mut g = m.lock();
+        while !c.notify_one() {
+            drop(g);
+            thread::yield_now();
+            g = m.lock();
+            thread::yield_now();
+        }
+        // The requeued thread is handed the mutex directly and must not try to lock it again.
+        *g = 1;
+        MutexGuard::unlock_fair(g);
+        t.join().unwrap();
+        assert_eq!(*m.lock(), 2);
+    }
+
     #[test]
     fn test_parking_lot_issue_129() {
         let locks = Arc::new((Mutex::new(()), Condvar::new()));
@@ -1018,7 +1873,7 @@ mod webkit_queue_test {
             let (should_notify, result) = {
                 let mut queue = input_queue.lock();
                 wait(
-                    &*empty_condition,
+                    &empty_condition,
                     &mut queue,
                     |state| -> bool { !state.items.is_empty() || !state.should_continue },
                     &timeout,
@@ -1031,7 +1886,7 @@ mod webkit_queue_test {
                 std::mem::drop(queue);
                 (should_notify, result)
             };
-            notify(notify_style, &*full_condition, should_notify);
+            notify(notify_style, &full_condition, should_notify);
 
             if let Some(result) = result {
                 output_queue.lock().push(result);
@@ -1053,7 +1908,7 @@ mod webkit_queue_test {
                 let should_notify = {
                     let mut queue = queue.lock();
                     wait(
-                        &*full_condition,
+                        &full_condition,
                         &mut queue,
                         |state| state.items.len() < max_queue_size,
                         &timeout,
@@ -1063,7 +1918,7 @@ mod webkit_queue_test {
                     std::mem::drop(queue);
                     should_notify
                 };
-                notify(notify_style, &*empty_condition, should_notify);
+                notify(notify_style, &empty_condition, should_notify);
             }
         })
     }
@@ -1144,7 +1999,7 @@ mod webkit_queue_test {
             num_producers: 1,
             num_consumers: 1,
             max_queue_size: if cfg!(miri) { 10 } else { 100 },
-            messages_per_producer: if cfg!(miri) { 100 } else { 1000_000 },
+            messages_per_producer: if cfg!(miri) { 100 } else { 1_000_000 },
             notification_style: NotifyStyle::All,
             timeout: Timeout::Forever,
             delay_seconds: 0
@@ -0,0 +1,263 @@
+//! Crate-wide tuning of how threads spin before blocking and which ones are woken up.
+//!
+//! All the primitives spin for a little while when they're contended, in hopes that the
+//! lock holder releases it before having to block. The best trade-off depends on the
+//! machine: spinning longer pays off on servers with many cores, while spinning at all
+//! can waste the time slice of the lock holder on small or oversubscribed machines.
+//!
+//! The [`Backoff`] is read once, the first time a primitive spins. It's taken from the
+//! value passed to [`set_backoff`] if it was called before that, or otherwise from the
+//! `USYNC_BACKOFF` environment variable, which holds comma-separated `key=value` pairs
+//! matching the [`Backoff`] setters (e.g. `spin_limit=40,max_exponent=3,yield_thread=true`).
+//!
+//! On multi-socket machines, [`set_numa_affinity`] makes unlocking a contended lock prefer
+//! waking up a writer which started waiting on the same NUMA node, and threads back off for
+//! longer between contended atomic operations. [`set_cache_affinity`] narrows the wake ups
+//! down to the writers which last ran on a CPU sharing the last level cache of the unlocking
+//! thread, so that the protected data is still in cache once they're woken up.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::config::{self, Backoff};
+//!
+//! // Spin less, as this program runs many more threads than there are cores.
+//! let backoff = Backoff::new().spin_limit(10).yield_thread(true);
+//! if config::set_backoff(backoff).is_ok() {
+//!     assert_eq!(config::backoff(), backoff);
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// How threads spin before blocking when a primitive is contended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    spin_limit: usize,
+    max_exponent: u32,
+    yield_thread: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backoff {
+    /// Returns the default backoff, which spins up to 100 times with an exponent up to 5
+    /// and never yields the thread.
+    pub const fn new() -> Self {
+        Self {
+            spin_limit: 100,
+            max_exponent: 5,
+            yield_thread: false,
+        }
+    }
+
+    /// Sets the maximum amount of spins before a thread blocks, where 0 disables spinning.
+    pub const fn spin_limit(mut self, spin_limit: usize) -> Self {
+        self.spin_limit = spin_limit;
+        self
+    }
+
+    /// Sets the cap on the exponential backoff used while waiting for short internal
+    /// critical sections, where each attempt spins `2^attempt` times up to `2^max_exponent`.
+    ///
+    /// The exponent is capped at 16.
+    pub const fn max_exponent(mut self, max_exponent: u32) -> Self {
+        self.max_exponent = if max_exponent > 16 { 16 } else { max_exponent };
+        self
+    }
+
+    /// Sets whether spinning yields the thread to the OS scheduler instead of
+    /// only hinting the CPU with [`spin_loop`](std::hint::spin_loop).
+    pub const fn yield_thread(mut self, yield_thread: bool) -> Self {
+        self.yield_thread = yield_thread;
+        self
+    }
+
+    /// Returns the maximum amount of spins before a thread blocks.
+    pub const fn get_spin_limit(&self) -> usize {
+        self.spin_limit
+    }
+
+    /// Returns the cap on the exponential backoff.
+    pub const fn get_max_exponent(&self) -> u32 {
+        self.max_exponent
+    }
+
+    /// Returns whether spinning yields the thread to the OS scheduler.
+    pub const fn get_yield_thread(&self) -> bool {
+        self.yield_thread
+    }
+
+    /// Parses the `key=value` pairs of the `USYNC_BACKOFF` environment variable,
+    /// returning `None` if any of them is invalid.
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    fn parse(value: &str) -> Option<Self> {
+        let mut backoff = Self::new();
+        for pair in value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (key, value) = pair.split_once('=')?;
+            let value = value.trim();
+            backoff = match key.trim() {
+                "spin_limit" => backoff.spin_limit(value.parse().ok()?),
+                "max_exponent" => backoff.max_exponent(value.parse().ok()?),
+                "yield_thread" => backoff.yield_thread(value.parse().ok()?),
+                _ => return None,
+            };
+        }
+        Some(backoff)
+    }
+}
+
+const UNINIT: u8 = 0;
+const UPDATING: u8 = 1;
+const INIT: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNINIT);
+static SPIN_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static MAX_EXPONENT: AtomicUsize = AtomicUsize::new(0);
+static YIELD_THREAD: AtomicBool = AtomicBool::new(false);
+
+/// Sets the crate-wide backoff.
+///
+/// This only succeeds if the backoff wasn't read yet, which happens the first time
+/// a primitive spins, and returns it back otherwise. It should be called early in
+/// `main` before any thread is spawned.
+pub fn set_backoff(backoff: Backoff) -> Result<(), Backoff> {
+    match STATE.compare_exchange(UNINIT, UPDATING, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => {
+            store(backoff);
+            Ok(())
+        }
+        Err(_) => Err(backoff),
+    }
+}
+
+/// Returns the crate-wide backoff, reading it from the `USYNC_BACKOFF` environment
+/// variable if [`set_backoff`] wasn't called before.
+pub fn backoff() -> Backoff {
+    // Acquire barrier ensures we see the values stored before the state was set to INIT.
+    if STATE.load(Ordering::Acquire) != INIT {
+        init_slow();
+    }
+
+    Backoff {
+        spin_limit: SPIN_LIMIT.load(Ordering::Relaxed),
+        max_exponent: MAX_EXPONENT.load(Ordering::Relaxed) as u32,
+        yield_thread: YIELD_THREAD.load(Ordering::Relaxed),
+    }
+}
+
+#[cold]
+fn init_slow() {
+    match STATE.compare_exchange(UNINIT, UPDATING, Ordering::Acquire, Ordering::Acquire) {
+        Ok(_) => store(from_env().unwrap_or_default()),
+        // Another thread is storing the backoff, which doesn't block so wait for it.
+        // This can't use SpinWait as it reads the backoff itself.
+        Err(_) => {
+            while STATE.load(Ordering::Acquire) != INIT {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
+
+fn store(backoff: Backoff) {
+    SPIN_LIMIT.store(backoff.spin_limit, Ordering::Relaxed);
+    MAX_EXPONENT.store(backoff.max_exponent as usize, Ordering::Relaxed);
+    YIELD_THREAD.store(backoff.yield_thread, Ordering::Relaxed);
+
+    // Release barrier ensures the values are visible to backoff() once it sees INIT.
+    STATE.store(INIT, Ordering::Release);
+}
+
+#[cfg(feature = "std")]
+fn from_env() -> Option<Backoff> {
+    Backoff::parse(&std::env::var("USYNC_BACKOFF").ok()?)
+}
+
+#[cfg(not(feature = "std"))]
+fn from_env() -> Option<Backoff> {
+    None
+}
+
+/// Sets whether unlocking prefers waking up a writer which started waiting on the NUMA node
+/// of the current thread, disabled by default.
+///
+/// A writer is only bypassed a few times before being woken up regardless. With several
+/// NUMA nodes, the exponential backoff between contended atomic operations is also 4 times
+/// longer, as the cache line likely has to come from another socket.
+///
+/// The NUMA topology is only known on Linux, where enabling this reads it from `/sys` right
+/// away so that blocking threads never do. This can be changed at any time.
+pub fn set_numa_affinity(enabled: bool) {
+    crate::shared::numa::set_numa_affinity(enabled);
+}
+
+/// Returns whether unlocking prefers waking up waiters on the same NUMA node.
+pub fn numa_affinity() -> bool {
+    crate::shared::numa::numa_affinity()
+}
+
+/// Sets whether unlocking prefers waking up a waiter which last ran on a CPU sharing the
+/// last level cache (L3 on most machines) with the current thread, disabled by default.
+///
+/// Like the NUMA preference, a waiter is only bypassed a few times before being woken
+/// up regardless. The cache topology is only known on Linux, where enabling this reads
+/// it from `/sys` right away. This can be changed at any time.
+pub fn set_cache_affinity(enabled: bool) {
+    crate::shared::numa::set_cache_affinity(enabled);
+}
+
+/// Returns whether unlocking prefers waking up waiters sharing the last level cache.
+pub fn cache_affinity() -> bool {
+    crate::shared::numa::cache_affinity()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backoff;
+
+    #[test]
+    fn builder() {
+        let backoff = Backoff::new()
+            .spin_limit(7)
+            .max_exponent(100)
+            .yield_thread(true);
+        assert_eq!(backoff.get_spin_limit(), 7);
+        assert_eq!(backoff.get_max_exponent(), 16);
+        assert!(backoff.get_yield_thread());
+        assert_eq!(Backoff::default(), Backoff::new());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Backoff::parse(""), Some(Backoff::new()));
+        assert_eq!(
+            Backoff::parse("spin_limit=40, max_exponent=3,yield_thread=true"),
+            Some(
+                Backoff::new()
+                    .spin_limit(40)
+                    .max_exponent(3)
+                    .yield_thread(true)
+            )
+        );
+        assert_eq!(Backoff::parse("spin_limit=-1"), None);
+        assert_eq!(Backoff::parse("spins=1"), None);
+        assert_eq!(Backoff::parse("yield_thread"), None);
+    }
+
+    #[test]
+    fn cache_affinity() {
+        super::set_cache_affinity(true);
+        assert!(super::cache_affinity());
+        super::set_cache_affinity(false);
+        assert!(!super::cache_affinity());
+    }
+}
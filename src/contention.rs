@@ -0,0 +1,175 @@
+//! Logging of slow lock acquisitions to stderr, for triaging contention in production.
+//!
+//! Once a threshold is set, every `Mutex` or `RwLock` acquisition which had to wait for
+//! longer than it writes a line to stderr with the thread, the lock and how long it waited.
+//! The threshold is taken from [`set_threshold`], or otherwise from the
+//! `USYNC_CONTENTION_LOG` environment variable holding a number of microseconds, which is
+//! read the first time a lock is contended. Logging is disabled when neither is set.
+//!
+//! Locks are reported by address unless they were given a name with [`label_mutex`] or
+//! [`label_rwlock`]. Acquisitions which time out or are cancelled are logged as well, as
+//! they waited just as long. Only contended acquisitions are timed, and only once the
+//! threshold is set, so leaving it unset costs nothing.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use usync::{contention, Mutex};
+//!
+//! static QUEUE: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+//!
+//! contention::label_mutex(&QUEUE, "job queue");
+//! contention::set_threshold(Some(Duration::from_millis(1)));
+//! // Logs e.g. "usync: thread 'worker' waited 3.2ms for lock 'job queue'".
+//! QUEUE.lock().push(1);
+//! ```
+
+use crate::{shared::label, Mutex, RwLock};
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The threshold in microseconds, or one of the values below.
+static THRESHOLD: AtomicUsize = AtomicUsize::new(UNINIT);
+
+/// The threshold wasn't read from the environment yet.
+const UNINIT: usize = usize::MAX;
+const DISABLED: usize = usize::MAX - 1;
+
+/// Sets how long a lock acquisition has to wait to be logged, or disables logging with `None`.
+///
+/// This overrides the `USYNC_CONTENTION_LOG` environment variable and can be changed at any
+/// time. Thresholds are counted in microseconds, and are capped to about an hour on 32-bit
+/// targets.
+pub fn set_threshold(threshold: Option<Duration>) {
+    THRESHOLD.store(to_micros(threshold), Ordering::Relaxed);
+}
+
+/// Returns how long a lock acquisition has to wait to be logged, or `None` if logging
+/// is disabled.
+pub fn threshold() -> Option<Duration> {
+    let micros = match THRESHOLD.load(Ordering::Relaxed) {
+        UNINIT => init_slow(),
+        micros => micros,
+    };
+
+    match micros {
+        DISABLED => None,
+        micros => Some(Duration::from_micros(micros as u64)),
+    }
+}
+
+#[cold]
+fn init_slow() -> usize {
+    let micros = to_micros(from_env());
+
+    // set_threshold() may have been called in the meantime, in which case it takes precedence.
+    match THRESHOLD.compare_exchange(UNINIT, micros, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => micros,
+        Err(current) => current,
+    }
+}
+
+fn from_env() -> Option<Duration> {
+    parse(&std::env::var("USYNC_CONTENTION_LOG").ok()?)
+}
+
+/// Parses the number of microseconds in the `USYNC_CONTENTION_LOG` environment variable.
+fn parse(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_micros)
+}
+
+fn to_micros(threshold: Option<Duration>) -> usize {
+    match threshold {
+        Some(threshold) => threshold.as_micros().min((DISABLED - 1) as u128) as usize,
+        None => DISABLED,
+    }
+}
+
+/// Names the mutex in the contention log.
+///
+/// Labels are looked up by the address of the lock, so a lock which is dropped should be
+/// relabeled if its memory ends up reused for another one. They're also used by the
+/// deadlock detector of the `deadlock_detection` feature.
+pub fn label_mutex<T: ?Sized>(mutex: &Mutex<T>, label: &'static str) {
+    label::set(label::mutex_addr(mutex), label);
+}
+
+/// Names the rwlock in the contention log, see [`label_mutex`].
+pub fn label_rwlock<T: ?Sized>(rwlock: &RwLock<T>, label: &'static str) {
+    label::set(label::rwlock_addr(rwlock), label);
+}
+
+/// Times a contended lock acquisition, logging it when dropped if it waited for too long.
+pub(crate) struct Waiting {
+    lock: usize,
+    started: Option<(Instant, Duration)>,
+}
+
+impl Waiting {
+    pub(crate) fn new<T>(lock: *const T) -> Self {
+        Self {
+            lock: lock as usize,
+            started: threshold().map(|threshold| (Instant::now(), threshold)),
+        }
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let (started, threshold) = match self.started {
+            Some(started) => started,
+            None => return,
+        };
+
+        let waited = started.elapsed();
+        if waited >= threshold {
+            log(self.lock, waited);
+        }
+    }
+}
+
+#[cold]
+fn log(lock: usize, waited: Duration) {
+    let thread = thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>");
+
+    // Errors writing to stderr are ignored, like eprintln!() would if it didn't panic.
+    let _ = match label::get(lock) {
+        Some(label) => writeln!(
+            io::stderr(),
+            "usync: thread '{}' waited {:?} for lock '{}'",
+            thread,
+            waited,
+            label
+        ),
+        None => writeln!(
+            io::stderr(),
+            "usync: thread '{}' waited {:?} for lock {:#x}",
+            thread,
+            waited,
+            lock
+        ),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, to_micros, DISABLED};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_threshold() {
+        assert_eq!(parse(" 250 "), Some(Duration::from_micros(250)));
+        assert_eq!(parse("0"), Some(Duration::ZERO));
+        assert_eq!(parse("1ms"), None);
+        assert_eq!(parse(""), None);
+
+        assert_eq!(to_micros(None), DISABLED);
+        assert_eq!(to_micros(Some(Duration::MAX)), DISABLED - 1);
+    }
+}
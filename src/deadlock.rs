@@ -0,0 +1,409 @@
+//! Detection of threads deadlocked on each other's locks, for the `deadlock_detection` feature.
+//!
+//! With the feature enabled, `Mutex` and `RwLock` keep track of which threads hold them and
+//! which ones are waiting for them. [`check_deadlock`] walks the resulting waits-for graph
+//! and returns its cycles: threads which are each waiting for a lock held by the next one,
+//! and will never wake up. [`start_detector`] runs the check periodically on a background
+//! thread and calls back with each new cycle. Locks can be given a name to report with
+//! [`label_mutex`] and [`label_rwlock`].
+//!
+//! The feature also catches a thread read locking an `RwLock` it already holds while a writer
+//! is waiting for it. New readers queue up behind waiting writers, so instead of deadlocking
+//! silently, the second `read()` panics.
+//!
+//! A thread is considered to hold a lock from when it acquired it to when it released it,
+//! so guards sent to other threads with the `send_guard` feature can hide deadlocks.
+//! Tracking the locks makes every acquisition slower, so the feature is meant for debugging.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use usync::deadlock;
+//!
+//! deadlock::start_detector(Duration::from_secs(10), |cycle| {
+//!     eprintln!("{} threads deadlocked:", cycle.len());
+//!     for thread in cycle {
+//!         eprintln!("  {:?} waiting on {:?}", thread.thread_id(), thread.label());
+//!     }
+//! });
+//! ```
+
+use crate::{const_spin_lock, shared::label, Mutex, RwLock, SpinLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+/// A thread which is part of a deadlock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlockedThread {
+    thread_id: ThreadId,
+    thread_name: Option<String>,
+    lock: usize,
+    label: Option<&'static str>,
+}
+
+impl DeadlockedThread {
+    /// Returns the id of the thread.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// Returns the name of the thread, if it has one.
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_deref()
+    }
+
+    /// Returns the address of the lock the thread is waiting for,
+    /// which is held by the next thread of the cycle.
+    pub fn lock_address(&self) -> usize {
+        self.lock
+    }
+
+    /// Returns the label given to the lock the thread is waiting for, if any.
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+}
+
+/// What a thread holds and waits for, shared with the threads checking for deadlocks.
+struct ThreadState {
+    thread_id: ThreadId,
+    thread_name: Option<String>,
+    /// The address of the lock the thread is waiting for, or 0.
+    waiting_on: AtomicUsize,
+    /// The addresses of the locks the thread holds, shared ones possibly more than once.
+    held: SpinLock<Vec<usize>>,
+}
+
+// The tracked state is protected by spin locks, as the instrumented locks would report
+// their own acquisitions. They're only contended while checking for deadlocks.
+static THREADS: SpinLock<Vec<Weak<ThreadState>>> = const_spin_lock(Vec::new());
+
+std::thread_local! {
+    static THREAD_STATE: Arc<ThreadState> = {
+        let thread = thread::current();
+        let state = Arc::new(ThreadState {
+            thread_id: thread.id(),
+            thread_name: thread.name().map(String::from),
+            waiting_on: AtomicUsize::new(0),
+            held: SpinLock::new(Vec::new()),
+        });
+        THREADS.lock().push(Arc::downgrade(&state));
+        state
+    };
+}
+
+fn with_thread_state(f: impl FnOnce(&ThreadState)) {
+    // Thread locals may already be destroyed, in which case the thread isn't tracked anymore.
+    let _ = THREAD_STATE.try_with(|state| f(state));
+}
+
+pub(crate) fn acquired(lock_addr: usize) {
+    with_thread_state(|state| state.held.lock().push(lock_addr));
+}
+
+pub(crate) fn released(lock_addr: usize) {
+    with_thread_state(|state| {
+        let mut held = state.held.lock();
+        if let Some(index) = held.iter().rposition(|&held_lock| held_lock == lock_addr) {
+            held.remove(index);
+        }
+    });
+}
+
+/// Panics if the current thread is about to wait for a read lock on an rwlock it already holds.
+///
+/// Readers only wait on a read locked rwlock when a writer is queued, and that writer waits
+/// for the current thread's read lock, so this would deadlock.
+pub(crate) fn check_recursive_read(lock_addr: usize) {
+    let mut held = false;
+    with_thread_state(|state| held = state.held.lock().contains(&lock_addr));
+    if !held {
+        return;
+    }
+
+    let lock = match label::get(lock_addr) {
+        Some(label) => format!("'{}'", label),
+        None => format!("{:#x}", lock_addr),
+    };
+    panic!(
+        "deadlock: RwLock {} is read locked again by a thread which already holds it, while \
+         a writer is waiting for it. Reuse the existing read guard instead of locking again.",
+        lock
+    );
+}
+
+/// Marks the current thread as waiting for a lock until it's dropped.
+pub(crate) struct Waiting(());
+
+impl Waiting {
+    pub(crate) fn new<T>(lock: *const T) -> Self {
+        with_thread_state(|state| state.waiting_on.store(lock as usize, Ordering::Relaxed));
+        Self(())
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        with_thread_state(|state| state.waiting_on.store(0, Ordering::Relaxed));
+    }
+}
+
+/// Names the mutex in the reported deadlocks.
+///
+/// Labels are looked up by the address of the lock, so a lock which is dropped should be
+/// relabeled if its memory ends up reused for another one. They're also used by the
+/// [`contention`](crate::contention) log.
+pub fn label_mutex<T: ?Sized>(mutex: &Mutex<T>, label: &'static str) {
+    label::set(label::mutex_addr(mutex), label);
+}
+
+/// Names the rwlock in the reported deadlocks, see [`label_mutex`].
+pub fn label_rwlock<T: ?Sized>(rwlock: &RwLock<T>, label: &'static str) {
+    label::set(label::rwlock_addr(rwlock), label);
+}
+
+/// Returns the cycles of threads waiting for each other's locks.
+///
+/// Each cycle lists the threads in order: every thread is waiting for a lock held by the
+/// next one, and the last one for a lock held by the first one.
+pub fn check_deadlock() -> Vec<Vec<DeadlockedThread>> {
+    let threads: Vec<Arc<ThreadState>> = {
+        let mut threads = THREADS.lock();
+        threads.retain(|state| state.strong_count() > 0);
+        threads.iter().filter_map(Weak::upgrade).collect()
+    };
+
+    // Snapshot the graph: which threads hold each lock and what each thread waits for.
+    let mut holders: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut waiting_on = Vec::with_capacity(threads.len());
+    for (index, state) in threads.iter().enumerate() {
+        for &held in state.held.lock().iter() {
+            holders.entry(held).or_default().push(index);
+        }
+        waiting_on.push(state.waiting_on.load(Ordering::Relaxed));
+    }
+
+    let deadlocked = |index: usize, lock: usize| DeadlockedThread {
+        thread_id: threads[index].thread_id,
+        thread_name: threads[index].thread_name.clone(),
+        lock,
+        label: label::get(lock),
+    };
+
+    // Depth-first search of the waits-for graph, where a thread has an edge to every
+    // other thread holding the lock it waits for. Reaching a thread of the current path
+    // again means the threads from there on wait for each other.
+    const UNVISITED: u8 = 0;
+    const ON_PATH: u8 = 1;
+    const DONE: u8 = 2;
+
+    let mut cycles = Vec::new();
+    let mut visited = vec![UNVISITED; threads.len()];
+    for start in 0..threads.len() {
+        if visited[start] != UNVISITED {
+            continue;
+        }
+
+        // The path holds each thread with the index of the next edge to follow from it.
+        let mut path = vec![(start, 0)];
+        visited[start] = ON_PATH;
+        while let Some((thread, edge)) = path.last_mut() {
+            let thread = *thread;
+            let lock_addr = waiting_on[thread];
+            let next = holders
+                .get(&lock_addr)
+                .filter(|_| lock_addr != 0)
+                .and_then(|holders| holders.iter().filter(|&&h| h != thread).nth(*edge))
+                .copied();
+            *edge += 1;
+
+            match next {
+                None => {
+                    visited[thread] = DONE;
+                    path.pop();
+                }
+                Some(next) if visited[next] == ON_PATH => {
+                    let from = path.iter().position(|&(t, _)| t == next).unwrap();
+                    let cycle = path[from..]
+                        .iter()
+                        .map(|&(t, _)| deadlocked(t, waiting_on[t]))
+                        .collect();
+                    cycles.push(cycle);
+                }
+                Some(next) if visited[next] == UNVISITED => {
+                    visited[next] = ON_PATH;
+                    path.push((next, 0));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    cycles
+}
+
+/// Starts a background thread checking for deadlocks every `interval`, which calls
+/// `on_deadlock` with each cycle of deadlocked threads the first time it's found.
+///
+/// A cycle is only reported once it was found by two checks in a row, which filters out
+/// threads that were only seen waiting as they were acquiring a lock. Only the first call
+/// starts a thread, and returns true.
+pub fn start_detector<F>(interval: Duration, on_deadlock: F) -> bool
+where
+    F: Fn(&[DeadlockedThread]) + Send + 'static,
+{
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    if STARTED.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+
+    // The locks the threads wait for identify the cycle, whatever thread it starts from.
+    let key = |cycle: &[DeadlockedThread]| {
+        let mut key: Vec<_> = cycle.iter().map(|thread| thread.lock).collect();
+        key.sort_unstable();
+        key
+    };
+
+    thread::Builder::new()
+        .name("usync-deadlock-detector".into())
+        .spawn(move || {
+            let mut previous = HashSet::new();
+            let mut reported = HashSet::new();
+            loop {
+                thread::sleep(interval);
+
+                let mut current = HashSet::new();
+                for cycle in check_deadlock() {
+                    let key = key(&cycle);
+                    if previous.contains(&key) && reported.insert(key.clone()) {
+                        on_deadlock(&cycle);
+                    }
+                    current.insert(key);
+                }
+
+                // Cycles which resolved can be reported again if they reoccur.
+                reported.retain(|key| current.contains(key));
+                previous = current;
+            }
+        })
+        .expect("failed to spawn the deadlock detector thread");
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_deadlock, label_mutex};
+    use crate::{Mutex, RwLock};
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{Arc, Barrier},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn detects_cycle() {
+        let a = Arc::new(Mutex::new(()));
+        let b = Arc::new(RwLock::new(()));
+        label_mutex(&a, "a");
+
+        let barrier = Arc::new(Barrier::new(2));
+        let _t1 = {
+            let (a, b, barrier) = (a.clone(), b.clone(), barrier.clone());
+            thread::spawn(move || {
+                let _a = a.lock();
+                barrier.wait();
+                let _b = b.write();
+            })
+        };
+        let _t2 = {
+            let (a, b, barrier) = (a.clone(), b.clone(), barrier.clone());
+            thread::spawn(move || {
+                let _b = b.read();
+                barrier.wait();
+                let _a = a.lock();
+            })
+        };
+
+        // The threads are leaked as they never wake up.
+        let cycle = loop {
+            thread::sleep(Duration::from_millis(10));
+            if let Some(cycle) = check_deadlock()
+                .into_iter()
+                .find(|cycle| cycle.iter().any(|thread| thread.label() == Some("a")))
+            {
+                break cycle;
+            }
+        };
+
+        assert_eq!(cycle.len(), 2);
+        let rwlock = crate::shared::label::rwlock_addr(&b);
+        assert!(cycle.iter().any(|thread| thread.lock_address() == rwlock));
+    }
+
+    #[test]
+    fn detects_recursive_read() {
+        let lock = Arc::new(RwLock::new(()));
+        let guard = lock.read();
+
+        // Recursive reads are fine while no writer is waiting.
+        drop(lock.read());
+
+        let writer = {
+            let lock = lock.clone();
+            thread::spawn(move || drop(lock.write()))
+        };
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| drop(lock.read())));
+        let message = result.unwrap_err();
+        let message = message.downcast_ref::<String>().unwrap();
+        assert!(
+            message.contains("Reuse the existing read guard"),
+            "{}",
+            message
+        );
+
+        drop(guard);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn no_false_positives() {
+        let lock = Arc::new(RwLock::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        if i % 2 == 0 {
+                            *lock.write() += 1;
+                        } else {
+                            drop(lock.read());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        let lock_addr = crate::shared::label::rwlock_addr(&lock);
+        assert!(check_deadlock()
+            .iter()
+            .flatten()
+            .all(|thread| thread.lock_address() != lock_addr));
+    }
+}
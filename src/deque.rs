@@ -0,0 +1,512 @@
+//! Work-stealing queues for distributing tasks between scheduler threads.
+//!
+//! Every worker thread owns a [`Worker`] deque which it pushes and pops tasks on without
+//! contention. Idle threads take tasks from the other end of busy threads' deques through
+//! their [`Stealer`]s, and tasks submitted from outside of the workers go through a shared
+//! [`Injector`] queue. None of these ever block: workers which find nothing to do should
+//! wait on another primitive of this crate, like an [`Event`](crate::Event), until new
+//! tasks are submitted.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::deque::{Injector, Steal, Worker};
+//!
+//! let injector = Injector::new();
+//! injector.push(1);
+//! injector.push(2);
+//!
+//! let worker = Worker::new();
+//! let stealer = worker.stealer();
+//!
+//! // Workers refill their own deque from the injector.
+//! assert_eq!(injector.steal_batch_and_pop(&worker), Steal::Success(1));
+//! worker.push(3);
+//!
+//! // Owners pop the most recently pushed tasks, stealers take the oldest ones.
+//! assert_eq!(stealer.steal(), Steal::Success(2));
+//! assert_eq!(worker.pop(), Some(3));
+//! assert_eq!(worker.pop(), None);
+//! ```
+
+use super::{const_mutex, Mutex};
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fmt,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{fence, AtomicIsize, AtomicPtr, Ordering},
+        Arc,
+    },
+};
+
+/// The capacity of a new deque buffer, which doubles whenever the deque is full.
+const MIN_CAPACITY: usize = 64;
+
+/// The result of a steal operation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The queue was empty.
+    Empty,
+    /// A task was stolen.
+    Success(T),
+    /// Another thread raced with this one, so the steal should be retried.
+    Retry,
+}
+
+impl<T> Steal<T> {
+    /// Returns the stolen task, if the steal succeeded.
+    pub fn success(self) -> Option<T> {
+        match self {
+            Steal::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the queue was empty.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Steal::Empty)
+    }
+
+    /// Returns true if the steal should be retried.
+    pub fn is_retry(&self) -> bool {
+        matches!(self, Steal::Retry)
+    }
+}
+
+/// A fixed-size ring of slots, indexed modulo its power of two capacity.
+struct Buffer<T> {
+    slots: Box<[MaybeUninit<T>]>,
+}
+
+impl<T> Buffer<T> {
+    fn alloc(capacity: usize) -> *mut Self {
+        let slots = (0..capacity).map(|_| MaybeUninit::uninit()).collect();
+        Box::into_raw(Box::new(Self { slots }))
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: isize) -> *mut T {
+        let index = (index as usize) & (self.capacity() - 1);
+        self.slots[index].as_ptr() as *mut T
+    }
+
+    unsafe fn write(&self, index: isize, value: T) {
+        ptr::write_volatile(self.slot(index), value)
+    }
+
+    /// Reads the value at the index without taking ownership of it,
+    /// as a stealer may be racing with another one for it.
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        ptr::read_volatile(self.slot(index) as *const MaybeUninit<T>)
+    }
+}
+
+/// The state shared by a worker and its stealers, following the Chase-Lev deque:
+/// the owner pushes and pops at `bottom` while stealers take from `top`.
+struct Inner<T> {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    buffer: AtomicPtr<Buffer<T>>,
+    /// Buffers replaced by a larger one, which stealers may still be reading from.
+    /// They're only freed with the deque.
+    retired: Mutex<Vec<*mut Buffer<T>>>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let (top, bottom) = (*self.top.get_mut(), *self.bottom.get_mut());
+        let buffer = *self.buffer.get_mut();
+        unsafe {
+            for index in top..bottom {
+                ptr::drop_in_place((*buffer).slot(index));
+            }
+
+            drop(Box::from_raw(buffer));
+            for retired in self.retired.get_mut().drain(..) {
+                drop(Box::from_raw(retired));
+            }
+        }
+    }
+}
+
+/// The owner side of a work-stealing deque, which pushes and pops tasks at one end.
+///
+/// A worker can only be used from one thread at a time: share its [`Stealer`]s instead.
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+unsafe impl<T: Send> Send for Worker<T> {}
+
+impl<T> Default for Worker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Worker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Worker { .. }")
+    }
+}
+
+impl<T> Worker<T> {
+    /// Creates a new empty deque, whose owner pops the most recently pushed task first.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                top: AtomicIsize::new(0),
+                bottom: AtomicIsize::new(0),
+                buffer: AtomicPtr::new(Buffer::alloc(MIN_CAPACITY)),
+                retired: const_mutex(Vec::new()),
+            }),
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Creates a new stealer which takes tasks from the other end of this deque.
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns the amount of tasks in the deque.
+    pub fn len(&self) -> usize {
+        let bottom = self.inner.bottom.load(Ordering::Relaxed);
+        let top = self.inner.top.load(Ordering::Relaxed);
+        bottom.wrapping_sub(top).max(0) as usize
+    }
+
+    /// Returns true if the deque is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes a task at the owner's end of the deque.
+    pub fn push(&self, value: T) {
+        let bottom = self.inner.bottom.load(Ordering::Relaxed);
+        let top = self.inner.top.load(Ordering::Acquire);
+        let mut buffer = self.inner.buffer.load(Ordering::Relaxed);
+
+        unsafe {
+            if bottom.wrapping_sub(top) as usize >= (*buffer).capacity() {
+                buffer = self.grow(top, bottom, buffer);
+            }
+
+            (*buffer).write(bottom, value);
+        }
+
+        // Release barrier ensures stealers which see the new bottom also see the task.
+        fence(Ordering::Release);
+        self.inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// Pops the most recently pushed task, or returns `None` if the deque is empty.
+    pub fn pop(&self) -> Option<T> {
+        let bottom = self.inner.bottom.load(Ordering::Relaxed).wrapping_sub(1);
+        let buffer = self.inner.buffer.load(Ordering::Relaxed);
+        self.inner.bottom.store(bottom, Ordering::Relaxed);
+
+        // SeqCst ensures stealers either see the decremented bottom
+        // or we see their increment of top.
+        fence(Ordering::SeqCst);
+        let top = self.inner.top.load(Ordering::Relaxed);
+
+        if bottom.wrapping_sub(top) < 0 {
+            self.inner
+                .bottom
+                .store(bottom.wrapping_add(1), Ordering::Relaxed);
+            return None;
+        }
+
+        let value = unsafe { (*buffer).read(bottom) };
+        if bottom != top {
+            return Some(unsafe { value.assume_init() });
+        }
+
+        // This is the last task, so race the stealers for it.
+        let won = self
+            .inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+
+        self.inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+        match won {
+            true => Some(unsafe { value.assume_init() }),
+            false => None,
+        }
+    }
+
+    /// Moves the tasks into a buffer twice as large, returning it.
+    #[cold]
+    unsafe fn grow(&self, top: isize, bottom: isize, old: *mut Buffer<T>) -> *mut Buffer<T> {
+        let new = Buffer::alloc((*old).capacity() * 2);
+        let mut index = top;
+        while index != bottom {
+            ptr::copy_nonoverlapping((*old).slot(index), (*new).slot(index), 1);
+            index = index.wrapping_add(1);
+        }
+
+        // Release barrier ensures stealers which see the new buffer also see its tasks.
+        // The old buffer may still be read by stealers, so only free it with the deque.
+        self.inner.buffer.store(new, Ordering::Release);
+        self.inner.retired.lock().push(old);
+        new
+    }
+}
+
+/// The stealing side of a work-stealing deque, which takes the oldest tasks of a [`Worker`].
+///
+/// Stealers can be cloned and shared between any number of threads.
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+unsafe impl<T: Send> Send for Stealer<T> {}
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Stealer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Stealer { .. }")
+    }
+}
+
+impl<T> Stealer<T> {
+    /// Returns true if the deque looked empty.
+    pub fn is_empty(&self) -> bool {
+        let top = self.inner.top.load(Ordering::Acquire);
+        let bottom = self.inner.bottom.load(Ordering::Acquire);
+        bottom.wrapping_sub(top) <= 0
+    }
+
+    /// Attempts to take the oldest task of the deque.
+    pub fn steal(&self) -> Steal<T> {
+        let top = self.inner.top.load(Ordering::Acquire);
+
+        // SeqCst ensures the owner either sees our increment of top
+        // or we see its decrement of bottom in pop().
+        fence(Ordering::SeqCst);
+        let bottom = self.inner.bottom.load(Ordering::Acquire);
+        if bottom.wrapping_sub(top) <= 0 {
+            return Steal::Empty;
+        }
+
+        // The task may be taken by someone else meanwhile, so only own it once we claimed it.
+        let buffer = self.inner.buffer.load(Ordering::Acquire);
+        let value = unsafe { (*buffer).read(top) };
+        match self.inner.top.compare_exchange(
+            top,
+            top.wrapping_add(1),
+            Ordering::SeqCst,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Steal::Success(unsafe { value.assume_init() }),
+            Err(_) => Steal::Retry,
+        }
+    }
+}
+
+/// A queue shared by all workers, through which tasks are submitted from outside of them.
+pub struct Injector<T> {
+    queue: Mutex<VecDeque<T>>,
+}
+
+impl<T> Default for Injector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Injector<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Injector")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<T> Injector<T> {
+    /// Creates a new empty injector queue.
+    pub const fn new() -> Self {
+        Self {
+            queue: const_mutex(VecDeque::new()),
+        }
+    }
+
+    /// Returns the amount of tasks in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// Returns true if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes a task at the back of the queue.
+    pub fn push(&self, value: T) {
+        self.queue.lock().push_back(value);
+    }
+
+    /// Takes the task at the front of the queue.
+    pub fn steal(&self) -> Steal<T> {
+        match self.queue.lock().pop_front() {
+            Some(value) => Steal::Success(value),
+            None => Steal::Empty,
+        }
+    }
+
+    /// Takes the task at the front of the queue along with up to half of the remaining ones,
+    /// which are pushed to the worker's deque.
+    ///
+    /// Taking a batch at once amortizes contention on the shared queue.
+    pub fn steal_batch_and_pop(&self, dest: &Worker<T>) -> Steal<T> {
+        let mut queue = self.queue.lock();
+        let value = match queue.pop_front() {
+            Some(value) => value,
+            None => return Steal::Empty,
+        };
+
+        let batch = queue.len() - queue.len() / 2;
+        for value in queue.drain(..batch) {
+            dest.push(value);
+        }
+
+        drop(queue);
+        Steal::Success(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Injector, Steal, Worker};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        assert_eq!(worker.pop(), None::<i32>);
+        assert_eq!(stealer.steal(), Steal::Empty);
+
+        worker.push(1);
+        worker.push(2);
+        worker.push(3);
+        assert_eq!(worker.len(), 3);
+        assert_eq!(stealer.steal(), Steal::Success(1));
+        assert_eq!(worker.pop(), Some(3));
+        assert_eq!(worker.pop(), Some(2));
+        assert!(worker.is_empty());
+        assert!(stealer.is_empty());
+    }
+
+    #[test]
+    fn grow_and_drop() {
+        let value = Arc::new(());
+        let worker = Worker::new();
+        let stealer = worker.stealer();
+        for _ in 0..1000 {
+            worker.push(value.clone());
+        }
+        for _ in 0..10 {
+            assert!(stealer.steal().success().is_some());
+        }
+
+        assert_eq!(worker.len(), 990);
+        drop((worker, stealer));
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn injector() {
+        let injector = Injector::new();
+        for i in 0..10 {
+            injector.push(i);
+        }
+        assert_eq!(injector.steal(), Steal::Success(0));
+
+        let worker = Worker::new();
+        assert_eq!(injector.steal_batch_and_pop(&worker), Steal::Success(1));
+        assert_eq!(injector.len(), 4);
+        assert_eq!(worker.len(), 4);
+        assert_eq!(worker.pop(), Some(5));
+    }
+
+    #[test]
+    fn steal_concurrently() {
+        const TASKS: usize = 50_000;
+        const STEALERS: usize = 4;
+
+        let worker = Worker::new();
+        let taken = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+
+        let stealers: Vec<_> = (0..STEALERS)
+            .map(|_| {
+                let (stealer, taken, sum) = (worker.stealer(), taken.clone(), sum.clone());
+                thread::spawn(move || {
+                    while taken.load(Ordering::Relaxed) < TASKS {
+                        if let Steal::Success(value) = stealer.steal() {
+                            sum.fetch_add(value, Ordering::Relaxed);
+                            taken.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Each task must be taken exactly once, whether by the owner or a stealer.
+        for i in 0..TASKS {
+            worker.push(i);
+            if i % 3 == 0 {
+                if let Some(value) = worker.pop() {
+                    sum.fetch_add(value, Ordering::Relaxed);
+                    taken.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        while let Some(value) = worker.pop() {
+            sum.fetch_add(value, Ordering::Relaxed);
+            taken.fetch_add(1, Ordering::Relaxed);
+        }
+
+        for t in stealers {
+            t.join().unwrap();
+        }
+        assert_eq!(taken.load(Ordering::Relaxed), TASKS);
+        assert_eq!(sum.load(Ordering::Relaxed), TASKS * (TASKS - 1) / 2);
+    }
+}
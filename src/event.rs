@@ -0,0 +1,337 @@
+use super::{
+    const_mutex,
+    shared::{chaos, Primitive, Waiter},
+    Mutex,
+};
+use std::{fmt, pin::Pin, ptr::NonNull, time::Duration};
+
+/// Set in the flags of a waiter which was dequeued by set() and is being woken up.
+const WAITER_SIGNALED: usize = 1;
+
+/// A signal which threads can wait for without any associated data.
+///
+/// An event is either set or unset. Threads calling [`wait`](Event::wait) block while
+/// it is unset and [`set`](Event::set) releases them depending on the event's flavor:
+///
+/// - A manual-reset event wakes up all waiting threads and stays set, letting further
+///   waits pass through, until [`reset`](Event::reset) is called.
+/// - An auto-reset event releases exactly one waiting thread per `set`, resetting
+///   itself once that thread was released. If no thread is waiting, the event stays
+///   set until the next `wait` consumes it.
+///
+/// # Examples
+///
+/// ```
+/// use usync::Event;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let ready = Arc::new(Event::manual_reset(false));
+///
+/// let waiters: Vec<_> = (0..4)
+///     .map(|_| {
+///         let ready = ready.clone();
+///         thread::spawn(move || ready.wait())
+///     })
+///     .collect();
+///
+/// // Releases all the waiting threads at once.
+/// ready.set();
+/// for waiter in waiters {
+///     waiter.join().unwrap();
+/// }
+/// assert!(ready.is_set());
+/// ```
+pub struct Event {
+    manual_reset: bool,
+    state: Mutex<State>,
+}
+
+/// Whether the event is set, along with a FIFO queue of waiting threads
+/// doubly-linked through their `next` and `prev` fields.
+struct State {
+    is_set: bool,
+    head: Option<NonNull<Waiter>>,
+    tail: Option<NonNull<Waiter>>,
+}
+
+// SAFETY: Queued waiters are only accessed while the state is locked
+// and they only return after being removed from the queue.
+unsafe impl Send for State {}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("manual_reset", &self.manual_reset)
+            .field("is_set", &self.is_set())
+            .finish()
+    }
+}
+
+impl Event {
+    /// Creates a new manual-reset event, which wakes up all waiting threads when set.
+    pub const fn manual_reset(is_set: bool) -> Self {
+        Self::new(true, is_set)
+    }
+
+    /// Creates a new auto-reset event, which wakes up a single waiting thread when set.
+    pub const fn auto_reset(is_set: bool) -> Self {
+        Self::new(false, is_set)
+    }
+
+    const fn new(manual_reset: bool, is_set: bool) -> Self {
+        Self {
+            manual_reset,
+            state: const_mutex(State {
+                is_set,
+                head: None,
+                tail: None,
+            }),
+        }
+    }
+
+    /// Returns true if the event is currently set.
+    pub fn is_set(&self) -> bool {
+        self.state.lock().is_set
+    }
+
+    /// Sets the event.
+    ///
+    /// For a manual-reset event, this wakes up all waiting threads.
+    /// For an auto-reset event, this wakes up the longest waiting thread
+    /// or leaves the event set for the next waiter if there are none.
+    pub fn set(&self) {
+        let woken = {
+            let mut state = self.state.lock();
+            match self.manual_reset {
+                true => {
+                    state.is_set = true;
+                    state.tail = None;
+
+                    // Mark all waiters as signaled so they don't try to remove themselves on timeout.
+                    let woken = state.head.take();
+                    let mut current = woken;
+                    while let Some(waiter) = current {
+                        let waiter = unsafe { waiter.as_ref() };
+                        waiter.flags.set(WAITER_SIGNALED);
+                        current = waiter.next.get();
+                    }
+                    woken
+                }
+                false => match state.head {
+                    Some(waiter) => {
+                        unsafe { state.remove(waiter) };
+                        Some(waiter)
+                    }
+                    None => {
+                        state.is_set = true;
+                        None
+                    }
+                },
+            }
+        };
+
+        // Wake up the dequeued waiters outside the lock.
+        // The next field must be read before unpark() as it lets the waiter return.
+        unsafe {
+            chaos::wake_list(
+                woken,
+                |waiter| waiter.next.get(),
+                |waiter| waiter.as_ref().parker.unpark(),
+            );
+        }
+    }
+
+    /// Resets the event so that further waits block until it is set again.
+    pub fn reset(&self) {
+        self.state.lock().is_set = false;
+    }
+
+    /// Blocks the current thread until the event is set.
+    ///
+    /// An auto-reset event is reset again before this returns.
+    pub fn wait(&self) {
+        assert!(self.wait_with(None));
+    }
+
+    /// Blocks the current thread until the event is set or the `timeout` elapses.
+    ///
+    /// Returns false if the timeout elapsed before the event was set.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_with(Some(timeout))
+    }
+
+    fn wait_with(&self, timeout: Option<Duration>) -> bool {
+        Waiter::with(Primitive::new("Event", self), |waiter| {
+            {
+                let mut state = self.state.lock();
+                if state.is_set {
+                    state.is_set = self.manual_reset;
+                    return true;
+                }
+
+                state.push(waiter);
+            }
+
+            if waiter.parker.park(timeout) {
+                return true;
+            }
+
+            // On timeout, remove ourselves from the queue unless set() already dequeued us.
+            // In that case it's about to unpark us, so wait for that before returning.
+            {
+                let mut state = self.state.lock();
+                if waiter.flags.get() & WAITER_SIGNALED == 0 {
+                    unsafe { state.remove(NonNull::from(&*waiter)) };
+                    return false;
+                }
+            }
+
+            assert!(waiter.parker.park(None));
+            true
+        })
+    }
+}
+
+impl State {
+    /// Appends the waiter to the tail of the queue.
+    fn push(&mut self, waiter: Pin<&Waiter>) {
+        let waiter_ptr = NonNull::from(&*waiter);
+        waiter.flags.set(0);
+        waiter.next.set(None);
+        waiter.prev.set(self.tail);
+
+        match self.tail.replace(waiter_ptr) {
+            Some(tail) => unsafe { tail.as_ref().next.set(Some(waiter_ptr)) },
+            None => self.head = Some(waiter_ptr),
+        }
+    }
+
+    /// Unlinks a queued waiter and marks it as signaled.
+    unsafe fn remove(&mut self, waiter: NonNull<Waiter>) {
+        let prev = waiter.as_ref().prev.get();
+        let next = waiter.as_ref().next.get();
+
+        match prev {
+            Some(prev) => prev.as_ref().next.set(next),
+            None => self.head = next,
+        }
+
+        match next {
+            Some(next) => next.as_ref().prev.set(prev),
+            None => self.tail = prev,
+        }
+
+        waiter.as_ref().next.set(None);
+        waiter.as_ref().flags.set(WAITER_SIGNALED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Event;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn manual_reset() {
+        let event = Event::manual_reset(false);
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+
+        event.set();
+        event.wait();
+        assert!(event.wait_timeout(Duration::from_millis(10)));
+        assert!(event.is_set());
+
+        event.reset();
+        assert!(!event.is_set());
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn auto_reset() {
+        let event = Event::auto_reset(true);
+        event.wait();
+        assert!(!event.is_set());
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+
+        event.set();
+        event.set();
+        assert!(event.wait_timeout(Duration::from_millis(10)));
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn manual_reset_wakes_all() {
+        let event = Arc::new(Event::manual_reset(false));
+        let waiters: Vec<_> = (0..10)
+            .map(|_| {
+                let event = event.clone();
+                thread::spawn(move || event.wait())
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        event.set();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn auto_reset_wakes_one() {
+        const WAITERS: usize = 5;
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+        let event = Arc::new(Event::auto_reset(false));
+        let waiters: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let event = event.clone();
+                thread::spawn(move || {
+                    event.wait();
+                    WOKEN.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        // Each set() releases exactly one thread, whether it's already waiting or not.
+        for i in 1..=WAITERS {
+            event.set();
+            while WOKEN.load(Ordering::SeqCst) < i {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            assert_eq!(WOKEN.load(Ordering::SeqCst), i);
+        }
+
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert!(!event.is_set());
+    }
+
+    #[test]
+    fn wait_timeout_races_set() {
+        let event = Arc::new(Event::auto_reset(false));
+        for _ in 0..1000 {
+            let t = {
+                let event = event.clone();
+                thread::spawn(move || event.wait_timeout(Duration::from_micros(50)))
+            };
+
+            thread::sleep(Duration::from_micros(50));
+            event.set();
+
+            // Either the waiter consumed the set() or it's still pending.
+            let woken = t.join().unwrap();
+            assert_eq!(event.is_set(), !woken);
+            event.reset();
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use super::RawMutex;
+use lock_api::RawMutexFair as _RawMutexFair;
+use std::fmt;
+
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// Raw mutex type which hands the lock off to waiting threads in the order they arrived.
+///
+/// This is a [`RawMutex`] which always unlocks fairly.
+#[derive(Default)]
+#[repr(transparent)]
+pub struct RawFifoMutex {
+    mutex: RawMutex,
+}
+
+impl fmt::Debug for RawFifoMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RawFifoMutex { .. }")
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawFifoMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self {
+        mutex: RawMutex::INIT,
+    };
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.mutex.is_locked()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        self.mutex.lock()
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.mutex.try_lock()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.mutex.unlock_fair()
+    }
+}
+
+unsafe impl lock_api::RawMutexFair for RawFifoMutex {
+    #[inline]
+    unsafe fn unlock_fair(&self) {
+        self.mutex.unlock_fair()
+    }
+
+    #[inline]
+    unsafe fn bump(&self) {
+        self.mutex.bump()
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl lock_api::RawMutexTimed for RawFifoMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.mutex.try_lock_for(timeout)
+    }
+
+    #[inline]
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.mutex.try_lock_until(deadline)
+    }
+}
+
+/// A mutual exclusion primitive which grants the lock in first-come-first-served order
+///
+/// This is the same as [`Mutex`](type.Mutex.html) except that every unlock is fair:
+/// if there are threads waiting for the lock, it is handed directly to the one which
+/// has been waiting the longest without ever being released. A thread calling `lock`
+/// can therefore never barge ahead of threads which are already queued.
+///
+/// This bounds the time an unlucky thread waits under contention at the cost of
+/// throughput, as every contended unlock requires a context switch before the
+/// lock can be used again. Prefer [`Mutex`](type.Mutex.html) unless tail latency
+/// of acquiring the lock matters more than its overall performance.
+///
+/// A `FifoMutex` is the same size as `Mutex` but can't be used with a [`Condvar`](struct.Condvar.html).
+///
+/// # Examples
+///
+/// ```
+/// use usync::FifoMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let data = Arc::new(FifoMutex::new(Vec::new()));
+///
+/// let threads: Vec<_> = (0..10)
+///     .map(|i| {
+///         let data = Arc::clone(&data);
+///         thread::spawn(move || data.lock().push(i))
+///     })
+///     .collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// assert_eq!(data.lock().len(), 10);
+/// ```
+pub type FifoMutex<T> = lock_api::Mutex<RawFifoMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of a FIFO mutex. When this structure is
+/// dropped (falls out of scope), the lock will be handed off to the next waiting thread.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type FifoMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawFifoMutex, T>;
+
+/// An RAII mutex guard returned by `FifoMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedFifoMutexGuard` and `FifoMutexGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedFifoMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawFifoMutex, T>;
+
+/// Creates a new FIFO mutex in an unlocked state ready for use.
+///
+/// This allows creating a mutex in a constant context on stable Rust.
+pub const fn const_fifo_mutex<T>(value: T) -> FifoMutex<T> {
+    FifoMutex::const_new(<RawFifoMutex as lock_api::RawMutex>::INIT, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FifoMutex;
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let m = FifoMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(FifoMutex::new(0));
+
+        fn inc(m: &FifoMutex<u32>) {
+            for _ in 0..J {
+                *m.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..K {
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+        }
+
+        drop(tx);
+        for _ in 0..2 * K {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = FifoMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        const N: usize = 5;
+
+        let m = Arc::new(FifoMutex::new(Vec::new()));
+        let guard = m.lock();
+
+        // Queue up the threads one by one while the lock is held,
+        // waiting for each of them to become the new queue head.
+        let raw = || unsafe { &m.raw().mutex.rwlock };
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let head = raw().queue_head();
+                let thread = {
+                    let m = m.clone();
+                    thread::spawn(move || m.lock().push(i))
+                };
+                while raw().queue_head() == head {
+                    thread::yield_now();
+                }
+                thread
+            })
+            .collect();
+
+        drop(guard);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*m.lock(), (0..N).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_lock_for() {
+        let m = Arc::new(FifoMutex::new(0));
+        let guard = m.lock();
+
+        let m2 = m.clone();
+        let timed_out = thread::spawn(move || m2.try_lock_for(Duration::from_millis(50)).is_none());
+        assert!(timed_out.join().unwrap());
+
+        let m2 = m.clone();
+        let acquired = thread::spawn(move || *m2.try_lock_for(Duration::from_secs(60)).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        assert_eq!(acquired.join().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fifo_mutex_debug() {
+        let mutex = FifoMutex::new(vec![0u8, 10]);
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: [0, 10] }");
+    }
+
+    #[cfg(feature = "parking_lot_compat")]
+    #[test]
+    fn test_fair_mutex_aliases() {
+        use crate::{const_fair_mutex, FairMutex, FairMutexGuard};
+
+        static MUTEX: FairMutex<u32> = const_fair_mutex(0);
+        let mut guard = MUTEX.lock();
+        *guard += 1;
+        FairMutexGuard::unlock_fair(guard);
+        assert_eq!(*MUTEX.lock(), 1);
+    }
+}
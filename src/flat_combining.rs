@@ -0,0 +1,414 @@
+use super::shared::{Primitive, Waiter};
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+/// Set in the flags of a waiter once its operation was executed by the combiner.
+const WAITER_DONE: usize = 1;
+
+/// Set in the flags of a waiter which was handed the lock to become the next combiner.
+const WAITER_COMBINE: usize = 2;
+
+/// The maximum amount of times a combiner drains the published operations before
+/// handing the lock to a waiting thread, which bounds how long it runs on behalf of others.
+const MAX_PASSES: usize = 16;
+
+/// An experimental mutex where the lock holder executes the critical sections of the
+/// threads waiting for it on their behalf.
+///
+/// Instead of locking the mutex and running its own critical section, a thread passes
+/// it as a closure to [`execute`](FlatCombiningMutex::execute). If the mutex is locked,
+/// the closure is published for the current lock holder (the "combiner") to run, and
+/// its result is handed back to the waiting thread. This keeps the protected data in
+/// the combiner's cache instead of moving it between cores for every critical section,
+/// which beats handing the lock off when critical sections are very short and contention
+/// is extreme. Without contention, the closure simply runs on the current thread.
+///
+/// Since closures may run on another thread, they and their results must be `Send`.
+/// A panic in a closure is propagated to the thread which passed it, without affecting
+/// the combiner or the other waiting threads.
+///
+/// # Examples
+///
+/// ```
+/// use usync::FlatCombiningMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let counter = Arc::new(FlatCombiningMutex::new(0));
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let counter = counter.clone();
+///         thread::spawn(move || {
+///             for _ in 0..100 {
+///                 counter.execute(|value| *value += 1);
+///             }
+///         })
+///     })
+///     .collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+/// assert_eq!(counter.execute(|value| *value), 400);
+/// ```
+pub struct FlatCombiningMutex<T: ?Sized> {
+    locked: AtomicBool,
+    /// A stack of waiters with a published operation, linked through their `next` fields.
+    /// Each waiter's `waiting_on` field points to its operation.
+    requests: AtomicPtr<Waiter>,
+    value: UnsafeCell<T>,
+}
+
+/// A published operation, which runs the caller's closure on the protected data.
+type Operation<'a, T> = &'a mut (dyn FnMut(&mut T) + Send);
+
+unsafe impl<T: ?Sized + Send> Send for FlatCombiningMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for FlatCombiningMutex<T> {}
+
+impl<T: Default> Default for FlatCombiningMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for FlatCombiningMutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for FlatCombiningMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlatCombiningMutex")
+            .field("is_locked", &self.locked.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<T> FlatCombiningMutex<T> {
+    /// Creates a new flat-combining mutex in an unlocked state.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            requests: AtomicPtr::new(ptr::null_mut()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> FlatCombiningMutex<T> {
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the mutex mutably, no actual locking needs to take place.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Runs `f` with exclusive access to the protected data and returns its result.
+    ///
+    /// `f` either runs on the current thread, or on the thread currently holding the lock
+    /// while the current thread is blocked until it completed.
+    pub fn execute<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R + Send,
+        R: Send,
+    {
+        // Fast path: run the closure ourselves if the lock is free.
+        if self.try_lock() {
+            let _combiner = Combiner(self);
+            return f(unsafe { &mut *self.value.get() });
+        }
+
+        self.execute_slow(f)
+    }
+
+    #[cold]
+    fn execute_slow<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R + Send,
+        R: Send,
+    {
+        let mut f = Some(f);
+        let mut result: Option<Result<R, Box<dyn Any + Send>>> = None;
+        let mut op = |value: &mut T| {
+            let f = f.take().expect("operation executed twice");
+            result = Some(panic::catch_unwind(AssertUnwindSafe(|| f(value))));
+        };
+
+        Waiter::with(Primitive::new("FlatCombiningMutex", self), |waiter| {
+            let mut op: Operation<'_, T> = &mut op;
+            waiter.flags.set(0);
+            waiter
+                .waiting_on
+                .set(Some(NonNull::from(&mut op).cast::<()>()));
+
+            // Publish the operation.
+            // Release barrier ensures the combiner sees our waiter fields when it takes the stack.
+            let waiter_ptr = NonNull::from(&*waiter).as_ptr();
+            let mut head = self.requests.load(Ordering::Relaxed);
+            loop {
+                waiter.next.set(NonNull::new(head));
+                match self.requests.compare_exchange_weak(
+                    head,
+                    waiter_ptr,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => head = e,
+                }
+            }
+
+            // Either we become the combiner and run our own operation along with the others,
+            // or the current combiner sees our operation once it releases the lock.
+            // SeqCst ensures it can't release the lock without either of those happening.
+            if self.try_lock() {
+                drop(Combiner(self));
+            } else {
+                assert!(waiter.parker.park(None));
+
+                // The previous combiner took our operation off the stack and handed us the lock
+                // to run it ourselves along with the ones still published.
+                if waiter.flags.get() & WAITER_COMBINE != 0 {
+                    let combiner = Combiner(self);
+                    op(unsafe { &mut *self.value.get() });
+                    waiter.flags.set(WAITER_DONE);
+                    drop(combiner);
+                }
+            }
+
+            // The combiner which ran our operation released the lock or unparked us after.
+            debug_assert_ne!(waiter.flags.get() & WAITER_DONE, 0);
+        });
+
+        match result.expect("operation wasn't executed") {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        // SeqCst on failure as well, as publishers rely on seeing the lock held
+        // to know that the combiner will see their operation.
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Runs the published operations, returning false if there were none.
+    ///
+    /// Must be called while holding the lock.
+    unsafe fn combine(&self) -> bool {
+        // Acquire barrier ensures we see the waiter fields of the published operations.
+        let mut requests = NonNull::new(self.requests.swap(ptr::null_mut(), Ordering::Acquire));
+        if requests.is_none() {
+            return false;
+        }
+
+        // The next field must be read before unpark() as it lets the waiter return.
+        // Operations can't unwind as their panics are caught for the waiter to resume them.
+        while let Some(waiter) = requests {
+            let waiter = waiter.as_ref();
+            requests = waiter.next.get();
+
+            let op = waiter
+                .waiting_on
+                .get()
+                .expect("published waiter without operation");
+            (*op.cast::<Operation<'_, T>>().as_ptr())(&mut *self.value.get());
+
+            waiter.flags.set(WAITER_DONE);
+            waiter.parker.unpark();
+        }
+
+        true
+    }
+
+    /// Takes a waiter off the stack of published operations and hands it the lock,
+    /// returning false if there were none.
+    ///
+    /// Must be called while holding the lock, which the waiter releases in our place.
+    unsafe fn hand_off(&self) -> bool {
+        // Only the lock holder takes waiters off the stack, so the head can't be
+        // popped and pushed again between reading its next field and the CAS.
+        // Acquire barrier ensures we see the waiter fields of the published operation.
+        let mut head = self.requests.load(Ordering::Acquire);
+        loop {
+            let waiter = match NonNull::new(head) {
+                Some(waiter) => waiter.as_ref(),
+                None => return false,
+            };
+
+            let next = waiter.next.get().map_or(ptr::null_mut(), NonNull::as_ptr);
+            match self.requests.compare_exchange_weak(
+                head,
+                next,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    waiter.flags.set(WAITER_COMBINE);
+                    waiter.parker.unpark();
+                    return true;
+                }
+                Err(e) => head = e,
+            }
+        }
+    }
+}
+
+/// Runs the published operations on drop before releasing the lock.
+///
+/// Threads publish operations while they find the lock held, so the lock is only
+/// released once no operation is left or another thread took over as the combiner.
+/// After [`MAX_PASSES`] the lock is handed to a waiting thread instead, which becomes
+/// the combiner so that a thread doesn't keep running operations under sustained load.
+struct Combiner<'a, T: ?Sized>(&'a FlatCombiningMutex<T>);
+
+impl<T: ?Sized> Drop for Combiner<'_, T> {
+    fn drop(&mut self) {
+        let mutex = self.0;
+        let mut passes = 0;
+        loop {
+            while passes < MAX_PASSES && unsafe { mutex.combine() } {
+                passes += 1;
+            }
+
+            if passes == MAX_PASSES && unsafe { mutex.hand_off() } {
+                return;
+            }
+
+            // SeqCst ensures threads publishing operations after we checked for them
+            // see the lock released and take over as the combiner.
+            mutex.locked.store(false, Ordering::SeqCst);
+            if mutex.requests.load(Ordering::SeqCst).is_null() || !mutex.try_lock() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FlatCombiningMutex;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let mut mutex = FlatCombiningMutex::new(vec![1]);
+        mutex.execute(|v| v.push(2));
+        assert_eq!(mutex.execute(|v| v.len()), 2);
+        mutex.get_mut().push(3);
+        assert_eq!(mutex.into_inner(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 10_000;
+
+        let mutex = Arc::new(FlatCombiningMutex::new((0usize, 0usize)));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..ITERS {
+                        // Results are returned to the thread which published the operation.
+                        let value = mutex.execute(|v| {
+                            v.0 += 1;
+                            v.1 += 1;
+                            *v
+                        });
+                        assert_eq!(value.0, value.1);
+                        assert!(value.0 > last);
+                        last = value.0;
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(mutex.execute(|v| *v), (THREADS * ITERS, THREADS * ITERS));
+    }
+
+    #[test]
+    fn combiner_returns_under_load() {
+        const THREADS: usize = 4;
+
+        let mutex = Arc::new(FlatCombiningMutex::new(0usize));
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mutex = mutex.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        mutex.execute(|v| *v += 1);
+                    }
+                })
+            })
+            .collect();
+
+        // The other threads keep publishing operations, so whoever is the combiner
+        // only returns by handing the lock to one of them.
+        for _ in 0..1000 {
+            mutex.execute(|v| *v += 1);
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(mutex.execute(|v| *v) >= 1000);
+    }
+
+    #[test]
+    fn panic_is_propagated() {
+        let mutex = Arc::new(FlatCombiningMutex::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    for j in 0..1000 {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            mutex.execute(|v| {
+                                if (i + j) % 10 == 0 {
+                                    panic!("operation panicked");
+                                }
+                                *v += 1;
+                            })
+                        }));
+                        assert_eq!(result.is_err(), (i + j) % 10 == 0);
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(mutex.execute(|v| *v), 3600);
+    }
+}
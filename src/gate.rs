@@ -0,0 +1,203 @@
+use super::shared::{chaos, invalid_mut, Primitive, Waiter};
+use std::{
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// The state of an opened gate. Waiters are aligned so this can't be a waiter address.
+const OPEN: *mut Waiter = invalid_mut(1);
+
+/// A one-shot gate which blocks threads until it is opened, and stays open forever after.
+///
+/// Any number of threads can [`wait`](Gate::wait) on a closed gate. Calling
+/// [`open`](Gate::open) wakes all of them up, and every later wait returns immediately.
+/// Unlike an [`Event`](crate::Event), a gate can't be closed again, which keeps it
+/// a single word large and makes waiting on an open gate a single atomic load.
+///
+/// # Examples
+///
+/// ```
+/// use usync::Gate;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let started = Arc::new(Gate::new());
+///
+/// let workers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let started = started.clone();
+///         thread::spawn(move || {
+///             // Block until initialization is complete.
+///             started.wait();
+///         })
+///     })
+///     .collect();
+///
+/// started.open();
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+/// assert!(started.is_open());
+/// ```
+pub struct Gate {
+    /// Either OPEN, or a stack of threads blocked in wait() linked through their `next` fields.
+    state: AtomicPtr<Waiter>,
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Gate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gate")
+            .field("is_open", &self.is_open())
+            .finish()
+    }
+}
+
+impl Gate {
+    /// Creates a new gate in the closed state.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns true if the gate was opened.
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.state.load(Ordering::Acquire) == OPEN
+    }
+
+    /// Opens the gate, waking up all the threads waiting on it.
+    ///
+    /// Opening an already opened gate does nothing.
+    pub fn open(&self) {
+        // Release barrier ensures our writes happen before waiters return.
+        // Acquire barrier ensures the waiter writes from wait_slow() happen before we wake them.
+        let state = self.state.swap(OPEN, Ordering::AcqRel);
+        if state == OPEN {
+            return;
+        }
+
+        // The next field must be read before unpark() as it lets the waiter return.
+        unsafe {
+            chaos::wake_list(
+                NonNull::new(state),
+                |waiter| waiter.next.get(),
+                |waiter| waiter.as_ref().parker.unpark(),
+            );
+        }
+    }
+
+    /// Blocks the current thread until the gate is opened.
+    ///
+    /// Returns immediately if the gate is already open.
+    #[inline]
+    pub fn wait(&self) {
+        if !self.is_open() {
+            self.wait_slow();
+        }
+    }
+
+    #[cold]
+    fn wait_slow(&self) {
+        Waiter::with(Primitive::new("Gate", self), |waiter| {
+            let waiter_ptr = NonNull::from(&*waiter).as_ptr();
+            let mut state = self.state.load(Ordering::Acquire);
+            loop {
+                if state == OPEN {
+                    return;
+                }
+
+                // Release barrier ensures open() sees our waiter fields when it takes the stack.
+                waiter.next.set(NonNull::new(state));
+                match self.state.compare_exchange_weak(
+                    state,
+                    waiter_ptr,
+                    Ordering::Release,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => state = e,
+                }
+            }
+
+            // open() takes the whole stack, so it's responsible for waking us up.
+            assert!(waiter.parker.park(None));
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Gate;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let gate = Gate::new();
+        assert!(!gate.is_open());
+        gate.open();
+        assert!(gate.is_open());
+        gate.wait();
+        gate.open();
+        gate.wait();
+        assert_eq!(format!("{:?}", gate), "Gate { is_open: true }");
+    }
+
+    #[test]
+    fn open_wakes_all() {
+        const THREADS: usize = 10;
+        let gate = Arc::new(Gate::new());
+        let passed = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (gate, passed) = (gate.clone(), passed.clone());
+                thread::spawn(move || {
+                    gate.wait();
+                    passed.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(passed.load(Ordering::Relaxed), 0);
+
+        gate.open();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(passed.load(Ordering::Relaxed), THREADS);
+    }
+
+    #[test]
+    fn open_races_wait() {
+        for _ in 0..100 {
+            let gate = Arc::new(Gate::new());
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let gate = gate.clone();
+                    thread::spawn(move || gate.wait())
+                })
+                .collect();
+
+            gate.open();
+            for t in threads {
+                t.join().unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! How long `Mutex` and `RwLock` are held, for the `hold_time` feature.
+//!
+//! Contention usually comes from locks being held for long rather than acquired often.
+//! With the feature enabled, every release of a lock records how long the releasing thread
+//! held it into a histogram kept for that lock, which [`for_mutex`] and [`for_rwlock`]
+//! return as [`HoldTimes`] to query percentiles from.
+//!
+//! Histograms are kept by the address of the lock and survive it, so [`reset`] should be
+//! called to start over once locks which were measured are dropped. A lock released on
+//! another thread than the one which acquired it (with the `send_guard` feature) isn't
+//! measured. Measuring makes every acquisition slower, so the feature is meant for profiling.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::{hold_time, Mutex};
+//!
+//! let mutex = Mutex::new(0);
+//! for _ in 0..100 {
+//!     *mutex.lock() += 1;
+//! }
+//!
+//! let hold_times = hold_time::for_mutex(&mutex).unwrap();
+//! assert_eq!(hold_times.count(), 100);
+//! assert!(hold_times.percentile(50.0) <= hold_times.max());
+//! ```
+
+use crate::{const_spin_lock, shared::label, Mutex, RwLock, SpinLock};
+use std::{collections::HashMap, fmt, time::Duration};
+
+/// Values below this are counted exactly, and each power of two above it is split into
+/// this many buckets, which keeps percentiles within 25% of the measured hold times.
+const SUB_BUCKETS: u64 = 4;
+const BUCKETS: usize = (SUB_BUCKETS + (64 - 2) * SUB_BUCKETS) as usize;
+
+fn bucket_index(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+
+    let exponent = 63 - nanos.leading_zeros() as u64;
+    let sub_bucket = (nanos >> (exponent - 2)) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS + (exponent - 2) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// Returns the largest value counted in the bucket.
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+
+    let exponent = (index - SUB_BUCKETS) / SUB_BUCKETS + 2;
+    let sub_bucket = (index - SUB_BUCKETS) % SUB_BUCKETS;
+    let start = (SUB_BUCKETS | sub_bucket) << (exponent - 2);
+    start.saturating_add((1 << (exponent - 2)) - 1)
+}
+
+/// A histogram of how long a lock was held.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HoldTimes {
+    count: u64,
+    total_nanos: u128,
+    max_nanos: u64,
+    buckets: Box<[u64; BUCKETS]>,
+}
+
+impl fmt::Debug for HoldTimes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HoldTimes")
+            .field("count", &self.count)
+            .field("mean", &self.mean())
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.max())
+            .finish()
+    }
+}
+
+impl HoldTimes {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            total_nanos: 0,
+            max_nanos: 0,
+            buckets: Box::new([0; BUCKETS]),
+        }
+    }
+
+    fn record(&mut self, held: Duration) {
+        let nanos = held.as_nanos().min(u64::MAX as u128) as u64;
+        self.count += 1;
+        self.total_nanos += nanos as u128;
+        self.max_nanos = self.max_nanos.max(nanos);
+        self.buckets[bucket_index(nanos)] += 1;
+    }
+
+    /// Returns how many times the lock was released.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the average time the lock was held for.
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total_nanos / count as u128) as u64),
+        }
+    }
+
+    /// Returns the longest time the lock was held for.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    /// Returns the time which the given percentage of the holds didn't exceed,
+    /// e.g. `percentile(99.0)` for the 99th percentile.
+    ///
+    /// The result is rounded up to the resolution of the histogram, but never exceeds [`max`].
+    ///
+    /// [`max`]: HoldTimes::max
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let percentile = percentile.clamp(0.0, 100.0);
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(index).min(self.max_nanos));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+// Spin locks are used as the instrumented locks would measure themselves.
+static HOLD_TIMES: SpinLock<Option<HashMap<usize, HoldTimes>>> = const_spin_lock(None);
+
+pub(crate) fn record(lock: usize, held: Duration) {
+    HOLD_TIMES
+        .lock()
+        .get_or_insert_with(HashMap::new)
+        .entry(lock)
+        .or_insert_with(HoldTimes::new)
+        .record(held);
+}
+
+fn hold_times(lock: usize) -> Option<HoldTimes> {
+    HOLD_TIMES.lock().as_ref()?.get(&lock).cloned()
+}
+
+/// Returns how long the mutex was held, or `None` if it was never released.
+pub fn for_mutex<T: ?Sized>(mutex: &Mutex<T>) -> Option<HoldTimes> {
+    hold_times(label::mutex_addr(mutex))
+}
+
+/// Returns how long the rwlock was held, in shared and exclusive mode together,
+/// or `None` if it was never released.
+pub fn for_rwlock<T: ?Sized>(rwlock: &RwLock<T>) -> Option<HoldTimes> {
+    hold_times(label::rwlock_addr(rwlock))
+}
+
+/// Forgets the hold times of all the locks.
+pub fn reset() {
+    *HOLD_TIMES.lock() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket_index, bucket_max, for_rwlock, HoldTimes, BUCKETS};
+    use crate::RwLock;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn buckets() {
+        let mut previous_max = None;
+        for index in 0..BUCKETS {
+            let max = bucket_max(index);
+            assert_eq!(bucket_index(max), index);
+            if let Some(previous_max) = previous_max {
+                assert_eq!(bucket_index(previous_max + 1), index);
+            }
+            previous_max = Some(max);
+        }
+        assert_eq!(bucket_max(BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut hold_times = HoldTimes::new();
+        assert_eq!(hold_times.percentile(50.0), Duration::ZERO);
+
+        for micros in 1..=100 {
+            hold_times.record(Duration::from_micros(micros));
+        }
+        assert_eq!(hold_times.count(), 100);
+        assert_eq!(hold_times.max(), Duration::from_micros(100));
+        assert_eq!(hold_times.percentile(100.0), Duration::from_micros(100));
+
+        let p50 = hold_times.percentile(50.0);
+        assert!(p50 >= Duration::from_micros(50) && p50 <= Duration::from_micros(63));
+    }
+
+    #[test]
+    fn records_holds() {
+        // Another test's lock may have been measured at the same address.
+        let rwlock = RwLock::new(());
+        let count = for_rwlock(&rwlock).map_or(0, |hold_times| hold_times.count());
+
+        drop(rwlock.read());
+        let guard = rwlock.write();
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+
+        let hold_times = for_rwlock(&rwlock).unwrap();
+        assert_eq!(hold_times.count(), count + 2);
+        assert!(hold_times.max() >= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,219 @@
+//! Interrupting blocked lock acquisitions with signals, for signal-driven shutdown on Unix.
+//!
+//! [`interrupt_on`] marks a signal as interrupting: once the process receives it, every
+//! `Mutex` acquisition blocked in
+//! [`MutexExt::lock_interruptible`](crate::MutexExt::lock_interruptible), as well as all
+//! future ones, returns [`Cancelled`](crate::Cancelled) instead of waiting for the lock.
+//! The same happens when calling [`interrupt`] directly. Interruption is permanent, so it's
+//! meant for shutting the process down rather than for aborting single waits, for which a
+//! [`CancellationToken`] works better.
+//!
+//! Waking up threads isn't possible from a signal handler, as it needs locks of its own.
+//! The installed handler only writes to a pipe, and a background thread reading from it
+//! does the actual wake ups.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::{interrupt, Mutex, MutexExt};
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! interrupt::interrupt_on(libc::SIGUSR1).unwrap();
+//!
+//! let mutex = Arc::new(Mutex::new(()));
+//! let guard = mutex.lock();
+//! let t = {
+//!     let mutex = mutex.clone();
+//!     thread::spawn(move || mutex.lock_interruptible().is_err())
+//! };
+//!
+//! unsafe { libc::raise(libc::SIGUSR1) };
+//! assert!(t.join().unwrap());
+//! assert!(interrupt::is_interrupted());
+//! drop(guard);
+//! ```
+
+use crate::{const_mutex, CancellationToken, Mutex};
+use std::{
+    io, mem, ptr,
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+};
+
+static TOKEN: CancellationToken = CancellationToken::new();
+
+/// The write end of the pipe signal handlers notify the interrupt thread through, or -1.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Serializes starting the interrupt thread and installing signal handlers.
+static SETUP: Mutex<()> = const_mutex(());
+
+/// Marks `signal` as interrupting blocked lock acquisitions, by installing a handler for it.
+///
+/// This replaces the signal's current handler, including its default action, so e.g. the
+/// process isn't terminated anymore when the signal is `SIGTERM`. The first call also starts
+/// the background thread doing the interruption.
+pub fn interrupt_on(signal: libc::c_int) -> io::Result<()> {
+    let _setup = SETUP.lock();
+    if PIPE.load(Ordering::Relaxed) < 0 {
+        start()?;
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Restarting interrupted system calls keeps the rest of the program unaffected.
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        check(libc::sigaction(signal, &action, ptr::null_mut()))
+    }
+}
+
+/// Interrupts every blocked interruptible lock acquisition, and all future ones.
+pub fn interrupt() {
+    TOKEN.cancel();
+}
+
+/// Returns true if the process was interrupted, by a signal or by [`interrupt`].
+pub fn is_interrupted() -> bool {
+    TOKEN.is_cancelled()
+}
+
+/// Returns the token which is cancelled when the process is interrupted.
+pub(crate) fn token() -> &'static CancellationToken {
+    &TOKEN
+}
+
+fn start() -> io::Result<()> {
+    let mut fds = [0; 2];
+    unsafe {
+        check(libc::pipe(fds.as_mut_ptr()))?;
+        let [read, write] = fds;
+
+        // The handler must never block, even if the pipe were to fill up.
+        let result = check(libc::fcntl(read, libc::F_SETFD, libc::FD_CLOEXEC))
+            .and_then(|_| check(libc::fcntl(write, libc::F_SETFD, libc::FD_CLOEXEC)))
+            .and_then(|_| check(libc::fcntl(write, libc::F_SETFL, libc::O_NONBLOCK)))
+            .and_then(|_| {
+                thread::Builder::new()
+                    .name("usync-interrupt".into())
+                    .spawn(move || wait(read))
+                    .map(drop)
+            });
+
+        if let Err(error) = result {
+            libc::close(read);
+            libc::close(write);
+            return Err(error);
+        }
+
+        PIPE.store(write, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn wait(read: libc::c_int) {
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(read, &mut byte as *mut u8 as *mut _, 1) } {
+            1 => return interrupt(),
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            // The write end is never closed, so this doesn't happen in practice.
+            _ => return,
+        }
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    // Only async-signal-safe functions can be called here. The errno of the interrupted code
+    // is preserved, as write() may change it.
+    let errno = errno::get();
+    let byte = 1u8;
+    unsafe {
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const _,
+            1,
+        );
+    }
+    errno::set(errno);
+}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+    match rc {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "emscripten",
+    target_vendor = "apple",
+    target_os = "freebsd"
+))]
+mod errno {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "emscripten"))]
+    use libc::__errno_location as location;
+    #[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+    use libc::__error as location;
+
+    pub(super) fn get() -> libc::c_int {
+        unsafe { *location() }
+    }
+
+    pub(super) fn set(errno: libc::c_int) {
+        unsafe { *location() = errno }
+    }
+}
+
+// Elsewhere, errno is left as changed by the handler.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "emscripten",
+    target_vendor = "apple",
+    target_os = "freebsd"
+)))]
+mod errno {
+    pub(super) fn get() -> libc::c_int {
+        0
+    }
+
+    pub(super) fn set(_errno: libc::c_int) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interrupt_on, is_interrupted};
+    use crate::{Mutex, MutexExt};
+    use std::{sync::Arc, thread, time::Duration};
+
+    // Interruption is process-wide and permanent, so this is the only test using it.
+    #[test]
+    fn signal_interrupts_lock() {
+        interrupt_on(libc::SIGUSR2).unwrap();
+        interrupt_on(libc::SIGUSR2).unwrap();
+
+        let mutex = Arc::new(Mutex::new(()));
+        assert!(mutex.lock_interruptible().is_ok());
+
+        let guard = mutex.lock();
+        let t = {
+            let mutex = mutex.clone();
+            thread::spawn(move || mutex.lock_interruptible().is_err())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!is_interrupted());
+
+        assert_eq!(unsafe { libc::raise(libc::SIGUSR2) }, 0);
+        assert!(t.join().unwrap());
+        assert!(is_interrupted());
+
+        // Free locks are still acquired, like with a cancelled token.
+        drop(guard);
+        assert!(mutex.lock_interruptible().is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+use super::Once;
+use std::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    mem::MaybeUninit,
+    ops::Deref,
+    panic::{RefUnwindSafe, UnwindSafe},
+};
+
+/// A value which is initialized on the first access.
+///
+/// The initialization function is run by the first thread to dereference the `LazyLock`
+/// while other threads accessing it concurrently block until it completes, using the
+/// same thread queues as [`Once`](struct.Once.html).
+///
+/// If the initialization function panics, the `LazyLock` is poisoned and every
+/// further access panics too.
+///
+/// # Examples
+///
+/// ```
+/// use usync::LazyLock;
+/// use std::collections::HashMap;
+///
+/// static HASHMAP: LazyLock<HashMap<u32, &'static str>> = LazyLock::new(|| {
+///     let mut m = HashMap::new();
+///     m.insert(0, "foo");
+///     m.insert(1, "bar");
+///     m
+/// });
+///
+/// assert_eq!(HASHMAP.get(&0), Some(&"foo"));
+/// assert_eq!(HASHMAP.len(), 2);
+/// ```
+pub struct LazyLock<T, F = fn() -> T> {
+    once: Once,
+    init: Cell<Option<F>>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is shared between threads once initialized and it may be initialized
+// by any thread, which then also drops the initialization function.
+unsafe impl<T: Send + Sync, F: Send> Sync for LazyLock<T, F> {}
+
+impl<T: UnwindSafe + RefUnwindSafe, F: UnwindSafe> RefUnwindSafe for LazyLock<T, F> {}
+impl<T: UnwindSafe, F: UnwindSafe> UnwindSafe for LazyLock<T, F> {}
+
+impl<T, F> LazyLock<T, F> {
+    /// Creates a new lazy value with the given initialization function.
+    pub const fn new(f: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: Cell::new(Some(f)),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    fn get(&self) -> Option<&T> {
+        match self.once.is_completed() {
+            true => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            false => None,
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> LazyLock<T, F> {
+    /// Forces the evaluation of this lazy value and returns a reference to the result.
+    ///
+    /// This is equivalent to the `Deref` impl, but is explicit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the initialization function panicked, now or on a previous access.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let f = match this.init.take() {
+                Some(f) => f,
+                None => unreachable!("LazyLock initialization function was already called"),
+            };
+
+            // Safety: only the thread running the Once writes the value.
+            let value = f();
+            unsafe { (*this.value.get()).as_mut_ptr().write(value) };
+        });
+
+        // Safety: call_once() only returns once the value has been written.
+        unsafe { (*this.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+impl<T: Default> Default for LazyLock<T> {
+    /// Creates a new lazy value using `Default` as the initialization function.
+    fn default() -> Self {
+        Self::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for LazyLock<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("LazyLock").field(value).finish(),
+            None => f.write_str("LazyLock(<uninit>)"),
+        }
+    }
+}
+
+impl<T, F> Drop for LazyLock<T, F> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { (*self.value.get()).as_mut_ptr().drop_in_place() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LazyLock;
+    use std::{
+        cell::Cell,
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let calls = Cell::new(0);
+        let lazy = LazyLock::new(|| {
+            calls.set(calls.get() + 1);
+            92
+        });
+
+        assert_eq!(calls.get(), 0);
+        assert_eq!(*lazy, 92);
+        assert_eq!(*LazyLock::force(&lazy), 92);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn static_lazy() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LAZY: LazyLock<Vec<i32>> = LazyLock::new(|| {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            vec![1, 2, 3]
+        });
+
+        let threads: Vec<_> = (0..10)
+            .map(|_| thread::spawn(|| assert_eq!(LAZY.len(), 3)))
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn poisoned() {
+        let lazy: LazyLock<i32> = LazyLock::new(|| panic!());
+        assert!(panic::catch_unwind(|| *lazy).is_err());
+        assert!(panic::catch_unwind(|| *lazy).is_err());
+    }
+
+    #[test]
+    fn drop_value() {
+        let value = Arc::new(());
+        let lazy: LazyLock<Arc<()>, _> = LazyLock::new(|| value.clone());
+        drop(lazy);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        let lazy = LazyLock::new(|| value.clone());
+        assert_eq!(Arc::strong_count(&*lazy), 2);
+        drop(lazy);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_lazy_lock_debug() {
+        let lazy = LazyLock::new(|| 5);
+        assert_eq!(format!("{:?}", lazy), "LazyLock(<uninit>)");
+        LazyLock::force(&lazy);
+        assert_eq!(format!("{:?}", lazy), "LazyLock(5)");
+    }
+}
@@ -1,3 +1,4 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(
     rust_2018_idioms,
     unreachable_pub,
@@ -6,21 +7,132 @@
 )]
 
 //! This library provides implementations of `Mutex`, `RwLock`, `Condvar`,
-//! `Barrier`, and `Once` that are smaller and faster than those in the Rust
-//! standard library. It also provides a `ReentrantMutex` type.
+//! `Barrier`, `Phaser`, `Semaphore`, `WaitGroup`, `Event`, `Gate`, `Mailbox`,
+//! `Once`, `OnceLock` and `LazyLock` that are smaller and faster than those
+//! in the Rust standard library.
+//! It also provides `ReentrantMutex`, `FifoMutex`, `McsMutex`, `AdaptiveMutex`, `ByteMutex`,
+//! `SpinLock`, `FlatCombiningMutex`, `ShardedLock`, `SeqLock`, `RcuCell`, `AtomicArc` and
+//! `AtomicCell` types, and a `BitLock` for locking spare bits of an existing `AtomicUsize`.
+//! `RateLimiter` throttles operations to a number per time window.
+//! For code relying on lock poisoning, [`std_compat`] has locks with the exact API of `std::sync`.
+//! `RawSharedMutex` and `RawSharedRwLock` have a fixed `#[repr(C)]` layout without pointers,
+//! for memory shared with C code or other processes.
 //!
-//! Everything is powered by lock-free thread queues in userspace
-//! which allows the synchronization primitives to be 1 word (`usize`) large.
-//! All thread blocking is done through [`std::thread::park`] for maximum portability.
+//! The core primitives are powered by lock-free thread queues in userspace, which allows
+//! `Mutex`, `RwLock`, `Condvar`, `Barrier` and `Once` to be 1 word (`usize`) large.
+//! Higher level types such as `Semaphore`, `Event`, `Phaser`, `Notify` and `Mailbox`
+//! keep their state behind one of those locks and are larger.
+//!
+//! Queued threads block on the most efficient primitive of each platform: a futex on Linux
+//! and Android, `WaitOnAddress` (or NT keyed events before Windows 8) on Windows, and
+//! `__ulock_wait` on macOS and iOS. WebAssembly without threads has a backend which panics
+//! instead of hanging, and other platforms fall back to [`std::thread::park`].
+//!
+//! # `no_std` support
+//!
+//! Disabling the default `std` feature makes the crate `no_std` (it still requires `alloc`).
+//! The application then has to tell how to block threads by implementing [`RawParker`] and
+//! registering it with [`set_parker!`]. Only the primitives which don't need a clock,
+//! panic unwinding or other services of the standard library are available in this mode.
+//!
+//! On bare-metal targets without threads, the `critical-section` feature registers a parker
+//! instead, which spins until an interrupt handler wakes the thread up and updates its state
+//! with the [`critical-section`](https://docs.rs/critical-section) crate.
+//!
+//! # Migrating from `parking_lot`
+//!
+//! The locks are built on [`lock_api`] like those of `parking_lot`, and the crate exports the
+//! same names for them. With the `parking_lot_compat` feature, which adds `FairMutex` and its
+//! guards as aliases of [`FifoMutex`], a codebase can switch by renaming the dependency:
+//!
+//! ```toml
+//! parking_lot = { package = "usync", version = "0.2", features = ["parking_lot_compat"] }
+//! ```
+//!
+//! `Mutex`, `FairMutex` and `AdaptiveMutex` support both fair unlocking and timed locking.
+//! `RwLock` supports fair unlocking, downgrading, timed locking and upgradable reads.
+
+// Lets the modules use `std::` paths for items which are also in `core` either way.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
 
+extern crate alloc;
+
+mod adaptive_mutex;
+#[cfg(feature = "async")]
+mod async_once_lock;
+#[cfg(feature = "std")]
+mod atomic_arc;
+mod atomic_cell;
+#[cfg(feature = "std")]
+pub mod atomic_wait;
 mod barrier;
+mod bit_lock;
+mod byte_mutex;
+mod cancel;
+#[cfg(feature = "std")]
+pub mod clock;
 mod condvar;
+pub mod config;
+#[cfg(feature = "std")]
+pub mod contention;
+#[cfg(feature = "deadlock_detection")]
+pub mod deadlock;
+#[cfg(feature = "std")]
+pub mod deque;
+mod event;
+mod fifo_mutex;
+#[cfg(feature = "std")]
+mod flat_combining;
+mod gate;
+#[cfg(feature = "hold_time")]
+pub mod hold_time;
+#[cfg(all(feature = "std", unix))]
+pub mod interrupt;
+mod lazy_lock;
+#[cfg(feature = "std")]
+mod mailbox;
+mod mcs_mutex;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod mutex;
+#[cfg(feature = "std")]
+pub mod named;
+#[cfg(all(feature = "std", unix))]
+mod named_semaphore;
+#[cfg(feature = "std")]
+mod notify;
 mod once;
+mod once_lock;
+pub mod parking;
+mod phaser;
+#[cfg(feature = "std")]
+pub mod priority;
+#[cfg(all(feature = "std", unix))]
+mod priority_ceiling_mutex;
+#[cfg(feature = "std")]
+mod process_shared;
+#[cfg(feature = "std")]
+mod rate_limiter;
+mod raw_parker;
+#[cfg(feature = "std")]
+mod rcu_cell;
 mod reentrant_mutex;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "freebsd")))]
+mod robust_mutex;
 mod rwlock;
+mod semaphore;
+mod seq_lock;
+#[cfg(feature = "std")]
+mod sharded_lock;
 mod shared;
+mod spin_lock;
+#[cfg(feature = "std")]
+pub mod std_compat;
 mod thread_id;
+mod wait_group;
+#[cfg(feature = "std")]
+pub mod watchdog;
 
 pub use ::lock_api;
 
@@ -30,16 +142,69 @@ type GuardMarker = lock_api::GuardSend;
 type GuardMarker = lock_api::GuardNoSend;
 
 pub use self::{
+    adaptive_mutex::{
+        const_adaptive_mutex, AdaptiveMutex, AdaptiveMutexGuard, MappedAdaptiveMutexGuard,
+        RawAdaptiveMutex,
+    },
+    atomic_cell::AtomicCell,
     barrier::{Barrier, BarrierWaitResult},
+    bit_lock::BitLock,
+    byte_mutex::{const_byte_mutex, ByteMutex, ByteMutexGuard, MappedByteMutexGuard, RawByteMutex},
+    cancel::{CancellationToken, Cancelled},
     condvar::{Condvar, WaitTimeoutResult},
-    mutex::{const_mutex, MappedMutexGuard, Mutex, MutexGuard, RawMutex},
-    once::{Once, OnceState},
+    event::Event,
+    fifo_mutex::{const_fifo_mutex, FifoMutex, FifoMutexGuard, MappedFifoMutexGuard, RawFifoMutex},
+    gate::Gate,
+    lazy_lock::LazyLock,
+    mcs_mutex::{const_mcs_mutex, MappedMcsMutexGuard, McsMutex, McsMutexGuard, RawMcsMutex},
+    mutex::{const_mutex, MappedMutexGuard, Mutex, MutexExt, MutexGuard, RawMutex},
+    once::{Once, OnceState, TryCallOnceResult},
+    once_lock::OnceLock,
+    phaser::Phaser,
+    raw_parker::RawParker,
     reentrant_mutex::{
         const_reentrant_mutex, MappedReentrantMutexGuard, ReentrantMutex, ReentrantMutexGuard,
     },
     rwlock::{
-        const_rwlock, MappedRwLockReadGuard, MappedRwLockWriteGuard, RawRwLock, RwLock,
-        RwLockReadGuard, RwLockWriteGuard,
+        const_rwlock, MappedRwLockReadGuard, MappedRwLockWriteGuard, RawRwLock, RwLock, RwLockExt,
+        RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
     },
+    semaphore::{AcquireError, OwnedPermit, Semaphore, SemaphorePermit},
+    seq_lock::{SeqLock, SeqLockWriteGuard},
+    spin_lock::{const_spin_lock, MappedSpinLockGuard, RawSpinLock, SpinLock, SpinLockGuard},
     thread_id::RawThreadId,
+    wait_group::WaitGroup,
+};
+
+#[cfg(feature = "std")]
+pub use self::{
+    atomic_arc::AtomicArc,
+    flat_combining::FlatCombiningMutex,
+    mailbox::Mailbox,
+    notify::Notify,
+    process_shared::{RawSharedMutex, RawSharedRwLock},
+    rate_limiter::RateLimiter,
+    rcu_cell::{RcuCell, RcuReadGuard},
+    sharded_lock::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard},
+};
+
+#[cfg(all(feature = "std", unix))]
+pub use self::named_semaphore::{NamedSemaphore, NamedSemaphorePermit};
+
+#[cfg(all(feature = "std", unix))]
+pub use self::priority_ceiling_mutex::{
+    const_priority_ceiling_mutex, MappedPriorityCeilingMutexGuard, PriorityCeilingMutex,
+    PriorityCeilingMutexGuard, RawPriorityCeilingMutex,
+};
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "freebsd")))]
+pub use self::robust_mutex::{RobustLockError, RobustMutex, RobustMutexGuard};
+
+#[cfg(feature = "async")]
+pub use self::{async_once_lock::AsyncOnceLock, notify::Notified};
+
+#[cfg(feature = "parking_lot_compat")]
+pub use self::fifo_mutex::{
+    const_fifo_mutex as const_fair_mutex, FifoMutex as FairMutex, FifoMutexGuard as FairMutexGuard,
+    MappedFifoMutexGuard as MappedFairMutexGuard, RawFifoMutex as RawFairMutex,
 };
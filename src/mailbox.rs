@@ -0,0 +1,231 @@
+use super::{clock, const_mutex, Condvar, Mutex};
+use std::{fmt, time::Duration};
+
+/// A single-slot mailbox which passes the latest value from producers to a consumer.
+///
+/// The mailbox is either empty or holds one value. [`take`](Mailbox::take) blocks until
+/// there is a value and empties the mailbox. What [`put`](Mailbox::put) does when the
+/// mailbox is already full depends on how it was created:
+///
+/// - An [`overwriting`](Mailbox::overwriting) mailbox replaces the unread value, so the
+///   consumer always sees the latest one. This fits "latest command wins" coordination,
+///   where stale values should be dropped instead of queued like with a channel.
+/// - A [`blocking`](Mailbox::blocking) mailbox waits for the consumer to take the unread
+///   value first, so that no value is ever lost.
+///
+/// # Examples
+///
+/// ```
+/// use usync::Mailbox;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let target = Arc::new(Mailbox::overwriting());
+///
+/// // Only the latest unread target is kept.
+/// assert_eq!(target.put(1), None);
+/// assert_eq!(target.put(2), Some(1));
+///
+/// let t = {
+///     let target = target.clone();
+///     thread::spawn(move || target.take())
+/// };
+/// assert_eq!(t.join().unwrap(), 2);
+/// assert!(target.is_empty());
+/// ```
+pub struct Mailbox<T> {
+    overwrite: bool,
+    slot: Mutex<Option<T>>,
+    /// Notified when a value was put, for consumers waiting for one.
+    filled: Condvar,
+    /// Notified when the value was taken, for blocking producers waiting for space.
+    emptied: Condvar,
+}
+
+impl<T> fmt::Debug for Mailbox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mailbox")
+            .field("overwrite", &self.overwrite)
+            .field("is_empty", &self.is_empty())
+            .finish()
+    }
+}
+
+impl<T> Mailbox<T> {
+    /// Creates a new empty mailbox where putting a value replaces the unread one.
+    pub const fn overwriting() -> Self {
+        Self::new(true)
+    }
+
+    /// Creates a new empty mailbox where putting a value waits for the unread one
+    /// to be taken.
+    pub const fn blocking() -> Self {
+        Self::new(false)
+    }
+
+    const fn new(overwrite: bool) -> Self {
+        Self {
+            overwrite,
+            slot: const_mutex(None),
+            filled: Condvar::new(),
+            emptied: Condvar::new(),
+        }
+    }
+
+    /// Returns true if the mailbox doesn't hold a value.
+    pub fn is_empty(&self) -> bool {
+        self.slot.lock().is_none()
+    }
+
+    /// Puts a value into the mailbox, waking up a consumer waiting for it.
+    ///
+    /// If the mailbox is full, an overwriting mailbox replaces the unread value and returns it,
+    /// while a blocking mailbox blocks the current thread until the unread value was taken.
+    pub fn put(&self, value: T) -> Option<T> {
+        let mut slot = self.slot.lock();
+        if !self.overwrite {
+            while slot.is_some() {
+                self.emptied.wait(&mut slot);
+            }
+        }
+
+        let replaced = slot.replace(value);
+        drop(slot);
+        self.filled.notify_one();
+        replaced
+    }
+
+    /// Puts a value into the mailbox only if it's empty, returning the value back otherwise.
+    ///
+    /// This never blocks nor replaces an unread value, whatever the kind of mailbox.
+    pub fn try_put(&self, value: T) -> Result<(), T> {
+        let mut slot = self.slot.lock();
+        if slot.is_some() {
+            return Err(value);
+        }
+
+        *slot = Some(value);
+        drop(slot);
+        self.filled.notify_one();
+        Ok(())
+    }
+
+    /// Takes the value out of the mailbox, blocking the current thread until there is one.
+    pub fn take(&self) -> T {
+        let mut slot = self.slot.lock();
+        loop {
+            if let Some(value) = slot.take() {
+                drop(slot);
+                self.emptied.notify_one();
+                return value;
+            }
+
+            self.filled.wait(&mut slot);
+        }
+    }
+
+    /// Takes the value out of the mailbox, or returns `None` if it's empty.
+    pub fn try_take(&self) -> Option<T> {
+        let value = self.slot.lock().take()?;
+        self.emptied.notify_one();
+        Some(value)
+    }
+
+    /// Takes the value out of the mailbox, blocking the current thread until there is one
+    /// or until the `timeout` elapses.
+    ///
+    /// Returns `None` if the timeout elapsed before a value was put.
+    pub fn take_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = clock::now().checked_add(timeout);
+        let mut slot = self.slot.lock();
+        loop {
+            if let Some(value) = slot.take() {
+                drop(slot);
+                self.emptied.notify_one();
+                return Some(value);
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if self.filled.wait_until(&mut slot, deadline).timed_out() {
+                        return None;
+                    }
+                }
+                None => self.filled.wait(&mut slot),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mailbox;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn overwriting() {
+        let mailbox = Mailbox::overwriting();
+        assert_eq!(mailbox.try_take(), None);
+        assert_eq!(mailbox.put(1), None);
+        assert_eq!(mailbox.put(2), Some(1));
+        assert_eq!(mailbox.try_put(3), Err(3));
+        assert_eq!(mailbox.take(), 2);
+        assert_eq!(mailbox.try_put(4), Ok(()));
+        assert_eq!(mailbox.try_take(), Some(4));
+        assert!(mailbox.is_empty());
+    }
+
+    #[test]
+    fn blocking_put_waits_for_take() {
+        let mailbox = Arc::new(Mailbox::blocking());
+        assert_eq!(mailbox.put(1), None);
+
+        let producer = {
+            let mailbox = mailbox.clone();
+            thread::spawn(move || mailbox.put(2))
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(mailbox.take(), 1);
+        assert_eq!(producer.join().unwrap(), None);
+        assert_eq!(mailbox.take(), 2);
+    }
+
+    #[test]
+    fn take_timeout() {
+        let mailbox = Arc::new(Mailbox::overwriting());
+        assert_eq!(mailbox.take_timeout(Duration::from_millis(10)), None);
+
+        let consumer = {
+            let mailbox = mailbox.clone();
+            thread::spawn(move || mailbox.take_timeout(Duration::from_secs(60)))
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        mailbox.put(5);
+        assert_eq!(consumer.join().unwrap(), Some(5));
+    }
+
+    #[test]
+    fn no_lost_values() {
+        const VALUES: usize = 1000;
+        let mailbox = Arc::new(Mailbox::blocking());
+
+        let producers: Vec<_> = (0..2)
+            .map(|_| {
+                let mailbox = mailbox.clone();
+                thread::spawn(move || {
+                    for i in 0..VALUES {
+                        assert_eq!(mailbox.put(i), None);
+                    }
+                })
+            })
+            .collect();
+
+        let sum: usize = (0..2 * VALUES).map(|_| mailbox.take()).sum();
+        for t in producers {
+            t.join().unwrap();
+        }
+        assert_eq!(sum, VALUES * (VALUES - 1));
+    }
+}
@@ -0,0 +1,428 @@
+use super::shared::{self, invalid_mut, Primitive, SpinWait, StrictProvenance, Waiter};
+use std::{
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, AtomicU8, Ordering},
+};
+
+const UNLOCKED: usize = 0;
+const LOCKED: usize = 1;
+
+const WAITING: u8 = 0;
+const PARKED: u8 = 1;
+const GRANTED: u8 = 2;
+
+/// Raw MCS queue lock where each waiting thread spins on its own queue node.
+///
+/// The lock keeps a pointer to the last queued thread and every thread only links
+/// itself to (and is handed the lock through) the node of the thread before it. This means
+/// that contended waiters never poll the shared lock word, unlike [`RawMutex`](struct.RawMutex.html).
+/// A waiting thread spins for a little while on a flag in its node, which sits on its own
+/// cache line, and only parks if the lock wasn't handed over to it by then.
+///
+/// The lock is 2 words large: the second one holds the next waiter in line so that
+/// the current owner doesn't need to keep its queue node alive while holding the lock.
+pub struct RawMcsMutex {
+    tail: AtomicPtr<Node>,
+    next: AtomicPtr<Node>,
+}
+
+/// The queue node of a waiting thread, which lives on its stack.
+struct Node {
+    /// Whether the lock was handed over to the thread, or whether it parked waiting for it.
+    /// Only the thread before it writes to its cache line, when handing the lock over.
+    state: CacheLine<AtomicU8>,
+    /// The node of the thread queued after this one, once it linked itself.
+    next: AtomicPtr<Node>,
+    waiter: NonNull<Waiter>,
+}
+
+/// Keeps the value on its own cache line.
+#[repr(align(128))]
+struct CacheLine<T>(T);
+
+impl Default for RawMcsMutex {
+    fn default() -> Self {
+        <Self as lock_api::RawMutex>::INIT
+    }
+}
+
+impl fmt::Debug for RawMcsMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RawMcsMutex { .. }")
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawMcsMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self {
+        tail: AtomicPtr::new(invalid_mut(UNLOCKED)),
+        next: AtomicPtr::new(ptr::null_mut()),
+    };
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        tail.address() != UNLOCKED
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.tail
+            .compare_exchange(
+                invalid_mut(UNLOCKED),
+                invalid_mut(LOCKED),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        if !self.try_lock() {
+            self.lock_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        // Fast path for when there's no waiting threads to hand the lock off to.
+        if self
+            .tail
+            .compare_exchange(
+                invalid_mut(LOCKED),
+                invalid_mut(UNLOCKED),
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            self.unlock_slow();
+        }
+    }
+}
+
+unsafe impl lock_api::RawMutexFair for RawMcsMutex {
+    #[inline]
+    unsafe fn unlock_fair(&self) {
+        lock_api::RawMutex::unlock(self)
+    }
+}
+
+impl Node {
+    fn new(waiter: &Waiter) -> Self {
+        Self {
+            state: CacheLine(AtomicU8::new(WAITING)),
+            next: AtomicPtr::new(ptr::null_mut()),
+            waiter: NonNull::from(waiter),
+        }
+    }
+
+    /// Waits for the thread before us to hand the lock over.
+    fn wait(&self) {
+        // Spin on our own cache line for a bit, as the lock is often handed over shortly.
+        // Acquire barrier to see the critical section of the thread which handed it over.
+        let mut spin = SpinWait::default();
+        while spin.try_yield_now() {
+            if self.state.0.load(Ordering::Acquire) == GRANTED {
+                return;
+            }
+        }
+
+        // Let the thread handing the lock over know that it has to unpark us,
+        // unless it just did so. Acquire on failure for the same reason as above.
+        if self
+            .state
+            .0
+            .compare_exchange(WAITING, PARKED, Ordering::Relaxed, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+
+        // The Acquire barrier in park() synchronizes with the Release one in unpark().
+        let waiter = unsafe { self.waiter.as_ref() };
+        assert!(waiter.parker.park(None));
+        debug_assert_eq!(self.state.0.load(Ordering::Relaxed), GRANTED);
+    }
+
+    /// Hands the lock over to the thread waiting on the node.
+    unsafe fn grant(node: NonNull<Self>) {
+        // The thread may return as soon as it sees the lock granted, invalidating its node,
+        // so only its waiter is accessed after that, if it's parked and waiting to be unparked.
+        // Release barrier so that the critical section happens before the lock is acquired.
+        let waiter = node.as_ref().waiter;
+        if node.as_ref().state.0.swap(GRANTED, Ordering::AcqRel) == PARKED {
+            waiter.as_ref().parker.unpark();
+        }
+    }
+}
+
+impl RawMcsMutex {
+    #[cold]
+    fn lock_slow(&self) {
+        Waiter::with(Primitive::new("McsMutex", self), |waiter| {
+            let node = Node::new(&waiter);
+
+            // Make ourselves the new tail of the queue.
+            // AcqRel as Release to publish our node to whoever links to it next
+            // and as Acquire to see the node of the thread before us.
+            let node_ptr = &node as *const Node as *mut Node;
+            let prev = self.tail.swap(node_ptr, Ordering::AcqRel);
+
+            // The lock was unlocked so it now belongs to us (with our node as the tail).
+            // Otherwise, link ourselves to the thread before us and wait for it to hand us the lock.
+            if prev.address() != UNLOCKED {
+                // The owner has no node when it was the tail, so we link to the lock itself.
+                // Release barrier so that the thread which hands us the lock sees our node's initialization.
+                if prev.address() == LOCKED {
+                    self.next.store(node_ptr, Ordering::Release);
+                } else {
+                    unsafe { (*prev).next.store(node_ptr, Ordering::Release) };
+                }
+
+                node.wait();
+            }
+
+            // We now own the lock but our node is about to be invalidated.
+            // Move the thread linked after us (if any) into the lock for unlock() to find.
+            self.unqueue(&node);
+        })
+    }
+
+    fn unqueue(&self, node: &Node) {
+        // If we're still the tail, there's no one waiting after us.
+        // Acquire barrier to synchronize with any threads that got queued
+        // between our swap() in lock_slow() and this point.
+        let node_ptr = node as *const Node as *mut Node;
+        if self
+            .tail
+            .compare_exchange(
+                node_ptr,
+                invalid_mut(LOCKED),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            return;
+        }
+
+        // Another thread queued after us so it will link itself to our node soon.
+        let next = Self::wait_for(|| NonNull::new(node.next.load(Ordering::Acquire)));
+        self.next.store(next.as_ptr(), Ordering::Release);
+    }
+
+    #[cold]
+    unsafe fn unlock_slow(&self) {
+        // There's a thread queued for the lock that may not have linked itself yet.
+        let next = Self::wait_for(|| NonNull::new(self.next.load(Ordering::Acquire)));
+        self.next.store(ptr::null_mut(), Ordering::Relaxed);
+
+        // Directly hand the lock to the next thread, waking it up if it stopped spinning.
+        Node::grant(next);
+    }
+
+    fn wait_for(mut poll: impl FnMut() -> Option<NonNull<Node>>) -> NonNull<Node> {
+        let mut spin = SpinWait::default();
+        loop {
+            if let Some(node) = poll() {
+                return node;
+            }
+
+            if !spin.try_yield_now() {
+                shared::yield_now();
+            }
+        }
+    }
+}
+
+/// A mutual exclusion primitive implemented as an MCS queue lock
+///
+/// This behaves like [`Mutex`](type.Mutex.html) but contended threads form a queue where each
+/// one waits on its own node instead of the shared lock word. This avoids the cache-line
+/// ping-pong of the lock word when many cores fight over the same lock.
+///
+/// The lock is always handed directly to the longest waiting thread on unlock, so it is
+/// strictly first-come-first-served like [`FifoMutex`](type.FifoMutex.html). This favors
+/// fairness and scalability under heavy contention over throughput when lightly contended.
+///
+/// # Examples
+///
+/// ```
+/// use usync::McsMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let counter = Arc::new(McsMutex::new(0));
+///
+/// let threads: Vec<_> = (0..10)
+///     .map(|_| {
+///         let counter = Arc::clone(&counter);
+///         thread::spawn(move || *counter.lock() += 1)
+///     })
+///     .collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// assert_eq!(*counter.lock(), 10);
+/// ```
+pub type McsMutex<T> = lock_api::Mutex<RawMcsMutex, T>;
+
+/// An RAII implementation of a "scoped lock" of an MCS mutex. When this structure is
+/// dropped (falls out of scope), the lock will be handed off to the next waiting thread.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type McsMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMcsMutex, T>;
+
+/// An RAII mutex guard returned by `McsMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedMcsMutexGuard` and `McsMutexGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedMcsMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawMcsMutex, T>;
+
+/// Creates a new MCS mutex in an unlocked state ready for use.
+///
+/// This allows creating a mutex in a constant context on stable Rust.
+pub const fn const_mcs_mutex<T>(value: T) -> McsMutex<T> {
+    McsMutex::const_new(<RawMcsMutex as lock_api::RawMutex>::INIT, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, PARKED};
+    use crate::{
+        shared::{Primitive, Waiter},
+        McsMutex,
+    };
+    use std::{
+        mem,
+        ptr::NonNull,
+        sync::{atomic::Ordering, mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let m = McsMutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(McsMutex::new(0));
+
+        fn inc(m: &McsMutex<u32>) {
+            for _ in 0..J {
+                *m.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..K {
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+            let tx2 = tx.clone();
+            let m2 = m.clone();
+            thread::spawn(move || {
+                inc(&m2);
+                tx2.send(()).unwrap();
+            });
+        }
+
+        drop(tx);
+        for _ in 0..2 * K {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = McsMutex::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        const N: usize = 5;
+
+        let m = Arc::new(McsMutex::new(Vec::new()));
+        let guard = m.lock();
+
+        // Queue up the threads one by one while the lock is held,
+        // waiting for each of them to become the new queue tail.
+        let tail = || unsafe { m.raw() }.tail.load(Ordering::Relaxed);
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let prev = tail();
+                let thread = {
+                    let m = m.clone();
+                    thread::spawn(move || m.lock().push(i))
+                };
+                while tail() == prev {
+                    thread::yield_now();
+                }
+                thread
+            })
+            .collect();
+
+        drop(guard);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(*m.lock(), (0..N).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn handoff_while_spinning() {
+        Waiter::with(Primitive::new("McsMutex", &()), |waiter| {
+            // Handing the lock over before the thread gave up spinning doesn't unpark it.
+            let node = Node::new(&waiter);
+            unsafe { Node::grant(NonNull::from(&node)) };
+            node.wait();
+            assert!(!waiter.parker.park(Some(Duration::ZERO)));
+
+            // Once it parked, it has to be unparked.
+            let node = Node::new(&waiter);
+            node.state.0.store(PARKED, Ordering::Relaxed);
+            unsafe { Node::grant(NonNull::from(&node)) };
+            assert!(waiter.parker.park(Some(Duration::ZERO)));
+        });
+    }
+
+    #[test]
+    fn node_cache_line() {
+        // The flag threads spin on doesn't share its cache line with the rest of the node.
+        assert_eq!(mem::align_of::<Node>(), 128);
+        assert!(mem::size_of::<Node>() >= 256);
+    }
+
+    #[test]
+    fn test_mcs_mutex_debug() {
+        let mutex = McsMutex::new(vec![0u8, 10]);
+        assert_eq!(format!("{:?}", mutex), "Mutex { data: [0, 10] }");
+    }
+}
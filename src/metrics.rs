@@ -0,0 +1,253 @@
+//! Counters and histograms reported by the primitives, for the `metrics` feature.
+//!
+//! The crate keeps global [`Counter`]s which can be read at any time with [`counter`], or
+//! all at once along with how many threads are currently blocked with [`snapshot`], e.g. to
+//! export them to Prometheus. Applications using a metrics library can also forward every
+//! measurement to it by registering a [`Sink`] with [`set_sink`], which is called on the
+//! thread that blocked or woke another thread up. Without the feature, none of this is
+//! compiled in.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use usync::metrics::{self, Counter, Sink};
+//!
+//! struct Parks(AtomicU64);
+//!
+//! impl Sink for Parks {
+//!     fn counter(&self, counter: Counter, value: u64) {
+//!         if counter == Counter::Parks {
+//!             self.0.fetch_add(value, Ordering::Relaxed);
+//!         }
+//!     }
+//! }
+//!
+//! static PARKS: Parks = Parks(AtomicU64::new(0));
+//! let _ = metrics::set_sink(&PARKS);
+//! assert!(metrics::counter(Counter::Parks) >= PARKS.0.load(Ordering::Relaxed));
+//! ```
+
+use crate::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Events counted by the primitives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Counter {
+    /// A thread blocked, after spinning didn't get it what it was waiting for.
+    Parks,
+    /// A thread woke up another one.
+    Unparks,
+    /// A blocked thread gave up waiting as its timeout elapsed.
+    Timeouts,
+    /// A `Mutex` or `RwLock` was already locked and the thread had to spin or queue up.
+    ContendedAcquisitions,
+}
+
+impl Counter {
+    const COUNT: usize = 4;
+
+    /// Returns all the counters.
+    pub const fn all() -> [Counter; Self::COUNT] {
+        [
+            Counter::Parks,
+            Counter::Unparks,
+            Counter::Timeouts,
+            Counter::ContendedAcquisitions,
+        ]
+    }
+
+    /// Returns the name of the counter in snake case, e.g. `contended_acquisitions`,
+    /// which is also its field in a serialized [`Snapshot`].
+    pub const fn name(self) -> &'static str {
+        match self {
+            Counter::Parks => "parks",
+            Counter::Unparks => "unparks",
+            Counter::Timeouts => "timeouts",
+            Counter::ContendedAcquisitions => "contended_acquisitions",
+        }
+    }
+}
+
+/// Distributions of values measured by the primitives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Histogram {
+    /// How many threads are blocked on the crate's primitives, including the one which
+    /// just blocked. Recorded each time a thread blocks.
+    QueueDepth,
+}
+
+/// Receives the measurements of the primitives, typically to forward them to a metrics library.
+///
+/// Both methods do nothing by default. They're called while a primitive is being
+/// waited on, so they must not block nor use the primitive they're reporting about.
+pub trait Sink: Send + Sync {
+    /// Adds `value` to `counter`.
+    fn counter(&self, counter: Counter, value: u64) {
+        let _ = (counter, value);
+    }
+
+    /// Records `value` in `histogram`.
+    fn histogram(&self, histogram: Histogram, value: u64) {
+        let _ = (histogram, value);
+    }
+}
+
+/// The values of all the counters at one point in time, returned by [`snapshot`].
+///
+/// With the `serde` feature, it serializes as a struct with a field named after each counter,
+/// along with the `parked` gauge.
+///
+/// # Examples
+///
+/// Formatting the snapshot in the Prometheus text exposition format:
+///
+/// ```
+/// use std::fmt::Write;
+/// use usync::metrics::{self, Counter};
+///
+/// let snapshot = metrics::snapshot();
+/// let mut exposition = String::new();
+/// for counter in Counter::all() {
+///     let name = format!("usync_{}_total", counter.name());
+///     writeln!(exposition, "# TYPE {} counter", name).unwrap();
+///     writeln!(exposition, "{} {}", name, snapshot.counter(counter)).unwrap();
+/// }
+/// writeln!(exposition, "# TYPE usync_parked gauge").unwrap();
+/// writeln!(exposition, "usync_parked {}", snapshot.parked).unwrap();
+/// assert!(exposition.contains("usync_parks_total "));
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub struct Snapshot {
+    /// The value of [`Counter::Parks`].
+    pub parks: u64,
+    /// The value of [`Counter::Unparks`].
+    pub unparks: u64,
+    /// The value of [`Counter::Timeouts`].
+    pub timeouts: u64,
+    /// The value of [`Counter::ContendedAcquisitions`].
+    pub contended_acquisitions: u64,
+    /// How many threads were blocked on the crate's primitives.
+    pub parked: u64,
+}
+
+impl Snapshot {
+    /// Returns the value of `counter`.
+    pub fn counter(&self, counter: Counter) -> u64 {
+        match counter {
+            Counter::Parks => self.parks,
+            Counter::Unparks => self.unparks,
+            Counter::Timeouts => self.timeouts,
+            Counter::ContendedAcquisitions => self.contended_acquisitions,
+        }
+    }
+}
+
+static SINK: OnceLock<&'static dyn Sink> = OnceLock::new();
+
+static COUNTERS: [AtomicUsize; Counter::COUNT] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers the sink receiving the measurements of all the primitives.
+///
+/// The sink can only be set once, and is returned back if one was already set.
+pub fn set_sink(sink: &'static dyn Sink) -> Result<(), &'static dyn Sink> {
+    SINK.set(sink)
+}
+
+/// Returns the current value of the global `counter`, which wraps around on overflow.
+pub fn counter(counter: Counter) -> u64 {
+    COUNTERS[counter as usize].load(Ordering::Relaxed) as u64
+}
+
+/// Returns the current value of all the global counters.
+///
+/// The counters are read one after the other while threads may keep updating them,
+/// so they're only consistent with each other when the primitives aren't in use.
+pub fn snapshot() -> Snapshot {
+    Snapshot {
+        parks: counter(Counter::Parks),
+        unparks: counter(Counter::Unparks),
+        timeouts: counter(Counter::Timeouts),
+        contended_acquisitions: counter(Counter::ContendedAcquisitions),
+        parked: PARKED.load(Ordering::Relaxed) as u64,
+    }
+}
+
+pub(crate) fn increment(counter: Counter) {
+    COUNTERS[counter as usize].fetch_add(1, Ordering::Relaxed);
+    if let Some(sink) = SINK.get() {
+        sink.counter(counter, 1);
+    }
+}
+
+/// Records that the current thread is about to block, until the returned guard is dropped.
+pub(crate) fn parked() -> Parked {
+    increment(Counter::Parks);
+    let depth = PARKED.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Some(sink) = SINK.get() {
+        sink.histogram(Histogram::QueueDepth, depth as u64);
+    }
+    Parked(())
+}
+
+pub(crate) struct Parked(());
+
+impl Drop for Parked {
+    fn drop(&mut self) {
+        PARKED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counter, snapshot, Counter};
+    use crate::Mutex;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn counters() {
+        let parks = counter(Counter::Parks);
+        let contended = counter(Counter::ContendedAcquisitions);
+
+        let mutex = Arc::new(Mutex::new(()));
+        let guard = mutex.lock();
+        let t = {
+            let mutex = mutex.clone();
+            thread::spawn(move || drop(mutex.lock()))
+        };
+
+        // Give the thread time to block on the mutex.
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        t.join().unwrap();
+
+        assert!(counter(Counter::Parks) > parks);
+        assert!(counter(Counter::ContendedAcquisitions) > contended);
+        assert!(counter(Counter::Unparks) > 0);
+    }
+
+    #[test]
+    fn snapshot_counters() {
+        let parks = counter(Counter::Parks);
+        let snapshot = snapshot();
+        assert!(snapshot.parks >= parks);
+        for counter in Counter::all() {
+            assert!(snapshot.counter(counter) <= super::counter(counter));
+        }
+    }
+}
@@ -1,17 +1,41 @@
-use super::RawRwLock;
+use super::{
+    shared::{
+        trace::{self, LockKind},
+        Primitive, Waiter,
+    },
+    CancellationToken, Cancelled, RawRwLock,
+};
 use lock_api::RawRwLock as _RawRwLock;
-use std::fmt;
+use std::{fmt, hint, pin::Pin};
+
+#[cfg(usync_track_owner)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "std")]
+use {
+    crate::{clock, shared::SpinWait},
+    std::time::{Duration, Instant},
+};
 
 /// Raw mutex type implemented with lock-free userspace thread queues.
+///
+/// With the `track_owner` feature (and without `send_guard`), the mutex also records
+/// the thread which currently holds it in order to panic on recursive locking
+/// instead of silently deadlocking. This makes the mutex 2 words large.
 #[derive(Default)]
-#[repr(transparent)]
+#[cfg_attr(not(usync_track_owner), repr(transparent))]
 pub struct RawMutex {
     pub(super) rwlock: RawRwLock,
+    #[cfg(usync_track_owner)]
+    owner: AtomicUsize,
 }
 
 impl fmt::Debug for RawMutex {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.pad("RawMutex { .. }")
+        f.debug_struct("RawMutex")
+            .field("locked", &self.rwlock.is_locked())
+            .field("has_waiters", &self.rwlock.is_queued())
+            .finish()
     }
 }
 
@@ -20,6 +44,8 @@ unsafe impl lock_api::RawMutex for RawMutex {
 
     const INIT: Self = Self {
         rwlock: RawRwLock::INIT,
+        #[cfg(usync_track_owner)]
+        owner: AtomicUsize::new(0),
     };
 
     #[inline]
@@ -29,20 +55,176 @@ unsafe impl lock_api::RawMutex for RawMutex {
 
     #[inline]
     fn lock(&self) {
-        self.rwlock.lock_exclusive()
+        #[cfg(usync_track_owner)]
+        self.assert_not_owned();
+
+        self.rwlock.lock_exclusive_on(self.primitive());
+
+        #[cfg(usync_track_owner)]
+        self.set_owned(true);
     }
 
     #[inline]
     fn try_lock(&self) -> bool {
-        self.rwlock.try_lock_exclusive()
+        let acquired = self.rwlock.try_lock_exclusive();
+
+        #[cfg(usync_track_owner)]
+        if acquired {
+            self.set_owned(true);
+        }
+
+        acquired
     }
 
     #[inline]
     unsafe fn unlock(&self) {
+        #[cfg(usync_track_owner)]
+        self.set_owned(false);
+
         self.rwlock.unlock_exclusive()
     }
 }
 
+unsafe impl lock_api::RawMutexFair for RawMutex {
+    #[inline]
+    unsafe fn unlock_fair(&self) {
+        #[cfg(usync_track_owner)]
+        self.set_owned(false);
+
+        self.rwlock.unlock_exclusive_fair()
+    }
+
+    #[inline]
+    unsafe fn bump(&self) {
+        if self.rwlock.is_queued() {
+            lock_api::RawMutexFair::unlock_fair(self);
+            lock_api::RawMutex::lock(self);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl lock_api::RawMutexTimed for RawMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => {
+                lock_api::RawMutex::lock(self);
+                true
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        lock_api::RawMutex::try_lock(self) || self.lock_until(SpinWait::default(), deadline)
+    }
+}
+
+impl RawMutex {
+    /// Returns how threads blocked on the mutex report it to the watchdog.
+    fn primitive(&self) -> Primitive {
+        Primitive::new("Mutex", self)
+    }
+
+    /// Acquires the mutex unless the deadline passes, queueing the thread right away instead
+    /// of spinning when it's contended. Returns whether the mutex was acquired.
+    #[cfg(feature = "std")]
+    pub(super) fn try_lock_until_without_spinning(&self, deadline: Instant) -> bool {
+        lock_api::RawMutex::try_lock(self) || self.lock_until(SpinWait::with_limit(0), deadline)
+    }
+
+    #[cfg(feature = "std")]
+    fn lock_until(&self, spin: SpinWait, deadline: Instant) -> bool {
+        #[cfg(usync_track_owner)]
+        self.assert_not_owned();
+
+        let acquired = self
+            .rwlock
+            .lock_exclusive_until(self.primitive(), spin, deadline);
+
+        #[cfg(usync_track_owner)]
+        if acquired {
+            self.set_owned(true);
+        }
+
+        acquired
+    }
+
+    /// Acquires the mutex, queueing the thread right away instead of spinning when it's contended.
+    pub(super) fn lock_without_spinning(&self) {
+        #[cfg(usync_track_owner)]
+        self.assert_not_owned();
+
+        self.rwlock
+            .lock_exclusive_without_spinning(self.primitive());
+
+        #[cfg(usync_track_owner)]
+        self.set_owned(true);
+    }
+
+    /// Acquires the mutex unless the token is cancelled while waiting for it.
+    pub(super) fn lock_with_cancel(&self, token: &CancellationToken) -> Result<(), Cancelled> {
+        if lock_api::RawMutex::try_lock(self) {
+            return Ok(());
+        }
+
+        if token.is_cancelled() {
+            return Err(Cancelled);
+        }
+
+        #[cfg(usync_track_owner)]
+        self.assert_not_owned();
+
+        token.lock_exclusive(&self.rwlock, self.primitive())?;
+
+        #[cfg(usync_track_owner)]
+        self.set_owned(true);
+
+        Ok(())
+    }
+
+    /// Re-acquires the mutex for a thread that was woken up from a Condvar.
+    /// The mutex may have already been handed off to the waiter if it was requeued onto it.
+    pub(super) fn relock(&self, waiter: Pin<&Waiter>) {
+        if RawRwLock::is_handed_off(waiter) {
+            trace::acquired(&self.rwlock, LockKind::Exclusive);
+
+            #[cfg(usync_track_owner)]
+            self.set_owned(true);
+        } else {
+            lock_api::RawMutex::lock(self);
+        }
+    }
+}
+
+#[cfg(usync_track_owner)]
+impl RawMutex {
+    fn current_thread_id() -> usize {
+        use lock_api::GetThreadId;
+        crate::RawThreadId.nonzero_thread_id().get()
+    }
+
+    fn set_owned(&self, owned: bool) {
+        let owner = if owned { Self::current_thread_id() } else { 0 };
+        self.owner.store(owner, Ordering::Relaxed);
+    }
+
+    #[track_caller]
+    fn assert_not_owned(&self) {
+        // Only the current thread ever stores its own id as the owner,
+        // so observing it means that we're the ones holding the lock.
+        let owner = self.owner.load(Ordering::Relaxed);
+        if owner == Self::current_thread_id() {
+            panic!("deadlock: this thread already holds this Mutex");
+        }
+    }
+}
+
 /// A mutual exclusion primitive useful for protecting shared data
 ///
 /// This mutex will block threads waiting for the lock to become available. The
@@ -64,11 +246,15 @@ unsafe impl lock_api::RawMutex for RawMutex {
 /// tries to at least wake up threads in the order that they we're queued as an
 /// attempt to avoid starvation, but it is entirely up to the OS scheduler.
 ///
+/// A fair unlock can be done through `MutexGuard::unlock_fair`, which hands the
+/// lock directly to the longest waiting thread instead. See [`FifoMutex`](type.FifoMutex.html)
+/// for a mutex which always does this.
+///
 /// # Differences from the standard library `Mutex`
 ///
 /// - No poisoning, the lock is released normally on panic.
-/// - Only requires 1 word (usize) of space, whereas the standard library boxes the
-///   `Mutex` due to platform limitations.
+/// - Only requires 1 word (usize) of space (2 with the `track_owner` feature), whereas the
+///   standard library boxes the `Mutex` due to platform limitations.
 /// - Can be statically constructed.
 /// - Does not require any drop glue when dropped.
 /// - Inline fast path for the uncontended case.
@@ -127,6 +313,114 @@ pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMutex, T>;
 /// thread.
 pub type MappedMutexGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawMutex, T>;
 
+/// Extension methods for [`Mutex`] which aren't part of `lock_api`.
+pub trait MutexExt<T: ?Sized> {
+    /// Acquires the mutex, blocking the current thread until it is able to do so
+    /// or until the `token` is cancelled.
+    ///
+    /// Returns `Err(Cancelled)` without acquiring the mutex if the token was cancelled
+    /// before the mutex could be acquired. A mutex which is free is still acquired
+    /// even if the token was already cancelled.
+    fn lock_with_cancel(&self, token: &CancellationToken) -> Result<MutexGuard<'_, T>, Cancelled>;
+
+    /// Acquires the mutex, runs `f` on the protected data and releases the mutex again.
+    ///
+    /// The guard never escapes the closure, so the mutex can't accidentally stay locked
+    /// for longer than the closure runs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::{Mutex, MutexExt};
+    ///
+    /// let counter = Mutex::new(0);
+    /// let value = counter.with(|counter| {
+    ///     *counter += 1;
+    ///     *counter
+    /// });
+    /// assert_eq!(value, 1);
+    /// ```
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+
+    /// Like [`with`](MutexExt::with), but gives up if the mutex couldn't be acquired
+    /// within `timeout`, in which case `f` isn't run and `None` is returned.
+    #[cfg(feature = "std")]
+    fn with_timeout<R>(&self, timeout: Duration, f: impl FnOnce(&mut T) -> R) -> Option<R>;
+
+    /// Attempts to acquire the mutex, spinning for up to `spins` iterations while it's
+    /// locked before giving up.
+    ///
+    /// Unlike `lock`, the thread is never parked, and unlike the adaptive spinning done
+    /// by `lock`, the budget is spent even on single-core machines. `try_lock_spin(0)` is
+    /// the same as `try_lock()`.
+    fn try_lock_spin(&self, spins: usize) -> Option<MutexGuard<'_, T>>;
+
+    /// Acquires the mutex, blocking the current thread until it is able to do so
+    /// or until the process is [interrupted](crate::interrupt), e.g. by a signal.
+    ///
+    /// Returns `Err(Cancelled)` without acquiring the mutex if the process was interrupted
+    /// before the mutex could be acquired, like [`lock_with_cancel`](MutexExt::lock_with_cancel).
+    #[cfg(all(feature = "std", unix))]
+    fn lock_interruptible(&self) -> Result<MutexGuard<'_, T>, Cancelled>;
+}
+
+impl<T: ?Sized> MutexExt<T> for Mutex<T> {
+    #[inline]
+    fn lock_with_cancel(&self, token: &CancellationToken) -> Result<MutexGuard<'_, T>, Cancelled> {
+        unsafe {
+            self.raw().lock_with_cancel(token)?;
+            Ok(self.make_guard_unchecked())
+        }
+    }
+
+    #[inline]
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut *self.lock())
+    }
+
+    #[cfg(feature = "std")]
+    #[inline]
+    fn with_timeout<R>(&self, timeout: Duration, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.try_lock_for(timeout).map(|mut guard| f(&mut *guard))
+    }
+
+    #[inline]
+    fn try_lock_spin(&self, spins: usize) -> Option<MutexGuard<'_, T>> {
+        try_spin(spins, || self.is_locked(), || self.try_lock())
+    }
+
+    #[cfg(all(feature = "std", unix))]
+    #[inline]
+    fn lock_interruptible(&self) -> Result<MutexGuard<'_, T>, Cancelled> {
+        self.lock_with_cancel(crate::interrupt::token())
+    }
+}
+
+/// Calls `try_lock` until it succeeds, spinning for up to `spins` iterations in between
+/// while `is_locked` returns true.
+pub(super) fn try_spin<G>(
+    spins: usize,
+    is_locked: impl Fn() -> bool,
+    try_lock: impl Fn() -> Option<G>,
+) -> Option<G> {
+    let mut spins = spins;
+    loop {
+        // Only poll the lock while it's held so the cache line isn't bounced between cores.
+        if !is_locked() {
+            if let Some(guard) = try_lock() {
+                return Some(guard);
+            }
+        }
+
+        if spins == 0 {
+            return None;
+        }
+
+        spins -= 1;
+        hint::spin_loop();
+    }
+}
+
 /// Creates a new mutex in an unlocked state ready for use.
 ///
 /// This allows creating a mutex in a constant context on stable Rust.
@@ -138,12 +432,14 @@ pub const fn const_mutex<T>(value: T) -> Mutex<T> {
 mod tests {
     use crate::{Condvar, Mutex};
     use std::{
+        mem,
         sync::{
             atomic::{AtomicUsize, Ordering},
-            mpsc::channel,
+            mpsc::{channel, Sender},
             Arc,
         },
         thread,
+        time::Duration,
     };
 
     struct Packet<T>(Arc<(Mutex<T>, Condvar)>);
@@ -161,6 +457,45 @@ mod tests {
         drop(m.lock());
     }
 
+    #[test]
+    fn test_try_lock_spin() {
+        use crate::MutexExt;
+
+        let m = Arc::new(Mutex::new(()));
+        assert!(m.try_lock_spin(0).is_some());
+
+        let guard = m.lock();
+        assert!(m.try_lock_spin(100).is_none());
+
+        let m2 = m.clone();
+        let t = thread::spawn(move || m2.try_lock_spin(usize::MAX).is_some());
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        assert!(t.join().unwrap());
+    }
+
+    #[test]
+    fn test_with() {
+        use crate::MutexExt;
+
+        let m = Arc::new(Mutex::new(0));
+        assert_eq!(m.with(|value| mem::replace(value, 1)), 0);
+        assert!(!m.is_locked());
+
+        let guard = m.lock();
+        let m2 = m.clone();
+        let timed_out = thread::spawn(move || {
+            m2.with_timeout(Duration::from_millis(50), |_| unreachable!())
+                .is_none()
+        });
+        assert!(timed_out.join().unwrap());
+        drop(guard);
+        assert_eq!(
+            m.with_timeout(Duration::from_millis(50), |value| *value),
+            Some(1)
+        );
+    }
+
     #[test]
     fn lots_and_lots() {
         const J: u32 = 1000;
@@ -242,13 +577,13 @@ mod tests {
         let _t = thread::spawn(move || {
             // wait until parent gets in
             rx.recv().unwrap();
-            let &(ref lock, ref cvar) = &*packet2.0;
+            let (lock, cvar) = &*packet2.0;
             let mut lock = lock.lock();
             *lock = true;
             cvar.notify_one();
         });
 
-        let &(ref lock, ref cvar) = &*packet.0;
+        let (lock, cvar) = &*packet.0;
         let mut lock = lock.lock();
         tx.send(()).unwrap();
         assert!(!*lock);
@@ -314,6 +649,40 @@ mod tests {
         sync(mutex.lock());
     }
 
+    #[test]
+    fn test_mutex_size() {
+        let words = if cfg!(usync_track_owner) { 2 } else { 1 };
+        assert_eq!(
+            mem::size_of::<crate::RawMutex>(),
+            words * mem::size_of::<usize>()
+        );
+        assert_eq!(mem::size_of::<Mutex<()>>(), words * mem::size_of::<usize>());
+    }
+
+    #[test]
+    #[cfg(usync_track_owner)]
+    #[should_panic(expected = "this thread already holds this Mutex")]
+    fn test_mutex_recursive_lock() {
+        let m = Mutex::new(());
+        let _g = m.lock();
+        let _g2 = m.lock();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_mutex_serde() {
+        use crate::RwLock;
+
+        // Deserializing creates an unlocked container.
+        let mutex: Mutex<u32> = serde_json::from_str("5").unwrap();
+        assert!(!mutex.is_locked());
+        assert_eq!(serde_json::to_string(&mutex).unwrap(), "5");
+
+        let rwlock: RwLock<Vec<u8>> = serde_json::from_str("[1,2]").unwrap();
+        assert_eq!(*rwlock.read(), [1, 2]);
+        assert_eq!(serde_json::to_string(&rwlock).unwrap(), "[1,2]");
+    }
+
     #[test]
     fn test_mutex_debug() {
         let mutex = Mutex::new(vec![0u8, 10]);
@@ -321,5 +690,125 @@ mod tests {
         assert_eq!(format!("{:?}", mutex), "Mutex { data: [0, 10] }");
         let _lock = mutex.lock();
         assert_eq!(format!("{:?}", mutex), "Mutex { data: <locked> }");
+        assert_eq!(
+            format!("{:?}", unsafe { mutex.raw() }),
+            "RawMutex { locked: true, has_waiters: false }"
+        );
+    }
+
+    #[test]
+    fn test_mutex_timed() {
+        let m = Arc::new(Mutex::new(0));
+        let guard = m.lock();
+
+        let m2 = m.clone();
+        let timed_out = thread::spawn(move || m2.try_lock_for(Duration::from_millis(50)).is_none());
+        assert!(timed_out.join().unwrap());
+
+        let m2 = m.clone();
+        let acquired =
+            thread::spawn(move || *m2.try_lock_for(Duration::from_secs(60)).unwrap() += 1);
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        acquired.join().unwrap();
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn test_mutex_timed_contended() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 1000;
+
+        // Waiters timing out unlink themselves from the middle of the queue.
+        let m = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    let mut acquired = 0;
+                    for _ in 0..ITERS {
+                        let guard = match i % 2 {
+                            0 => m.try_lock_for(Duration::from_micros(10)),
+                            _ => Some(m.lock()),
+                        };
+                        if let Some(mut guard) = guard {
+                            *guard += 1;
+                            acquired += 1;
+                            thread::yield_now();
+                        }
+                    }
+                    acquired
+                })
+            })
+            .collect();
+
+        let acquired: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(*m.lock(), acquired);
+    }
+
+    #[test]
+    fn test_mutex_in_tls_destructor() {
+        struct LockOnDrop(Arc<Mutex<usize>>, Sender<()>);
+
+        impl Drop for LockOnDrop {
+            fn drop(&mut self) {
+                self.1.send(()).unwrap();
+                *self.0.lock() += 1;
+            }
+        }
+
+        thread_local! {
+            static LOCK_ON_DROP: std::cell::RefCell<Option<LockOnDrop>> =
+                const { std::cell::RefCell::new(None) };
+        }
+
+        // The thread blocks on the mutex while its thread locals are being destroyed.
+        let m = Arc::new(Mutex::new(0));
+        let guard = m.lock();
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move || {
+            LOCK_ON_DROP.with(|slot| *slot.borrow_mut() = Some(LockOnDrop(m2, tx)));
+        });
+
+        // Wait for the destructor to start and queue up on the mutex.
+        rx.recv().unwrap();
+        while unsafe { m.raw() }.rwlock.queue_head() == 0 {
+            thread::yield_now();
+        }
+        drop(guard);
+        t.join().unwrap();
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "deterministic")]
+    fn test_mutex_fifo() {
+        const THREADS: usize = 4;
+
+        let m = Arc::new(Mutex::new(Vec::new()));
+        let guard = m.lock();
+        let raw = || unsafe { &m.raw().rwlock };
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let head = raw().queue_head();
+                let t = {
+                    let m = m.clone();
+                    thread::spawn(move || m.lock().push(i))
+                };
+                // Let the thread queue up before spawning the next one.
+                while raw().queue_head() == head {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        // Each unlock hands the mutex off to the thread which queued first.
+        drop(guard);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock(), (0..THREADS).collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,64 @@
+//! Process-global locks identified by name.
+//!
+//! These are useful for coordinating access to resources outside of the process's memory
+//! (e.g. files or devices) between unrelated parts of a program, without having to pass
+//! a lock between them.
+
+use super::{const_mutex, Mutex};
+use std::collections::HashMap;
+
+static MUTEXES: Mutex<Option<HashMap<Box<str>, &'static Mutex<()>>>> = const_mutex(None);
+
+/// Returns the process-global mutex with the given name, creating it on first use.
+///
+/// Every call with the same name returns the same mutex. Named mutexes live until
+/// the process exits, so this should be used with a bounded set of names.
+///
+/// # Examples
+///
+/// ```
+/// use usync::named;
+///
+/// let guard = named::mutex("config.toml").lock();
+/// assert!(named::mutex("config.toml").try_lock().is_none());
+/// drop(guard);
+/// ```
+pub fn mutex(name: &str) -> &'static Mutex<()> {
+    let mut mutexes = MUTEXES.lock();
+    let mutexes = mutexes.get_or_insert_with(HashMap::new);
+
+    if let Some(mutex) = mutexes.get(name) {
+        return mutex;
+    }
+
+    let mutex = Box::leak(Box::new(const_mutex(())));
+    mutexes.insert(name.into(), mutex);
+    mutex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mutex;
+    use std::{ptr, thread};
+
+    #[test]
+    fn test_named_mutex_same_name() {
+        let a = mutex("test_named_mutex_same_name");
+        let b = mutex("test_named_mutex_same_name");
+        assert!(ptr::eq(a, b));
+        assert!(!ptr::eq(a, mutex("test_named_mutex_other_name")));
+    }
+
+    #[test]
+    fn test_named_mutex_threads() {
+        let guard = mutex("test_named_mutex_threads").lock();
+        thread::spawn(|| assert!(mutex("test_named_mutex_threads").try_lock().is_none()))
+            .join()
+            .unwrap();
+
+        drop(guard);
+        thread::spawn(|| assert!(mutex("test_named_mutex_threads").try_lock().is_some()))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{atomic_wait, AcquireError};
+use std::{
+    ffi::CString,
+    fmt, io, mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Set in the state once the semaphore is closed, the other bits hold the available permits.
+const CLOSED: u32 = 1 << 31;
+
+/// The largest amount of permits a named semaphore can hold.
+const MAX_PERMITS: u32 = CLOSED - 1;
+
+/// Set in `initialized` by the process which created the shared memory, once it wrote the
+/// initial permits.
+const INITIALIZED: u32 = 1;
+
+/// The shared memory backing a named semaphore. All-zero memory is a semaphore which
+/// isn't initialized yet.
+#[repr(C)]
+struct Shared {
+    state: AtomicU32,
+    /// The amount of threads, across all processes, waiting for permits.
+    waiters: AtomicU32,
+    initialized: AtomicU32,
+}
+
+/// A counting semaphore shared between processes, identified by a name.
+///
+/// Every process opening the same name gets a handle to the same semaphore, which makes
+/// it useful for throttling access to machine-wide resources like devices or expensive
+/// services. The API is the same as [`Semaphore`](crate::Semaphore), except that permits
+/// are only available as borrowed [`NamedSemaphorePermit`]s.
+///
+/// The semaphore lives in a POSIX shared memory object (see `shm_open(3)`) which persists
+/// until it's removed with [`NamedSemaphore::unlink`], even when no process has it open.
+/// Unlike `Semaphore`, waiting threads aren't served in FIFO order: all of them are woken up
+/// when permits are released and race to take them. On Linux and Android they sleep on a
+/// process-shared futex, while on other platforms they poll the semaphore instead.
+///
+/// Permits held by a process which exits without releasing them are lost.
+///
+/// This type is only available on Unix platforms.
+///
+/// # Examples
+///
+/// ```
+/// use usync::NamedSemaphore;
+///
+/// // Allow at most two processes to use the GPU at the same time.
+/// let semaphore = NamedSemaphore::open("usync-doc-gpu", 2)?;
+/// {
+///     let _permit = semaphore.acquire().unwrap();
+///     // ... use the GPU ...
+/// }
+/// # NamedSemaphore::unlink("usync-doc-gpu")?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct NamedSemaphore {
+    shared: ptr::NonNull<Shared>,
+    name: Box<str>,
+}
+
+unsafe impl Send for NamedSemaphore {}
+unsafe impl Sync for NamedSemaphore {}
+
+impl fmt::Debug for NamedSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedSemaphore")
+            .field("name", &self.name)
+            .field("permits", &self.available_permits())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl NamedSemaphore {
+    /// Opens the semaphore with the given name, creating it with `permits` available permits
+    /// if it doesn't exist yet.
+    ///
+    /// When the semaphore already exists, `permits` is ignored. The name must not contain
+    /// slashes nor NUL bytes, and is limited to `NAME_MAX` (usually 255) bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `permits` is larger than `u32::MAX / 2`.
+    pub fn open(name: &str, permits: usize) -> io::Result<Self> {
+        let permits = u32::try_from(permits)
+            .ok()
+            .filter(|&permits| permits <= MAX_PERMITS)
+            .expect("NamedSemaphore permit count overflowed");
+
+        let path = shm_path(name)?;
+        let (fd, created) = unsafe { open_shm(&path)? };
+
+        // The creator sizes the object, but other processes may map it before that happens.
+        // Extending it to the same size again is harmless and keeps it zeroed.
+        let size = mem::size_of::<Shared>();
+        let shared = unsafe {
+            let mapped = if libc::ftruncate(fd, size as libc::off_t) == 0 {
+                libc::mmap(
+                    ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            } else {
+                libc::MAP_FAILED
+            };
+
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+            if mapped == libc::MAP_FAILED {
+                if created {
+                    libc::shm_unlink(path.as_ptr());
+                }
+                return Err(error);
+            }
+            ptr::NonNull::new_unchecked(mapped as *mut Shared)
+        };
+
+        let semaphore = Self {
+            shared,
+            name: name.into(),
+        };
+
+        let shared = semaphore.shared();
+        if created {
+            shared.state.store(permits, Ordering::Relaxed);
+            shared.initialized.store(INITIALIZED, Ordering::Release);
+        } else {
+            // The creator only has a few stores left to do, so just give it some time.
+            while shared.initialized.load(Ordering::Acquire) != INITIALIZED {
+                thread::sleep(Duration::from_micros(50));
+            }
+        }
+
+        Ok(semaphore)
+    }
+
+    /// Removes the semaphore with the given name.
+    ///
+    /// Processes which have the semaphore open can keep using it, but opening the name
+    /// afterwards creates a new semaphore.
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let path = shm_path(name)?;
+        match unsafe { libc::shm_unlink(path.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Returns the name the semaphore was opened with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the amount of permits which are currently available.
+    pub fn available_permits(&self) -> usize {
+        (self.shared().state.load(Ordering::Relaxed) & MAX_PERMITS) as usize
+    }
+
+    /// Returns true if [`close`](NamedSemaphore::close) was called on this semaphore,
+    /// by any process.
+    pub fn is_closed(&self) -> bool {
+        self.shared().state.load(Ordering::Relaxed) & CLOSED != 0
+    }
+
+    /// Acquires a single permit, blocking the current thread until one is available.
+    ///
+    /// The permit is returned to the semaphore when the [`NamedSemaphorePermit`] is dropped.
+    /// Returns an error if the semaphore is closed before the permit could be acquired.
+    #[inline]
+    pub fn acquire(&self) -> Result<NamedSemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `n` permits at once, blocking the current thread until they are all available.
+    ///
+    /// The permits are only taken when all `n` of them can be acquired together, so a
+    /// request for many permits may be starved by smaller ones. Asking for more permits
+    /// than will ever be released blocks until the semaphore is closed.
+    ///
+    /// Returns an error if the semaphore is closed before the permits could be acquired.
+    pub fn acquire_many(&self, n: usize) -> Result<NamedSemaphorePermit<'_>, AcquireError> {
+        let shared = self.shared();
+        let mut state = shared.state.load(Ordering::Relaxed);
+
+        loop {
+            if state & CLOSED != 0 {
+                return Err(AcquireError(()));
+            }
+
+            if n <= state as usize {
+                match shared.state.compare_exchange_weak(
+                    state,
+                    state - n as u32,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Ok(self.permit(n)),
+                    Err(s) => state = s,
+                }
+                continue;
+            }
+
+            // Registering as a waiter before re-checking the state means that a release
+            // either sees us waiting and wakes us up, or happens before the futex compares
+            // the state and makes it return right away.
+            shared.waiters.fetch_add(1, Ordering::SeqCst);
+            atomic_wait::wait_shared(&shared.state, state);
+            shared.waiters.fetch_sub(1, Ordering::Relaxed);
+            state = shared.state.load(Ordering::Relaxed);
+        }
+    }
+
+    /// Tries to acquire a single permit without blocking.
+    ///
+    /// Returns `None` if no permit is available or the semaphore is closed.
+    #[inline]
+    pub fn try_acquire(&self) -> Option<NamedSemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Tries to acquire `n` permits at once without blocking.
+    ///
+    /// Returns `None` if fewer than `n` permits are available or the semaphore is closed.
+    pub fn try_acquire_many(&self, n: usize) -> Option<NamedSemaphorePermit<'_>> {
+        self.shared()
+            .state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                // The closed bit makes the state larger than any amount of permits.
+                match state & CLOSED {
+                    0 if n <= state as usize => Some(state - n as u32),
+                    _ => None,
+                }
+            })
+            .ok()
+            .map(|_| self.permit(n))
+    }
+
+    /// Adds `n` permits to the semaphore, waking up the threads waiting for permits.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of available permits overflows `u32::MAX / 2`.
+    pub fn release(&self, n: usize) {
+        let shared = self.shared();
+        shared
+            .state
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |state| {
+                let permits = (state & MAX_PERMITS) as usize + n;
+                match permits <= MAX_PERMITS as usize {
+                    true => Some((state & CLOSED) | permits as u32),
+                    false => None,
+                }
+            })
+            .expect("NamedSemaphore permit count overflowed");
+
+        self.wake_waiters();
+    }
+
+    /// Closes the semaphore for every process, making every thread blocked in an `acquire`
+    /// call, as well as all future ones, return an [`AcquireError`].
+    ///
+    /// Permits which are currently held can still be released, but nobody can acquire them anymore.
+    /// Returns true if this call closed the semaphore, or false if it was already closed.
+    pub fn close(&self) -> bool {
+        let state = self.shared().state.fetch_or(CLOSED, Ordering::SeqCst);
+        self.wake_waiters();
+        state & CLOSED == 0
+    }
+
+    fn wake_waiters(&self) {
+        // Waiters may want different amounts of permits, so wake all of them up to check.
+        let shared = self.shared();
+        if shared.waiters.load(Ordering::SeqCst) > 0 {
+            atomic_wait::wake_shared(&shared.state, true);
+        }
+    }
+
+    fn permit(&self, permits: usize) -> NamedSemaphorePermit<'_> {
+        NamedSemaphorePermit {
+            semaphore: self,
+            permits,
+        }
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { self.shared.as_ref() }
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.shared.as_ptr().cast(), mem::size_of::<Shared>());
+        }
+    }
+}
+
+fn shm_path(name: &str) -> io::Result<CString> {
+    if name.contains('/') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "semaphore names can't contain slashes",
+        ));
+    }
+
+    CString::new(format!("/{}", name)).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Opens the shared memory object, creating it if it doesn't exist yet.
+///
+/// Returns the file descriptor and whether this call created the object.
+unsafe fn open_shm(path: &CString) -> io::Result<(libc::c_int, bool)> {
+    let flags = libc::O_RDWR | libc::O_CLOEXEC;
+    let mode = 0o600 as libc::mode_t;
+    // The mode is a variadic argument there, so it has to be promoted by hand.
+    #[cfg(target_vendor = "apple")]
+    let mode = mode as libc::c_uint;
+
+    loop {
+        let fd = libc::shm_open(path.as_ptr(), flags | libc::O_CREAT | libc::O_EXCL, mode);
+        if fd >= 0 {
+            return Ok((fd, true));
+        }
+
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            return Err(error);
+        }
+
+        let fd = libc::shm_open(path.as_ptr(), flags, mode);
+        if fd >= 0 {
+            return Ok((fd, false));
+        }
+
+        // The semaphore was unlinked in the meantime, so try creating it again.
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::NotFound {
+            return Err(error);
+        }
+    }
+}
+
+/// An RAII guard holding permits acquired from a [`NamedSemaphore`].
+///
+/// The permits are released back to the semaphore when this guard is dropped.
+#[must_use = "if unused the permits will immediately be released"]
+pub struct NamedSemaphorePermit<'a> {
+    semaphore: &'a NamedSemaphore,
+    permits: usize,
+}
+
+impl<'a> NamedSemaphorePermit<'a> {
+    /// Returns the amount of permits held by this guard.
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Forgets the permits without releasing them back to the semaphore.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl<'a> Drop for NamedSemaphorePermit<'a> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.release(self.permits);
+        }
+    }
+}
+
+impl<'a> fmt::Debug for NamedSemaphorePermit<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedSemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NamedSemaphore;
+    use std::{
+        process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// Returns a name unique to the test and process, so concurrent test runs don't collide.
+    fn name(test: &str) -> String {
+        format!("usync-test-{}-{}", test, process::id())
+    }
+
+    #[test]
+    fn test_named_semaphore_shared() {
+        let name = name("shared");
+        let a = NamedSemaphore::open(&name, 2).unwrap();
+        let b = NamedSemaphore::open(&name, 100).unwrap();
+        assert_eq!(b.available_permits(), 2);
+
+        let permit = a.acquire_many(2).unwrap();
+        assert!(b.try_acquire().is_none());
+        drop(permit);
+        assert_eq!(b.try_acquire().unwrap().num_permits(), 1);
+
+        // Unlinking only removes the name, so the next open creates a new semaphore.
+        NamedSemaphore::unlink(&name).unwrap();
+        assert!(b.close());
+        let c = NamedSemaphore::open(&name, 5).unwrap();
+        assert!(!c.is_closed());
+        assert_eq!(c.available_permits(), 5);
+        assert!(a.acquire().is_err());
+        NamedSemaphore::unlink(&name).unwrap();
+
+        assert_eq!(
+            format!("{:?}", c),
+            format!(
+                "NamedSemaphore {{ name: {:?}, permits: 5, closed: false }}",
+                name
+            )
+        );
+    }
+
+    #[test]
+    fn test_named_semaphore_invalid_name() {
+        assert!(NamedSemaphore::open("a/b", 1).is_err());
+        assert!(NamedSemaphore::open("a\0b", 1).is_err());
+        assert!(NamedSemaphore::unlink("usync-test-missing").is_err());
+    }
+
+    #[test]
+    fn test_named_semaphore_threads() {
+        const THREADS: usize = 4;
+        let name = name("threads");
+        let semaphore = Arc::new(NamedSemaphore::open(&name, 1).unwrap());
+        let active = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (semaphore, active) = (semaphore.clone(), active.clone());
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        let _permit = semaphore.acquire().unwrap();
+                        assert_eq!(active.fetch_add(1, Ordering::Relaxed), 0);
+                        thread::sleep(Duration::from_micros(100));
+                        active.fetch_sub(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(semaphore.available_permits(), 1);
+
+        // Closing wakes up blocked threads.
+        let permit = semaphore.acquire().unwrap();
+        let t = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || semaphore.acquire().is_err())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(semaphore.close());
+        assert!(t.join().unwrap());
+        drop(permit);
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+use super::{
+    const_mutex,
+    shared::{Primitive, Waiter},
+    Mutex,
+};
+use std::{collections::VecDeque, fmt, pin::Pin, ptr::NonNull, time::Duration};
+
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    task::{Context, Poll, Waker},
+};
+
+/// Notifies threads and tasks waiting for an event, without any associated data.
+///
+/// [`notify_one`](Notify::notify_one) wakes up the longest waiting thread or task, or stores
+/// a permit if none are waiting, which the next wait then consumes without blocking. At most
+/// one permit is stored, however many times it's called. [`notify_waiters`](Notify::notify_waiters)
+/// wakes up all the threads and tasks currently waiting, but doesn't store a permit.
+///
+/// Threads block with [`wait`](Notify::wait), and with the `async` feature, tasks await
+/// `notified` instead. Both wait in the same queue, so a thread can
+/// notify a task and a task can notify a thread.
+///
+/// # Examples
+///
+/// ```
+/// use usync::Notify;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let notify = Arc::new(Notify::new());
+/// let waiter = {
+///     let notify = notify.clone();
+///     thread::spawn(move || notify.wait())
+/// };
+///
+/// // Wakes up the thread, or lets it pass through if it didn't wait yet.
+/// notify.notify_one();
+/// waiter.join().unwrap();
+/// ```
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    next_id: usize,
+    /// The waiting threads and tasks in FIFO order. They stay in the queue once
+    /// notified until they remove themselves, which is how they learn about it.
+    waiters: VecDeque<Entry>,
+}
+
+struct Entry {
+    id: usize,
+    wake: Wake,
+    notified: Option<Notification>,
+}
+
+enum Wake {
+    Thread(NonNull<Waiter>),
+    #[cfg(feature = "async")]
+    Task(Waker),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+// SAFETY: Queued waiters are only accessed while the state is locked, and only
+// return once notified, or after removing themselves from the queue.
+unsafe impl Send for State {}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Notify")
+            .field("permit", &state.permit)
+            .field("waiters", &state.waiting())
+            .finish()
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notify {
+    /// Creates a new `Notify` without a stored permit.
+    pub const fn new() -> Self {
+        Self {
+            state: const_mutex(State {
+                permit: false,
+                next_id: 0,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wakes up the longest waiting thread or task, or stores a permit for the next one
+    /// to wait if none are waiting.
+    pub fn notify_one(&self) {
+        let woken = self.state.lock().notify_one();
+        if let Some(wake) = woken {
+            unsafe { wake.wake() };
+        }
+    }
+
+    /// Wakes up all the threads and tasks currently waiting, without storing a permit.
+    pub fn notify_waiters(&self) {
+        let woken: Vec<Wake> = {
+            let mut state = self.state.lock();
+            state
+                .waiters
+                .iter_mut()
+                .filter(|entry| entry.notified.is_none())
+                .map(|entry| {
+                    entry.notified = Some(Notification::All);
+                    entry.wake.take()
+                })
+                .collect()
+        };
+
+        // Wake up the waiters outside the lock.
+        for wake in woken {
+            unsafe { wake.wake() };
+        }
+    }
+
+    /// Blocks the current thread until it's notified, or consumes the stored permit.
+    pub fn wait(&self) {
+        assert!(self.wait_with(None));
+    }
+
+    /// Blocks the current thread until it's notified or the `timeout` elapses,
+    /// or consumes the stored permit.
+    ///
+    /// Returns false if the timeout elapsed before the thread was notified.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_with(Some(timeout))
+    }
+
+    fn wait_with(&self, timeout: Option<Duration>) -> bool {
+        Waiter::with(Primitive::new("Notify", self), |waiter| {
+            let id = {
+                let mut state = self.state.lock();
+                if state.permit {
+                    state.permit = false;
+                    return true;
+                }
+
+                state.push(Wake::Thread(NonNull::from(&*waiter)))
+            };
+
+            let notified = waiter.parker.park(timeout);
+
+            // Only the thread can remove its entry, which it must do before returning.
+            let notification = self.state.lock().remove(id);
+            if notified {
+                return true;
+            }
+
+            // On timeout, the notifying thread may be about to unpark us, so wait for that.
+            match notification {
+                Some(_) => {
+                    assert!(waiter.parker.park(None));
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Returns a future which completes once the task is notified, or consumes the stored permit.
+    ///
+    /// The future only starts waiting once it's first polled. It's cancel safe: dropping it
+    /// after it was woken up by [`notify_one`](Notify::notify_one) but before it completed
+    /// passes the notification on to the next waiter, or stores it back as a permit.
+    ///
+    /// This method is only available with the `async` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Notify;
+    ///
+    /// async fn consumer(notify: &Notify) {
+    ///     notify.notified().await;
+    ///     println!("notified");
+    /// }
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+            done: false,
+        }
+    }
+}
+
+impl State {
+    /// Queues a waiter, returning the id identifying its entry.
+    fn push(&mut self, wake: Wake) -> usize {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.waiters.push_back(Entry {
+            id,
+            wake,
+            notified: None,
+        });
+        id
+    }
+
+    /// Returns how many waiters weren't notified yet.
+    fn waiting(&self) -> usize {
+        let waiting = self.waiters.iter().filter(|entry| entry.notified.is_none());
+        waiting.count()
+    }
+
+    /// Removes the entry of a waiter, returning how it was notified if it was.
+    fn remove(&mut self, id: usize) -> Option<Notification> {
+        let index = self.waiters.iter().position(|entry| entry.id == id)?;
+        self.waiters.remove(index)?.notified
+    }
+
+    /// Notifies the first waiter which wasn't notified yet, returning how to wake it up,
+    /// or stores a permit if there are none.
+    fn notify_one(&mut self) -> Option<Wake> {
+        let waiting = self
+            .waiters
+            .iter_mut()
+            .find(|entry| entry.notified.is_none());
+        match waiting {
+            Some(entry) => {
+                entry.notified = Some(Notification::One);
+                Some(entry.wake.take())
+            }
+            None => {
+                self.permit = true;
+                None
+            }
+        }
+    }
+}
+
+impl Wake {
+    /// Copies what's needed to wake up the waiter outside the lock.
+    fn take(&self) -> Self {
+        match self {
+            Self::Thread(waiter) => Self::Thread(*waiter),
+            #[cfg(feature = "async")]
+            Self::Task(waker) => Self::Task(waker.clone()),
+        }
+    }
+
+    /// Wakes up the waiter.
+    ///
+    /// # Safety
+    ///
+    /// A thread waiter must have been notified while the state was locked,
+    /// which keeps it from returning until it's unparked.
+    unsafe fn wake(self) {
+        match self {
+            Self::Thread(waiter) => Pin::new_unchecked(waiter.as_ref()).parker.unpark(),
+            #[cfg(feature = "async")]
+            Self::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+/// Future returned by [`Notify::notified`], which completes once the task is notified.
+///
+/// This type is only available with the `async` feature.
+#[cfg(feature = "async")]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    id: Option<usize>,
+    done: bool,
+}
+
+#[cfg(feature = "async")]
+impl fmt::Debug for Notified<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notified")
+            .field("waiting", &self.id.is_some())
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        assert!(!self.done, "Notified polled after completion");
+        let notify = self.notify;
+        let mut state = notify.state.lock();
+
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                if state.permit {
+                    state.permit = false;
+                    drop(state);
+                    self.done = true;
+                    return Poll::Ready(());
+                }
+
+                let id = state.push(Wake::Task(cx.waker().clone()));
+                drop(state);
+                self.id = Some(id);
+                return Poll::Pending;
+            }
+        };
+
+        let entry = state.waiters.iter_mut().find(|entry| entry.id == id);
+        let entry = entry.expect("Notified entry removed while queued");
+        if entry.notified.is_none() {
+            if let Wake::Task(waker) = &mut entry.wake {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            return Poll::Pending;
+        }
+
+        state.remove(id);
+        drop(state);
+        self.id = None;
+        self.done = true;
+        Poll::Ready(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+
+        // A notification meant for a single waiter mustn't be lost with the future.
+        let woken = {
+            let mut state = self.notify.state.lock();
+            match state.remove(id) {
+                Some(Notification::One) => state.notify_one(),
+                _ => None,
+            }
+        };
+
+        if let Some(wake) = woken {
+            unsafe { wake.wake() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Notify;
+    use std::{sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn permit() {
+        let notify = Notify::new();
+        assert!(!notify.wait_timeout(Duration::from_millis(10)));
+
+        // Only a single permit is stored.
+        notify.notify_one();
+        notify.notify_one();
+        notify.wait();
+        assert!(!notify.wait_timeout(Duration::from_millis(10)));
+
+        // notify_waiters() doesn't store a permit.
+        notify.notify_waiters();
+        assert!(!notify.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn notify_waiters_wakes_all() {
+        let notify = Arc::new(Notify::new());
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let notify = notify.clone();
+                thread::spawn(move || notify.wait())
+            })
+            .collect();
+
+        while format!("{:?}", notify) != "Notify { permit: false, waiters: 4 }" {
+            thread::yield_now();
+        }
+        notify.notify_waiters();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn wait_timeout_races_notify() {
+        let notify = Arc::new(Notify::new());
+        for _ in 0..1000 {
+            let t = {
+                let notify = notify.clone();
+                thread::spawn(move || notify.wait_timeout(Duration::from_micros(50)))
+            };
+
+            thread::sleep(Duration::from_micros(50));
+            notify.notify_one();
+
+            // Either the thread consumed the notification or it's left as a permit.
+            if !t.join().unwrap() {
+                notify.wait();
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    mod asynchronous {
+        use crate::Notify;
+        use std::{
+            future::Future,
+            pin::Pin,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+            task::{Context, Wake, Waker},
+            thread,
+        };
+
+        struct CountingWaker(AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn waker() -> (Arc<CountingWaker>, Waker) {
+            let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+            (counter.clone(), Waker::from(counter))
+        }
+
+        #[test]
+        fn notified() {
+            let notify = Notify::new();
+            let (woken, waker) = waker();
+            let mut cx = Context::from_waker(&waker);
+
+            let mut notified = notify.notified();
+            assert!(Pin::new(&mut notified).poll(&mut cx).is_pending());
+
+            notify.notify_one();
+            assert_eq!(woken.0.load(Ordering::SeqCst), 1);
+            assert!(Pin::new(&mut notified).poll(&mut cx).is_ready());
+
+            // The permit is consumed by the first poll.
+            notify.notify_one();
+            assert!(Pin::new(&mut notify.notified()).poll(&mut cx).is_ready());
+        }
+
+        #[test]
+        fn dropped_notified_passes_notification_on() {
+            let notify = Notify::new();
+            let (_, waker) = waker();
+            let mut cx = Context::from_waker(&waker);
+
+            let mut first = notify.notified();
+            let mut second = notify.notified();
+            assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+            assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+
+            notify.notify_one();
+            drop(first);
+            assert!(Pin::new(&mut second).poll(&mut cx).is_ready());
+
+            // Without other waiters, the notification is stored back as a permit.
+            let mut third = notify.notified();
+            assert!(Pin::new(&mut third).poll(&mut cx).is_pending());
+            notify.notify_one();
+            drop(third);
+            notify.wait();
+        }
+
+        #[test]
+        fn thread_notifies_task() {
+            let notify = Arc::new(Notify::new());
+            let (woken, waker) = waker();
+            let mut cx = Context::from_waker(&waker);
+
+            let mut notified = notify.notified();
+            assert!(Pin::new(&mut notified).poll(&mut cx).is_pending());
+
+            let t = {
+                let notify = notify.clone();
+                thread::spawn(move || notify.notify_waiters())
+            };
+            t.join().unwrap();
+
+            assert_eq!(woken.0.load(Ordering::SeqCst), 1);
+            assert!(Pin::new(&mut notified).poll(&mut cx).is_ready());
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use crate::shared::{fence_acquire, invalid_mut, SpinWait, StrictProvenance, Waiter};
+use crate::shared::{fence_acquire, invalid_mut, Primitive, SpinWait, StrictProvenance, Waiter};
 use std::{
     fmt,
     mem::drop,
+    pin::Pin,
     ptr::NonNull,
     sync::atomic::{AtomicPtr, Ordering},
 };
@@ -11,6 +12,9 @@ const CALLING: usize = 1;
 const POISONED: usize = 2;
 const COMPLETED: usize = 3;
 
+// The state is stored in the low bits of the queue head's address.
+const _: () = assert!((CALLING | POISONED | COMPLETED) & Waiter::MASK == 0);
+
 /// A synchronization primitive which can be used to run a one-time
 /// initialization. Useful for one-time initialization for globals, FFI or
 /// related functionality.
@@ -48,7 +52,11 @@ pub struct Once {
     ///            |           | The Remaining bits point to the head of the waiting-thread queue
     //             |           | if there is any.
     /// -----------+-----------+--------------------------------------------------------------------
-    ///  POISONED  |     0     | The once was once calling and the function panicked, poisoning the state.
+    ///   UNINIT   |  *Waiter  | The once state is fresh and the Remaining bits point to the head of a queue
+    ///            |           | of threads waiting for another thread to complete it.
+    /// -----------+-----------+---------------------------------------------------------------------
+    ///  POISONED  | ?*Waiter  | The once was once calling and the function panicked, poisoning the state.
+    ///            |           | The Remaining bits point to the head of the waiting-thread queue if there is any.
     /// -----------+-----------+--------------------------------------------------------------------
     ///  COMPLETED |     0     | The once was once calling and the function completed, resolving the state.
     /// -----------+-----------+--------------------------------------------------------------------
@@ -86,6 +94,57 @@ impl Once {
         }
     }
 
+    /// Returns whether an initialization routine has successfully completed.
+    ///
+    /// When this returns true, any memory writes performed by the routine can be
+    /// reliably observed by the current thread, like after a call to `call_once`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Once;
+    ///
+    /// static INIT: Once = Once::new();
+    ///
+    /// assert!(!INIT.is_completed());
+    /// INIT.call_once(|| {});
+    /// assert!(INIT.is_completed());
+    /// ```
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        // Acquire barrier to ensure that Once function call and completion happens before we return.
+        let state = self.state.load(Ordering::Acquire);
+        state.address() == COMPLETED
+    }
+
+    /// Returns whether a previous initialization routine panicked, poisoning this `Once`.
+    ///
+    /// A poisoned `Once` makes `call_once` panic until an initialization
+    /// routine started with `call_once_force` completes successfully.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Once;
+    /// use std::panic;
+    ///
+    /// static INIT: Once = Once::new();
+    ///
+    /// let result = panic::catch_unwind(|| INIT.call_once(|| panic!()));
+    /// assert!(result.is_err());
+    /// assert!(INIT.is_poisoned());
+    ///
+    /// INIT.call_once_force(|_| {});
+    /// assert!(!INIT.is_poisoned());
+    /// assert!(INIT.is_completed());
+    /// ```
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        // Acquire barrier to ensure the Once function call panic happened before we return.
+        let state = self.state.load(Ordering::Acquire);
+        state.address() & !Waiter::MASK == POISONED
+    }
+
     /// Performs an initialization routine once and only once. The given closure
     /// will be executed if this is the first time `call_once` has been called,
     /// and otherwise the routine will *not* be invoked.
@@ -147,7 +206,10 @@ impl Once {
             return;
         }
 
-        self.call_once_slow(false, |_: OnceState| f());
+        self.call_once_slow(false, |_: OnceState| {
+            f();
+            true
+        });
     }
 
     /// Performs the same function as `call_once` except ignores poisoning.
@@ -171,15 +233,187 @@ impl Once {
             return;
         }
 
+        self.call_once_slow(true, |state| {
+            f(state);
+            true
+        });
+    }
+
+    /// Performs the same function as `call_once` except that it never blocks.
+    ///
+    /// If no initialization routine has run yet, `f` is called and `Called` is returned.
+    /// If another thread is in the middle of running one, this returns `InProgress` right
+    /// away instead of waiting for it to finish, and `Done` once one has completed.
+    ///
+    /// This is meant for latency-sensitive code which can make progress without the
+    /// initialization, and wants to opportunistically perform it but never park.
+    ///
+    /// # Panics
+    ///
+    /// If this `Once` has been poisoned because an initialization closure has panicked,
+    /// this method will also panic. If `f` panics, the `Once` is poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::{Once, TryCallOnceResult};
+    ///
+    /// static INIT: Once = Once::new();
+    ///
+    /// assert_eq!(INIT.try_call_once(|| {}), TryCallOnceResult::Called);
+    /// assert_eq!(INIT.try_call_once(|| unreachable!()), TryCallOnceResult::Done);
+    /// ```
+    pub fn try_call_once<F>(&self, f: F) -> TryCallOnceResult
+    where
+        F: FnOnce(),
+    {
+        // Acquire barrier to ensure that Once function call and completion happens before we return.
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match state.address() & !Waiter::MASK {
+                COMPLETED => return TryCallOnceResult::Done,
+                CALLING => return TryCallOnceResult::InProgress,
+                POISONED => panic!("Once instance was previously poisoned"),
+                _ => {}
+            }
+
+            // Keep any threads waiting for completion queued while we're calling.
+            match self.state.compare_exchange_weak(
+                state,
+                state.map_address(|addr| (addr & Waiter::MASK) | CALLING),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.do_call(state, |_| {
+                        f();
+                        true
+                    });
+                    return TryCallOnceResult::Called;
+                }
+                Err(e) => state = e,
+            }
+        }
+    }
+
+    /// Blocks the current thread until initialization has completed.
+    ///
+    /// Unlike `call_once`, this never runs an initialization routine itself:
+    /// it waits for another thread to successfully complete one instead.
+    ///
+    /// # Panics
+    ///
+    /// If this `Once` has been poisoned because an initialization closure has
+    /// panicked, this method will also panic. Use `wait_force` if this
+    /// behavior is not desired.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Once;
+    /// use std::thread;
+    ///
+    /// static READY: Once = Once::new();
+    ///
+    /// let waiter = thread::spawn(|| READY.wait());
+    ///
+    /// READY.call_once(|| {
+    ///     // run initialization here
+    /// });
+    ///
+    /// waiter.join().unwrap();
+    /// ```
+    #[inline]
+    pub fn wait(&self) {
+        // Acquire barrier to ensure that Once function call and completion happens before we return.
+        let state = self.state.load(Ordering::Acquire);
+        if state.address() == COMPLETED {
+            return;
+        }
+
+        self.wait_slow(false);
+    }
+
+    /// Blocks the current thread until initialization has completed, ignoring poisoning.
+    ///
+    /// If this `Once` has been poisoned, this keeps waiting until an initialization
+    /// routine started with `call_once_force` completes successfully.
+    #[inline]
+    pub fn wait_force(&self) {
+        // Acquire barrier to ensure that Once function call and completion happens before we return.
+        let state = self.state.load(Ordering::Acquire);
+        if state.address() == COMPLETED {
+            return;
+        }
+
+        self.wait_slow(true);
+    }
+
+    #[cold]
+    fn wait_slow(&self, ignore_poison: bool) {
+        Waiter::with(Primitive::new("Once", self), |waiter| {
+            let mut spin = SpinWait::default();
+            let mut state = self.state.load(Ordering::Relaxed);
+
+            loop {
+                // Once the state is completed, we can return.
+                // Acquire barrier to ensure the Once function call and completion happen before we return.
+                if state.address() == COMPLETED {
+                    fence_acquire(&self.state);
+                    return;
+                }
+
+                // Check for poision and panic if the caller can't ignore it.
+                // Acquire barrier to ensure the Once function call panic happened before we return.
+                if state.address() & !Waiter::MASK == POISONED && !ignore_poison {
+                    fence_acquire(&self.state);
+                    panic!("Once instance was previously poisoned");
+                }
+
+                // Try to spin a little bit in hopes that a calling thread finishes soon.
+                // Don't spin if there's already threads waiting as we should start waiting too.
+                let is_calling = state.address() & !Waiter::MASK == CALLING;
+                let head = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
+                if is_calling && head.is_none() && spin.try_yield_now() {
+                    state = self.state.load(Ordering::Relaxed);
+                    continue;
+                }
+
+                // Wait for a thread to call and complete the Once.
+                state = match self.park(waiter, state) {
+                    Ok(_) => self.state.load(Ordering::Relaxed),
+                    Err(e) => e,
+                };
+            }
+        })
+    }
+
+    /// Performs the same function as `call_once_force` except that the `Once`
+    /// is only completed if `f` returns true.
+    ///
+    /// Otherwise, the `Once` goes back to the state it was in before calling `f`
+    /// and other threads waiting on it try to call their own function instead.
+    #[inline]
+    pub(super) fn try_call_once_force<F>(&self, f: F)
+    where
+        F: FnOnce(OnceState) -> bool,
+    {
+        // Fast path to check if the state was completed.
+        // Acquire barrier to ensure that Once function call and completion happens before we return.
+        let state = self.state.load(Ordering::Acquire);
+        if state.address() == COMPLETED {
+            return;
+        }
+
         self.call_once_slow(true, f);
     }
 
     #[cold]
     fn call_once_slow<F>(&self, ignore_poison: bool, f: F)
     where
-        F: FnOnce(OnceState),
+        F: FnOnce(OnceState) -> bool,
     {
-        Waiter::with(|waiter| {
+        Waiter::with(Primitive::new("Once", self), |waiter| {
             let mut spin = SpinWait::default();
             let mut state = self.state.load(Ordering::Relaxed);
 
@@ -193,7 +427,7 @@ impl Once {
 
                 // Check for poision and panic if the caller can't ignore it.
                 // Acquire barrier to ensure the Once function call panic happened before we return.
-                if state.address() == POISONED && !ignore_poison {
+                if state.address() & !Waiter::MASK == POISONED && !ignore_poison {
                     fence_acquire(&self.state);
                     panic!("Once instance was previously poisoned");
                 }
@@ -209,32 +443,18 @@ impl Once {
                         continue;
                     }
 
-                    // Push our waiter to the queue in a stack-like manner.
-                    waiter.next.set(head);
-                    let waiter_ptr = NonNull::from(&*waiter).as_ptr();
-                    let new_state = waiter_ptr.map_address(|addr| addr | CALLING);
-
-                    // Release barrier to ensure our waiter's writes happen before the calling thread
-                    // iterates the queue in order to wake us up.
-                    if let Err(e) = self.state.compare_exchange_weak(
-                        state,
-                        new_state,
-                        Ordering::Release,
-                        Ordering::Relaxed,
-                    ) {
-                        state = e;
-                        continue;
-                    }
-
                     // Sleep and check the Once state again.
-                    assert!(waiter.parker.park(None));
-                    state = self.state.load(Ordering::Relaxed);
+                    state = match self.park(waiter, state) {
+                        Ok(_) => self.state.load(Ordering::Relaxed),
+                        Err(e) => e,
+                    };
                     continue;
                 }
 
+                // Keep any threads waiting for completion queued while we're calling.
                 match self.state.compare_exchange_weak(
                     state,
-                    state.with_address(CALLING),
+                    state.map_address(|addr| (addr & Waiter::MASK) | CALLING),
                     Ordering::Acquire,
                     Ordering::Relaxed,
                 ) {
@@ -245,10 +465,29 @@ impl Once {
         })
     }
 
+    /// Queues the waiter on the current Once state and blocks until it's woken up,
+    /// which happens when a calling thread completes or poisons the Once.
+    fn park(&self, waiter: Pin<&Waiter>, state: *mut Waiter) -> Result<(), *mut Waiter> {
+        // Push our waiter to the queue in a stack-like manner.
+        let head = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
+        waiter.next.set(head);
+
+        let waiter_ptr = NonNull::from(&*waiter).as_ptr();
+        let new_state = waiter_ptr.map_address(|addr| addr | (state.address() & !Waiter::MASK));
+
+        // Release barrier to ensure our waiter's writes happen before the calling thread
+        // iterates the queue in order to wake us up.
+        self.state
+            .compare_exchange_weak(state, new_state, Ordering::Release, Ordering::Relaxed)?;
+
+        assert!(waiter.parker.park(None));
+        Ok(())
+    }
+
     #[cold]
     fn do_call<F>(&self, old_state: *mut Waiter, f: F)
     where
-        F: FnOnce(OnceState),
+        F: FnOnce(OnceState) -> bool,
     {
         /// The state guard is used to ensure that waiting threads are woken up
         /// regardless of it a panic occurs when calling f() or not.
@@ -282,7 +521,8 @@ impl Once {
             reset_to: old_state.with_address(POISONED),
         };
 
-        f(match old_state.address() {
+        let old_status = old_state.address() & !Waiter::MASK;
+        let completed = f(match old_status {
             UNINIT => OnceState::New,
             POISONED => OnceState::Poisoned,
             _ => unreachable!("invalid once state on invokation"),
@@ -290,7 +530,11 @@ impl Once {
 
         // The function call completed safely,
         // so resolve the Once with COMPLETED instead of POISONED.
-        state_guard.reset_to = old_state.with_address(COMPLETED);
+        // If the function gave up, reset the Once instead so that the woken up threads can retry.
+        state_guard.reset_to = match completed {
+            true => old_state.with_address(COMPLETED),
+            false => old_state.with_address(old_status),
+        };
         drop(state_guard);
     }
 }
@@ -329,10 +573,50 @@ impl OnceState {
     }
 }
 
+/// The outcome of [`Once::try_call_once`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TryCallOnceResult {
+    /// The closure was called and completed the `Once`.
+    Called,
+
+    /// Another thread is currently executing a closure, so this one wasn't called.
+    InProgress,
+
+    /// A closure had already completed successfully, so this one wasn't called.
+    Done,
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::Once;
-    use std::{panic, sync::mpsc::channel, thread};
+    use crate::{
+        shared::{StrictProvenance, Waiter},
+        Once, TryCallOnceResult,
+    };
+    use std::{
+        panic,
+        ptr::NonNull,
+        sync::{atomic::Ordering, mpsc::channel},
+        thread,
+    };
+
+    // Waits for `count` threads to be queued on the once.
+    fn wait_for_waiters(once: &Once, count: usize) {
+        loop {
+            // Acquire barrier to see the writes of the waiters pushed to the queue.
+            let state = once.state.load(Ordering::Acquire);
+            let mut queued = 0;
+            let mut current = NonNull::new(state.map_address(|addr| addr & Waiter::MASK));
+            while let Some(waiter) = current {
+                queued += 1;
+                current = unsafe { waiter.as_ref().next.get() };
+            }
+
+            if queued == count {
+                return;
+            }
+            thread::yield_now();
+        }
+    }
 
     #[test]
     fn smoke_once() {
@@ -446,10 +730,135 @@ mod tests {
         assert!(t2.join().is_ok());
     }
 
+    #[test]
+    fn wait() {
+        static O: Once = Once::new();
+
+        let (tx, rx) = channel();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    O.wait();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        wait_for_waiters(&O, 4);
+        assert!(rx.try_recv().is_err());
+
+        O.call_once(|| {});
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        O.wait();
+    }
+
+    #[test]
+    fn wait_poisoned() {
+        static O: Once = Once::new();
+
+        let (tx, rx) = channel();
+        let waiter = thread::spawn(move || {
+            tx.send(()).unwrap();
+            O.wait();
+        });
+
+        // poison the once while a thread is waiting on it
+        rx.recv().unwrap();
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+
+        assert!(waiter.join().is_err());
+        assert!(panic::catch_unwind(|| O.wait()).is_err());
+    }
+
+    #[test]
+    fn wait_force() {
+        static O: Once = Once::new();
+
+        // poison the once
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+
+        // waiting while poisoned keeps blocking until the once completes
+        let (tx, rx) = channel();
+        let waiter = thread::spawn(move || {
+            O.wait_force();
+            tx.send(()).unwrap();
+        });
+        wait_for_waiters(&O, 1);
+        assert!(rx.try_recv().is_err());
+
+        O.call_once_force(|p| assert!(p.poisoned()));
+        waiter.join().unwrap();
+        assert!(O.state().done());
+    }
+
+    #[test]
+    fn is_completed_and_poisoned() {
+        static O: Once = Once::new();
+        assert!(!O.is_completed());
+        assert!(!O.is_poisoned());
+
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+        assert!(!O.is_completed());
+        assert!(O.is_poisoned());
+
+        O.call_once_force(|_| {});
+        assert!(O.is_completed());
+        assert!(!O.is_poisoned());
+    }
+
     #[test]
     fn test_once_debug() {
         static O: Once = Once::new();
 
         assert_eq!(format!("{:?}", O), "Once { state: New }");
     }
+
+    #[test]
+    fn try_call_once() {
+        static O: Once = Once::new();
+
+        // Another thread calling makes the attempt return without blocking.
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let t = thread::spawn(move || {
+            O.call_once(|| {
+                tx1.send(()).unwrap();
+                rx2.recv().unwrap();
+            });
+        });
+
+        rx1.recv().unwrap();
+        assert_eq!(
+            O.try_call_once(|| unreachable!()),
+            TryCallOnceResult::InProgress
+        );
+        tx2.send(()).unwrap();
+        t.join().unwrap();
+        assert_eq!(O.try_call_once(|| unreachable!()), TryCallOnceResult::Done);
+
+        let mut called = false;
+        let o = Once::new();
+        assert_eq!(o.try_call_once(|| called = true), TryCallOnceResult::Called);
+        assert!(called);
+        assert!(o.is_completed());
+
+        // A panic poisons the Once like with call_once.
+        let o = Once::new();
+        let t = panic::catch_unwind(|| o.try_call_once(|| panic!()));
+        assert!(t.is_err());
+        assert!(o.is_poisoned());
+        assert!(panic::catch_unwind(|| o.try_call_once(|| {})).is_err());
+    }
 }
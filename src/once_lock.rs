@@ -0,0 +1,392 @@
+use super::Once;
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    mem::MaybeUninit,
+    panic::{RefUnwindSafe, UnwindSafe},
+};
+
+/// A thread-safe cell which can be written to only once.
+///
+/// Threads racing to initialize the cell through `get_or_init` or `get_or_try_init`
+/// block until one of them succeeds, using the same thread queues as
+/// [`Once`](struct.Once.html).
+///
+/// If an initialization function panics or returns an error, the cell is left
+/// uninitialized and one of the waiting threads tries to initialize it instead.
+///
+/// # Examples
+///
+/// ```
+/// use usync::OnceLock;
+///
+/// static CELL: OnceLock<String> = OnceLock::new();
+/// assert!(CELL.get().is_none());
+///
+/// std::thread::spawn(|| {
+///     let value: &String = CELL.get_or_init(|| "Hello, World!".to_string());
+///     assert_eq!(value, "Hello, World!");
+/// })
+/// .join()
+/// .unwrap();
+///
+/// let value: Option<&String> = CELL.get();
+/// assert_eq!(value.unwrap(), "Hello, World!");
+/// ```
+pub struct OnceLock<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value can be initialized by any thread and then shared between all of them.
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T: UnwindSafe + RefUnwindSafe> RefUnwindSafe for OnceLock<T> {}
+impl<T: UnwindSafe> UnwindSafe for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    /// Creates a new empty cell.
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Gets the reference to the underlying value.
+    ///
+    /// Returns `None` if the cell is empty, or being initialized.
+    pub fn get(&self) -> Option<&T> {
+        if !self.is_initialized() {
+            return None;
+        }
+
+        Some(unsafe { self.get_unchecked() })
+    }
+
+    /// Gets the mutable reference to the underlying value.
+    ///
+    /// Returns `None` if the cell is empty.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        match self.is_initialized() {
+            true => Some(unsafe { (*self.value.get()).assume_init_mut() }),
+            false => None,
+        }
+    }
+
+    /// Sets the contents of this cell to `value`.
+    ///
+    /// May block if another thread is currently attempting to initialize the cell.
+    /// Returns `Err(value)` if the cell was already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        match self.try_insert(value) {
+            Ok(_) => Ok(()),
+            Err((_, value)) => Err(value),
+        }
+    }
+
+    /// Sets the contents of this cell to `value` if it was empty, returning a reference to it.
+    ///
+    /// May block if another thread is currently attempting to initialize the cell.
+    /// If the cell was already initialized, returns `Err` with a reference to the existing
+    /// value along with the rejected `value`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// assert_eq!(cell.try_insert(92), Ok(&92));
+    /// assert_eq!(cell.try_insert(62), Err((&92, 62)));
+    /// ```
+    pub fn try_insert(&self, value: T) -> Result<&T, (&T, T)> {
+        let mut value = Some(value);
+        let current = self.get_or_init(|| value.take().unwrap());
+        match value {
+            Some(value) => Err((current, value)),
+            None => Ok(current),
+        }
+    }
+
+    /// Gets the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// Many threads may call `get_or_init` concurrently with different initializing
+    /// functions, but it is guaranteed that only one function will be executed.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is propagated to the caller, and the cell remains uninitialized.
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        enum Never {}
+        match self.get_or_try_init(|| Ok::<T, Never>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Gets the contents of the cell, initializing it with `f` if the cell was empty.
+    /// If the cell was empty and `f` failed, an error is returned.
+    ///
+    /// When `f` returns an error, the cell is left uninitialized and the threads
+    /// waiting for it to be initialized are woken up to try their own function.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is propagated to the caller, and the cell remains uninitialized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert!(cell.get().is_none());
+    ///
+    /// let value = cell.get_or_try_init(|| -> Result<i32, ()> { Ok(92) });
+    /// assert_eq!(value, Ok(&92));
+    /// assert_eq!(cell.get(), Some(&92));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+
+        let mut result = Ok(());
+        self.once.try_call_once_force(|_| match f() {
+            Ok(value) => {
+                // Safety: only the thread running the Once writes the value.
+                unsafe { (*self.value.get()).as_mut_ptr().write(value) };
+                true
+            }
+            Err(error) => {
+                result = Err(error);
+                false
+            }
+        });
+
+        // Safety: try_call_once_force() only returns without an error once the value has been written.
+        result.map(|_| unsafe { self.get_unchecked() })
+    }
+
+    /// Consumes the cell, returning the wrapped value.
+    ///
+    /// Returns `None` if the cell was empty.
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    /// Takes the value out of this cell, moving it back to an uninitialized state.
+    ///
+    /// Has no effect and returns `None` if the cell hasn't been initialized.
+    pub fn take(&mut self) -> Option<T> {
+        if !self.is_initialized() {
+            return None;
+        }
+
+        self.once = Once::new();
+        Some(unsafe { (*self.value.get()).as_ptr().read() })
+    }
+
+    fn is_initialized(&self) -> bool {
+        self.once.is_completed()
+    }
+
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<T> for OnceLock<T> {
+    fn from(value: T) -> Self {
+        let cell = Self::new();
+        let _ = cell.set(value);
+        cell
+    }
+}
+
+impl<T: Clone> Clone for OnceLock<T> {
+    fn clone(&self) -> Self {
+        match self.get() {
+            Some(value) => Self::from(value.clone()),
+            None => Self::new(),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("OnceLock").field(value).finish(),
+            None => f.write_str("OnceLock(<uninit>)"),
+        }
+    }
+}
+
+/// Serializes the value as an `Option`, which is `None` if the cell is empty.
+#[cfg(feature = "serde")]
+impl<T: serde_crate::Serialize> serde_crate::Serialize for OnceLock<T> {
+    fn serialize<S: serde_crate::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().serialize(serializer)
+    }
+}
+
+/// Deserializes an `Option` of the value, leaving the cell empty if it's `None`.
+#[cfg(feature = "serde")]
+impl<'de, T: serde_crate::Deserialize<'de>> serde_crate::Deserialize<'de> for OnceLock<T> {
+    fn deserialize<D: serde_crate::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Option::<T>::deserialize(deserializer)? {
+            Some(value) => Self::from(value),
+            None => Self::new(),
+        })
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.is_initialized() {
+            unsafe { (*self.value.get()).as_mut_ptr().drop_in_place() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::OnceLock;
+    use std::{
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::channel,
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let cell = OnceLock::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_init(|| 92), &92);
+        assert_eq!(cell.get_or_init(|| 62), &92);
+        assert_eq!(cell.set(5), Err(5));
+        assert_eq!(cell.into_inner(), Some(92));
+    }
+
+    #[test]
+    fn stampede() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static CELL: OnceLock<usize> = OnceLock::new();
+
+        let threads: Vec<_> = (0..10)
+            .map(|i| {
+                thread::spawn(move || {
+                    *CELL.get_or_init(|| {
+                        CALLS.fetch_add(1, Ordering::Relaxed);
+                        i
+                    })
+                })
+            })
+            .collect();
+
+        let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(values.iter().all(|&value| value == values[0]));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn get_or_init_panic() {
+        let cell = OnceLock::new();
+        assert!(panic::catch_unwind(|| cell.get_or_init(|| panic!())).is_err());
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_init(|| 1), &1);
+    }
+
+    #[test]
+    fn get_or_try_init_retry() {
+        let cell = Arc::new(OnceLock::new());
+        let (tx, rx) = channel();
+
+        // Block another thread on the cell while the first initializer fails.
+        let failing = cell.get_or_try_init(|| {
+            let cell = cell.clone();
+            let waiter = thread::spawn(move || *cell.get_or_try_init(|| Ok::<_, ()>(2)).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            tx.send(waiter).unwrap();
+            Err("failed")
+        });
+        assert_eq!(failing, Err("failed"));
+
+        let waiter = rx.recv().unwrap();
+        assert_eq!(waiter.join().unwrap(), 2);
+        assert_eq!(cell.get(), Some(&2));
+    }
+
+    #[test]
+    fn take() {
+        let mut cell = OnceLock::from(vec![1]);
+        assert_eq!(cell.get_mut(), Some(&mut vec![1]));
+        assert_eq!(cell.take(), Some(vec![1]));
+        assert_eq!(cell.take(), None);
+        assert_eq!(cell.get_or_init(|| vec![2]), &vec![2]);
+    }
+
+    #[test]
+    fn try_insert() {
+        let mut cell = OnceLock::new();
+        assert_eq!(cell.try_insert(vec![1]), Ok(&vec![1]));
+        assert_eq!(cell.try_insert(vec![2]), Err((&vec![1], vec![2])));
+
+        // Taking the value allows reinitializing the cell.
+        assert_eq!(cell.take(), Some(vec![1]));
+        assert_eq!(cell.try_insert(vec![3]), Ok(&vec![3]));
+        assert_eq!(cell.into_inner(), Some(vec![3]));
+    }
+
+    #[test]
+    fn drop_value() {
+        let value = Arc::new(());
+        let cell = OnceLock::new();
+        let _ = cell.set(value.clone());
+        assert_eq!(Arc::strong_count(&value), 2);
+        drop(cell);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn test_once_lock_debug() {
+        let cell = OnceLock::new();
+        assert_eq!(format!("{:?}", cell), "OnceLock(<uninit>)");
+        let _ = cell.set(5);
+        assert_eq!(format!("{:?}", cell), "OnceLock(5)");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let cell = OnceLock::from(5u32);
+        assert_eq!(serde_json::to_string(&cell).unwrap(), "5");
+        let cell: OnceLock<u32> = serde_json::from_str("5").unwrap();
+        assert_eq!(cell.get(), Some(&5));
+
+        let cell = OnceLock::<u32>::new();
+        assert_eq!(serde_json::to_string(&cell).unwrap(), "null");
+        let cell: OnceLock<u32> = serde_json::from_str("null").unwrap();
+        assert_eq!(cell.get(), None);
+    }
+}
@@ -0,0 +1,428 @@
+//! Address-keyed thread parking for building custom synchronization primitives.
+//!
+//! Threads [`park`] on a `usize` key, usually the address of the synchronization
+//! primitive they're waiting on, and other threads wake them up through the same key
+//! with [`unpark_one`], [`unpark_all`] or [`unpark_requeue`]. This lets a primitive keep
+//! its own state in a single atomic while the queues of waiting threads live in a
+//! process-global table, like the `parking_lot_core` crate does.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::parking::{self, ParkResult};
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! let ready = Arc::new(AtomicBool::new(false));
+//! let key = &*ready as *const AtomicBool as usize;
+//!
+//! let t = {
+//!     let ready = ready.clone();
+//!     thread::spawn(move || {
+//!         // The validation runs under the queue lock, so unparks after the store can't be missed.
+//!         while !ready.load(Ordering::Acquire) {
+//!             parking::park(key, || !ready.load(Ordering::Relaxed), None);
+//!         }
+//!     })
+//! };
+//!
+//! ready.store(true, Ordering::Release);
+//! parking::unpark_all(key);
+//! t.join().unwrap();
+//! ```
+
+use super::{
+    const_mutex,
+    shared::{Primitive, Waiter},
+    Mutex,
+};
+use std::{pin::Pin, ptr::NonNull, sync::atomic::Ordering, time::Duration};
+
+/// Set in the flags of a waiter which was dequeued by an unpark and is being woken up.
+const WAITER_UNPARKED: usize = 1;
+
+const BUCKETS: usize = 64;
+
+/// The result of a call to [`park`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParkResult {
+    /// The thread was woken up by an unpark on its key.
+    Unparked,
+    /// The validation callback returned false so the thread didn't park.
+    Invalid,
+    /// The timeout elapsed before the thread was woken up.
+    TimedOut,
+}
+
+impl ParkResult {
+    /// Returns true if the thread was woken up by an unpark.
+    pub fn is_unparked(self) -> bool {
+        self == ParkResult::Unparked
+    }
+}
+
+/// The result of a call to [`unpark_requeue`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequeueResult {
+    /// Whether a thread parked on the source key was woken up.
+    pub unparked: bool,
+    /// The amount of threads which were moved to the destination key.
+    pub requeued: usize,
+}
+
+/// Parks the current thread on `key` until it's woken up by an unpark on the same key,
+/// or until `timeout` elapses if there is one.
+///
+/// `validate` is called with the queue of `key` locked, which unparks also lock, and the thread
+/// only parks if it returns true. Checking the primitive's state in `validate` thus ensures
+/// that an unpark after changing that state can't be missed.
+///
+/// `validate` must not call any function of this module, as it would deadlock.
+pub fn park(key: usize, validate: impl FnOnce() -> bool, timeout: Option<Duration>) -> ParkResult {
+    park_on(Primitive::at("parking", key), key, validate, timeout)
+}
+
+/// Parks the current thread like [`park`], for the crate's primitives built on top of this
+/// module to report themselves to the watchdog.
+pub(crate) fn park_on(
+    primitive: Primitive,
+    key: usize,
+    validate: impl FnOnce() -> bool,
+    timeout: Option<Duration>,
+) -> ParkResult {
+    Waiter::with(primitive, |waiter| {
+        {
+            let mut bucket = bucket(key).lock();
+            if !validate() {
+                return ParkResult::Invalid;
+            }
+
+            bucket.push(waiter, key);
+        }
+
+        if waiter.parker.park(timeout) {
+            return ParkResult::Unparked;
+        }
+
+        // On timeout, remove ourselves from the queue unless an unpark already dequeued us.
+        // In that case it's about to unpark us, so wait for that before returning.
+        // The key is only changed by unpark_requeue() under the lock of its queue,
+        // so it's stable once we locked the queue it maps to.
+        loop {
+            let key = waiter.counter.load(Ordering::Relaxed);
+            let mut bucket = bucket(key).lock();
+            if waiter.flags.get() & WAITER_UNPARKED != 0 {
+                break;
+            }
+
+            if waiter.counter.load(Ordering::Relaxed) == key {
+                unsafe { bucket.remove(NonNull::from(&*waiter)) };
+                return ParkResult::TimedOut;
+            }
+        }
+
+        assert!(waiter.parker.park(None));
+        ParkResult::Unparked
+    })
+}
+
+/// Wakes up the longest parked thread on `key`.
+///
+/// Returns true if there was a thread to wake up.
+pub fn unpark_one(key: usize) -> bool {
+    let woken = bucket(key).lock().dequeue(key, false);
+    unsafe { unpark(woken) > 0 }
+}
+
+/// Wakes up all the threads parked on `key`.
+///
+/// Returns the amount of threads which were woken up.
+pub fn unpark_all(key: usize) -> usize {
+    let woken = bucket(key).lock().dequeue(key, true);
+    unsafe { unpark(woken) }
+}
+
+/// Wakes up the longest parked thread on `from` and moves all the other threads parked
+/// on it to `to`, so that they're woken up by unparks on `to` instead.
+///
+/// This avoids a thundering herd when a condition variable wakes up all its threads,
+/// which would otherwise all contend on the same mutex right away.
+pub fn unpark_requeue(from: usize, to: usize) -> RequeueResult {
+    let (from_bucket, to_bucket) = (bucket(from), bucket(to));
+
+    // Lock both queues in a consistent order so concurrent requeues can't deadlock.
+    let (mut from_queue, mut to_queue) = match from_bucket as *const _ as usize {
+        address if address == to_bucket as *const _ as usize => (from_bucket.lock(), None),
+        address if address < to_bucket as *const _ as usize => {
+            let from_queue = from_bucket.lock();
+            (from_queue, Some(to_bucket.lock()))
+        }
+        _ => {
+            let to_queue = to_bucket.lock();
+            (from_bucket.lock(), Some(to_queue))
+        }
+    };
+
+    let woken = from_queue.dequeue(from, false);
+    let mut requeued = 0;
+
+    let mut current = from_queue.head;
+    while let Some(waiter) = current {
+        unsafe {
+            current = waiter.as_ref().next.get();
+            if waiter.as_ref().counter.load(Ordering::Relaxed) != from {
+                continue;
+            }
+
+            // Moving waiters within the same queue only needs a new key,
+            // and keeps them in order relative to the waiters already on `to`.
+            waiter.as_ref().counter.store(to, Ordering::Relaxed);
+            if let Some(to_queue) = to_queue.as_mut() {
+                from_queue.remove(waiter);
+                to_queue.push(Pin::new_unchecked(waiter.as_ref()), to);
+            }
+        }
+        requeued += 1;
+    }
+
+    drop((from_queue, to_queue));
+    RequeueResult {
+        unparked: unsafe { unpark(woken) } > 0,
+        requeued,
+    }
+}
+
+/// Wakes up the waiters dequeued by Bucket::dequeue() after their queue was unlocked,
+/// returning how many there were.
+unsafe fn unpark(mut woken: Option<NonNull<Waiter>>) -> usize {
+    // The next field must be read before unpark() as it lets the waiter return.
+    let mut count = 0;
+    while let Some(waiter) = woken {
+        woken = waiter.as_ref().next.get();
+        waiter.as_ref().parker.unpark();
+        count += 1;
+    }
+    count
+}
+
+/// A queue of threads parked on keys which hash to it, doubly-linked through their
+/// `next` and `prev` fields. The key of each waiter is stored in its `counter` field.
+struct Bucket {
+    head: Option<NonNull<Waiter>>,
+    tail: Option<NonNull<Waiter>>,
+}
+
+// SAFETY: Queued waiters are only accessed while the bucket is locked
+// and they only return after being removed from it.
+unsafe impl Send for Bucket {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUCKET: Mutex<Bucket> = const_mutex(Bucket {
+    head: None,
+    tail: None,
+});
+
+static TABLE: [Mutex<Bucket>; BUCKETS] = [EMPTY_BUCKET; BUCKETS];
+
+fn bucket(key: usize) -> &'static Mutex<Bucket> {
+    // Fibonacci hashing spreads neighbouring addresses over the buckets.
+    let hash = key.wrapping_mul(0x9E3779B97F4A7C15u64 as usize);
+    &TABLE[hash >> (usize::BITS - BUCKETS.trailing_zeros())]
+}
+
+impl Bucket {
+    /// Appends the waiter to the tail of the queue.
+    fn push(&mut self, waiter: Pin<&Waiter>, key: usize) {
+        let waiter_ptr = NonNull::from(&*waiter);
+        waiter.counter.store(key, Ordering::Relaxed);
+        waiter.flags.set(0);
+        waiter.next.set(None);
+        waiter.prev.set(self.tail);
+
+        match self.tail.replace(waiter_ptr) {
+            Some(tail) => unsafe { tail.as_ref().next.set(Some(waiter_ptr)) },
+            None => self.head = Some(waiter_ptr),
+        }
+    }
+
+    /// Unlinks a queued waiter.
+    unsafe fn remove(&mut self, waiter: NonNull<Waiter>) {
+        let prev = waiter.as_ref().prev.get();
+        let next = waiter.as_ref().next.get();
+
+        match prev {
+            Some(prev) => prev.as_ref().next.set(next),
+            None => self.head = next,
+        }
+
+        match next {
+            Some(next) => next.as_ref().prev.set(prev),
+            None => self.tail = prev,
+        }
+
+        waiter.as_ref().next.set(None);
+    }
+
+    /// Removes the first or all the waiters parked on `key` and marks them as unparked,
+    /// returning them chained through their next fields to be woken up after unlocking.
+    fn dequeue(&mut self, key: usize, all: bool) -> Option<NonNull<Waiter>> {
+        let mut woken = None;
+        let mut woken_tail: Option<NonNull<Waiter>> = None;
+
+        let mut current = self.head;
+        while let Some(waiter) = current {
+            unsafe {
+                current = waiter.as_ref().next.get();
+                if waiter.as_ref().counter.load(Ordering::Relaxed) != key {
+                    continue;
+                }
+
+                // Keep the woken waiters in queue order.
+                self.remove(waiter);
+                waiter.as_ref().flags.set(WAITER_UNPARKED);
+                match woken_tail.replace(waiter) {
+                    Some(tail) => tail.as_ref().next.set(Some(waiter)),
+                    None => woken = Some(waiter),
+                }
+            }
+
+            if !all {
+                break;
+            }
+        }
+
+        woken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{park, unpark_all, unpark_one, unpark_requeue, ParkResult, RequeueResult};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn wait_for_parked(count: &AtomicUsize, n: usize) {
+        while count.load(Ordering::SeqCst) < n {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn invalid_and_timeout() {
+        let key = 0x1000;
+        assert_eq!(park(key, || false, None), ParkResult::Invalid);
+        assert_eq!(
+            park(key, || true, Some(Duration::from_millis(10))),
+            ParkResult::TimedOut
+        );
+        assert!(!unpark_one(key));
+        assert_eq!(unpark_all(key), 0);
+    }
+
+    #[test]
+    fn unpark_one_and_all() {
+        const THREADS: usize = 4;
+        let key = 0x2000;
+        let parked = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let parked = parked.clone();
+                thread::spawn(move || {
+                    let result = park(
+                        key,
+                        || {
+                            parked.fetch_add(1, Ordering::SeqCst);
+                            true
+                        },
+                        None,
+                    );
+                    assert!(result.is_unparked());
+                })
+            })
+            .collect();
+
+        wait_for_parked(&parked, THREADS);
+        assert!(unpark_one(key));
+        assert_eq!(unpark_all(key), THREADS - 1);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn requeue() {
+        const THREADS: usize = 4;
+        let (from, to) = (0x3000, 0x4000);
+        let parked = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let parked = parked.clone();
+                thread::spawn(move || {
+                    park(
+                        from,
+                        || {
+                            parked.fetch_add(1, Ordering::SeqCst);
+                            true
+                        },
+                        None,
+                    )
+                })
+            })
+            .collect();
+
+        wait_for_parked(&parked, THREADS);
+        assert_eq!(
+            unpark_requeue(from, to),
+            RequeueResult {
+                unparked: true,
+                requeued: THREADS - 1,
+            }
+        );
+        assert!(!unpark_one(from));
+        assert_eq!(unpark_all(to), THREADS - 1);
+        for t in threads {
+            assert!(t.join().unwrap().is_unparked());
+        }
+    }
+
+    #[test]
+    fn timeout_after_requeue() {
+        let (from, to) = (0x5000, 0x6000);
+        let parked = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let parked = parked.clone();
+                thread::spawn(move || {
+                    park(
+                        from,
+                        || {
+                            parked.fetch_add(1, Ordering::SeqCst);
+                            true
+                        },
+                        Some(Duration::from_millis(500)),
+                    )
+                })
+            })
+            .collect();
+
+        wait_for_parked(&parked, 2);
+        assert_eq!(unpark_requeue(from, to).requeued, 1);
+
+        // The requeued thread removes itself from the queue of its new key on timeout.
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(results.contains(&ParkResult::Unparked));
+        assert!(results.contains(&ParkResult::TimedOut));
+        assert!(!unpark_one(to));
+    }
+}
@@ -0,0 +1,257 @@
+use super::{const_mutex, Condvar, Mutex};
+use std::fmt;
+
+/// A reusable barrier whose parties can register and deregister at runtime.
+///
+/// Each phase completes when all the parties registered for it have arrived, at which point
+/// the phase number is advanced and threads waiting for that phase are woken up. Unlike
+/// [`Barrier`](struct.Barrier.html), the amount of parties can change between (or during)
+/// phases through [`register`](Phaser::register) and
+/// [`arrive_and_deregister`](Phaser::arrive_and_deregister).
+///
+/// # Examples
+///
+/// ```
+/// use usync::Phaser;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let phaser = Arc::new(Phaser::new(1));
+///
+/// let workers: Vec<_> = (0..4)
+///     .map(|i| {
+///         phaser.register();
+///         let phaser = phaser.clone();
+///         thread::spawn(move || {
+///             // Each worker stays for a different amount of phases.
+///             for _ in 0..i {
+///                 phaser.arrive_and_wait();
+///             }
+///             phaser.arrive_and_deregister();
+///         })
+///     })
+///     .collect();
+///
+/// // The coordinator steps through the phases until it's the last party left.
+/// while phaser.registered_parties() > 1 {
+///     phaser.arrive_and_wait();
+/// }
+///
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+/// ```
+pub struct Phaser {
+    state: Mutex<State>,
+    advanced: Condvar,
+}
+
+struct State {
+    phase: usize,
+    registered: usize,
+    arrived: usize,
+}
+
+impl Default for Phaser {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl fmt::Debug for Phaser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Phaser")
+            .field("phase", &state.phase)
+            .field("registered", &state.registered)
+            .field("arrived", &state.arrived)
+            .finish()
+    }
+}
+
+impl Phaser {
+    /// Creates a new phaser starting at phase 0 with the given amount of registered parties.
+    pub const fn new(parties: usize) -> Self {
+        Self {
+            state: const_mutex(State {
+                phase: 0,
+                registered: parties,
+                arrived: 0,
+            }),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Returns the current phase number, which wraps around on overflow.
+    pub fn phase(&self) -> usize {
+        self.state.lock().phase
+    }
+
+    /// Returns the amount of parties registered for the current phase.
+    pub fn registered_parties(&self) -> usize {
+        self.state.lock().registered
+    }
+
+    /// Returns the amount of registered parties which arrived at the current phase.
+    pub fn arrived_parties(&self) -> usize {
+        self.state.lock().arrived
+    }
+
+    /// Adds a new party to the phaser, returning the phase it was registered for.
+    ///
+    /// The current phase can't complete until the new party arrives.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of registered parties overflows a `usize`.
+    pub fn register(&self) -> usize {
+        let mut state = self.state.lock();
+        state.registered = state
+            .registered
+            .checked_add(1)
+            .expect("Phaser registered parties overflowed");
+        state.phase
+    }
+
+    /// Arrives at the current phase without waiting for the other parties,
+    /// returning the phase number arrived at.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all registered parties already arrived at the current phase.
+    pub fn arrive(&self) -> usize {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        self.arrive_locked(&mut state, false);
+        phase
+    }
+
+    /// Arrives at the current phase and deregisters from the phaser without waiting
+    /// for the other parties, returning the phase number arrived at.
+    ///
+    /// If the remaining parties all arrived already, this completes the phase.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all registered parties already arrived at the current phase.
+    pub fn arrive_and_deregister(&self) -> usize {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        self.arrive_locked(&mut state, true);
+        phase
+    }
+
+    /// Arrives at the current phase and blocks until all other registered parties arrived too,
+    /// returning the phase number arrived at.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all registered parties already arrived at the current phase.
+    pub fn arrive_and_wait(&self) -> usize {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        self.arrive_locked(&mut state, false);
+
+        while state.phase == phase {
+            self.advanced.wait(&mut state);
+        }
+
+        phase
+    }
+
+    fn arrive_locked(&self, state: &mut State, deregister: bool) {
+        assert!(
+            state.arrived < state.registered,
+            "Phaser arrival without an unarrived registered party"
+        );
+
+        if deregister {
+            state.registered -= 1;
+        } else {
+            state.arrived += 1;
+        }
+
+        // Advance to the next phase once all the registered parties arrived.
+        if state.arrived == state.registered {
+            state.phase = state.phase.wrapping_add(1);
+            state.arrived = 0;
+            self.advanced.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Phaser;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let phaser = Phaser::new(2);
+        assert_eq!(phaser.arrive(), 0);
+        assert_eq!(phaser.arrived_parties(), 1);
+        assert_eq!(phaser.arrive(), 0);
+        assert_eq!(phaser.phase(), 1);
+        assert_eq!(phaser.arrived_parties(), 0);
+
+        assert_eq!(phaser.register(), 1);
+        assert_eq!(phaser.registered_parties(), 3);
+        phaser.arrive();
+        phaser.arrive_and_deregister();
+        assert_eq!(phaser.phase(), 1);
+        assert_eq!(phaser.arrive_and_wait(), 1);
+        assert_eq!(phaser.phase(), 2);
+        assert_eq!(phaser.registered_parties(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_arrivals() {
+        let phaser = Phaser::new(1);
+        assert_eq!(phaser.arrive_and_deregister(), 0);
+        assert_eq!(phaser.phase(), 1);
+        phaser.arrive();
+    }
+
+    #[test]
+    fn dynamic_parties() {
+        const PHASES: usize = 20;
+        let phaser = Arc::new(Phaser::new(1));
+        let arrivals: Arc<Vec<_>> = Arc::new((0..PHASES).map(|_| AtomicUsize::new(0)).collect());
+
+        // Each worker takes part in a different amount of phases.
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                phaser.register();
+                let (phaser, arrivals) = (phaser.clone(), arrivals.clone());
+                thread::spawn(move || {
+                    for _ in 0..(i * 2) {
+                        let phase = phaser.arrive_and_wait();
+                        arrivals[phase].fetch_add(1, Ordering::Relaxed);
+                    }
+                    phaser.arrive_and_deregister();
+                })
+            })
+            .collect();
+
+        for phase in 0..PHASES {
+            assert_eq!(phaser.arrive_and_wait(), phase);
+        }
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        // Every phase saw all the workers which hadn't deregistered yet.
+        for (phase, arrivals) in arrivals.iter().enumerate() {
+            let expected = (0..8).filter(|i| i * 2 > phase).count();
+            assert_eq!(arrivals.load(Ordering::Relaxed), expected);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Priorities deciding which waiting thread is woken up first.
+//!
+//! Locks normally wake up the thread which has been waiting the longest. In soft real-time
+//! applications this can make a high priority thread wait behind a queue of low priority
+//! ones. Threads can be given a priority with [`set_thread_priority`], for instance mapped
+//! from their OS scheduling priority, in which case unlocking a `Mutex` or an exclusively
+//! locked `RwLock` wakes up the highest priority writer among the first few queued ones.
+//!
+//! The thread which has been waiting the longest is only bypassed a few times before being
+//! woken up regardless, so that a stream of high priority threads can't starve it.
+//! Waiters with the same priority are still woken up in the order they started waiting,
+//! and threads which never set a priority have the lowest one (0). Nothing changes until
+//! a thread is given a priority, and the `deterministic` feature ignores priorities.
+//!
+//! # Examples
+//!
+//! ```
+//! use usync::priority;
+//!
+//! // This thread handles latency sensitive work.
+//! priority::set_thread_priority(10);
+//! assert_eq!(priority::thread_priority(), 10);
+//! ```
+
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set once any thread is given a priority, so that the queues aren't scanned for nothing.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+std::thread_local! {
+    static THREAD_PRIORITY: Cell<u8> = const { Cell::new(0) };
+}
+
+/// Sets the priority of the current thread when it waits, returning the previous one.
+///
+/// Higher values are woken up first.
+pub fn set_thread_priority(priority: u8) -> u8 {
+    if priority != 0 {
+        IN_USE.store(true, Ordering::Relaxed);
+    }
+
+    THREAD_PRIORITY.with(|thread_priority| thread_priority.replace(priority))
+}
+
+/// Returns the priority of the current thread.
+pub fn thread_priority() -> u8 {
+    THREAD_PRIORITY.with(Cell::get)
+}
+
+/// Returns true if priorities should be taken into account when waking up threads.
+#[inline]
+pub(crate) fn is_in_use() -> bool {
+    !cfg!(feature = "deterministic") && IN_USE.load(Ordering::Relaxed)
+}
@@ -0,0 +1,500 @@
+use super::RawMutex;
+use lock_api::RawMutex as _RawMutex;
+use std::{cell::RefCell, fmt, io, mem};
+
+// Ceiling used by `RawPriorityCeilingMutex::without_ceiling` which leaves the thread priority alone.
+const NO_CEILING: i32 = i32::MIN;
+
+/// The ceilings of the mutexes held by a thread, which may be unlocked in any order.
+struct HeldCeilings {
+    /// The scheduling policy and priority of the thread before it locked any of the mutexes.
+    base: (i32, i32),
+    /// The addresses and ceilings of the mutexes, in locking order.
+    mutexes: Vec<(usize, i32)>,
+}
+
+impl HeldCeilings {
+    /// Returns the policy and priority the thread should run at while holding the mutexes.
+    fn target(&self) -> (i32, i32) {
+        let (policy, priority) = self.base;
+        let is_realtime = policy == libc::SCHED_FIFO || policy == libc::SCHED_RR;
+        match self.mutexes.iter().map(|&(_, ceiling)| ceiling).max() {
+            Some(ceiling) if !is_realtime || priority < ceiling => (libc::SCHED_FIFO, ceiling),
+            _ => self.base,
+        }
+    }
+}
+
+std::thread_local! {
+    static HELD_CEILINGS: RefCell<HeldCeilings> = const {
+        RefCell::new(HeldCeilings {
+            base: (0, 0),
+            mutexes: Vec::new(),
+        })
+    };
+}
+
+/// Raw mutex type implementing the priority-ceiling protocol.
+///
+/// Threads locking the mutex run at the mutex's ceiling priority (under `SCHED_FIFO`)
+/// while holding it and go back to their original scheduling policy and priority once
+/// it's unlocked. Threads which already run at or above the ceiling are left alone.
+///
+/// A thread holding several of these mutexes runs at the highest of their ceilings, and unlocking
+/// them in any order goes back to the highest ceiling of the ones still held. The priority is
+/// only restored by the thread which locked the mutex, so guards sent to other threads (with the
+/// `send_guard` feature) leave the locking thread at the ceiling.
+///
+/// Note that [`INIT`](lock_api::RawMutex::INIT), which `lock_api` requires, creates a mutex
+/// [`without_ceiling`](Self::without_ceiling). [`PriorityCeilingMutex`] doesn't use it.
+pub struct RawPriorityCeilingMutex {
+    mutex: RawMutex,
+    ceiling: i32,
+}
+
+impl fmt::Debug for RawPriorityCeilingMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawPriorityCeilingMutex")
+            .field("ceiling", &self.ceiling())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Restores the priority of the current thread for the mutex when dropped,
+/// including when locking or unlocking panics.
+struct RestorePriority<'a>(&'a RawPriorityCeilingMutex);
+
+impl Drop for RestorePriority<'_> {
+    fn drop(&mut self) {
+        // Panicking while already unwinding would abort the process,
+        // so failing to restore the priority is only reported otherwise.
+        let result = self.0.restore_priority();
+        if !std::thread::panicking() {
+            check_os_error(result, "restore");
+        }
+    }
+}
+
+/// Unlocks the inner mutex when dropped, in case raising the priority after acquiring it panics.
+struct Unlock<'a>(&'a RawMutex);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        unsafe { self.0.unlock() };
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawPriorityCeilingMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    /// A mutex without a ceiling, which never changes the priority of the threads locking it.
+    const INIT: Self = Self::without_ceiling();
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.mutex.is_locked()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        // The priority is raised before locking so that a thread holding the mutex
+        // can't be preempted by the threads which may contend on it.
+        self.raise_priority();
+        let restore = RestorePriority(self);
+        self.mutex.lock();
+        mem::forget(restore);
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        // Raising the priority first would cost two syscalls for every failed attempt,
+        // so it's the first thing done while holding the mutex instead.
+        if !self.mutex.try_lock() {
+            return false;
+        }
+
+        let unlock = Unlock(&self.mutex);
+        self.raise_priority();
+        mem::forget(unlock);
+        true
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        let _restore = RestorePriority(self);
+        self.mutex.unlock();
+    }
+}
+
+impl RawPriorityCeilingMutex {
+    /// Creates a raw mutex which raises the priority of the threads holding it to `ceiling`.
+    ///
+    /// The ceiling is a `SCHED_FIFO` priority which should be at least the highest priority
+    /// of all the threads which lock the mutex.
+    pub const fn new(ceiling: i32) -> Self {
+        Self {
+            mutex: RawMutex::INIT,
+            ceiling,
+        }
+    }
+
+    /// Creates a raw mutex without a ceiling, which never changes the priority of the threads
+    /// locking it and behaves like a [`RawMutex`].
+    pub const fn without_ceiling() -> Self {
+        Self::new(NO_CEILING)
+    }
+
+    /// Returns the priority ceiling of the mutex, if any.
+    pub fn ceiling(&self) -> Option<i32> {
+        Some(self.ceiling).filter(|&ceiling| ceiling != NO_CEILING)
+    }
+
+    /// Raises the current thread to the highest ceiling of the mutexes it holds after this one.
+    fn raise_priority(&self) {
+        let ceiling = match self.ceiling() {
+            Some(ceiling) => ceiling,
+            None => return,
+        };
+
+        // Thread locals may already be destroyed, in which case the priority is left alone.
+        let _ = HELD_CEILINGS.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if held.mutexes.is_empty() {
+                held.base = current_priority();
+            }
+
+            let mutex = (self as *const Self as usize, ceiling);
+            let before = held.target();
+            held.mutexes.push(mutex);
+            let after = held.target();
+            if after != before {
+                // Only keep track of the mutex once the priority was changed.
+                held.mutexes.pop();
+                set_priority(after.0, after.1);
+                held.mutexes.push(mutex);
+            }
+        });
+    }
+
+    /// Lowers the current thread to the highest ceiling of the other mutexes it holds,
+    /// or to its original priority if there are none.
+    fn restore_priority(&self) -> io::Result<()> {
+        if self.ceiling().is_none() {
+            return Ok(());
+        }
+
+        let result = HELD_CEILINGS.try_with(|held| {
+            let mut held = held.borrow_mut();
+            let addr = self as *const Self as usize;
+            let index = match held.mutexes.iter().rposition(|&(mutex, _)| mutex == addr) {
+                Some(index) => index,
+                None => return Ok(()),
+            };
+
+            let before = held.target();
+            held.mutexes.remove(index);
+            let after = held.target();
+            if after != before {
+                return try_set_priority(after.0, after.1);
+            }
+
+            Ok(())
+        });
+
+        // Thread locals may already be destroyed, in which case the priority is left alone.
+        result.unwrap_or(Ok(()))
+    }
+}
+
+fn current_priority() -> (i32, i32) {
+    unsafe {
+        let mut policy = 0;
+        let mut param: libc::sched_param = mem::zeroed();
+        let rc = libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param);
+        check_os_error(os_result(rc), "get");
+        (policy, param.sched_priority)
+    }
+}
+
+fn set_priority(policy: i32, priority: i32) {
+    check_os_error(try_set_priority(policy, priority), "set");
+}
+
+fn try_set_priority(policy: i32, priority: i32) -> io::Result<()> {
+    unsafe {
+        let mut param: libc::sched_param = mem::zeroed();
+        param.sched_priority = priority;
+        os_result(libc::pthread_setschedparam(
+            libc::pthread_self(),
+            policy,
+            &param,
+        ))
+    }
+}
+
+fn os_result(rc: libc::c_int) -> io::Result<()> {
+    match rc {
+        0 => Ok(()),
+        rc => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+#[track_caller]
+fn check_os_error(result: io::Result<()>, action: &str) {
+    // Realtime code relying on the ceiling can't run correctly without it,
+    // so fail loudly (e.g. when missing the permissions to use `SCHED_FIFO`).
+    if let Err(error) = result {
+        panic!("failed to {} the thread priority: {}", action, error);
+    }
+}
+
+/// A mutual exclusion primitive implementing the priority-ceiling protocol
+///
+/// This is the same as [`Mutex`](type.Mutex.html) except that a thread holding the mutex runs
+/// at the mutex's ceiling priority. As long as the ceiling is at least the priority of every
+/// thread using the mutex, a lower priority thread holding it can't be preempted by the
+/// higher priority threads waiting for it, which bounds priority inversion.
+///
+/// The ceiling is applied with `pthread_setschedparam` using `SCHED_FIFO`, which usually requires
+/// elevated permissions (e.g. `CAP_SYS_NICE` on Linux). Locking panics if the priority can't be changed.
+/// Unlike the other mutexes, it has no `Default` implementation as every mutex needs a ceiling.
+///
+/// This type is only available on Unix platforms.
+///
+/// # Examples
+///
+/// ```
+/// use usync::PriorityCeilingMutex;
+///
+/// let mutex = PriorityCeilingMutex::new(10, 0);
+/// assert_eq!(mutex.ceiling(), Some(10));
+/// ```
+pub struct PriorityCeilingMutex<T: ?Sized> {
+    inner: lock_api::Mutex<RawPriorityCeilingMutex, T>,
+}
+
+impl<T> PriorityCeilingMutex<T> {
+    /// Creates a new unlocked mutex which raises the priority of the threads holding it to
+    /// `ceiling`, a `SCHED_FIFO` priority which should be at least the highest priority of
+    /// all the threads which lock the mutex.
+    pub const fn new(ceiling: i32, value: T) -> Self {
+        Self {
+            inner: lock_api::Mutex::const_new(RawPriorityCeilingMutex::new(ceiling), value),
+        }
+    }
+
+    /// Consumes the mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> PriorityCeilingMutex<T> {
+    /// Acquires the mutex at its ceiling priority, blocking the current thread until it's able to.
+    pub fn lock(&self) -> PriorityCeilingMutexGuard<'_, T> {
+        self.inner.lock()
+    }
+
+    /// Attempts to acquire the mutex without blocking, only raising the priority of the
+    /// current thread if it succeeds.
+    pub fn try_lock(&self) -> Option<PriorityCeilingMutexGuard<'_, T>> {
+        self.inner.try_lock()
+    }
+
+    /// Returns whether the mutex is currently locked.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns a mutable reference to the underlying data, which doesn't need locking
+    /// as the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Returns the priority ceiling of the mutex.
+    pub fn ceiling(&self) -> Option<i32> {
+        unsafe { self.inner.raw() }.ceiling()
+    }
+
+    /// Returns the underlying raw mutex.
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe because it allows unlocking the mutex while still holding
+    /// a reference to a guard.
+    pub unsafe fn raw(&self) -> &RawPriorityCeilingMutex {
+        self.inner.raw()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for PriorityCeilingMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+/// An RAII implementation of a "scoped lock" of a priority-ceiling mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked and the thread priority restored.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type PriorityCeilingMutexGuard<'a, T> = lock_api::MutexGuard<'a, RawPriorityCeilingMutex, T>;
+
+/// An RAII mutex guard returned by `PriorityCeilingMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedPriorityCeilingMutexGuard` and `PriorityCeilingMutexGuard`
+/// is that the former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedPriorityCeilingMutexGuard<'a, T> =
+    lock_api::MappedMutexGuard<'a, RawPriorityCeilingMutex, T>;
+
+/// Creates a new priority-ceiling mutex with the given ceiling in an unlocked state ready for use.
+///
+/// This is the same as [`PriorityCeilingMutex::new`], for consistency with the other mutexes.
+pub const fn const_priority_ceiling_mutex<T>(ceiling: i32, value: T) -> PriorityCeilingMutex<T> {
+    PriorityCeilingMutex::new(ceiling, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current_priority, set_priority, RawPriorityCeilingMutex};
+    use crate::const_priority_ceiling_mutex;
+    use std::{
+        panic,
+        sync::{mpsc::channel, Arc},
+        thread,
+    };
+
+    #[test]
+    fn test_priority_ceiling_no_ceiling() {
+        let m = lock_api::Mutex::const_new(RawPriorityCeilingMutex::without_ceiling(), 0);
+        let before = current_priority();
+        *m.lock() += 1;
+        assert!(m.try_lock().is_some());
+        assert_eq!(current_priority(), before);
+    }
+
+    // Changing to a realtime policy needs permissions which the test may not have,
+    // in which case this returns None.
+    fn realtime_ceiling(offset: i32) -> Option<i32> {
+        let before = current_priority();
+        let ceiling = unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) } + offset;
+        panic::catch_unwind(|| set_priority(libc::SCHED_FIFO, ceiling)).ok()?;
+        set_priority(before.0, before.1);
+        Some(ceiling)
+    }
+
+    #[test]
+    fn test_priority_ceiling_raise_and_restore() {
+        thread::spawn(|| {
+            let ceiling = match realtime_ceiling(1) {
+                Some(ceiling) => ceiling,
+                None => return,
+            };
+
+            let before = current_priority();
+            let m = const_priority_ceiling_mutex(ceiling, ());
+            let guard = m.lock();
+            assert_eq!(current_priority(), (libc::SCHED_FIFO, ceiling));
+            drop(guard);
+            assert_eq!(current_priority(), before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_priority_ceiling_unlock_out_of_order() {
+        thread::spawn(|| {
+            let (low, high) = match (realtime_ceiling(1), realtime_ceiling(2)) {
+                (Some(low), Some(high)) => (low, high),
+                _ => return,
+            };
+
+            let before = current_priority();
+            let low_mutex = const_priority_ceiling_mutex(low, ());
+            let high_mutex = const_priority_ceiling_mutex(high, ());
+
+            // Unlocking the higher ceiling first goes back to the lower one still held.
+            let high_guard = high_mutex.lock();
+            let low_guard = low_mutex.lock();
+            assert_eq!(current_priority(), (libc::SCHED_FIFO, high));
+            drop(high_guard);
+            assert_eq!(current_priority(), (libc::SCHED_FIFO, low));
+            drop(low_guard);
+            assert_eq!(current_priority(), before);
+
+            // Unlocking the lower ceiling first keeps the higher one.
+            let low_guard = low_mutex.lock();
+            let high_guard = high_mutex.try_lock().unwrap();
+            drop(low_guard);
+            assert_eq!(current_priority(), (libc::SCHED_FIFO, high));
+            drop(high_guard);
+            assert_eq!(current_priority(), before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_priority_ceiling_try_lock_failure() {
+        thread::spawn(|| {
+            let ceiling = match realtime_ceiling(1) {
+                Some(ceiling) => ceiling,
+                None => return,
+            };
+
+            let before = current_priority();
+            let m = Arc::new(const_priority_ceiling_mutex(ceiling, ()));
+            let (locked_tx, locked_rx) = channel();
+            let (unlock_tx, unlock_rx) = channel();
+            let holder = {
+                let m = m.clone();
+                thread::spawn(move || {
+                    let _guard = m.lock();
+                    locked_tx.send(()).unwrap();
+                    unlock_rx.recv().unwrap();
+                })
+            };
+
+            locked_rx.recv().unwrap();
+            assert!(m.try_lock().is_none());
+            assert_eq!(current_priority(), before);
+
+            unlock_tx.send(()).unwrap();
+            holder.join().unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[cfg(usync_track_owner)]
+    #[test]
+    fn test_priority_ceiling_restored_on_panic() {
+        thread::spawn(|| {
+            let ceiling = match realtime_ceiling(1) {
+                Some(ceiling) => ceiling,
+                None => return,
+            };
+
+            let before = current_priority();
+            let m = const_priority_ceiling_mutex(ceiling, ());
+            let guard = m.lock();
+
+            // Locking recursively panics, which must forget about the second lock.
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| m.lock()));
+            assert!(result.is_err());
+            assert_eq!(current_priority(), (libc::SCHED_FIFO, ceiling));
+            drop(guard);
+            assert_eq!(current_priority(), before);
+
+            drop(m.lock());
+            assert_eq!(current_priority(), before);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,696 @@
+//! Locks with a fixed, address-independent layout for memory shared with C code or other
+//! processes.
+
+use crate::{atomic_wait, shared::SpinWait};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Raw mutex with a stable `#[repr(C)]` layout, usable from memory shared between processes.
+///
+/// The other locks in this crate queue waiting threads on their stacks and store pointers
+/// to them in the lock, so they only work within a single address space. This mutex holds
+/// no pointers and can be placed in memory-mapped regions, or embedded in structs shared
+/// with C code, regardless of where they're mapped.
+///
+/// # Layout
+///
+/// The mutex is a single 32-bit integer aligned to 4 bytes, which is 0 when unlocked,
+/// 1 when locked and 2 when locked with threads possibly waiting for it. All-zero memory
+/// (e.g. a freshly created shared memory object) is an unlocked mutex, and [`init`] resets
+/// one explicitly. C code sharing the mutex must only access it through the functions of
+/// this crate.
+///
+/// On Linux and Android, waiting threads sleep on a process-shared futex. On other platforms,
+/// threads of other processes can't be woken up, so waiting threads poll the mutex instead,
+/// sleeping for a short while in between.
+///
+/// This only implements the [`lock_api::RawMutex`] trait, and can be used with
+/// [`lock_api::Mutex`] when the protected data lives next to it.
+///
+/// [`init`]: RawSharedMutex::init
+///
+/// # Examples
+///
+/// ```
+/// use usync::{lock_api::RawMutex, RawSharedMutex};
+/// use std::mem::MaybeUninit;
+///
+/// // This would usually live in memory shared with other processes.
+/// let mut storage = MaybeUninit::<RawSharedMutex>::uninit();
+/// let mutex = unsafe {
+///     RawSharedMutex::init(storage.as_mut_ptr());
+///     &*storage.as_ptr()
+/// };
+///
+/// mutex.lock();
+/// assert!(!mutex.try_lock());
+/// unsafe { mutex.unlock() };
+/// ```
+#[repr(C)]
+pub struct RawSharedMutex {
+    state: AtomicU32,
+}
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+impl RawSharedMutex {
+    /// Creates a new unlocked mutex.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+        }
+    }
+
+    /// Initializes an unlocked mutex at the given location.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes and aligned, and must not contain a mutex which is
+    /// currently in use by any thread or process.
+    pub unsafe fn init(ptr: *mut Self) {
+        ptr.write(Self::new());
+    }
+
+    #[cold]
+    fn lock_slow(&self) {
+        let mut state = self.spin();
+
+        if state == UNLOCKED {
+            match self.state.compare_exchange(
+                UNLOCKED,
+                LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(s) => state = s,
+            }
+        }
+
+        loop {
+            // Marking the mutex as contended makes its owner wake us up when it unlocks.
+            // This also marks it for the other waiters when we acquire it, which may cause
+            // a needless wake up but never a missed one.
+            if state != CONTENDED && self.state.swap(CONTENDED, Ordering::Acquire) == UNLOCKED {
+                return;
+            }
+
+            atomic_wait::wait_shared(&self.state, CONTENDED);
+            state = self.spin();
+        }
+    }
+
+    fn spin(&self) -> u32 {
+        let mut spin = SpinWait::default();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state != LOCKED || !spin.try_yield_now() {
+                return state;
+            }
+        }
+    }
+}
+
+impl Default for RawSharedMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RawSharedMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.load(Ordering::Relaxed);
+        f.debug_struct("RawSharedMutex")
+            .field("locked", &(state != UNLOCKED))
+            .field("has_waiters", &(state == CONTENDED))
+            .finish()
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawSharedMutex {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self::new();
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != UNLOCKED
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        if !self.try_lock() {
+            self.lock_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            atomic_wait::wake_shared(&self.state, false);
+        }
+    }
+}
+
+/// Raw reader-writer lock with a stable `#[repr(C)]` layout, usable from memory shared between
+/// processes.
+///
+/// Like [`RawSharedMutex`], this lock holds no pointers, so it can be placed in memory-mapped
+/// regions or embedded in structs shared with C code. Waiting writers are preferred over new
+/// readers, so a thread which already holds a read lock must not acquire it again.
+///
+/// # Layout
+///
+/// The lock is two 32-bit integers aligned to 4 bytes (8 bytes in total). The first one holds
+/// the state: the amount of readers in its low 30 bits (all of them set when write locked),
+/// followed by a bit for waiting readers and a bit for waiting writers. The second one is a
+/// counter bumped to wake up writers. All-zero memory is an unlocked lock, and [`init`]
+/// resets one explicitly. C code sharing the lock must only access it through the functions
+/// of this crate.
+///
+/// Waiting works the same way as for [`RawSharedMutex`].
+///
+/// [`init`]: RawSharedRwLock::init
+///
+/// # Examples
+///
+/// ```
+/// use usync::{lock_api::RawRwLock, RawSharedRwLock};
+/// use std::mem::MaybeUninit;
+///
+/// let mut storage = MaybeUninit::<RawSharedRwLock>::uninit();
+/// let rwlock = unsafe {
+///     RawSharedRwLock::init(storage.as_mut_ptr());
+///     &*storage.as_ptr()
+/// };
+///
+/// rwlock.lock_shared();
+/// assert!(rwlock.try_lock_shared());
+/// assert!(!rwlock.try_lock_exclusive());
+/// unsafe {
+///     rwlock.unlock_shared();
+///     rwlock.unlock_shared();
+/// }
+/// assert!(rwlock.try_lock_exclusive());
+/// ```
+#[repr(C)]
+pub struct RawSharedRwLock {
+    state: AtomicU32,
+    writer_notify: AtomicU32,
+}
+
+const READ_LOCKED: u32 = 1;
+const MASK: u32 = (1 << 30) - 1;
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+const READERS_WAITING: u32 = 1 << 30;
+const WRITERS_WAITING: u32 = 1 << 31;
+
+#[inline]
+fn is_unlocked(state: u32) -> bool {
+    state & MASK == 0
+}
+
+#[inline]
+fn is_write_locked(state: u32) -> bool {
+    state & MASK == WRITE_LOCKED
+}
+
+#[inline]
+fn has_readers_waiting(state: u32) -> bool {
+    state & READERS_WAITING != 0
+}
+
+#[inline]
+fn has_writers_waiting(state: u32) -> bool {
+    state & WRITERS_WAITING != 0
+}
+
+#[inline]
+fn is_read_lockable(state: u32) -> bool {
+    // Readers queue up behind waiting writers (and waiting readers, which are only
+    // waiting because of a writer) so that writers aren't starved.
+    state & MASK < MAX_READERS && !has_readers_waiting(state) && !has_writers_waiting(state)
+}
+
+impl RawSharedRwLock {
+    /// Creates a new unlocked reader-writer lock.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_notify: AtomicU32::new(0),
+        }
+    }
+
+    /// Initializes an unlocked reader-writer lock at the given location.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes and aligned, and must not contain a lock which is
+    /// currently in use by any thread or process.
+    pub unsafe fn init(ptr: *mut Self) {
+        ptr.write(Self::new());
+    }
+
+    #[cold]
+    fn lock_shared_slow(&self) {
+        let mut state = self.spin_shared();
+
+        loop {
+            if is_read_lockable(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            assert_ne!(state & MASK, MAX_READERS, "too many active read locks");
+
+            if !has_readers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | READERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            atomic_wait::wait_shared(&self.state, state | READERS_WAITING);
+            state = self.spin_shared();
+        }
+    }
+
+    #[cold]
+    fn lock_exclusive_slow(&self) {
+        let mut state = self.spin_exclusive();
+
+        // Once we waited, other writers may still be waiting too, and as we can't tell,
+        // the waiting bit is kept set when acquiring the lock.
+        let mut other_writers_waiting = 0;
+
+        loop {
+            if is_unlocked(state) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | WRITE_LOCKED | other_writers_waiting,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            if !has_writers_waiting(state) {
+                if let Err(s) = self.state.compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            other_writers_waiting = WRITERS_WAITING;
+
+            // Read the notification counter before re-checking the state, so an unlock
+            // happening in between changes the counter and makes the wait return.
+            let seq = self.writer_notify.load(Ordering::Acquire);
+            state = self.state.load(Ordering::Relaxed);
+            if is_unlocked(state) || !has_writers_waiting(state) {
+                continue;
+            }
+
+            atomic_wait::wait_shared(&self.writer_notify, seq);
+            state = self.spin_exclusive();
+        }
+    }
+
+    /// Wakes up waiting threads after the lock became unlocked, preferring a writer.
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u32) {
+        debug_assert!(is_unlocked(state));
+
+        if state == WRITERS_WAITING {
+            match self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    self.wake_writer();
+                    return;
+                }
+                Err(s) => state = s,
+            }
+        }
+
+        if state == READERS_WAITING + WRITERS_WAITING {
+            if self
+                .state
+                .compare_exchange(state, READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                // Someone else locked the lock and will wake the waiters when unlocking it.
+                return;
+            }
+
+            // Only wake up the readers if there turned out to be no writer to wake up.
+            if self.wake_writer() {
+                return;
+            }
+
+            state = READERS_WAITING;
+        }
+
+        if state == READERS_WAITING
+            && self
+                .state
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            atomic_wait::wake_shared(&self.state, true);
+        }
+    }
+
+    fn wake_writer(&self) -> bool {
+        self.writer_notify.fetch_add(1, Ordering::Release);
+        atomic_wait::wake_shared(&self.writer_notify, false)
+    }
+
+    fn spin_shared(&self) -> u32 {
+        self.spin_until(|state| !is_write_locked(state) || state & !MASK != 0)
+    }
+
+    fn spin_exclusive(&self) -> u32 {
+        self.spin_until(|state| is_unlocked(state) || has_writers_waiting(state))
+    }
+
+    fn spin_until(&self, done: impl Fn(u32) -> bool) -> u32 {
+        let mut spin = SpinWait::default();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if done(state) || !spin.try_yield_now() {
+                return state;
+            }
+        }
+    }
+}
+
+impl Default for RawSharedRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RawSharedRwLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.load(Ordering::Relaxed);
+        let mut f = f.debug_struct("RawSharedRwLock");
+        if is_unlocked(state) {
+            f.field("locked", &format_args!("unlocked"));
+        } else if is_write_locked(state) {
+            f.field("locked", &format_args!("exclusive"));
+        } else {
+            f.field("locked", &format_args!("shared"));
+            f.field("readers", &(state & MASK));
+        }
+        f.field("has_waiters", &(state & !MASK != 0)).finish()
+    }
+}
+
+unsafe impl lock_api::RawRwLock for RawSharedRwLock {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self::new();
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        !is_unlocked(self.state.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn is_locked_exclusive(&self) -> bool {
+        is_write_locked(self.state.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while is_read_lockable(state) {
+            match self.state.compare_exchange_weak(
+                state,
+                state + READ_LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.try_lock_shared() {
+            self.lock_shared_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        let state = self.state.fetch_sub(READ_LOCKED, Ordering::Release) - READ_LOCKED;
+
+        // Readers only wait behind a write lock or a waiting writer, so when the last
+        // reader leaves there is either nobody to wake up or a writer to wake up first.
+        if is_unlocked(state) && has_writers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while is_unlocked(state) {
+            match self.state.compare_exchange_weak(
+                state,
+                state | WRITE_LOCKED,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        if self
+            .state
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_exclusive_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive(&self) {
+        let state = self.state.fetch_sub(WRITE_LOCKED, Ordering::Release) - WRITE_LOCKED;
+        debug_assert!(is_unlocked(state));
+        if state != 0 {
+            self.wake_writer_or_readers(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawSharedMutex, RawSharedRwLock};
+    use lock_api::{RawMutex, RawRwLock};
+    use std::{
+        mem::{align_of, size_of, MaybeUninit},
+        sync::Arc,
+        thread,
+    };
+
+    type SharedMutex<T> = lock_api::Mutex<RawSharedMutex, T>;
+    type SharedRwLock<T> = lock_api::RwLock<RawSharedRwLock, T>;
+
+    #[test]
+    fn test_shared_layout() {
+        assert_eq!(
+            (size_of::<RawSharedMutex>(), align_of::<RawSharedMutex>()),
+            (4, 4)
+        );
+        assert_eq!(
+            (size_of::<RawSharedRwLock>(), align_of::<RawSharedRwLock>()),
+            (8, 4)
+        );
+
+        // Zeroed memory, like a new shared memory object, is unlocked.
+        let mutex = unsafe { MaybeUninit::<RawSharedMutex>::zeroed().assume_init() };
+        assert!(mutex.try_lock());
+        let rwlock = unsafe { MaybeUninit::<RawSharedRwLock>::zeroed().assume_init() };
+        assert!(rwlock.try_lock_exclusive());
+    }
+
+    #[test]
+    fn test_shared_mutex_contended() {
+        const THREADS: usize = 4;
+        const ITERS: usize = 1000;
+
+        let mutex = Arc::new(SharedMutex::new(0));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mutex = mutex.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        *mutex.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*mutex.lock(), THREADS * ITERS);
+        assert_eq!(
+            format!("{:?}", unsafe { mutex.raw() }),
+            "RawSharedMutex { locked: false, has_waiters: false }"
+        );
+    }
+
+    #[test]
+    fn test_shared_rwlock_contended() {
+        const THREADS: usize = 4;
+        const ITERS: usize = 1000;
+
+        let rwlock = Arc::new(SharedRwLock::new(0));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let rwlock = rwlock.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        if i % 2 == 0 {
+                            *rwlock.write() += 1;
+                        } else {
+                            assert!(*rwlock.read() <= THREADS * ITERS);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*rwlock.read(), (THREADS / 2) * ITERS);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_shared_mutex_processes() {
+        use std::ptr;
+
+        #[repr(C)]
+        struct Shared {
+            mutex: RawSharedMutex,
+            counter: u32,
+        }
+
+        const ITERS: u32 = 1000;
+
+        let shared = unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                size_of::<Shared>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(ptr, libc::MAP_FAILED);
+            ptr as *mut Shared
+        };
+
+        let increment = move || {
+            for _ in 0..ITERS {
+                unsafe {
+                    (*shared).mutex.lock();
+                    (*shared).counter += 1;
+                    (*shared).mutex.unlock();
+                }
+            }
+        };
+
+        unsafe {
+            RawSharedMutex::init(ptr::addr_of_mut!((*shared).mutex));
+            (*shared).counter = 0;
+
+            let pid = libc::fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                increment();
+                libc::_exit(0);
+            }
+
+            increment();
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert_eq!((*shared).counter, 2 * ITERS);
+            libc::munmap(shared as *mut _, size_of::<Shared>());
+        }
+    }
+
+    #[test]
+    fn test_shared_rwlock_debug() {
+        let rwlock = RawSharedRwLock::new();
+        rwlock.lock_shared();
+        rwlock.lock_shared();
+        assert_eq!(
+            format!("{:?}", rwlock),
+            "RawSharedRwLock { locked: shared, readers: 2, has_waiters: false }"
+        );
+        unsafe {
+            rwlock.unlock_shared();
+            rwlock.unlock_shared();
+        }
+
+        rwlock.lock_exclusive();
+        assert_eq!(
+            format!("{:?}", rwlock),
+            "RawSharedRwLock { locked: exclusive, has_waiters: false }"
+        );
+    }
+}
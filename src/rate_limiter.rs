@@ -0,0 +1,221 @@
+use crate::{
+    clock,
+    shared::{Primitive, Waiter},
+    Mutex,
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A token-bucket rate limiter allowing a number of operations per time window.
+///
+/// The bucket holds up to `permits` tokens and is refilled continuously at the rate of
+/// `permits` per `window`, so a burst of `permits` operations is allowed after being idle,
+/// after which operations are spread out evenly. Each operation takes a token with
+/// [`acquire`](RateLimiter::acquire), which blocks until one is available, or with
+/// [`try_acquire`](RateLimiter::try_acquire), which doesn't.
+///
+/// Blocked threads reserve their token up front and are served in the order they called
+/// `acquire`, without waking each other up. Time is measured with the
+/// [`clock`](crate::clock) of the calling thread, so tests can use a `MockClock`.
+///
+/// # Examples
+///
+/// ```
+/// use usync::RateLimiter;
+/// use std::time::Duration;
+///
+/// // Allow 10 operations per 10 seconds.
+/// let limiter = RateLimiter::new(10, Duration::from_secs(10));
+///
+/// // A burst of 10 goes through right away, after which the bucket is empty for a while.
+/// for _ in 0..10 {
+///     limiter.acquire();
+/// }
+/// assert!(!limiter.try_acquire());
+/// ```
+pub struct RateLimiter {
+    permits: u32,
+    window: Duration,
+    /// The time between two operations at the full rate.
+    interval: Duration,
+    /// How far ahead of the current time the bucket can be emptied, which is the window
+    /// rounded down to a whole number of intervals to keep bursts to exactly `permits`.
+    burst: Duration,
+    /// The time at which the bucket will be full again, or `None` if it was never used.
+    full_at: Mutex<Option<Instant>>,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("permits", &self.permits)
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter allowing `permits` operations per `window`, starting with a full
+    /// bucket.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `permits` is 0 or doesn't fit in a `u32`.
+    pub fn new(permits: usize, window: Duration) -> Self {
+        let permits = u32::try_from(permits)
+            .ok()
+            .filter(|&permits| permits > 0)
+            .expect("RateLimiter needs between 1 and u32::MAX permits");
+
+        let interval = window / permits;
+        Self {
+            permits,
+            window,
+            interval,
+            burst: interval * permits,
+            full_at: Mutex::new(None),
+        }
+    }
+
+    /// Returns the amount of operations which could currently go through without blocking.
+    pub fn available_permits(&self) -> usize {
+        let now = clock::now();
+        let full_at = match *self.full_at.lock() {
+            Some(full_at) if full_at > now => full_at,
+            _ => return self.permits as usize,
+        };
+
+        // Tokens are taken from the bucket one interval at a time.
+        let used = full_at.duration_since(now);
+        let available = self.burst.saturating_sub(used);
+        match self.interval.as_nanos() {
+            0 => self.permits as usize,
+            interval => (available.as_nanos() / interval) as usize,
+        }
+    }
+
+    /// Takes a token, blocking the current thread until one is available.
+    pub fn acquire(&self) {
+        let now = clock::now();
+        let ready_at = {
+            let mut full_at = self.full_at.lock();
+            let next = self.take(*full_at, now);
+            *full_at = Some(next);
+            now + next.duration_since(now).saturating_sub(self.burst)
+        };
+
+        if ready_at > now {
+            Waiter::with(Primitive::new("RateLimiter", self), |waiter| {
+                // Nobody unparks the waiter, so it only returns once the deadline passed.
+                while waiter.parker.park_until(Some(ready_at)) {}
+            });
+        }
+    }
+
+    /// Tries to take a token without blocking, returning whether one was available.
+    pub fn try_acquire(&self) -> bool {
+        let now = clock::now();
+        let mut full_at = self.full_at.lock();
+        let next = self.take(*full_at, now);
+        if next.duration_since(now) > self.burst {
+            return false;
+        }
+
+        *full_at = Some(next);
+        true
+    }
+
+    /// Returns the time at which the bucket will be full again after taking a token now.
+    fn take(&self, full_at: Option<Instant>, now: Instant) -> Instant {
+        let start = match full_at {
+            Some(full_at) if full_at > now => full_at,
+            _ => now,
+        };
+        start + self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use crate::clock::{self, MockClock};
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn burst_and_refill() {
+        let clock = Arc::new(MockClock::new());
+        clock::set_thread_clock(Some(clock.clone()));
+
+        let limiter = RateLimiter::new(4, Duration::from_secs(4));
+        assert_eq!(limiter.available_permits(), 4);
+        for _ in 0..4 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+        assert_eq!(limiter.available_permits(), 0);
+
+        // Tokens come back one interval at a time, up to a full bucket.
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(limiter.available_permits(), 1);
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(limiter.available_permits(), 4);
+
+        clock::set_thread_clock(None);
+    }
+
+    #[test]
+    fn acquire_blocks() {
+        let clock = Arc::new(MockClock::new());
+        let limiter = Arc::new(RateLimiter::new(2, Duration::from_secs(60)));
+
+        let (tx, rx) = channel();
+        let t = {
+            let (clock, limiter) = (clock.clone(), limiter.clone());
+            thread::spawn(move || {
+                clock::set_thread_clock(Some(clock));
+                for i in 0..3 {
+                    limiter.acquire();
+                    tx.send(i).unwrap();
+                }
+            })
+        };
+
+        // The burst goes through, then the third acquisition waits for a refill.
+        assert_eq!(rx.recv().unwrap(), 0);
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        let third = loop {
+            clock.advance(Duration::from_secs(30));
+            if let Ok(i) = rx.recv_timeout(Duration::from_millis(10)) {
+                break i;
+            }
+        };
+        assert_eq!(third, 2);
+        t.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "permits")]
+    fn zero_permits() {
+        let _ = RateLimiter::new(0, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_debug_rate_limiter() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(1));
+        assert_eq!(
+            format!("{:?}", limiter),
+            "RateLimiter { permits: 3, window: 1s }"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+/// A way to block and wake up threads, used by all the synchronization primitives
+/// when the `std` feature is disabled.
+///
+/// Without the standard library, usync doesn't know how to block a thread, so it has
+/// to be provided by the application (e.g. with RTOS semaphores or a `WFE`/`SEV` loop)
+/// by implementing this trait and registering it once with [`set_parker!`](crate::set_parker).
+/// With the `std` feature enabled, threads are blocked with the standard library and
+/// this trait is unused.
+///
+/// Threads are identified by tokens, and each thread has a "permit" which works like
+/// the one of [`std::thread::park`]: [`unpark`](RawParker::unpark) makes it available,
+/// and [`park`](RawParker::park) consumes it or blocks until it's made available.
+///
+/// # Safety
+///
+/// [`current`](RawParker::current) must return a non-zero token which is unique to
+/// the current thread for as long as it runs, and [`unpark`](RawParker::unpark) must
+/// wake up the thread identified by the token if it's blocked in [`park`](RawParker::park),
+/// or make its next call to it return immediately otherwise.
+///
+/// # Examples
+///
+/// ```ignore
+/// struct RtosParker;
+///
+/// unsafe impl usync::RawParker for RtosParker {
+///     fn current() -> usize {
+///         rtos::current_task().id()
+///     }
+///
+///     fn park(timeout: Option<core::time::Duration>) -> bool {
+///         rtos::current_task().wait_notification(timeout)
+///     }
+///
+///     unsafe fn unpark(token: usize) {
+///         rtos::Task::from_id(token).notify();
+///     }
+///
+///     fn yield_now() {
+///         rtos::yield_now();
+///     }
+/// }
+///
+/// usync::set_parker!(RtosParker);
+/// ```
+pub unsafe trait RawParker {
+    /// Returns the non-zero token identifying the current thread.
+    fn current() -> usize;
+
+    /// Blocks the current thread until its permit is available or until the `timeout` elapses,
+    /// returning false if it did.
+    ///
+    /// This may return true spuriously without the permit being available.
+    fn park(timeout: Option<Duration>) -> bool;
+
+    /// Makes the permit of the thread identified by `token` available, waking it up if
+    /// it's blocked in [`park`](RawParker::park).
+    ///
+    /// # Safety
+    ///
+    /// `token` must have been returned by [`current`](RawParker::current) on a thread
+    /// which is still running.
+    unsafe fn unpark(token: usize);
+
+    /// Hints that the current thread is waiting for another one to make progress,
+    /// which runs on the same core on uniprocessor systems.
+    fn yield_now() {
+        std::hint::spin_loop();
+    }
+}
+
+/// Registers the [`RawParker`](crate::RawParker) used to block threads when the `std`
+/// feature is disabled.
+///
+/// This must be invoked exactly once in the final binary, with the type implementing
+/// the trait. Blocking in any of the synchronization primitives fails to link otherwise.
+/// It must not be invoked when the `critical-section` feature is enabled, as that feature
+/// already registers a parker.
+#[macro_export]
+macro_rules! set_parker {
+    ($parker:ty) => {
+        #[no_mangle]
+        fn __usync_parker_current() -> usize {
+            <$parker as $crate::RawParker>::current()
+        }
+
+        #[no_mangle]
+        fn __usync_parker_park(timeout: ::core::option::Option<::core::time::Duration>) -> bool {
+            <$parker as $crate::RawParker>::park(timeout)
+        }
+
+        #[no_mangle]
+        unsafe fn __usync_parker_unpark(token: usize) {
+            <$parker as $crate::RawParker>::unpark(token)
+        }
+
+        #[no_mangle]
+        fn __usync_parker_yield_now() {
+            <$parker as $crate::RawParker>::yield_now()
+        }
+    };
+}
+
+/// The parker used when the `critical-section` feature is enabled without `std`, for
+/// bare-metal targets where the only thread of execution is preempted by interrupt handlers.
+///
+/// The thread blocks by spinning until an interrupt handler unparks it (e.g. by releasing a
+/// `Semaphore`), with the permit updated inside critical sections. Without a clock, timed waits
+/// time out as soon as the permit isn't available.
+#[cfg(all(feature = "critical-section", not(feature = "std")))]
+mod single_thread {
+    use super::RawParker;
+    use critical_section::Mutex;
+    use std::{cell::Cell, hint::spin_loop, time::Duration};
+
+    static PERMIT: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+    struct SingleThreadParker;
+
+    unsafe impl RawParker for SingleThreadParker {
+        fn current() -> usize {
+            1
+        }
+
+        fn park(timeout: Option<Duration>) -> bool {
+            loop {
+                if critical_section::with(|cs| PERMIT.borrow(cs).replace(false)) {
+                    return true;
+                }
+
+                if timeout.is_some() {
+                    return false;
+                }
+
+                spin_loop();
+            }
+        }
+
+        unsafe fn unpark(_token: usize) {
+            critical_section::with(|cs| PERMIT.borrow(cs).set(true));
+        }
+    }
+
+    crate::set_parker!(SingleThreadParker);
+}
@@ -0,0 +1,281 @@
+use super::{
+    const_mutex,
+    shared::{Epoch, Primitive},
+    Mutex,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    ptr::NonNull,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+/// A read-mostly cell using read-copy-update: readers never block and writers
+/// replace the whole value, reclaiming the old one once no reader can see it.
+///
+/// [`read`](RcuCell::read) pins the current epoch and returns a guard to the current value
+/// in a bounded amount of steps, whatever writers are doing. Writers install a new value
+/// and then wait for a grace period: every reader which pinned an epoch before the new value
+/// was installed must drop its guard before the old value is dropped.
+///
+/// This fits read-mostly data like configuration, where readers shouldn't contend with
+/// each other or with writers as they would on a [`RwLock`](type.RwLock.html), and where
+/// writers can afford to wait for readers.
+///
+/// # Examples
+///
+/// ```
+/// use usync::RcuCell;
+///
+/// let config = RcuCell::new(vec!["a"]);
+/// assert_eq!(*config.read(), ["a"]);
+///
+/// // Writers copy the current value and install the modified copy.
+/// config.update(|old| {
+///     let mut new = old.clone();
+///     new.push("b");
+///     new
+/// });
+///
+/// assert_eq!(*config.read(), ["a", "b"]);
+/// ```
+pub struct RcuCell<T> {
+    value: AtomicPtr<T>,
+    epoch: Epoch,
+    writer: Mutex<()>,
+    _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T: Send> Send for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T: Default> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RcuCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RcuCell")
+            .field("data", &&*self.read())
+            .finish()
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(*self.value.get_mut()) });
+    }
+}
+
+impl<T> RcuCell<T> {
+    /// Creates a new cell holding the given value.
+    pub fn new(value: T) -> Self {
+        Self {
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            epoch: Epoch::new(),
+            writer: const_mutex(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Consumes the cell, returning the current value.
+    pub fn into_inner(mut self) -> T {
+        let value = unsafe { Box::from_raw(*self.value.get_mut()) };
+        std::mem::forget(self);
+        *value
+    }
+
+    /// Returns a mutable reference to the current value.
+    ///
+    /// Since this call borrows the cell mutably, no readers can exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut **self.value.get_mut() }
+    }
+
+    /// Returns a snapshot of the current value, which stays valid until the guard is dropped
+    /// even if writers replace the value meanwhile.
+    ///
+    /// This never blocks nor retries. Writers wait for the guard to be dropped before
+    /// reclaiming the value, so guards should be short-lived.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        // Pin the epoch by registering as a reader before loading the value.
+        let parity = self.epoch.enter();
+        let value = self.value.load(Ordering::SeqCst);
+        RcuReadGuard {
+            cell: self,
+            parity,
+            value: NonNull::new(value).expect("RcuCell holding a null value"),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Replaces the value, returning the old one once no reader can observe it anymore.
+    ///
+    /// This blocks until every reader which could have seen the old value dropped its guard,
+    /// so calling it while holding a guard from the same cell deadlocks.
+    pub fn replace(&self, value: T) -> T {
+        let _writer = self.writer.lock();
+        unsafe { self.replace_locked(value) }
+    }
+
+    /// Replaces the value, dropping the old one once no reader can observe it anymore.
+    ///
+    /// This blocks like [`replace`](RcuCell::replace).
+    pub fn store(&self, value: T) {
+        drop(self.replace(value));
+    }
+
+    /// Replaces the value with the one computed by `f` from the current value,
+    /// returning the old one once no reader can observe it anymore.
+    ///
+    /// Writers are serialized, so no other writer can replace the value while `f` runs.
+    /// This blocks like [`replace`](RcuCell::replace).
+    pub fn update(&self, f: impl FnOnce(&T) -> T) -> T {
+        let _writer = self.writer.lock();
+
+        // Only writers replace the value, so it can't be reclaimed under us.
+        let new_value = f(unsafe { &*self.value.load(Ordering::Acquire) });
+        unsafe { self.replace_locked(new_value) }
+    }
+
+    unsafe fn replace_locked(&self, value: T) -> T {
+        let old_value = self
+            .value
+            .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+
+        // Wait for the readers which could have seen the old value before dropping it.
+        self.epoch.synchronize(Primitive::new("RcuCell", self));
+        *Box::from_raw(old_value)
+    }
+}
+
+/// A snapshot of the value of an [`RcuCell`], which keeps it alive until dropped.
+#[must_use = "if unused the snapshot will immediately be released"]
+pub struct RcuReadGuard<'a, T> {
+    cell: &'a RcuCell<T>,
+    parity: usize,
+    value: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+unsafe impl<T: Sync> Sync for RcuReadGuard<'_, T> {}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T> Drop for RcuReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.cell.epoch.leave(self.parity);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RcuReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::RcuCell;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let mut cell = RcuCell::new(1);
+        assert_eq!(*cell.read(), 1);
+        assert_eq!(cell.replace(2), 1);
+        assert_eq!(cell.update(|v| v + 1), 2);
+        cell.store(4);
+        *cell.get_mut() += 1;
+        assert_eq!(format!("{:?}", cell), "RcuCell { data: 5 }");
+        assert_eq!(cell.into_inner(), 5);
+    }
+
+    #[test]
+    fn writer_waits_for_readers() {
+        let cell = Arc::new(RcuCell::new(Arc::new(())));
+        let snapshot = cell.read();
+        let value = Arc::clone(&*snapshot);
+
+        let writer = {
+            let cell = cell.clone();
+            thread::spawn(move || cell.store(Arc::new(())))
+        };
+
+        // The old value can't be reclaimed while it's being read.
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(Arc::strong_count(&value), 2);
+
+        // New readers see the new value without blocking on the writer.
+        assert!(!Arc::ptr_eq(&*cell.read(), &value));
+
+        drop(snapshot);
+        writer.join().unwrap();
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        struct Value(usize, usize);
+        impl Drop for Value {
+            fn drop(&mut self) {
+                assert_eq!(self.0, self.1);
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let cell = Arc::new(RcuCell::new(Value(0, 0)));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    while !DONE.load(Ordering::Relaxed) {
+                        let value = cell.read();
+                        assert_eq!(value.0, value.1);
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        cell.update(|v| Value(v.0 + 1, v.1 + 1));
+                    }
+                })
+            })
+            .collect();
+
+        for t in writers {
+            t.join().unwrap();
+        }
+        DONE.store(true, Ordering::Relaxed);
+        for t in readers {
+            t.join().unwrap();
+        }
+
+        assert_eq!(cell.read().0, 2000);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 2000);
+    }
+}
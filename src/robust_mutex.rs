@@ -0,0 +1,255 @@
+use std::{cell::UnsafeCell, fmt, io, marker::PhantomData, mem::MaybeUninit};
+
+/// A process-shared mutex which detects when its owner died while holding it.
+///
+/// Unlike the other locks in this crate, this mutex is meant to be placed in memory shared
+/// between processes. It can't use the crate's userspace thread queues (which live on each
+/// thread's stack) and wraps a robust, process-shared `pthread_mutex_t` instead. The kernel
+/// tracks robust mutexes held by each thread (the robust futex list on Linux) and releases them
+/// when the thread or its process exits.
+///
+/// When that happens, the next `lock()` returns [`RobustLockError::OwnerDied`] with a guard for
+/// the mutex. The new owner can repair the protected state, then call
+/// [`RobustMutexGuard::make_consistent`] to keep using the mutex. Unlocking it without doing so
+/// marks the mutex as permanently unusable, and further locking returns
+/// [`RobustLockError::NotRecoverable`].
+///
+/// The mutex must be initialized in place with [`RobustMutex::init`] by one of the processes
+/// and must not be moved afterwards.
+///
+/// This type is only available on Linux and FreeBSD.
+///
+/// # Examples
+///
+/// ```
+/// use usync::{RobustLockError, RobustMutex};
+/// use std::mem::MaybeUninit;
+///
+/// // This would usually live in memory shared with other processes.
+/// let mut storage = MaybeUninit::<RobustMutex>::uninit();
+/// let mutex = unsafe {
+///     RobustMutex::init(storage.as_mut_ptr()).unwrap();
+///     &*storage.as_ptr()
+/// };
+///
+/// match mutex.lock() {
+///     Ok(_guard) => {}
+///     Err(RobustLockError::OwnerDied(mut guard)) => {
+///         // Repair the state protected by the mutex here.
+///         guard.make_consistent().unwrap();
+///     }
+///     Err(error) => panic!("{}", error),
+/// }
+///
+/// unsafe { RobustMutex::destroy(storage.as_mut_ptr()) };
+/// ```
+#[repr(transparent)]
+pub struct RobustMutex {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+}
+
+unsafe impl Send for RobustMutex {}
+unsafe impl Sync for RobustMutex {}
+
+impl fmt::Debug for RobustMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RobustMutex { .. }")
+    }
+}
+
+impl RobustMutex {
+    /// Initializes a robust process-shared mutex at the given location.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes and must not already contain an initialized mutex
+    /// which is in use. The mutex must not be moved once initialized.
+    pub unsafe fn init(ptr: *mut Self) -> io::Result<()> {
+        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
+        check(libc::pthread_mutexattr_init(attr.as_mut_ptr()))?;
+
+        let attr = attr.as_mut_ptr();
+        let result = check(libc::pthread_mutexattr_setpshared(
+            attr,
+            libc::PTHREAD_PROCESS_SHARED,
+        ))
+        .and_then(|_| {
+            check(libc::pthread_mutexattr_setrobust(
+                attr,
+                libc::PTHREAD_MUTEX_ROBUST,
+            ))
+        })
+        .and_then(|_| check(libc::pthread_mutex_init((*ptr).mutex.get(), attr)));
+
+        libc::pthread_mutexattr_destroy(attr);
+        result
+    }
+
+    /// Destroys a mutex previously initialized with [`RobustMutex::init`].
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be unlocked and no process may use it afterwards.
+    pub unsafe fn destroy(ptr: *mut Self) {
+        libc::pthread_mutex_destroy((*ptr).mutex.get());
+    }
+
+    /// Acquires the mutex, blocking the current thread until it is able to do so.
+    ///
+    /// Returns [`RobustLockError::OwnerDied`] (which still holds the mutex) if the previous
+    /// owner exited without unlocking it.
+    pub fn lock(&self) -> Result<RobustMutexGuard<'_>, RobustLockError<'_>> {
+        let rc = unsafe { libc::pthread_mutex_lock(self.mutex.get()) };
+        self.guard_from(rc)
+    }
+
+    /// Attempts to acquire the mutex without blocking.
+    ///
+    /// Returns [`RobustLockError::WouldBlock`] if the mutex is held by another thread.
+    pub fn try_lock(&self) -> Result<RobustMutexGuard<'_>, RobustLockError<'_>> {
+        let rc = unsafe { libc::pthread_mutex_trylock(self.mutex.get()) };
+        self.guard_from(rc)
+    }
+
+    fn guard_from(&self, rc: libc::c_int) -> Result<RobustMutexGuard<'_>, RobustLockError<'_>> {
+        let guard = RobustMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        };
+
+        match rc {
+            0 => Ok(guard),
+            libc::EOWNERDEAD => Err(RobustLockError::OwnerDied(guard)),
+            libc::ENOTRECOVERABLE => Err(RobustLockError::NotRecoverable),
+            libc::EBUSY => Err(RobustLockError::WouldBlock),
+            _ => panic!(
+                "failed to lock RobustMutex: {}",
+                io::Error::from_raw_os_error(rc)
+            ),
+        }
+    }
+}
+
+/// An RAII implementation of a "scoped lock" of a robust mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+pub struct RobustMutexGuard<'a> {
+    mutex: &'a RobustMutex,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl Sync for RobustMutexGuard<'_> {}
+
+impl fmt::Debug for RobustMutexGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RobustMutexGuard { .. }")
+    }
+}
+
+impl RobustMutexGuard<'_> {
+    /// Marks the state protected by the mutex as consistent again after its owner died.
+    ///
+    /// This should be called once recovery is done after getting [`RobustLockError::OwnerDied`].
+    /// It returns an error if the mutex wasn't acquired from an owner which died.
+    pub fn make_consistent(&mut self) -> io::Result<()> {
+        unsafe { check(libc::pthread_mutex_consistent(self.mutex.mutex.get())) }
+    }
+}
+
+impl Drop for RobustMutexGuard<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::pthread_mutex_unlock(self.mutex.mutex.get());
+        }
+    }
+}
+
+/// An error returned when locking a [`RobustMutex`] doesn't succeed normally.
+pub enum RobustLockError<'a> {
+    /// The previous owner exited while holding the mutex.
+    /// The mutex is now held through the guard and its protected state may need recovery.
+    OwnerDied(RobustMutexGuard<'a>),
+    /// A previous owner died and the mutex was unlocked without being made consistent.
+    NotRecoverable,
+    /// The mutex is held by another thread (only returned by `try_lock`).
+    WouldBlock,
+}
+
+impl fmt::Debug for RobustLockError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OwnerDied(_) => f.pad("OwnerDied(..)"),
+            Self::NotRecoverable => f.pad("NotRecoverable"),
+            Self::WouldBlock => f.pad("WouldBlock"),
+        }
+    }
+}
+
+impl fmt::Display for RobustLockError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OwnerDied(_) => "the owner of the mutex died while holding it",
+            Self::NotRecoverable => "the mutex is not recoverable",
+            Self::WouldBlock => "the mutex is locked",
+        })
+    }
+}
+
+impl std::error::Error for RobustLockError<'_> {}
+
+fn check(rc: libc::c_int) -> io::Result<()> {
+    match rc {
+        0 => Ok(()),
+        _ => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RobustLockError, RobustMutex};
+    use std::{mem, mem::MaybeUninit, thread};
+
+    fn new_mutex() -> &'static RobustMutex {
+        let storage = Box::leak(Box::new(MaybeUninit::<RobustMutex>::uninit()));
+        unsafe {
+            RobustMutex::init(storage.as_mut_ptr()).unwrap();
+            &*storage.as_ptr()
+        }
+    }
+
+    fn lock_and_exit(mutex: &'static RobustMutex) {
+        thread::spawn(move || mem::forget(mutex.lock().unwrap()))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_robust_mutex_lock() {
+        let mutex = new_mutex();
+        let guard = mutex.lock().unwrap();
+        assert!(matches!(mutex.try_lock(), Err(RobustLockError::WouldBlock)));
+        drop(guard);
+        assert!(mutex.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_robust_mutex_owner_died() {
+        let mutex = new_mutex();
+        lock_and_exit(mutex);
+
+        match mutex.lock() {
+            Err(RobustLockError::OwnerDied(mut guard)) => guard.make_consistent().unwrap(),
+            result => panic!("expected OwnerDied, got {:?}", result),
+        }
+
+        assert!(mutex.lock().is_ok());
+    }
+
+    #[test]
+    fn test_robust_mutex_not_recoverable() {
+        let mutex = new_mutex();
+        lock_and_exit(mutex);
+
+        assert!(matches!(mutex.lock(), Err(RobustLockError::OwnerDied(_))));
+        assert!(matches!(mutex.lock(), Err(RobustLockError::NotRecoverable)));
+    }
+}
@@ -1,19 +1,46 @@
 #![allow(unused_imports)]
-use super::shared::{fence_acquire, invalid_mut, AtomicPtrRmw, SpinWait, StrictProvenance, Waiter};
+use super::{
+    parking::{self, ParkResult},
+    shared::{
+        self, chaos, fence_acquire, invalid_mut, numa,
+        trace::{self, LockKind},
+        AtomicPtrRmw, CasBackoff, Primitive, SpinWait, StrictProvenance, Waiter,
+    },
+};
 use std::{
     fmt,
     pin::Pin,
     ptr::{self, NonNull},
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{fence, AtomicPtr, Ordering},
+    time::Duration,
 };
 
+#[cfg(feature = "std")]
+use {crate::clock, std::time::Instant};
+
 const UNLOCKED: usize = 0;
 const LOCKED: usize = 1;
 const READING: usize = 2;
 const QUEUED: usize = 4;
 const QUEUE_LOCKED: usize = 8;
-const READER_SHIFT: u32 = 16usize.trailing_zeros();
-const SINGLE_READER: usize = LOCKED | READING | (1 << READER_SHIFT);
+const READER_SHIFT: u32 = Waiter::TAG_BITS;
+const UPGRADABLE: usize = 1 << READER_SHIFT;
+const UPGRADING: usize = 1 << (READER_SHIFT + 1);
+const ONE_READER: usize = 1 << (READER_SHIFT + 2);
+const SINGLE_READER: usize = LOCKED | READING | ONE_READER;
+const SINGLE_UPGRADABLE: usize = SINGLE_READER | UPGRADABLE;
+
+// The bits above READER_SHIFT, as they're stored in the `counter` of the queue tail.
+const TAIL_UPGRADABLE: usize = UPGRADABLE >> READER_SHIFT;
+const TAIL_UPGRADING: usize = UPGRADING >> READER_SHIFT;
+const TAIL_READER: usize = ONE_READER >> READER_SHIFT;
+
+// Bits stored in `Waiter::flags` by threads queued on the RwLock.
+const WAITER_WRITER: usize = 1;
+const WAITER_HANDOFF: usize = 2;
+
+// The state bits are stored in the low bits of the queue head's address.
+const _: () = assert!((LOCKED | READING | QUEUED | QUEUE_LOCKED) & Waiter::MASK == 0);
 
 /// Raw rwlock type implemented with lock-free userspace thread queues.
 #[derive(Default)]
@@ -51,12 +78,29 @@ pub struct RawRwLock {
     ///        |         |        |              |           | the head Waiter node of the waiting thread queue. There is
     ///        |         |        |              |           | also a thread which is updating the waiting-thread queue.
     /// -------+---------+--------+--------------+-----------+-------------------------------------------------------------
+    ///
+    /// The lowest two bits of the reader count are flags: UPGRADABLE is set while one of the readers
+    /// holds an upgradable read lock, and UPGRADING while it waits for the other readers to leave
+    /// in order to upgrade it, which keeps new readers from acquiring the lock.
     pub(super) state: AtomicPtr<Waiter>,
 }
 
 impl fmt::Debug for RawRwLock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.pad("RawRwLock { .. }")
+        let state = self.state.load(Ordering::Relaxed).address();
+        let mut debug = f.debug_struct("RawRwLock");
+        match state & (LOCKED | READING) {
+            UNLOCKED => debug.field("locked", &format_args!("unlocked")),
+            LOCKED => debug.field("locked", &format_args!("exclusive")),
+            _ => debug.field("locked", &format_args!("shared")),
+        };
+
+        // The reader count is only in the state when there's no queue of waiting threads.
+        if state & (LOCKED | READING | QUEUED) == (LOCKED | READING) {
+            debug.field("readers", &(state / ONE_READER));
+        }
+
+        debug.field("has_waiters", &(state & QUEUED != 0)).finish()
     }
 }
 
@@ -84,44 +128,256 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
 
     #[inline]
     fn try_lock_exclusive(&self) -> bool {
-        self.try_lock_exclusive_fast()
+        let acquired = self.try_lock_exclusive_fast();
+        if acquired {
+            trace::acquired(self, LockKind::Exclusive);
+        }
+        acquired
     }
 
     #[inline]
     fn lock_exclusive(&self) {
-        if !self.try_lock_exclusive() {
-            self.lock_exclusive_slow();
-        }
+        self.lock_exclusive_on(Primitive::new("RwLock", self))
     }
 
     #[inline]
     unsafe fn unlock_exclusive(&self) {
+        // The deterministic feature hands the lock off to the next queued writer
+        // so that threads acquire it in the order in which they queued.
+        if cfg!(feature = "deterministic") {
+            return self.unlock_exclusive_fair();
+        }
+
+        trace::released(self, LockKind::Exclusive);
         self.unlock_exclusive_fast()
     }
 
     #[inline]
     fn try_lock_shared(&self) -> bool {
-        self.try_lock_shared_fast() || self.try_lock_shared_slow()
+        let acquired = self.try_lock_shared_fast(0) || self.try_lock_shared_slow(0);
+        if acquired {
+            trace::acquired(self, LockKind::Shared);
+        }
+        acquired
     }
 
     #[inline]
     fn lock_shared(&self) {
-        if !self.try_lock_shared_fast() {
-            self.lock_shared_slow();
+        if !self.try_lock_shared_fast(0) {
+            self.lock_shared_slow(Primitive::new("RwLock", self), 0);
         }
+        trace::acquired(self, LockKind::Shared);
     }
 
     #[inline]
     unsafe fn unlock_shared(&self) {
-        if !self.unlock_shared_fast() {
-            self.unlock_shared_slow();
+        trace::released(self, LockKind::Shared);
+        if !self.unlock_shared_fast(SINGLE_READER) {
+            self.unlock_shared_slow(TAIL_READER);
+        }
+    }
+}
+
+unsafe impl lock_api::RawRwLockFair for RawRwLock {
+    #[inline]
+    unsafe fn unlock_shared_fair(&self) {
+        // The lock is only ever handed off to writers, which the last reader wakes up anyway.
+        use lock_api::RawRwLock as _;
+        self.unlock_shared()
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive_fair(&self) {
+        RawRwLock::unlock_exclusive_fair(self)
+    }
+}
+
+unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
+    #[inline]
+    unsafe fn downgrade(&self) {
+        trace::released(self, LockKind::Exclusive);
+        self.downgrade_to(TAIL_READER);
+        trace::acquired(self, LockKind::Shared);
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
+    #[inline]
+    fn lock_upgradable(&self) {
+        if !self.try_lock_shared_fast(UPGRADABLE) {
+            self.lock_shared_slow(Primitive::new("RwLock", self), UPGRADABLE);
+        }
+        trace::acquired(self, LockKind::Shared);
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        let acquired =
+            self.try_lock_shared_fast(UPGRADABLE) || self.try_lock_shared_slow(UPGRADABLE);
+        if acquired {
+            trace::acquired(self, LockKind::Shared);
+        }
+        acquired
+    }
+
+    #[inline]
+    unsafe fn unlock_upgradable(&self) {
+        trace::released(self, LockKind::Shared);
+        if !self.unlock_shared_fast(SINGLE_UPGRADABLE) {
+            self.unlock_shared_slow(TAIL_READER | TAIL_UPGRADABLE);
+        }
+    }
+
+    #[inline]
+    unsafe fn upgrade(&self) {
+        trace::released(self, LockKind::Shared);
+        if !self.try_upgrade_fast() {
+            let upgraded = self.upgrade_slow(|| None);
+            debug_assert!(upgraded);
+        }
+        trace::acquired(self, LockKind::Exclusive);
+    }
+
+    #[inline]
+    unsafe fn try_upgrade(&self) -> bool {
+        let upgraded = self.try_upgrade_fast() || self.try_upgrade_slow();
+        if upgraded {
+            trace::released(self, LockKind::Shared);
+            trace::acquired(self, LockKind::Exclusive);
+        }
+        upgraded
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgradeFair for RawRwLock {
+    #[inline]
+    unsafe fn unlock_upgradable_fair(&self) {
+        // Like unlock_shared_fair(), there's nothing to hand off to readers.
+        use lock_api::RawRwLockUpgrade as _;
+        self.unlock_upgradable()
+    }
+}
+
+unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawRwLock {
+    #[inline]
+    unsafe fn downgrade_upgradable(&self) {
+        // The lock stays held shared, so this doesn't count as a release for tracing.
+        let _ = self.update_readers(false, |readers| Some(readers - TAIL_UPGRADABLE));
+    }
+
+    #[inline]
+    unsafe fn downgrade_to_upgradable(&self) {
+        trace::released(self, LockKind::Exclusive);
+        self.downgrade_to(TAIL_READER | TAIL_UPGRADABLE);
+        trace::acquired(self, LockKind::Shared);
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl lock_api::RawRwLockTimed for RawRwLock {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_shared_until(deadline),
+            None => {
+                lock_api::RawRwLock::lock_shared(self);
+                true
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, deadline: Instant) -> bool {
+        let acquired = self.try_lock_shared_fast(0) || self.lock_shared_until(deadline, 0);
+        if acquired {
+            trace::acquired(self, LockKind::Shared);
+        }
+        acquired
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_exclusive_until(deadline),
+            None => {
+                lock_api::RawRwLock::lock_exclusive(self);
+                true
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, deadline: Instant) -> bool {
+        if self.try_lock_exclusive_fast() {
+            trace::acquired(self, LockKind::Exclusive);
+            return true;
+        }
+
+        let primitive = Primitive::new("RwLock", self);
+        self.lock_exclusive_until(primitive, SpinWait::default(), deadline)
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl lock_api::RawRwLockUpgradeTimed for RawRwLock {
+    #[inline]
+    fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_upgradable_until(deadline),
+            None => {
+                lock_api::RawRwLockUpgrade::lock_upgradable(self);
+                true
+            }
+        }
+    }
+
+    #[inline]
+    fn try_lock_upgradable_until(&self, deadline: Instant) -> bool {
+        let acquired =
+            self.try_lock_shared_fast(UPGRADABLE) || self.lock_shared_until(deadline, UPGRADABLE);
+        if acquired {
+            trace::acquired(self, LockKind::Shared);
+        }
+        acquired
+    }
+
+    #[inline]
+    unsafe fn try_upgrade_for(&self, timeout: Duration) -> bool {
+        match clock::now().checked_add(timeout) {
+            Some(deadline) => self.try_upgrade_until(deadline),
+            None => {
+                lock_api::RawRwLockUpgrade::upgrade(self);
+                true
+            }
         }
     }
+
+    #[inline]
+    unsafe fn try_upgrade_until(&self, deadline: Instant) -> bool {
+        trace::released(self, LockKind::Shared);
+        let upgraded = self.try_upgrade_fast()
+            || self.upgrade_slow(|| Some(deadline.saturating_duration_since(clock::now())));
+        match upgraded {
+            true => trace::acquired(self, LockKind::Exclusive),
+            false => trace::acquired(self, LockKind::Shared),
+        }
+        upgraded
+    }
 }
 
-//  --- X86 Specializations
+//  --- Fetch-and-op Specializations
+//
+// These rely on `fetch_or` and `fetch_sub` being faster than a `compare_exchange` loop,
+// which holds on x86 (`lock bts` and `lock xadd` against `lock cmpxchg`), on RISC-V
+// (`amoor` and `amoadd` against an `lr`/`sc` loop which can fail spuriously) and on
+// AArch64 (`ldsetal` and `ldaddl` against a `casal` loop).
+// The build script only enables them there, and never with the `portable` feature.
+// They're also disabled under Miri in favor of the plain `compare_exchange` versions below.
 
-#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(miri)))]
+#[cfg(all(usync_rmw_fast_paths, not(miri)))]
 impl RawRwLock {
     #[inline(always)]
     fn try_lock_exclusive_assuming(&self, _state: *mut Waiter) -> bool {
@@ -131,10 +387,10 @@ impl RawRwLock {
 
     #[inline(always)]
     fn try_lock_exclusive_fast(&self) -> bool {
-        // Since 1.65, rustc lowers this to a single `lock bts` on x86, which
-        // is often faster for acquiring exclusive ownership than a `lock cmpxchg`
-        // as the former wont spuriously fail when a thread is updating the
-        // QUEUE_LOCKED bit or adding themselves to the queue.
+        // rustc lowers this to a single `lock bts` on x86 (and to an `amoor.aq` on RISC-V
+        // or an `ldseta` on AArch64 with LSE), which is often faster for acquiring exclusive
+        // ownership than a `lock cmpxchg` as the former wont spuriously fail when a thread is
+        // updating the QUEUE_LOCKED bit or adding themselves to the queue.
         self.state
             .fetch_ptr_or(invalid_mut(LOCKED), Ordering::Acquire)
             .address()
@@ -144,8 +400,8 @@ impl RawRwLock {
 
     #[inline(always)]
     unsafe fn unlock_exclusive_fast(&self) {
-        // On x86, we unlock the exclusive lock first, then try and wake later.
-        // This is faster than using a `lock cmpxchg` loop as it doesn't have
+        // We unlock the exclusive lock first, then try and wake later.
+        // This is faster than using a `compare_exchange` loop as it doesn't have
         // to fail and retry from other threads updating QUEUE_LOCKED bit or queueing themselves.
         let locked = ptr::null_mut::<Waiter>().with_address(LOCKED);
         let state = self.state.fetch_sub(locked, Ordering::Release);
@@ -157,24 +413,10 @@ impl RawRwLock {
         }
     }
 
-    #[cold]
-    unsafe fn unlock_shared_and_unpark(&self) {
-        // On x86, we unlock the shared lock first, then try and wake later.
-        // This is faster than using a `lock cmpxchg` loop as it doesn't have
-        // to fail and retry from other threads updating QUEUE_LOCKED bit or queueing themselves.
-        let read_locked = ptr::null_mut::<Waiter>().with_address(LOCKED | READING);
-        let state = self.state.fetch_sub(read_locked, Ordering::Release);
-        debug_assert_eq!(state.address() & (LOCKED | READING), LOCKED | READING);
-
-        // Only try to unpark if there's no QUEUE_LOCKED owner yet and if there's threads queued.
-        if state.address() & (QUEUED | QUEUE_LOCKED) == QUEUED {
-            self.try_unpark();
-        }
-    }
-
     #[cold]
     fn try_unpark(&self) {
         let mut state = self.state.load(Ordering::Relaxed);
+        let mut backoff = CasBackoff::default();
 
         // Try to grab the QUEUE_LOCKED bit to wake up threads iff:
         // - theres no lock holder, as they can be the ones to do the wake up
@@ -191,14 +433,16 @@ impl RawRwLock {
                 Ok(_) => return unsafe { self.unpark(new_state) },
                 Err(e) => state = e,
             }
+            backoff.retry();
         }
     }
 }
 
-#[cfg(any(miri, not(any(target_arch = "x86", target_arch = "x86_64"))))]
+#[cfg(any(miri, not(usync_rmw_fast_paths)))]
 impl RawRwLock {
     #[inline(always)]
     fn try_lock_exclusive_assuming(&self, mut state: *mut Waiter) -> bool {
+        let mut backoff = CasBackoff::default();
         while state.address() & LOCKED == 0 {
             match self.state.compare_exchange_weak(
                 state,
@@ -209,6 +453,7 @@ impl RawRwLock {
                 Ok(_) => return true,
                 Err(e) => state = e,
             }
+            backoff.retry();
         }
 
         false
@@ -242,14 +487,10 @@ impl RawRwLock {
         }
     }
 
-    #[inline(always)]
-    unsafe fn unlock_shared_and_unpark(&self) {
-        self.unlock_and_unpark()
-    }
-
     #[cold]
     unsafe fn unlock_and_unpark(&self) {
         let mut state = self.state.load(Ordering::Relaxed);
+        let mut backoff = CasBackoff::default();
         loop {
             assert_ne!(state.address() & LOCKED, 0);
             assert_ne!(state.address() & QUEUED, 0);
@@ -268,6 +509,7 @@ impl RawRwLock {
                 Ordering::Relaxed,
             ) {
                 state = e;
+                backoff.retry();
                 continue;
             }
 
@@ -283,27 +525,31 @@ impl RawRwLock {
 //  --- Generic Code
 
 impl RawRwLock {
+    /// Tries to add a reader to the lock, as the upgradable one if `upgradable` is UPGRADABLE.
     #[inline(always)]
     fn try_lock_shared_assuming(
         &self,
         state: *mut Waiter,
+        upgradable: usize,
     ) -> Option<Result<*mut Waiter, *mut Waiter>> {
-        // Returns None if the lock is held by a writer
-        if state.address() != UNLOCKED {
-            if state.address() & (LOCKED | READING | QUEUED) != (LOCKED | READING) {
-                return None;
-            }
+        // Returns None if the lock is held by a writer, by a reader waiting to upgrade its lock,
+        // or by another upgradable reader when trying to acquire an upgradable lock.
+        if state.address() != UNLOCKED
+            && state.address() & (LOCKED | READING | QUEUED | UPGRADING | upgradable)
+                != (LOCKED | READING)
+        {
+            return None;
         }
 
         // Check for reader count overflow when trying to add a reader.
         // On overflow, readers will queue themselves and be woken up by the last active reader.
-        // Overflow is very unlikely though as it requires `usize::MAX >> 4` active readers at once.
-        // On a system where `usize` is 64 bits, that's over a quintillion (1 million ^ 5) readers.
-        // On a system where `usize` is 32 bits, that's still over 260 million readers.
-        if let Some(with_reader) = state.address().checked_add(1 << READER_SHIFT) {
+        // Overflow is very unlikely though as it requires `usize::MAX >> 6` active readers at once.
+        // On a system where `usize` is 64 bits, that's over 288 quadrillion readers.
+        // On a system where `usize` is 32 bits, that's still over 67 million readers.
+        if let Some(with_reader) = state.address().checked_add(ONE_READER) {
             return Some(self.state.compare_exchange_weak(
                 state,
-                state.with_address(with_reader | LOCKED | READING),
+                state.with_address(with_reader | upgradable | LOCKED | READING),
                 Ordering::Acquire,
                 Ordering::Relaxed,
             ));
@@ -313,35 +559,38 @@ impl RawRwLock {
     }
 
     #[inline(always)]
-    fn try_lock_shared_fast(&self) -> bool {
+    fn try_lock_shared_fast(&self, upgradable: usize) -> bool {
         let state = self.state.load(Ordering::Relaxed);
-        let result = self.try_lock_shared_assuming(state);
+        let result = self.try_lock_shared_assuming(state, upgradable);
         matches!(result, Some(Ok(_)))
     }
 
     #[cold]
-    fn try_lock_shared_slow(&self) -> bool {
+    fn try_lock_shared_slow(&self, upgradable: usize) -> bool {
         let mut state = self.state.load(Ordering::Relaxed);
+        let mut backoff = CasBackoff::default();
         loop {
-            match self.try_lock_shared_assuming(state) {
+            match self.try_lock_shared_assuming(state, upgradable) {
                 None => return false,
                 Some(Err(e)) => state = e,
                 Some(Ok(_)) => return true,
             }
+            backoff.retry();
         }
     }
 
+    /// Releases the read lock of the only reader, whose state is `single`.
     #[inline(always)]
-    unsafe fn unlock_shared_fast(&self) -> bool {
+    unsafe fn unlock_shared_fast(&self, single: usize) -> bool {
         // Just go to the slow path if we're not the only reader
         let state = self.state.load(Ordering::Relaxed);
-        if state.address() != SINGLE_READER {
+        if state.address() != single {
             return false;
         }
 
         self.state
             .compare_exchange(
-                state.with_address(SINGLE_READER),
+                state.with_address(single),
                 state.with_address(UNLOCKED),
                 Ordering::Release,
                 Ordering::Relaxed,
@@ -349,62 +598,301 @@ impl RawRwLock {
             .is_ok()
     }
 
+    /// Releases a read lock by removing `units` from the reader count, as counted on the queue tail.
     #[cold]
-    unsafe fn unlock_shared_slow(&self) {
-        // Try to just bump the reader count down when there's no waiting threads.
+    unsafe fn unlock_shared_slow(&self, units: usize) {
+        let result = self.update_readers(false, |readers| {
+            assert!(readers >= units);
+            Some(readers - units)
+        });
+
+        // The last reader to leave wakes up the one waiting to upgrade its lock.
+        let readers = result.unwrap_or_else(|_| unreachable!());
+        if readers - units == TAIL_UPGRADING {
+            parking::unpark_one(self.upgrade_key());
+        }
+    }
+
+    /// Updates the reader count of the lock held shared with `update`, which gets and returns it
+    /// as counted on the queue tail: without the state bits. Returns the previous count, or
+    /// the current one as an error if `update` returned None.
+    ///
+    /// Once no readers are left, the lock is released, or kept locked exclusively if `exclusive`.
+    unsafe fn update_readers(
+        &self,
+        exclusive: bool,
+        mut update: impl FnMut(usize) -> Option<usize>,
+    ) -> Result<usize, usize> {
+        // Try to just update the reader count in the state when there's no waiting threads.
         // This only works because the Remaining bits still point to the reader count.
         // When threads start waiting, they override these bits with the queue pointer.
+        let mut spin = SpinWait::default();
+        let mut backoff = CasBackoff::default();
         let mut state = self.state.load(Ordering::Relaxed);
-        while state.address() & QUEUED == 0 {
-            assert_ne!(state.address() & LOCKED, 0);
-            assert_ne!(state.address() & READING, 0);
-            assert_ne!(state.address() >> READER_SHIFT, 0);
+        loop {
+            assert_eq!(state.address() & (LOCKED | READING), LOCKED | READING);
+
+            if state.address() & QUEUED == 0 {
+                let readers = state.address() >> READER_SHIFT;
+                let new_state = match update(readers) {
+                    None => return Err(readers),
+                    Some(0) if exclusive => state.with_address(LOCKED),
+                    Some(0) => state.with_address(UNLOCKED),
+                    Some(readers) => {
+                        state.with_address((readers << READER_SHIFT) | LOCKED | READING)
+                    }
+                };
+
+                // Release barrier to ensure RwLock-protected reads/loads happen before we "release" the read lock.
+                // Acquire barrier to ensure those of the other readers happen before we lock exclusively.
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Ok(readers),
+                    Err(e) => state = e,
+                }
+                backoff.retry();
+                continue;
+            }
 
-            let mut new_state = state.map_address(|addr| addr - (1 << READER_SHIFT));
-            if state.address() == SINGLE_READER {
-                new_state = state.with_address(UNLOCKED);
+            // The'ers threads waiting on the RwLock. The reader count has moved to the tail of the
+            // queue. Waiters may be unlinking themselves from the queue, even while it's locked shared,
+            // so wait for the QUEUE_LOCKED bit holder, which never blocks, and grab it to find the tail.
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
             }
 
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
             match self.state.compare_exchange_weak(
                 state,
                 new_state,
-                Ordering::Release,
+                Ordering::Relaxed,
                 Ordering::Relaxed,
             ) {
-                Ok(_) => return,
+                Ok(_) => {
+                    state = new_state;
+                    break;
+                }
                 Err(e) => state = e,
             }
         }
 
-        // The'ers threads waiting on the RwLock.
-        // The reader count has moved to the tail of the queue.
-        assert_ne!(state.address() & LOCKED, 0);
-        assert_ne!(state.address() & QUEUED, 0);
-        assert_ne!(state.address() & READING, 0);
-
-        // Find the tail of the wait queue while also caching it at the current head.
-        // As long as the Waiter writes are atomic, this can be soundly racing with
-        // other callers to get_and_link_queue() like link_queue_or_unpark() or other readers.
-        // Acquire barrier to ensure Waiter queue writes to head happen before we start scanning.
+        // Acquire barrier to ensure Waiter queue writes to head happen before we start scanning,
+        // and that the reads/loads of the readers which left before happen before we lock exclusively.
         fence_acquire(&self.state);
         let (_head, tail) = Waiter::get_and_link_queue(state, |_| {});
+        let counter = &tail.as_ref().counter;
+        let readers = counter.load(Ordering::Relaxed);
+
+        // Release the QUEUE_LOCKED bit, which unpark() does when the lock is held.
+        let new_readers = match update(readers) {
+            Some(new_readers) => new_readers,
+            None => {
+                self.unpark(state);
+                return Err(readers);
+            }
+        };
+        counter.store(new_readers, Ordering::Relaxed);
 
-        // Decrement the reader count which was moved to the tail.
+        // The last reader unsets the READING bit, along with the QUEUE_LOCKED bit when locking
+        // exclusively, or with the LOCKED bit to wake up waiting threads otherwise.
         // Release barrier to ensure RwLock-protected reads/loads happen before we "release" the read lock.
-        let readers = tail.as_ref().counter.fetch_sub(1, Ordering::Release);
-        assert_ne!(readers, 0);
+        match new_readers {
+            0 if exclusive => {
+                let bits = ptr::null_mut::<Waiter>().with_address(READING | QUEUE_LOCKED);
+                self.state.fetch_sub(bits, Ordering::Release);
+            }
+            0 => {
+                let bits = ptr::null_mut::<Waiter>().with_address(LOCKED | READING);
+                let state = self.state.fetch_sub(bits, Ordering::Release);
+                self.unpark(state.map_address(|addr| addr & !(LOCKED | READING)));
+            }
+            _ => self.unpark(state),
+        }
 
-        // The last reader unsets the LOCKED bit and tries to wake up waiting threads.
-        // Acquire barrier synchronizes with the Release to counter above to ensure
-        // that the unsetting of the LOCKED bit happens after all the readers reads/loads occur.
-        if readers == 1 {
-            fence_acquire(&self.state);
-            self.unlock_shared_and_unpark();
+        Ok(readers)
+    }
+
+    /// The parking key of the upgradable reader waiting for the other readers to leave.
+    fn upgrade_key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[inline(always)]
+    fn try_upgrade_fast(&self) -> bool {
+        self.state
+            .compare_exchange(
+                invalid_mut(SINGLE_UPGRADABLE),
+                invalid_mut(LOCKED),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    #[cold]
+    unsafe fn try_upgrade_slow(&self) -> bool {
+        let sole = TAIL_READER | TAIL_UPGRADABLE;
+        let result = self.update_readers(true, |readers| (readers == sole).then_some(0));
+        result.is_ok()
+    }
+
+    /// Upgrades the upgradable read lock held by the caller once the other readers left, waiting
+    /// for up to `timeout()` at a time for them. Returns false, with the upgradable read lock
+    /// still held, if the wait timed out.
+    #[cold]
+    unsafe fn upgrade_slow(&self, timeout: impl Fn() -> Option<Duration>) -> bool {
+        #[cfg(feature = "metrics")]
+        crate::metrics::increment(crate::metrics::Counter::ContendedAcquisitions);
+        #[cfg(feature = "deadlock_detection")]
+        let _waiting = crate::deadlock::Waiting::new(self);
+        #[cfg(feature = "std")]
+        let _timed = crate::contention::Waiting::new(self);
+
+        // Trade our read lock for the UPGRADING bit, which keeps new readers from acquiring the
+        // lock, or lock exclusively right away if we're the only reader left.
+        let result = self.update_readers(true, |readers| {
+            match readers - TAIL_READER - TAIL_UPGRADABLE {
+                0 => Some(0),
+                others => Some(others | TAIL_UPGRADING),
+            }
+        });
+        if result == Ok(TAIL_READER | TAIL_UPGRADABLE) {
+            return true;
+        }
+
+        // The last reader wakes us up when it leaves, so we lock exclusively from validate()
+        // if it already left, which keeps us from parking.
+        let try_upgrade = || {
+            let result =
+                self.update_readers(true, |readers| (readers == TAIL_UPGRADING).then_some(0));
+            result.is_ok()
+        };
+
+        loop {
+            let primitive = Primitive::new("RwLock", self);
+            let validate = || !try_upgrade();
+            match parking::park_on(primitive, self.upgrade_key(), validate, timeout()) {
+                ParkResult::Invalid => return true,
+                ParkResult::Unparked => continue,
+                ParkResult::TimedOut => {}
+            }
+
+            // Trade the UPGRADING bit back for our read lock, unless the last reader left meanwhile.
+            let result = self.update_readers(true, |readers| match readers {
+                TAIL_UPGRADING => Some(0),
+                _ => Some(readers - TAIL_UPGRADING + TAIL_READER + TAIL_UPGRADABLE),
+            });
+            return result == Ok(TAIL_UPGRADING);
+        }
+    }
+
+    /// Turns the exclusive lock held by the caller into a shared one, held by `readers` as
+    /// counted on the queue tail.
+    ///
+    /// Threads which are already queued keep waiting until the lock is released.
+    #[cold]
+    unsafe fn downgrade_to(&self, readers: usize) {
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            assert_eq!(state.address() & (LOCKED | READING), LOCKED);
+
+            // Without waiting threads, the Remaining bits hold the reader count.
+            // Release barrier so that our writes happen before readers (and unlock_shared()) see the lock.
+            if state.address() & QUEUED == 0 {
+                let new_state = (readers << READER_SHIFT) | LOCKED | READING;
+                match self.state.compare_exchange_weak(
+                    state,
+                    state.with_address(new_state),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            // Otherwise, it's kept on the queue tail. Waiters may be unlinking themselves from the
+            // queue while it's locked exclusively, so grab the QUEUE_LOCKED bit before looking for it.
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    state = new_state;
+                    break;
+                }
+                Err(e) => state = e,
+            }
+        }
+
+        // Acquire barrier to ensure writes to waiters pushed to the queue happen before we scan it.
+        fence_acquire(&self.state);
+        let (_head, tail) = Waiter::get_and_link_queue(state, |_| {});
+        tail.as_ref().counter.store(readers, Ordering::Relaxed);
+
+        // Set the READING bit while releasing the QUEUE_LOCKED bit.
+        // Release barrier so that our writes happen before readers (and unlock_shared()) see the lock.
+        loop {
+            match self.state.compare_exchange_weak(
+                state,
+                state.map_address(|addr| (addr | READING) & !QUEUE_LOCKED),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(e) => state = e,
+            }
+        }
+    }
+
+    /// Acquires an exclusive lock, for a thread blocked on the `primitive` built on top of this
+    /// RwLock when contended.
+    #[inline]
+    pub(super) fn lock_exclusive_on(&self, primitive: Primitive) {
+        use lock_api::RawRwLock as _;
+        if !self.try_lock_exclusive() {
+            self.lock_exclusive_slow(primitive);
+            trace::acquired(self, LockKind::Exclusive);
+        }
+    }
+
+    #[cold]
+    fn lock_exclusive_slow(&self, primitive: Primitive) {
+        self.lock_exclusive_spinning(primitive, SpinWait::default())
+    }
+
+    /// Acquires an exclusive lock, queueing the thread right away instead of spinning when contended.
+    #[inline]
+    pub(super) fn lock_exclusive_without_spinning(&self, primitive: Primitive) {
+        if !self.try_lock_exclusive_fast() {
+            self.lock_exclusive_spinning(primitive, SpinWait::with_limit(0));
         }
+        trace::acquired(self, LockKind::Exclusive);
     }
 
     #[cold]
-    fn lock_exclusive_slow(&self) {
+    fn lock_exclusive_spinning(&self, primitive: Primitive, spin: SpinWait) {
         let is_writer = true;
         let try_lock = |state: *mut Waiter| -> Option<bool> {
             match state.address() & LOCKED {
@@ -413,26 +901,38 @@ impl RawRwLock {
             }
         };
 
-        self.lock_common(is_writer, try_lock)
+        self.lock_common(primitive, is_writer, spin, try_lock)
     }
 
     #[cold]
-    fn lock_shared_slow(&self) {
+    fn lock_shared_slow(&self, primitive: Primitive, upgradable: usize) {
         let is_writer = false;
         let try_lock = |state: *mut Waiter| -> Option<bool> {
-            let result = self.try_lock_shared_assuming(state)?;
+            let result = self.try_lock_shared_assuming(state, upgradable)?;
             Some(result.is_ok())
         };
 
-        self.lock_common(is_writer, try_lock)
+        self.lock_common(primitive, is_writer, SpinWait::default(), try_lock)
     }
 
-    fn lock_common(&self, is_writer: bool, mut try_lock: impl FnMut(*mut Waiter) -> Option<bool>) {
-        Waiter::with(|waiter| {
+    fn lock_common(
+        &self,
+        primitive: Primitive,
+        is_writer: bool,
+        mut spin: SpinWait,
+        mut try_lock: impl FnMut(*mut Waiter) -> Option<bool>,
+    ) {
+        #[cfg(feature = "metrics")]
+        crate::metrics::increment(crate::metrics::Counter::ContendedAcquisitions);
+        #[cfg(feature = "deadlock_detection")]
+        let _waiting = crate::deadlock::Waiting::new(self);
+        #[cfg(feature = "std")]
+        let _timed = crate::contention::Waiting::new(self);
+
+        Waiter::with(primitive, |waiter| {
             waiter.waiting_on.set(Some(NonNull::from(self).cast()));
             waiter.flags.set(is_writer as usize);
 
-            let mut spin = SpinWait::default();
             loop {
                 let mut state = self.state.load(Ordering::Relaxed);
                 loop {
@@ -456,8 +956,22 @@ impl RawRwLock {
                         continue;
                     }
 
+                    #[cfg(feature = "deadlock_detection")]
+                    if !is_writer {
+                        crate::deadlock::check_recursive_read(self as *const Self as usize);
+                    }
+
                     if unsafe { self.try_queue(&mut state, waiter.as_ref()) } {
                         assert!(waiter.parker.park(None));
+
+                        // A fair unlock hands the lock over to us without releasing it.
+                        if Self::is_handed_off(waiter.as_ref()) {
+                            return;
+                        }
+
+                        // We'll be queued at the head again if we fail to acquire the lock.
+                        waiter.bypassed.set(0);
+
                         break;
                     }
                 }
@@ -467,7 +981,7 @@ impl RawRwLock {
 
     #[cold]
     pub(super) unsafe fn try_requeue(&self, waiter: Pin<&Waiter>) -> bool {
-        let is_writer = waiter.flags.get() != 0;
+        let is_writer = waiter.flags.get() & WAITER_WRITER != 0;
         assert!(is_writer);
 
         let waiting_on = waiter.waiting_on.get();
@@ -487,6 +1001,21 @@ impl RawRwLock {
         }
     }
 
+    /// Like `try_requeue()`, but queues the waiter even if the lock is unlocked.
+    /// The caller must make sure that a thread is going to acquire the lock after this,
+    /// so that the waiter is woken up when it's released.
+    #[cold]
+    pub(super) unsafe fn requeue(&self, waiter: Pin<&Waiter>) {
+        let is_writer = waiter.flags.get() & WAITER_WRITER != 0;
+        assert!(is_writer);
+
+        let waiting_on = waiter.waiting_on.get();
+        assert_eq!(waiting_on, Some(NonNull::from(self).cast()));
+
+        let mut state = self.state.load(Ordering::Relaxed);
+        while !self.try_queue(&mut state, waiter.as_ref()) {}
+    }
+
     unsafe fn try_queue(&self, state: &mut *mut Waiter, waiter: Pin<&Waiter>) -> bool {
         // Prepare to push our waiter to the head of the wait queue.
         let waiter_ptr = NonNull::from(&*waiter).as_ptr();
@@ -602,10 +1131,28 @@ impl RawRwLock {
 
             // If the tail (the waiter to wake up) is a writer,
             // then we can just wake up that one and leave the rest queued.
-            let is_writer = tail.as_ref().flags.get() != 0;
+            let is_writer = tail.as_ref().flags.get() & WAITER_WRITER != 0;
             if is_writer {
                 // We only leave the reset queued if there is a "rest" to begin with.
                 if let Some(new_tail) = tail.as_ref().prev.get() {
+                    // Prefer waking up a higher priority writer, or one close to this thread to keep
+                    // the lock from migrating. It's unlinked from the middle of the queue, which
+                    // leaves the head and tail as is.
+                    // Release barrier ensures the queue updates happen before the next QUEUE_LOCKED bit owner.
+                    if let Some(preferred) = Self::find_preferred_writer(tail) {
+                        let prev = preferred.as_ref().prev.get();
+                        let prev = prev.expect("queued waiter without a previous waiter");
+                        let next = preferred.as_ref().next.get();
+                        let next = next.expect("queued waiter without a next waiter");
+                        prev.as_ref().next.set(Some(next));
+                        next.as_ref().prev.set(Some(prev));
+
+                        self.state
+                            .fetch_sub(state.with_address(QUEUE_LOCKED), Ordering::Release);
+                        preferred.as_ref().prev.set(None);
+                        return self.unpark_waiters(preferred);
+                    }
+
                     // The tail is dequeued by updating the cached head references to it with the new tail.
                     // Unset the QUEUE_LOCKED bit now that we have dequeued the tail for waking.
                     // Release barrier ensures the head/tail updates happen before the next QUEUE_LOCKED bit owner.
@@ -641,25 +1188,529 @@ impl RawRwLock {
         }
     }
 
-    #[cold]
-    unsafe fn unpark_waiters(&self, mut tail: NonNull<Waiter>) {
-        loop {
-            let waiting_on = tail.as_ref().waiting_on.get();
-            let waiting_on = waiting_on.expect("waking a waiter thats not waiting on anything");
+    /// Looks for a writer queued shortly after the `tail` writer which should be woken up first:
+    /// the first one with the highest priority if it's higher than the tail's, or otherwise the
+    /// first one with the same priority that started waiting in the locality domain (NUMA node
+    /// or last level cache) of the current thread, when the tail itself didn't.
+    ///
+    /// Each tail can only be bypassed a few times, for either reason, before it's woken up
+    /// regardless so that it isn't starved by a stream of higher priority writers. Readers are
+    /// never bypassed as they're woken up together from the tail.
+    unsafe fn find_preferred_writer(tail: NonNull<Waiter>) -> Option<NonNull<Waiter>> {
+        const MAX_BYPASS: usize = 4;
+        const MAX_SCAN: usize = 8;
+
+        // The deterministic feature wakes up waiters in strict FIFO order.
+        if cfg!(feature = "deterministic") {
+            return None;
+        }
 
-            assert_eq!(
-                waiting_on,
-                NonNull::from(self).cast(),
-                "waking a waiter thats not waiting on this lock",
-            );
+        let tail = tail.as_ref();
+        if tail.bypassed.get() >= MAX_BYPASS {
+            return None;
+        }
 
-            let prev = tail.as_ref().prev.get();
-            tail.as_ref().parker.unpark();
+        #[cfg(feature = "std")]
+        let by_priority = crate::priority::is_in_use();
+        #[cfg(not(feature = "std"))]
+        let by_priority = false;
+        let local_domain = if numa::is_multi_domain() {
+            Some(numa::current_domain()).filter(|&domain| tail.domain.get() != domain)
+        } else {
+            None
+        };
+
+        if !by_priority && local_domain.is_none() {
+            return None;
+        }
 
-            tail = match prev {
-                Some(prev) => prev,
+        let mut highest: Option<NonNull<Waiter>> = None;
+        let mut local = None;
+        let mut current = tail.prev.get();
+        for _ in 0..MAX_SCAN {
+            let waiter = match current {
+                Some(waiter) => waiter,
                 None => break,
             };
+
+            let waiter_ref = waiter.as_ref();
+            if waiter_ref.flags.get() & WAITER_WRITER == 0 {
+                break;
+            }
+
+            // The head can't be unlinked without updating the state,
+            // which new waiters may be pushing onto concurrently.
+            current = match waiter_ref.prev.get() {
+                Some(prev) => Some(prev),
+                None => break,
+            };
+
+            let priority = waiter_ref.priority.get();
+            let best = highest.map_or(tail.priority.get(), |w| w.as_ref().priority.get());
+            if priority > best {
+                highest = Some(waiter);
+            }
+
+            if local.is_none()
+                && local_domain == Some(waiter_ref.domain.get())
+                && priority == tail.priority.get()
+            {
+                local = Some(waiter);
+                if !by_priority {
+                    break;
+                }
+            }
+        }
+
+        let preferred = highest.or(local)?;
+        tail.bypassed.set(tail.bypassed.get() + 1);
+        Some(preferred)
+    }
+
+    #[cold]
+    unsafe fn unpark_waiters(&self, tail: NonNull<Waiter>) {
+        chaos::wake_list(
+            Some(tail),
+            |waiter| waiter.prev.get(),
+            |waiter| {
+                let waiting_on = waiter.as_ref().waiting_on.get();
+                let waiting_on = waiting_on.expect("waking a waiter thats not waiting on anything");
+
+                assert_eq!(
+                    waiting_on,
+                    NonNull::from(self).cast(),
+                    "waking a waiter thats not waiting on this lock",
+                );
+
+                waiter.as_ref().parker.unpark();
+            },
+        );
+    }
+
+    #[inline]
+    pub(super) fn is_queued(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state.address() & QUEUED != 0
+    }
+
+    /// Returns the address of the waiter which queued last, or 0 if there are none.
+    #[cfg(test)]
+    pub(super) fn queue_head(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed).address();
+        match state & QUEUED {
+            0 => 0,
+            _ => state & Waiter::MASK,
+        }
+    }
+
+    /// Returns true if the waiter was woken up by a fair unlock
+    /// which handed it the lock without ever releasing it.
+    pub(super) fn is_handed_off(waiter: Pin<&Waiter>) -> bool {
+        waiter.flags.get() & WAITER_HANDOFF != 0
+    }
+
+    #[inline]
+    pub(super) unsafe fn unlock_exclusive_fair(&self) {
+        trace::released(self, LockKind::Exclusive);
+
+        // Fast path for when there's no waiting threads to hand the lock off to.
+        if self
+            .state
+            .compare_exchange(
+                invalid_mut(LOCKED),
+                invalid_mut(UNLOCKED),
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            self.unlock_exclusive_fair_slow();
+        }
+    }
+
+    #[cold]
+    unsafe fn unlock_exclusive_fair_slow(&self) {
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            assert_eq!(state.address() & (LOCKED | READING), LOCKED);
+
+            // There's no one to hand the lock off to, so unlock it normally.
+            if state.address() & QUEUED == 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state.with_address(UNLOCKED),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            // Another thread is updating the queue.
+            // It will release the QUEUE_LOCKED bit soon since we still hold the lock.
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Grab the QUEUE_LOCKED bit while keeping the RwLock locked.
+            // Acquire barrier is done in handoff() before the waiters are accessed.
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return self.handoff(new_state),
+                Err(e) => state = e,
+            }
+        }
+    }
+
+    #[cold]
+    unsafe fn handoff(&self, mut state: *mut Waiter) {
+        loop {
+            assert_eq!(state.address() & (LOCKED | READING), LOCKED);
+            assert_ne!(state.address() & QUEUED, 0);
+            assert_ne!(state.address() & QUEUE_LOCKED, 0);
+
+            // Fix and get the ends of the wait queue in order to hand the lock to the tail.
+            // Acquire barrier ensures that writes to waiters pushed to the queue
+            // happen before we start fixing/getting it.
+            fence_acquire(&self.state);
+            let (head, tail) = Waiter::get_and_link_queue(state, |_| {});
+
+            // Readers can't be handed an exclusively held lock.
+            // Release the QUEUE_LOCKED bit and unlock normally to wake them up instead.
+            let is_writer = tail.as_ref().flags.get() & WAITER_WRITER != 0;
+            if !is_writer {
+                let new_state = state.map_address(|addr| addr & !QUEUE_LOCKED);
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return self.unlock_exclusive_fast(),
+                    Err(e) => state = e,
+                }
+                continue;
+            }
+
+            // Dequeue the tail while leaving the rest queued (if any) and the lock held.
+            // Release barrier ensures the head/tail updates happen before the next QUEUE_LOCKED bit owner.
+            if let Some(new_tail) = tail.as_ref().prev.get() {
+                head.as_ref().tail.set(Some(new_tail));
+                self.state
+                    .fetch_sub(state.with_address(QUEUE_LOCKED), Ordering::Release);
+            } else {
+                // The tail is the only waiter so the queue is removed entirely.
+                // Release barrier ensures the RwLock-protected writes happen before the waiter is handed the lock.
+                if let Err(e) = self.state.compare_exchange_weak(
+                    state,
+                    state.with_address(LOCKED),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    state = e;
+                    continue;
+                }
+            }
+
+            // Mark the tail as now owning the lock and wake it up.
+            let flags = tail.as_ref().flags.get();
+            tail.as_ref().flags.set(flags | WAITER_HANDOFF);
+            tail.as_ref().prev.set(None);
+            return self.unpark_waiters(tail);
+        }
+    }
+
+    /// Acquires an exclusive lock by queueing the given waiter, giving up once `is_cancelled` returns true.
+    /// Returns whether the lock was acquired.
+    ///
+    /// Threads which cancel the acquisition must wake up the waiter through `cancel_waiter()`.
+    pub(super) fn lock_exclusive_cancellable(
+        &self,
+        waiter: Pin<&Waiter>,
+        is_cancelled: impl Fn() -> bool,
+    ) -> bool {
+        let acquired = self.lock_abortable(
+            waiter,
+            true,
+            SpinWait::default(),
+            |state| self.try_lock_exclusive_if_unlocked(state),
+            is_cancelled,
+            |waiter| waiter.parker.park(None),
+        );
+        if acquired {
+            trace::acquired(self, LockKind::Exclusive);
+        }
+        acquired
+    }
+
+    /// Acquires an exclusive lock, spinning with `spin` before queueing and giving up once
+    /// the deadline passed. Returns whether the lock was acquired.
+    #[cfg(feature = "std")]
+    pub(super) fn lock_exclusive_until(
+        &self,
+        primitive: Primitive,
+        spin: SpinWait,
+        deadline: Instant,
+    ) -> bool {
+        let acquired = Waiter::with(primitive, |waiter| {
+            self.lock_abortable(
+                waiter,
+                true,
+                spin,
+                |state| self.try_lock_exclusive_if_unlocked(state),
+                || clock::now() >= deadline,
+                |waiter| waiter.parker.park_until(Some(deadline)),
+            )
+        });
+        if acquired {
+            trace::acquired(self, LockKind::Exclusive);
+        }
+        acquired
+    }
+
+    /// Acquires a read lock, as the upgradable one if `upgradable` is UPGRADABLE,
+    /// giving up once the deadline passed. Returns whether the lock was acquired.
+    #[cfg(feature = "std")]
+    #[cold]
+    fn lock_shared_until(&self, deadline: Instant, upgradable: usize) -> bool {
+        Waiter::with(Primitive::new("RwLock", self), |waiter| {
+            self.lock_abortable(
+                waiter,
+                false,
+                SpinWait::default(),
+                |state| {
+                    let result = self.try_lock_shared_assuming(state, upgradable)?;
+                    Some(result.is_ok())
+                },
+                || clock::now() >= deadline,
+                |waiter| waiter.parker.park_until(Some(deadline)),
+            )
+        })
+    }
+
+    #[inline(always)]
+    fn try_lock_exclusive_if_unlocked(&self, state: *mut Waiter) -> Option<bool> {
+        match state.address() & LOCKED {
+            0 => Some(self.try_lock_exclusive_assuming(state)),
+            _ => None,
+        }
+    }
+
+    /// Acquires the lock like `lock_common()`, giving up once `is_aborted` returns true before
+    /// queueing or once `park` returns false while queued. Returns whether the lock was acquired.
+    fn lock_abortable(
+        &self,
+        waiter: Pin<&Waiter>,
+        is_writer: bool,
+        mut spin: SpinWait,
+        mut try_lock: impl FnMut(*mut Waiter) -> Option<bool>,
+        is_aborted: impl Fn() -> bool,
+        park: impl Fn(Pin<&Waiter>) -> bool,
+    ) -> bool {
+        #[cfg(feature = "metrics")]
+        crate::metrics::increment(crate::metrics::Counter::ContendedAcquisitions);
+        #[cfg(feature = "deadlock_detection")]
+        let _waiting = crate::deadlock::Waiting::new(self);
+        #[cfg(feature = "std")]
+        let _timed = crate::contention::Waiting::new(self);
+
+        waiter.waiting_on.set(Some(NonNull::from(self).cast()));
+        waiter.flags.set(is_writer as usize);
+
+        loop {
+            let mut state = self.state.load(Ordering::Relaxed);
+            loop {
+                let mut backoff = SpinWait::default();
+                while let Some(was_locked) = try_lock(state) {
+                    if was_locked {
+                        return true;
+                    }
+
+                    backoff.yield_now();
+                    state = self.state.load(Ordering::Relaxed);
+                }
+
+                if is_aborted() {
+                    return false;
+                }
+
+                if (state.address() & QUEUED == 0) && spin.try_yield_now() {
+                    state = self.state.load(Ordering::Relaxed);
+                    continue;
+                }
+
+                if !unsafe { self.try_queue(&mut state, waiter) } {
+                    continue;
+                }
+
+                // The cancelling thread may have missed our waiter if it looked before we queued it.
+                // SeqCst fence pairs with the one in cancel() to ensure that either it sees
+                // our waiter in the queue or we see the cancellation and unlink ourselves.
+                fence(Ordering::SeqCst);
+                if is_aborted() && unsafe { self.cancel_waiter(waiter, false) } {
+                    return false;
+                }
+
+                // On timeout, unlink ourselves from the queue unless another thread already
+                // dequeued us, in which case it's about to wake us up and we must wait for it.
+                if !park(waiter) {
+                    if unsafe { self.cancel_waiter(waiter, false) } {
+                        return false;
+                    }
+                    assert!(waiter.parker.park(None));
+                }
+
+                if Self::is_handed_off(waiter) {
+                    return true;
+                }
+
+                // We'll be queued at the head again if we fail to acquire the lock.
+                waiter.bypassed.set(0);
+
+                break;
+            }
+        }
+    }
+
+    /// Unlinks the waiter from the queue, waking it up if `wake` is set.
+    /// Returns false if the waiter was already dequeued and is being woken up by another thread.
+    #[cold]
+    pub(super) unsafe fn cancel_waiter(&self, waiter: Pin<&Waiter>, wake: bool) -> bool {
+        let mut spin = SpinWait::default();
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            // The waiter can't be queued if there's no queue.
+            if state.address() & QUEUED == 0 {
+                return false;
+            }
+
+            // The QUEUE_LOCKED bit holder never blocks so wait for it to finish.
+            if state.address() & QUEUE_LOCKED != 0 {
+                if !spin.try_yield_now() {
+                    shared::yield_now();
+                }
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            let new_state = state.map_address(|addr| addr | QUEUE_LOCKED);
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    state = new_state;
+                    break;
+                }
+                Err(e) => state = e,
+            }
+        }
+
+        let target = NonNull::from(&*waiter);
+        loop {
+            assert_ne!(state.address() & QUEUED, 0);
+            assert_ne!(state.address() & QUEUE_LOCKED, 0);
+
+            // Acquire barrier ensures that writes to waiters pushed to the queue
+            // happen before we start fixing/searching it.
+            fence_acquire(&self.state);
+            let (head, tail) = Waiter::get_and_link_queue(state, |_| {});
+
+            // Search the queue from the tail since the `next` link of the tail may be stale.
+            let mut current = Some(tail);
+            while let Some(waiter) = current.filter(|&waiter| waiter != target) {
+                current = waiter.as_ref().prev.get();
+            }
+
+            // The waiter was dequeued by another thread which is going to wake it up.
+            // Release the QUEUE_LOCKED bit (or wake up a waiter if the lock was released meanwhile).
+            if current.is_none() {
+                self.unpark(state);
+                return false;
+            }
+
+            if target == head {
+                // Replace the head of the queue, or remove the queue entirely if it was the only waiter.
+                // This fails if a new waiter was pushed to the head, in which case we retry unlinking.
+                // Release barrier ensures the queue updates happen before the next QUEUE_LOCKED bit holder.
+                let new_state = match waiter.next.get().filter(|_| target != tail) {
+                    Some(new_head) => {
+                        new_head.as_ref().tail.set(Some(tail));
+                        new_head.as_ref().prev.set(None);
+                        new_head
+                            .as_ptr()
+                            .map_address(|addr| addr | (state.address() & !Waiter::MASK))
+                    }
+                    // The reader count of a lock held shared moves back to the state.
+                    None if state.address() & READING != 0 => {
+                        let readers = waiter.counter.load(Ordering::Relaxed);
+                        state.with_address((readers << READER_SHIFT) | LOCKED | READING)
+                    }
+                    None => state.map_address(|addr| addr & LOCKED),
+                };
+
+                if let Err(e) = self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    state = e;
+                    continue;
+                }
+
+                state = new_state;
+            } else if target == tail {
+                let new_tail = waiter
+                    .prev
+                    .get()
+                    .expect("queue tail without a previous waiter");
+                head.as_ref().tail.set(Some(new_tail));
+
+                // The reader count of a lock held shared moves along with the tail.
+                let readers = waiter.counter.load(Ordering::Relaxed);
+                new_tail.as_ref().counter.store(readers, Ordering::Relaxed);
+            } else {
+                let prev = waiter
+                    .prev
+                    .get()
+                    .expect("queued waiter without a previous waiter");
+                let next = waiter
+                    .next
+                    .get()
+                    .expect("queued waiter without a next waiter");
+                prev.as_ref().next.set(Some(next));
+                next.as_ref().prev.set(Some(prev));
+            }
+
+            // Release the QUEUE_LOCKED bit if we didn't remove the queue entirely.
+            // If the lock was released while we held it, we're also in charge of waking up the next waiter.
+            if state.address() & QUEUED != 0 {
+                self.unpark(state);
+            }
+
+            waiter.prev.set(None);
+            if wake {
+                waiter.parker.unpark();
+            }
+
+            return true;
         }
     }
 }
@@ -711,6 +1762,9 @@ impl RawRwLock {
 /// - Inline fast path for the uncontended case.
 /// - Efficient handling of micro-contention using adaptive spinning.
 /// - Allows raw locking & unlocking without a guard.
+/// - Write locks can be downgraded to read locks, and both can be acquired with a timeout.
+/// - Upgradable read locks, which share the lock with readers and can be atomically upgraded
+///   to write locks.
 ///
 /// # Examples
 ///
@@ -744,6 +1798,10 @@ pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawRwLock, T>;
 /// dropped.
 pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawRwLock, T>;
 
+/// RAII structure used to release the upgradable read access of a lock when
+/// dropped.
+pub type RwLockUpgradableReadGuard<'a, T> = lock_api::RwLockUpgradableReadGuard<'a, RawRwLock, T>;
+
 /// An RAII read lock guard returned by `RwLockReadGuard::map`, which can point to a
 /// subfield of the protected data.
 ///
@@ -762,6 +1820,62 @@ pub type MappedRwLockReadGuard<'a, T> = lock_api::MappedRwLockReadGuard<'a, RawR
 /// thread.
 pub type MappedRwLockWriteGuard<'a, T> = lock_api::MappedRwLockWriteGuard<'a, RawRwLock, T>;
 
+/// Extension methods for [`RwLock`] which aren't part of `lock_api`.
+pub trait RwLockExt<T: ?Sized> {
+    /// Acquires a read lock, runs `f` on the protected data and releases the lock again.
+    ///
+    /// The guard never escapes the closure, so the lock can't accidentally stay held
+    /// for longer than the closure runs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::{RwLock, RwLockExt};
+    ///
+    /// let config = RwLock::new(vec![1, 2]);
+    /// config.with_write(|config| config.push(3));
+    /// assert_eq!(config.with_read(|config| config.len()), 3);
+    /// ```
+    fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R;
+
+    /// Acquires the write lock, runs `f` on the protected data and releases the lock again.
+    fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+
+    /// Attempts to acquire a read lock, spinning for up to `spins` iterations while it's
+    /// write locked before giving up, without ever parking the thread.
+    ///
+    /// See [`MutexExt::try_lock_spin`](crate::MutexExt::try_lock_spin).
+    fn try_read_spin(&self, spins: usize) -> Option<RwLockReadGuard<'_, T>>;
+
+    /// Attempts to acquire the write lock, spinning for up to `spins` iterations while it's
+    /// locked before giving up, without ever parking the thread.
+    ///
+    /// See [`MutexExt::try_lock_spin`](crate::MutexExt::try_lock_spin).
+    fn try_write_spin(&self, spins: usize) -> Option<RwLockWriteGuard<'_, T>>;
+}
+
+impl<T: ?Sized> RwLockExt<T> for RwLock<T> {
+    #[inline]
+    fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&*self.read())
+    }
+
+    #[inline]
+    fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut *self.write())
+    }
+
+    #[inline]
+    fn try_read_spin(&self, spins: usize) -> Option<RwLockReadGuard<'_, T>> {
+        crate::mutex::try_spin(spins, || self.is_locked_exclusive(), || self.try_read())
+    }
+
+    #[inline]
+    fn try_write_spin(&self, spins: usize) -> Option<RwLockWriteGuard<'_, T>> {
+        crate::mutex::try_spin(spins, || self.is_locked(), || self.try_write())
+    }
+}
+
 /// Creates a new instance of an `RwLock<T>` which is unlocked.
 ///
 /// This allows creating a `RwLock<T>` in a constant context on stable Rust.
@@ -771,7 +1885,7 @@ pub const fn const_rwlock<T>(value: T) -> RwLock<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::RwLock;
+    use crate::{RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard};
     use rand::Rng;
     use std::{
         sync::{
@@ -780,6 +1894,7 @@ mod tests {
             Arc,
         },
         thread,
+        time::{Duration, Instant},
     };
 
     #[derive(Eq, PartialEq, Debug)]
@@ -794,6 +1909,38 @@ mod tests {
         drop(l.write());
     }
 
+    #[test]
+    fn test_try_spin() {
+        use crate::RwLockExt;
+
+        let l = RwLock::new(());
+        let read = l.try_read_spin(0).unwrap();
+        assert!(l.try_read_spin(100).is_some());
+        assert!(l.try_write_spin(100).is_none());
+        drop(read);
+
+        let write = l.try_write_spin(0).unwrap();
+        assert!(l.try_read_spin(100).is_none());
+        assert!(l.try_write_spin(100).is_none());
+        drop(write);
+        assert!(l.try_write_spin(100).is_some());
+    }
+
+    #[test]
+    fn test_with_read_write() {
+        use crate::RwLockExt;
+
+        let l = RwLock::new(vec![1]);
+        l.with_read(|v| {
+            // Read locks can be held together.
+            assert_eq!(l.with_read(|v| v.len()), 1);
+            assert_eq!(v[0], 1);
+        });
+        l.with_write(|v| v.push(2));
+        assert!(!l.is_locked());
+        assert_eq!(*l.read(), [1, 2]);
+    }
+
     #[test]
     fn frob() {
         const N: u32 = 10;
@@ -821,6 +1968,59 @@ mod tests {
         let _ = rx.recv();
     }
 
+    #[test]
+    fn frob_upgradable_timed() {
+        const N: u32 = 10;
+        const M: u32 = if cfg!(miri) { 100 } else { 1000 };
+
+        let r = Arc::new(RwLock::new(0u32));
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let r = r.clone();
+                thread::spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    let timeout = Duration::from_micros(50);
+                    let mut writes = 0;
+                    for _ in 0..M {
+                        match rng.gen_range(0..6) {
+                            0 => {
+                                *r.write() += 1;
+                                writes += 1;
+                            }
+                            1 => {
+                                let upgradable = r.upgradable_read();
+                                *RwLockUpgradableReadGuard::upgrade(upgradable) += 1;
+                                writes += 1;
+                            }
+                            2 => {
+                                let upgradable = r.try_upgradable_read_for(timeout);
+                                let upgraded = upgradable.map(|upgradable| {
+                                    RwLockUpgradableReadGuard::try_upgrade_for(upgradable, timeout)
+                                });
+                                if let Some(Ok(mut write)) = upgraded {
+                                    *write += 1;
+                                    writes += 1;
+                                }
+                            }
+                            3 => {
+                                if let Some(mut write) = r.try_write_for(timeout) {
+                                    *write += 1;
+                                    writes += 1;
+                                }
+                            }
+                            4 => drop(r.try_read_for(timeout)),
+                            _ => drop(r.read()),
+                        }
+                    }
+                    writes
+                })
+            })
+            .collect();
+
+        let writes: u32 = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(*r.read(), writes);
+    }
+
     #[test]
     fn test_rw_arc_no_poison_wr() {
         let arc = Arc::new(RwLock::new(1));
@@ -1043,6 +2243,201 @@ mod tests {
         sync(rwlock.write());
     }
 
+    #[test]
+    fn test_rwlock_unlock_fair() {
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.write();
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() += 1)
+        };
+
+        // Wait for the writer to queue, for the lock to be handed off to it.
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::yield_now();
+        }
+        RwLockWriteGuard::unlock_fair(guard);
+        t.join().unwrap();
+
+        let mut guard = lock.read();
+        RwLockReadGuard::bump(&mut guard);
+        RwLockReadGuard::unlock_fair(guard);
+        assert_eq!(*lock.write(), 1);
+    }
+
+    #[test]
+    fn test_rwlock_downgrade() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut guard = lock.write();
+        *guard += 1;
+
+        // Other readers can share the downgraded lock, but writers can't acquire it.
+        let guard = RwLockWriteGuard::downgrade(guard);
+        assert_eq!(*lock.try_read().unwrap(), 1);
+        assert!(lock.try_write().is_none());
+        drop(guard);
+
+        // The reader count moves to the queue when threads are waiting.
+        let guard = lock.write();
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() += 1)
+        };
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::yield_now();
+        }
+        let guard = RwLockWriteGuard::downgrade(guard);
+        assert_eq!(*guard, 1);
+        drop(guard);
+
+        t.join().unwrap();
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn test_rwlock_timed() {
+        let lock = Arc::new(RwLock::new(()));
+        let read = lock.read();
+        assert!(lock.try_read_for(Duration::from_millis(10)).is_some());
+        assert!(lock.try_write_for(Duration::from_millis(10)).is_none());
+
+        // A timed writer acquires the lock once the readers leave.
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || lock.try_write_for(Duration::from_secs(60)).is_some())
+        };
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::yield_now();
+        }
+        drop(read);
+        assert!(t.join().unwrap());
+
+        let write = lock.write();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(lock.try_read_until(deadline).is_none());
+        drop(write);
+        assert!(lock.try_read_until(Instant::now()).is_some());
+    }
+
+    #[test]
+    fn test_rwlock_timed_queued() {
+        let lock = Arc::new(RwLock::new(0));
+        let read = lock.read();
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() += 1)
+        };
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::yield_now();
+        }
+
+        // Timed attempts queue behind the writer, and leave the queue when they time out
+        // even though readers hold the lock.
+        assert!(lock.try_read_for(Duration::from_millis(10)).is_none());
+        assert!(lock.try_write_for(Duration::from_millis(10)).is_none());
+        assert!(lock
+            .try_upgradable_read_for(Duration::from_millis(10))
+            .is_none());
+
+        // A timed reader queued behind the writer acquires the lock after it.
+        let t2 = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let read = lock.try_read_for(Duration::from_secs(60));
+                read.map(|read| *read)
+            })
+        };
+        let head = unsafe { lock.raw() }.queue_head();
+        while unsafe { lock.raw() }.queue_head() == head {
+            thread::yield_now();
+        }
+        drop(read);
+        t.join().unwrap();
+        assert_eq!(t2.join().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_rwlock_upgradable_read() {
+        let lock = Arc::new(RwLock::new(0));
+        let upgradable = lock.upgradable_read();
+
+        // Readers can share the lock with the upgradable reader, but not other upgradable readers.
+        assert!(lock.try_read().is_some());
+        assert!(lock.try_upgradable_read().is_none());
+        assert!(lock.try_write().is_none());
+
+        // Upgrading is only possible once the other readers left.
+        let read = lock.read();
+        let upgradable = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap_err();
+        let upgradable = RwLockUpgradableReadGuard::try_upgrade_for(upgradable, Duration::ZERO);
+        let upgradable = upgradable.unwrap_err();
+        drop(read);
+        let mut write = RwLockUpgradableReadGuard::try_upgrade(upgradable).unwrap();
+        *write += 1;
+
+        // The downgraded lock can be upgraded again, or turned into a normal read lock.
+        let upgradable = RwLockWriteGuard::downgrade_to_upgradable(write);
+        assert!(lock.try_upgradable_read().is_none());
+        let mut write = RwLockUpgradableReadGuard::upgrade(upgradable);
+        *write += 1;
+        let upgradable = RwLockWriteGuard::downgrade_to_upgradable(write);
+        let read = RwLockUpgradableReadGuard::downgrade(upgradable);
+        assert_eq!(*lock.try_upgradable_read().unwrap(), 2);
+        drop(read);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_rwlock_upgrade_waits_for_readers() {
+        let lock = Arc::new(RwLock::new(0));
+        let read = lock.read();
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let upgradable = lock.upgradable_read();
+                *RwLockUpgradableReadGuard::upgrade(upgradable) += 1;
+            })
+        };
+
+        // New readers can't acquire the lock while the upgrade waits for the existing ones.
+        while lock.try_read().is_some() {
+            thread::yield_now();
+        }
+        assert_eq!(*read, 0);
+        drop(read);
+
+        t.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_rwlock_upgrade_queued() {
+        let lock = Arc::new(RwLock::new(0));
+        let write = lock.write();
+        let t = {
+            let lock = lock.clone();
+            thread::spawn(move || *lock.write() += 1)
+        };
+        while !unsafe { lock.raw() }.is_queued() {
+            thread::yield_now();
+        }
+
+        // The reader count moves to the queue while threads are waiting.
+        let upgradable = RwLockWriteGuard::downgrade_to_upgradable(write);
+        assert!(lock.try_read().is_none());
+        let upgradable = RwLockUpgradableReadGuard::try_upgrade_for(upgradable, Duration::ZERO);
+        let mut write = upgradable.unwrap();
+        *write += 1;
+
+        let upgradable = RwLockWriteGuard::downgrade_to_upgradable(write);
+        let read = RwLockUpgradableReadGuard::downgrade(upgradable);
+        assert_eq!(*read, 1);
+        drop(read);
+
+        t.join().unwrap();
+        assert_eq!(*lock.read(), 2);
+    }
+
     #[test]
     fn test_rwlock_debug() {
         let x = RwLock::new(vec![0u8, 10]);
@@ -1052,6 +2447,31 @@ mod tests {
         assert_eq!(format!("{:?}", x), "RwLock { data: <locked> }");
     }
 
+    #[test]
+    fn test_raw_rwlock_debug() {
+        let x = RwLock::new(());
+        let raw = unsafe { x.raw() };
+        assert_eq!(
+            format!("{:?}", raw),
+            "RawRwLock { locked: unlocked, has_waiters: false }"
+        );
+
+        {
+            let _a = x.read();
+            let _b = x.read();
+            assert_eq!(
+                format!("{:?}", raw),
+                "RawRwLock { locked: shared, readers: 2, has_waiters: false }"
+            );
+        }
+
+        let _lock = x.write();
+        assert_eq!(
+            format!("{:?}", raw),
+            "RawRwLock { locked: exclusive, has_waiters: false }"
+        );
+    }
+
     #[test]
     fn test_clone() {
         let rwlock = RwLock::new(Arc::new(1));
@@ -1071,7 +2491,7 @@ mod tests {
         }
 
         thread_local! {
-            static B: Bar = Bar(RwLock::new(()));
+            static B: Bar = const { Bar(RwLock::new(())) };
         }
 
         thread::spawn(|| {
@@ -1101,4 +2521,105 @@ mod tests {
             assert!(lock.is_locked_exclusive());
         }
     }
+
+    #[test]
+    fn numa_local_wakeups() {
+        const THREADS: usize = 8;
+        const ITERS: usize = if cfg!(miri) { 100 } else { 2_000 };
+
+        let lock = Arc::new(RwLock::new(0usize));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    // Pretend the threads are spread over two nodes so that
+                    // unlocking bypasses remote writers at the tail of the queue.
+                    crate::shared::numa::tests::fake_node(i % 2);
+                    for j in 0..ITERS {
+                        if j % 8 == 0 {
+                            drop(lock.read());
+                        } else {
+                            // Yield while holding the lock for the other threads to queue up.
+                            let mut guard = lock.write();
+                            *guard += 1;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), THREADS * (ITERS - ITERS / 8));
+    }
+
+    #[test]
+    #[cfg(not(feature = "deterministic"))]
+    fn priority_wakeups() {
+        const PRIORITIES: [u8; 4] = [0, 0, 5, 0];
+
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let guard = lock.write();
+        let threads: Vec<_> = PRIORITIES
+            .iter()
+            .map(|&priority| {
+                let head = unsafe { lock.raw() }.queue_head();
+                let t = {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        crate::priority::set_thread_priority(priority);
+                        lock.write().push(priority);
+                    })
+                };
+                // Let the thread queue up before spawning the next one.
+                while unsafe { lock.raw() }.queue_head() == head {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        // The high priority writer is woken up before the ones queued earlier.
+        drop(guard);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), [5, 0, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "deterministic"))]
+    fn priority_wakeups_bounded_bypass() {
+        const PRIORITIES: [u8; 7] = [0, 5, 5, 5, 5, 5, 0];
+
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let guard = lock.write();
+        let threads: Vec<_> = PRIORITIES
+            .iter()
+            .map(|&priority| {
+                let head = unsafe { lock.raw() }.queue_head();
+                let t = {
+                    let lock = lock.clone();
+                    thread::spawn(move || {
+                        crate::priority::set_thread_priority(priority);
+                        lock.write().push(priority);
+                    })
+                };
+                // Let the thread queue up before spawning the next one.
+                while unsafe { lock.raw() }.queue_head() == head {
+                    thread::yield_now();
+                }
+                t
+            })
+            .collect();
+
+        // The low priority writer at the tail is only bypassed a few times.
+        drop(guard);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), [5, 5, 5, 5, 0, 5, 0]);
+    }
 }
@@ -0,0 +1,719 @@
+use super::{
+    const_mutex,
+    shared::{Primitive, Waiter},
+    Mutex,
+};
+use alloc::sync::Arc;
+use std::{cmp, fmt, mem, pin::Pin, ptr::NonNull, sync::atomic::Ordering};
+
+/// Set in the flags of a waiter woken up because the semaphore was closed.
+const WAITER_CLOSED: usize = 1;
+
+/// A counting semaphore which hands out permits to threads in strict FIFO order.
+///
+/// Threads are served in the order they started waiting: a thread asking for many permits
+/// at once with [`acquire_many`](Semaphore::acquire_many) blocks every thread queued after it,
+/// even those which only need a single permit which is already available. This means that
+/// large requests can't be starved forever by a stream of smaller ones.
+///
+/// A semaphore can be [`close`](Semaphore::close)d to shut it down, failing every
+/// pending and future acquisition with an [`AcquireError`].
+///
+/// # Examples
+///
+/// ```
+/// use usync::Semaphore;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// // Allow at most two threads to work at the same time.
+/// let semaphore = Arc::new(Semaphore::new(2));
+///
+/// let handles: Vec<_> = (0..10)
+///     .map(|_| {
+///         let semaphore = semaphore.clone();
+///         thread::spawn(move || {
+///             let _permit = semaphore.acquire().unwrap();
+///             // ... do some work ...
+///         })
+///     })
+///     .collect();
+///
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(semaphore.available_permits(), 2);
+/// ```
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+/// The available permits along with a FIFO queue of waiting threads,
+/// linked through their `next` fields. Each waiter's counter holds the permits it wants.
+/// The debt counts the removed permits which were held at the time, and are taken from
+/// the next released ones.
+struct State {
+    permits: usize,
+    debt: usize,
+    closed: bool,
+    head: Option<NonNull<Waiter>>,
+    tail: Option<NonNull<Waiter>>,
+}
+
+// SAFETY: Queued waiters are only accessed while the state is locked
+// and they only return after being removed from the queue and unparked.
+unsafe impl Send for State {}
+
+impl Default for Semaphore {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the given amount of available permits.
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: const_mutex(State {
+                permits,
+                debt: 0,
+                closed: false,
+                head: None,
+                tail: None,
+            }),
+        }
+    }
+
+    /// Returns the amount of permits which are currently available.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Returns true if [`close`](Semaphore::close) was called on this semaphore.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+
+    /// Acquires a single permit, blocking the current thread until one is available.
+    ///
+    /// The permit is returned to the semaphore when the [`SemaphorePermit`] is dropped.
+    /// Returns an error if the semaphore is closed before the permit could be acquired.
+    #[inline]
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `n` permits at once, blocking the current thread until they are all available.
+    ///
+    /// The permits are only taken when all `n` of them can be acquired together and every
+    /// thread which started waiting before this one has been served. Asking for more permits
+    /// than will ever be released blocks until the semaphore is closed.
+    ///
+    /// Returns an error if the semaphore is closed before the permits could be acquired.
+    pub fn acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        if !self.try_take(n) {
+            self.acquire_slow(n)?;
+        }
+
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    /// Tries to acquire a single permit without blocking.
+    ///
+    /// Returns `None` if no permit is available or the semaphore is closed.
+    #[inline]
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// Tries to acquire `n` permits at once without blocking.
+    ///
+    /// This fails if not enough permits are available or if other threads are already
+    /// waiting for permits, as taking them would jump ahead of the queue,
+    /// or if the semaphore is closed.
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        match self.try_take(n) {
+            true => Some(SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            }),
+            false => None,
+        }
+    }
+
+    /// Acquires a single permit like [`acquire`](Semaphore::acquire), returning a guard
+    /// which keeps the semaphore alive through an `Arc` instead of borrowing it.
+    ///
+    /// The returned [`OwnedPermit`] is `'static` so it can be moved into other threads
+    /// and release the permit when dropped there.
+    #[inline]
+    pub fn acquire_owned(self: &Arc<Self>) -> Result<OwnedPermit, AcquireError> {
+        self.acquire_many_owned(1)
+    }
+
+    /// Acquires `n` permits at once like [`acquire_many`](Semaphore::acquire_many),
+    /// returning a guard which keeps the semaphore alive through an `Arc`.
+    pub fn acquire_many_owned(self: &Arc<Self>, n: usize) -> Result<OwnedPermit, AcquireError> {
+        self.acquire_many(n)?.forget();
+        Ok(OwnedPermit {
+            semaphore: self.clone(),
+            permits: n,
+        })
+    }
+
+    /// Tries to acquire a single permit without blocking, returning a guard which
+    /// keeps the semaphore alive through an `Arc`.
+    #[inline]
+    pub fn try_acquire_owned(self: &Arc<Self>) -> Option<OwnedPermit> {
+        self.try_acquire_many_owned(1)
+    }
+
+    /// Tries to acquire `n` permits at once without blocking, returning a guard which
+    /// keeps the semaphore alive through an `Arc`.
+    pub fn try_acquire_many_owned(self: &Arc<Self>, n: usize) -> Option<OwnedPermit> {
+        self.try_acquire_many(n).map(|permit| {
+            permit.forget();
+            OwnedPermit {
+                semaphore: self.clone(),
+                permits: n,
+            }
+        })
+    }
+
+    /// Adds `n` permits to the semaphore, waking up the queued threads whose requests
+    /// can now be satisfied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of available permits overflows a `usize`.
+    pub fn release(&self, n: usize) {
+        let woken = {
+            let mut state = self.state.lock();
+
+            // Permits which were removed while held aren't made available again.
+            let repaid = cmp::min(state.debt, n);
+            state.debt -= repaid;
+            state.permits = state
+                .permits
+                .checked_add(n - repaid)
+                .expect("Semaphore permit count overflowed");
+
+            // Serve the waiters in the queue order, stopping at the first one
+            // which wants more permits than are available so that it isn't starved.
+            let woken = state.head;
+            let mut last = None;
+            while let Some(waiter) = state.head {
+                let wanted = unsafe { waiter.as_ref().counter.load(Ordering::Relaxed) };
+                if wanted > state.permits {
+                    break;
+                }
+
+                state.permits -= wanted;
+                state.head = unsafe { waiter.as_ref().next.get() };
+                last = Some(waiter);
+            }
+
+            // Detach the served waiters from the queue.
+            match last {
+                None => None,
+                Some(last) => {
+                    unsafe { last.as_ref().next.set(None) };
+                    if state.head.is_none() {
+                        state.tail = None;
+                    }
+                    woken
+                }
+            }
+        };
+
+        // Unpark the served waiters outside the lock.
+        unsafe { Self::unpark_all(woken) }
+    }
+
+    /// Adds `n` permits to the semaphore, raising its concurrency limit.
+    ///
+    /// This is the same as [`release`](Semaphore::release), for when the permits don't come
+    /// from an acquisition, like when tuning the limit in response to load. Permits which
+    /// [`remove_permits`](Semaphore::remove_permits) is still waiting for are cancelled out
+    /// first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of available permits overflows a `usize`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(1);
+    /// semaphore.add_permits(2);
+    /// assert_eq!(semaphore.available_permits(), 3);
+    /// ```
+    #[inline]
+    pub fn add_permits(&self, n: usize) {
+        self.release(n);
+    }
+
+    /// Removes `n` permits from the semaphore, lowering its concurrency limit.
+    ///
+    /// Available permits are removed right away. If there aren't enough of them, the rest
+    /// are removed as held permits are returned, so that the semaphore settles at the new limit
+    /// without having to wait for the current holders.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of permits waiting to be removed overflows a `usize`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use usync::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(2);
+    /// let permit = semaphore.acquire().unwrap();
+    ///
+    /// // Only one permit is available, so the held one is removed once it's returned.
+    /// semaphore.remove_permits(2);
+    /// assert_eq!(semaphore.available_permits(), 0);
+    /// drop(permit);
+    /// assert_eq!(semaphore.available_permits(), 0);
+    ///
+    /// semaphore.add_permits(1);
+    /// assert_eq!(semaphore.available_permits(), 1);
+    /// ```
+    pub fn remove_permits(&self, n: usize) {
+        let mut state = self.state.lock();
+        let removed = cmp::min(state.permits, n);
+        state.permits -= removed;
+        state.debt = state
+            .debt
+            .checked_add(n - removed)
+            .expect("Semaphore permit count overflowed");
+    }
+
+    /// Closes the semaphore, making every thread blocked in an `acquire` call, as well as
+    /// all future ones, return an [`AcquireError`].
+    ///
+    /// Permits which are currently held can still be released, but nobody can acquire them anymore.
+    /// Returns true if this call closed the semaphore, or false if it was already closed.
+    pub fn close(&self) -> bool {
+        let woken = {
+            let mut state = self.state.lock();
+            if mem::replace(&mut state.closed, true) {
+                return false;
+            }
+
+            // Take the whole queue, marking each waiter as failed.
+            let woken = state.head.take();
+            state.tail = None;
+
+            let mut current = woken;
+            while let Some(waiter) = current {
+                let waiter = unsafe { waiter.as_ref() };
+                waiter.flags.set(WAITER_CLOSED);
+                current = waiter.next.get();
+            }
+
+            woken
+        };
+
+        unsafe { Self::unpark_all(woken) };
+        true
+    }
+
+    /// Unparks a list of waiters which were removed from the queue.
+    unsafe fn unpark_all(mut woken: Option<NonNull<Waiter>>) {
+        // The next field must be read before unpark() as it lets the waiter return.
+        while let Some(waiter) = woken {
+            woken = waiter.as_ref().next.get();
+            waiter.as_ref().parker.unpark();
+        }
+    }
+
+    fn try_take(&self, n: usize) -> bool {
+        let mut state = self.state.lock();
+        if state.closed || state.head.is_some() || state.permits < n {
+            return false;
+        }
+
+        state.permits -= n;
+        true
+    }
+
+    #[cold]
+    fn acquire_slow(&self, n: usize) -> Result<(), AcquireError> {
+        Waiter::with(Primitive::new("Semaphore", self), |waiter| {
+            {
+                let mut state = self.state.lock();
+                if state.closed {
+                    return Err(AcquireError(()));
+                }
+
+                if state.head.is_none() && state.permits >= n {
+                    state.permits -= n;
+                    return Ok(());
+                }
+
+                waiter.flags.set(0);
+                state.push(waiter, n);
+            }
+
+            // Either the releasing thread took our permits before unparking us
+            // or we were woken up by close() which marked us as failed.
+            assert!(waiter.parker.park(None));
+            match waiter.flags.get() & WAITER_CLOSED {
+                0 => Ok(()),
+                _ => Err(AcquireError(())),
+            }
+        })
+    }
+}
+
+impl State {
+    /// Appends the waiter asking for `n` permits to the tail of the queue.
+    fn push(&mut self, waiter: Pin<&Waiter>, n: usize) {
+        let waiter_ptr = NonNull::from(&*waiter);
+        waiter.counter.store(n, Ordering::Relaxed);
+        waiter.next.set(None);
+
+        match self.tail.replace(waiter_ptr) {
+            Some(tail) => unsafe { tail.as_ref().next.set(Some(waiter_ptr)) },
+            None => self.head = Some(waiter_ptr),
+        }
+    }
+}
+
+/// An RAII guard holding permits acquired from a [`Semaphore`].
+///
+/// The permits are released back to the semaphore when this guard is dropped.
+#[must_use = "if unused the permits will immediately be released"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Returns the amount of permits held by this guard.
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Forgets the permits without releasing them back to the semaphore.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.release(self.permits);
+        }
+    }
+}
+
+impl<'a> fmt::Debug for SemaphorePermit<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+/// An RAII guard holding permits acquired from a [`Semaphore`] behind an `Arc`.
+///
+/// This is the owned equivalent of [`SemaphorePermit`], returned by
+/// [`acquire_owned`](Semaphore::acquire_owned) and its variants.
+/// The permits are released back to the semaphore when this guard is dropped.
+#[must_use = "if unused the permits will immediately be released"]
+pub struct OwnedPermit {
+    semaphore: Arc<Semaphore>,
+    permits: usize,
+}
+
+impl OwnedPermit {
+    /// Returns the amount of permits held by this guard.
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Returns the semaphore the permits were acquired from.
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.semaphore
+    }
+
+    /// Forgets the permits without releasing them back to the semaphore.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.release(self.permits);
+        }
+    }
+}
+
+impl fmt::Debug for OwnedPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OwnedPermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+/// An error returned from the `acquire` methods of a [`Semaphore`] when it was closed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AcquireError(pub(crate) ());
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("semaphore was closed")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AcquireError {}
+
+#[cfg(test)]
+mod tests {
+    use super::AcquireError;
+    use crate::Semaphore;
+    use std::{
+        sync::{mpsc::channel, Arc},
+        thread,
+    };
+
+    // Waits for `count` threads to be queued on the semaphore.
+    fn wait_for_waiters(semaphore: &Semaphore, count: usize) {
+        loop {
+            let queued = {
+                let state = semaphore.state.lock();
+                let mut queued = 0;
+                let mut current = state.head;
+                while let Some(waiter) = current {
+                    queued += 1;
+                    current = unsafe { waiter.as_ref().next.get() };
+                }
+                queued
+            };
+
+            if queued == count {
+                return;
+            }
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let semaphore = Semaphore::new(2);
+        let a = semaphore.acquire().unwrap();
+        let b = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        assert_eq!(semaphore.available_permits(), 0);
+
+        drop(a);
+        assert_eq!(semaphore.available_permits(), 1);
+        b.forget();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        semaphore.release(2);
+        let c = semaphore.try_acquire_many(3).unwrap();
+        assert_eq!(c.num_permits(), 3);
+        assert!(semaphore.try_acquire_many(1).is_none());
+        assert!(semaphore.try_acquire_many(0).is_some());
+    }
+
+    #[test]
+    fn acquire_blocks() {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let (tx, rx) = channel();
+
+        let t = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let permit = semaphore.acquire().unwrap();
+                tx.send(()).unwrap();
+                permit.forget();
+            })
+        };
+
+        wait_for_waiters(&semaphore, 1);
+        assert!(rx.try_recv().is_err());
+
+        semaphore.release(1);
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn acquire_many_fifo() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let (tx, rx) = channel();
+
+        // Queue a large request which can't be satisfied yet.
+        let big = {
+            let (semaphore, tx) = (semaphore.clone(), tx.clone());
+            thread::spawn(move || {
+                let permit = semaphore.acquire_many(3).unwrap();
+                tx.send(3).unwrap();
+                permit.forget();
+            })
+        };
+        // Wait for it to be queued: try_acquire fails even for zero permits once threads wait.
+        while semaphore.try_acquire_many(0).is_some() {
+            thread::yield_now();
+        }
+
+        // A single permit is available, but it can't be taken ahead of the large request.
+        assert!(semaphore.try_acquire().is_none());
+        let small = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                let permit = semaphore.acquire().unwrap();
+                tx.send(1).unwrap();
+                permit.forget();
+            })
+        };
+
+        wait_for_waiters(&semaphore, 2);
+        assert!(rx.try_recv().is_err());
+
+        semaphore.release(2);
+        assert_eq!(rx.recv().unwrap(), 3);
+        big.join().unwrap();
+
+        semaphore.release(1);
+        assert_eq!(rx.recv().unwrap(), 1);
+        small.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn limits_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const PERMITS: usize = 3;
+        static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+        let semaphore = Arc::new(Semaphore::new(PERMITS));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let semaphore = semaphore.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let _permit = semaphore.acquire_many(1 + i % 2).unwrap();
+                        let active = ACTIVE.fetch_add(1 + i % 2, Ordering::SeqCst);
+                        assert!(active + 1 + i % 2 <= PERMITS);
+                        ACTIVE.fetch_sub(1 + i % 2, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(semaphore.available_permits(), PERMITS);
+    }
+
+    #[test]
+    fn owned_permit() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let permit = semaphore.acquire_many_owned(2).unwrap();
+        assert_eq!(permit.num_permits(), 2);
+        assert!(semaphore.try_acquire_owned().is_none());
+
+        // The permit keeps the semaphore alive and releases it from the other thread.
+        let t = thread::spawn(move || {
+            assert_eq!(permit.semaphore().available_permits(), 0);
+            drop(permit);
+        });
+        let permit = semaphore.acquire_owned().unwrap();
+        t.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        permit.forget();
+        assert_eq!(semaphore.available_permits(), 1);
+        assert_eq!(Arc::strong_count(&semaphore), 1);
+    }
+
+    #[test]
+    fn adjust_permits() {
+        let semaphore = Arc::new(Semaphore::new(0));
+
+        // Adding permits wakes up the waiting threads like releasing them.
+        let t = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || semaphore.acquire_many(2).unwrap().forget())
+        };
+        wait_for_waiters(&semaphore, 1);
+        semaphore.add_permits(3);
+        t.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 1);
+
+        // Removal takes the available permit right away and the others as they're returned.
+        semaphore.add_permits(2);
+        let permits = semaphore.acquire_many(2).unwrap();
+        semaphore.remove_permits(2);
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(permits);
+        assert_eq!(semaphore.available_permits(), 1);
+
+        // Adding permits cancels out the ones waiting to be removed.
+        let permit = semaphore.acquire().unwrap();
+        semaphore.remove_permits(2);
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 0);
+        semaphore.add_permits(1);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn close() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let permit = semaphore.acquire().unwrap();
+
+        let waiters: Vec<_> = (1..4)
+            .map(|n| {
+                let semaphore = semaphore.clone();
+                thread::spawn(move || semaphore.acquire_many(n).map(|p| p.num_permits()))
+            })
+            .collect();
+
+        wait_for_waiters(&semaphore, 3);
+        assert!(semaphore.close());
+        assert!(!semaphore.close());
+        assert!(semaphore.is_closed());
+
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError(())));
+        }
+
+        // Held permits can still be released, but not acquired again.
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(semaphore.try_acquire().is_none());
+        assert!(semaphore.acquire().is_err());
+        assert!(semaphore.acquire_owned().is_err());
+    }
+}
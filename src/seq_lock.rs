@@ -0,0 +1,253 @@
+use super::{
+    shared::{self, SpinWait},
+    RawMutex,
+};
+use lock_api::RawMutex as _RawMutex;
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+/// A sequence lock which publishes small `Copy` values without ever blocking readers.
+///
+/// Readers copy the value out optimistically and retry if a writer modified it concurrently,
+/// so they never write to shared memory and never wait on a writer being descheduled.
+/// Writers are serialized by an internal mutex and bump a sequence counter around their
+/// modification to let readers detect it.
+///
+/// This fits values which are small, cheap to copy and read far more often than written,
+/// like timestamps or statistics snapshots. Readers can starve if writes are continuous.
+///
+/// # Examples
+///
+/// ```
+/// use usync::SeqLock;
+///
+/// let lock = SeqLock::new((0u64, 0u64));
+/// {
+///     let mut value = lock.write();
+///     value.0 += 1;
+///     value.1 += 2;
+/// }
+/// assert_eq!(lock.read(), (1, 2));
+/// ```
+pub struct SeqLock<T: Copy> {
+    /// Odd while a writer is modifying the value.
+    seq: AtomicUsize,
+    writer: RawMutex,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeqLock")
+            .field("data", &self.read())
+            .finish()
+    }
+}
+
+impl<T: Copy> SeqLock<T> {
+    /// Creates a new sequence lock holding the given value.
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            writer: RawMutex::INIT,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the lock, returning the underlying value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Returns a mutable reference to the underlying value.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Returns a copy of the value, retrying while a writer modifies it concurrently.
+    pub fn read(&self) -> T {
+        let mut spin = SpinWait::default();
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+
+            if !spin.try_yield_now() {
+                shared::yield_now();
+            }
+        }
+    }
+
+    /// Returns a copy of the value, or `None` if a writer modified it concurrently.
+    pub fn try_read(&self) -> Option<T> {
+        // Acquire barrier ensures the last write completed before we read the value.
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq & 1 != 0 {
+            return None;
+        }
+
+        // The value may be torn by a concurrent writer, so keep it uninitialized until validated.
+        // Volatile prevents the compiler from assuming it's unchanged between two reads.
+        let value = unsafe { ptr::read_volatile(self.value.get() as *const MaybeUninit<T>) };
+
+        // Acquire barrier ensures the value read happens before we check the sequence again.
+        fence(Ordering::Acquire);
+        match self.seq.load(Ordering::Relaxed) == seq {
+            true => Some(unsafe { value.assume_init() }),
+            false => None,
+        }
+    }
+
+    /// Locks the value for writing, blocking other writers but not readers.
+    ///
+    /// Readers retry until the returned guard is dropped.
+    pub fn write(&self) -> SeqLockWriteGuard<'_, T> {
+        self.writer.lock();
+
+        // Release barrier ensures readers observing our writes also observe the odd sequence.
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        SeqLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Replaces the value, returning the previous one.
+    pub fn replace(&self, value: T) -> T {
+        let mut guard = self.write();
+        std::mem::replace(&mut *guard, value)
+    }
+}
+
+/// RAII structure used to publish the modified value of a [`SeqLock`] when dropped.
+#[must_use = "if unused the SeqLock will immediately unlock"]
+pub struct SeqLockWriteGuard<'a, T: Copy> {
+    lock: &'a SeqLock<T>,
+    _marker: PhantomData<(&'a mut T, crate::GuardMarker)>,
+}
+
+impl<T: Copy> Deref for SeqLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: Copy> DerefMut for SeqLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: Copy> Drop for SeqLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release barrier ensures our writes happen before readers see the even sequence.
+        let seq = self.lock.seq.load(Ordering::Relaxed);
+        self.lock.seq.store(seq.wrapping_add(1), Ordering::Release);
+        unsafe { self.lock.writer.unlock() };
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SeqLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SeqLock;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn smoke() {
+        let mut lock = SeqLock::new(1);
+        assert_eq!(lock.read(), 1);
+        assert_eq!(lock.try_read(), Some(1));
+
+        *lock.write() = 2;
+        assert_eq!(lock.replace(3), 2);
+        *lock.get_mut() += 1;
+        assert_eq!(lock.into_inner(), 4);
+    }
+
+    #[test]
+    fn read_during_write() {
+        let lock = SeqLock::new(0);
+        let guard = lock.write();
+        assert_eq!(lock.try_read(), None);
+        drop(guard);
+        assert_eq!(lock.try_read(), Some(0));
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        static DONE: AtomicBool = AtomicBool::new(false);
+        let lock = Arc::new(SeqLock::new([0u64; 8]));
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for i in 0..10_000 {
+                        *lock.write() = [i; 8];
+                    }
+                })
+            })
+            .collect();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    while !DONE.load(Ordering::Relaxed) {
+                        let value = lock.read();
+                        assert!(value.iter().all(|&v| v == value[0]));
+                    }
+                })
+            })
+            .collect();
+
+        for t in writers {
+            t.join().unwrap();
+        }
+        DONE.store(true, Ordering::Relaxed);
+        for t in readers {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_seq_lock_debug() {
+        let lock = SeqLock::new(5);
+        assert_eq!(format!("{:?}", lock), "SeqLock { data: 5 }");
+    }
+}
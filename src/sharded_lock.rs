@@ -0,0 +1,322 @@
+use super::RawRwLock;
+use lock_api::RawRwLock as _RawRwLock;
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+/// A reader-writer lock which is split into one internal lock per CPU shard.
+///
+/// Readers only lock the shard assigned to their thread, so uncontended reads from different
+/// threads never write to the same cache line. Writers have to lock every shard instead,
+/// which makes writing considerably more expensive than with [`RwLock`](type.RwLock.html).
+/// This fits read-dominated data, like global registries, which is read from many cores.
+///
+/// Unlike `RwLock`, a `ShardedLock` is not a single word large: it allocates one cache line
+/// per shard, with as many shards as there are CPUs available to the program.
+///
+/// # Examples
+///
+/// ```
+/// use usync::ShardedLock;
+///
+/// let lock = ShardedLock::new(5);
+///
+/// // Many read locks can be held at once.
+/// {
+///     let r1 = lock.read();
+///     let r2 = lock.read();
+///     assert_eq!(*r1, 5);
+///     assert_eq!(*r2, 5);
+/// }
+///
+/// // Only one write lock may be held.
+/// {
+///     let mut w = lock.write();
+///     *w += 1;
+///     assert_eq!(*w, 6);
+/// }
+/// ```
+pub struct ShardedLock<T: ?Sized> {
+    shards: Box<[Shard]>,
+    value: UnsafeCell<T>,
+}
+
+/// Keeps each internal lock on its own cache line.
+#[repr(align(128))]
+struct Shard(RawRwLock);
+
+unsafe impl<T: ?Sized + Send> Send for ShardedLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for ShardedLock<T> {}
+
+impl<T: Default> Default for ShardedLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for ShardedLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ShardedLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f
+                .debug_struct("ShardedLock")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("ShardedLock { data: <locked> }"),
+        }
+    }
+}
+
+impl<T> ShardedLock<T> {
+    /// Creates a new sharded lock in an unlocked state, with one shard per available CPU.
+    pub fn new(value: T) -> Self {
+        let shards = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        Self {
+            shards: (0..shards)
+                .map(|_| Shard(RawRwLock::INIT))
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> ShardedLock<T> {
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Locks the shard of the current thread with shared read access, blocking the current
+    /// thread until the lock can be acquired.
+    pub fn read(&self) -> ShardedLockReadGuard<'_, T> {
+        let shard = self.current_shard();
+        self.shards[shard].0.lock_shared();
+        ShardedLockReadGuard {
+            lock: self,
+            shard,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to lock the shard of the current thread with shared read access.
+    ///
+    /// Returns `None` if a writer holds or is acquiring the shard.
+    pub fn try_read(&self) -> Option<ShardedLockReadGuard<'_, T>> {
+        let shard = self.current_shard();
+        match self.shards[shard].0.try_lock_shared() {
+            true => Some(ShardedLockReadGuard {
+                lock: self,
+                shard,
+                _marker: PhantomData,
+            }),
+            false => None,
+        }
+    }
+
+    /// Locks every shard with exclusive write access, blocking the current thread
+    /// until it can be acquired.
+    pub fn write(&self) -> ShardedLockWriteGuard<'_, T> {
+        // Shards are always locked in the same order so that writers can't deadlock each other.
+        for shard in self.shards.iter() {
+            shard.0.lock_exclusive();
+        }
+
+        ShardedLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to lock every shard with exclusive write access.
+    ///
+    /// Returns `None` if any of the shards is currently locked.
+    pub fn try_write(&self) -> Option<ShardedLockWriteGuard<'_, T>> {
+        for (locked, shard) in self.shards.iter().enumerate() {
+            if !shard.0.try_lock_exclusive() {
+                unsafe { self.unlock_exclusive(locked) };
+                return None;
+            }
+        }
+
+        Some(ShardedLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Unlocks the first `count` shards which were locked exclusively, in reverse order.
+    unsafe fn unlock_exclusive(&self, count: usize) {
+        for shard in self.shards[..count].iter().rev() {
+            shard.0.unlock_exclusive();
+        }
+    }
+
+    /// Returns the shard assigned to the current thread.
+    ///
+    /// Threads are assigned shards round-robin on their first read. Unlike the CPU
+    /// a thread runs on, this doesn't change when the thread migrates.
+    fn current_shard(&self) -> usize {
+        static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+        thread_local!(static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed));
+        SHARD.with(|&shard| shard % self.shards.len())
+    }
+}
+
+/// RAII structure used to release the shared read access of a [`ShardedLock`] when dropped.
+#[must_use = "if unused the ShardedLock will immediately unlock"]
+pub struct ShardedLockReadGuard<'a, T: ?Sized> {
+    lock: &'a ShardedLock<T>,
+    shard: usize,
+    _marker: PhantomData<(&'a T, crate::GuardMarker)>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for ShardedLockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for ShardedLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ShardedLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.shards[self.shard].0.unlock_shared() };
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ShardedLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// RAII structure used to release the exclusive write access of a [`ShardedLock`] when dropped.
+#[must_use = "if unused the ShardedLock will immediately unlock"]
+pub struct ShardedLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a ShardedLock<T>,
+    _marker: PhantomData<(&'a mut T, crate::GuardMarker)>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for ShardedLockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for ShardedLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for ShardedLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ShardedLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.unlock_exclusive(self.lock.shards.len()) };
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ShardedLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ShardedLock;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn smoke() {
+        let lock = ShardedLock::new(());
+        drop(lock.read());
+        drop(lock.write());
+        drop((lock.read(), lock.read()));
+        drop(lock.write());
+    }
+
+    #[test]
+    fn try_lock() {
+        let lock = ShardedLock::new(0);
+        {
+            let _read = lock.read();
+            assert!(lock.try_read().is_some());
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let _write = lock.write();
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+        }
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn write_excludes_readers_on_all_shards() {
+        const THREADS: usize = 8;
+        const ITERS: usize = 1000;
+        let lock = Arc::new(ShardedLock::new((0usize, 0usize)));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        if i % 2 == 0 {
+                            let mut value = lock.write();
+                            value.0 += 1;
+                            value.1 += 1;
+                        } else {
+                            let value = lock.read();
+                            assert_eq!(value.0, value.1);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(lock.read().0, (THREADS / 2) * ITERS);
+    }
+
+    #[test]
+    fn test_sharded_lock_debug() {
+        let mut lock = ShardedLock::new(vec![1u8]);
+        assert_eq!(format!("{:?}", lock), "ShardedLock { data: [1] }");
+        lock.get_mut().push(2);
+
+        let write = lock.write();
+        assert_eq!(format!("{:?}", lock), "ShardedLock { data: <locked> }");
+        drop(write);
+        assert_eq!(lock.into_inner(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Fault injection for the `chaos` feature, which makes the primitives behave in all the
+//! ways their APIs allow but which rarely happen in practice: condition variables wake up
+//! spuriously, unparks are delayed and threads woken up together are woken in a random order.
+//!
+//! The faults are random, seeded from the `USYNC_CHAOS_SEED` environment variable when it's
+//! set, and from the current time otherwise. Each thread gets its own sequence derived from
+//! the seed, in the order in which threads first hit a fault point.
+//! Without the feature, all of this compiles down to nothing.
+
+use super::Waiter;
+use std::ptr::NonNull;
+
+/// Returns true if a condition variable should wake up without being notified.
+#[inline]
+pub(crate) fn spurious_wakeup() -> bool {
+    imp::one_in(16)
+}
+
+/// Delays the current thread for a little while, sometimes, before it unparks another one.
+#[inline]
+pub(crate) fn delay_unpark() {
+    if imp::one_in(8) {
+        for _ in 0..imp::below(4) + 1 {
+            super::yield_now();
+        }
+    }
+}
+
+/// Calls `wake` on the waiters of the list starting at `first` and linked by `next`,
+/// in a random order in chaos mode and in the order of the list otherwise.
+///
+/// The link of a waiter is always read before it's passed to `wake`, which can let it return.
+#[inline]
+pub(crate) unsafe fn wake_list(
+    first: Option<NonNull<Waiter>>,
+    next: impl Fn(&Waiter) -> Option<NonNull<Waiter>>,
+    mut wake: impl FnMut(NonNull<Waiter>),
+) {
+    #[cfg(feature = "chaos")]
+    {
+        let mut waiters = Vec::new();
+        let mut current = first;
+        while let Some(waiter) = current {
+            current = next(waiter.as_ref());
+            waiters.push(waiter);
+        }
+
+        // Fisher-Yates shuffle.
+        for i in (1..waiters.len()).rev() {
+            waiters.swap(i, imp::below(i + 1));
+        }
+
+        for waiter in waiters {
+            wake(waiter);
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    {
+        let mut current = first;
+        while let Some(waiter) = current {
+            current = next(waiter.as_ref());
+            wake(waiter);
+        }
+    }
+}
+
+#[cfg(feature = "chaos")]
+mod imp {
+    use std::{
+        cell::Cell,
+        sync::atomic::{AtomicU64, Ordering},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    /// The seed of the thread spawned next, derived from the global seed.
+    static NEXT_SEED: AtomicU64 = AtomicU64::new(0);
+
+    std::thread_local! {
+        static STATE: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn seed() -> u64 {
+        if let Some(seed) = std::env::var("USYNC_CHAOS_SEED")
+            .ok()
+            .and_then(|seed| seed.trim().parse().ok())
+        {
+            return seed;
+        }
+
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_nanos() as u64)
+            .unwrap_or_default()
+    }
+
+    /// Returns the next random number of the thread, using xorshift64*.
+    fn next() -> u64 {
+        // Thread locals may already be destroyed, in which case no fault is injected.
+        STATE
+            .try_with(|state| {
+                let mut x = state.get();
+                if x == 0 {
+                    if NEXT_SEED.load(Ordering::Relaxed) == 0 {
+                        // Racing threads may each derive it, but they all read the same seed.
+                        let _ = NEXT_SEED.compare_exchange(
+                            0,
+                            seed_nonzero(seed()),
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        );
+                    }
+
+                    // Each thread starts from the next seed of a Weyl sequence.
+                    let thread_seed = NEXT_SEED.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+                    x = seed_nonzero(thread_seed);
+                }
+
+                x ^= x >> 12;
+                x ^= x << 25;
+                x ^= x >> 27;
+                state.set(x);
+                x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+            })
+            .unwrap_or(1)
+    }
+
+    fn seed_nonzero(seed: u64) -> u64 {
+        if seed == 0 {
+            1
+        } else {
+            seed
+        }
+    }
+
+    /// Returns a random number in `0..n`.
+    pub(super) fn below(n: usize) -> usize {
+        ((next() >> 32) as usize) % n
+    }
+
+    pub(super) fn one_in(n: usize) -> bool {
+        below(n) == 0
+    }
+}
+
+#[cfg(not(feature = "chaos"))]
+mod imp {
+    #[inline(always)]
+    pub(super) fn below(_n: usize) -> usize {
+        0
+    }
+
+    #[inline(always)]
+    pub(super) fn one_in(_n: usize) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wake_list, Waiter};
+    use std::ptr::NonNull;
+
+    #[test]
+    fn wake_list_visits_all() {
+        let waiters: Vec<Waiter> = (0..10).map(|_| Waiter::default()).collect();
+        for (i, waiter) in waiters.iter().enumerate() {
+            waiter
+                .counter
+                .store(i, std::sync::atomic::Ordering::Relaxed);
+            waiter.next.set(waiters.get(i + 1).map(NonNull::from));
+        }
+
+        let mut woken = Vec::new();
+        unsafe {
+            wake_list(
+                Some(NonNull::from(&waiters[0])),
+                |waiter| waiter.next.get(),
+                |waiter| {
+                    woken.push(
+                        waiter
+                            .as_ref()
+                            .counter
+                            .load(std::sync::atomic::Ordering::Relaxed),
+                    )
+                },
+            );
+        }
+
+        woken.sort_unstable();
+        assert_eq!(woken, (0..10).collect::<Vec<_>>());
+    }
+}
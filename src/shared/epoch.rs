@@ -0,0 +1,90 @@
+use super::{Primitive, SpinWait};
+use crate::parking;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Set in a reader counter while a writer is parked waiting for its readers to leave.
+const PARKED: usize = 1;
+
+/// The amount a reader adds to the counter it registers on, above the PARKED bit.
+const READER: usize = 2;
+
+/// Grace period tracking for the primitives whose readers never block.
+///
+/// Readers register on the counter of the current epoch's parity for as long as they access
+/// the shared value. After replacing the value, a writer waits for a grace period, at the end
+/// of which no reader can still access the old value. Writers must be serialized.
+pub(crate) struct Epoch {
+    /// The current epoch, whose lowest bit selects the reader counter new readers register on.
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+}
+
+impl Epoch {
+    pub(crate) const fn new() -> Self {
+        Self {
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Registers a reader, returning the parity to [`leave`](Epoch::leave) with.
+    ///
+    /// SeqCst ensures a writer waiting for a grace period after replacing the value either
+    /// sees the registration, or the reader sees the new value when loading it with SeqCst.
+    #[inline]
+    pub(crate) fn enter(&self) -> usize {
+        let parity = self.epoch.load(Ordering::SeqCst) & 1;
+        self.readers[parity].fetch_add(READER, Ordering::SeqCst);
+        parity
+    }
+
+    /// Unregisters a reader, waking up the writer waiting for it if it was the last one.
+    #[inline]
+    pub(crate) fn leave(&self, parity: usize) {
+        // Release barrier ensures the reader's accesses to the value happen before a writer reclaims it.
+        let readers = &self.readers[parity];
+        if readers.fetch_sub(READER, Ordering::Release) == READER | PARKED {
+            Self::unpark(readers);
+        }
+    }
+
+    #[cold]
+    fn unpark(readers: &AtomicUsize) {
+        parking::unpark_one(readers as *const AtomicUsize as usize);
+    }
+
+    /// Waits for a grace period, after which no reader registered before the call is left.
+    ///
+    /// Readers registered on either parity, depending on when they loaded the epoch,
+    /// so advance the epoch twice and wait for the readers of each parity to leave.
+    /// New readers register on the other parity so they can't starve us.
+    pub(crate) fn synchronize(&self, primitive: Primitive) {
+        for _ in 0..2 {
+            let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+            let readers = &self.readers[epoch & 1];
+
+            // Readers are usually short-lived, so spin for a bit before parking.
+            // Acquire barrier ensures the readers are done with the value before it's reclaimed.
+            let mut spin = SpinWait::default();
+            while readers.load(Ordering::Acquire) != 0 {
+                if !spin.try_yield_now() {
+                    Self::park(readers, primitive);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[cold]
+    fn park(readers: &AtomicUsize, primitive: Primitive) {
+        // The last reader to leave sees the PARKED bit and unparks us. Readers only add and
+        // remove READER, so our validation sees the count drop to zero under the queue lock.
+        let key = readers as *const AtomicUsize as usize;
+        while readers.fetch_or(PARKED, Ordering::Acquire) & !PARKED != 0 {
+            let validate = || readers.load(Ordering::Relaxed) != PARKED;
+            parking::park_on(primitive, key, validate, None);
+        }
+
+        readers.fetch_and(!PARKED, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    cell::Cell,
+    marker::PhantomPinned,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// The primary blocking primitive used by all the synchronization data structures.
+///
+/// Without the standard library, threads are blocked with the
+/// [`RawParker`](crate::RawParker) registered by the application.
+pub(crate) struct Event {
+    token: Cell<usize>,
+    is_set: AtomicBool,
+    _pinned: PhantomPinned,
+}
+
+impl Event {
+    pub(crate) const fn new() -> Self {
+        Self {
+            token: Cell::new(0),
+            is_set: AtomicBool::new(false),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+        // SAFETY: The event lives on the thread's stack.
+        let event = Self::new();
+        event.token.set(current());
+        f(unsafe { Pin::new_unchecked(&event) })
+    }
+
+    #[cold]
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+        loop {
+            // Returns true when the event is set.
+            // Acquire barrier ensures that the set() happens before we return.
+            if self.is_set.load(Ordering::Acquire) {
+                return true;
+            }
+
+            // There is no clock to tell how much of the timeout is left,
+            // so a spurious wake up restarts the full timeout.
+            if !unsafe { __usync_parker_park(timeout) } {
+                return self.is_set.load(Ordering::Acquire);
+            }
+        }
+    }
+
+    #[cold]
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
+        let token = self.token.replace(0);
+        assert_ne!(token, 0, "Event waiting without a thread");
+
+        // Try to not leave dangling references when returning (see below)
+        let is_set_ptr = &self.is_set as *const AtomicBool;
+        let _ = self;
+
+        // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
+        // `store()` has a potentially dangling ref to `is_set` once wait() thread sees true and returns.
+        // Release barrier ensures `token.replace()` happens before is_set is true and wait() thread returns.
+        (*is_set_ptr).store(true, Ordering::Release);
+        __usync_parker_unpark(token);
+    }
+}
+
+/// Returns the token of the current thread from the registered parker.
+pub(crate) fn current() -> usize {
+    let token = unsafe { __usync_parker_current() };
+    assert_ne!(token, 0, "RawParker::current() returned a zero token");
+    token
+}
+
+/// Yields the current thread with the registered parker.
+pub(crate) fn yield_now() {
+    unsafe { __usync_parker_yield_now() }
+}
+
+// Defined by `set_parker!` in the final binary.
+extern "Rust" {
+    fn __usync_parker_current() -> usize;
+    fn __usync_parker_park(timeout: Option<Duration>) -> bool;
+    fn __usync_parker_unpark(token: usize);
+    fn __usync_parker_yield_now();
+}
@@ -0,0 +1,250 @@
+use std::{
+    cell::UnsafeCell,
+    ffi::c_void,
+    marker::PhantomPinned,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const EMPTY: u32 = 0;
+const SET: u32 = 1;
+
+/// The primary blocking primitive used by all the synchronization data structures.
+///
+/// Waits with `__ulock_wait` on macOS 10.12 / iOS 10 and later, and with a global,
+/// statically initialized pthread mutex and condition variable on older versions.
+/// Neither of them allocates per parked thread.
+pub(crate) struct Event {
+    state: AtomicU32,
+    _pinned: PhantomPinned,
+}
+
+impl Event {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(EMPTY),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+        // SAFETY: The event lives on the thread's stack.
+        let event = Self::new();
+        f(unsafe { Pin::new_unchecked(&event) })
+    }
+
+    #[cold]
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        match Backend::get() {
+            Backend::ULock(wait, _) => self.wait_ulock(wait, deadline),
+            Backend::Condvar => self.wait_condvar(deadline),
+        }
+    }
+
+    #[cold]
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
+        // Try to not leave dangling references when returning (see below)
+        let state_ptr = &self.state as *const AtomicU32;
+        let _ = self;
+
+        // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
+        // `store()` has a potentially dangling ref to `state` once wait() thread sees SET and returns.
+        // Release barrier ensures the set() happens before wait() sees SET and returns.
+        (*state_ptr).store(SET, Ordering::Release);
+
+        match Backend::get() {
+            // Waking up an address which isn't waited on anymore is harmless,
+            // so it doesn't matter if the waiter already returned.
+            Backend::ULock(_, wake) => {
+                wake(
+                    UL_COMPARE_AND_WAIT | ULF_NO_ERRNO,
+                    state_ptr as *mut c_void,
+                    0,
+                );
+            }
+            // The waiter checks the state while holding the mutex, so it either sees SET
+            // or is already waiting on the condition variable once we hold it.
+            Backend::Condvar => {
+                let _guard = CONDVAR.lock();
+                let status = libc::pthread_cond_broadcast(CONDVAR.cond.get());
+                debug_assert_eq!(status, 0);
+            }
+        }
+    }
+
+    fn wait_ulock(&self, wait: ULockWaitFn, deadline: Option<Instant>) -> bool {
+        loop {
+            // Acquire barrier ensures that the set() happens before we return.
+            if self.state.load(Ordering::Acquire) == SET {
+                return true;
+            }
+
+            // A timeout of zero means waiting forever.
+            let timeout_us = match deadline {
+                None => 0,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) => duration_to_us(remaining),
+                    None => return false,
+                },
+            };
+
+            // This may return spuriously, in which case the state and the deadline are checked again.
+            unsafe {
+                wait(
+                    UL_COMPARE_AND_WAIT | ULF_NO_ERRNO,
+                    &self.state as *const AtomicU32 as *mut c_void,
+                    EMPTY as u64,
+                    timeout_us,
+                );
+            }
+        }
+    }
+
+    fn wait_condvar(&self, deadline: Option<Instant>) -> bool {
+        let _guard = CONDVAR.lock();
+        loop {
+            // Acquire barrier ensures that the set() happens before we return.
+            if self.state.load(Ordering::Acquire) == SET {
+                return true;
+            }
+
+            // The condition variable is shared by all events, so this also returns
+            // when other events are set, in which case the state and the deadline are checked again.
+            unsafe {
+                match deadline {
+                    None => {
+                        let status =
+                            libc::pthread_cond_wait(CONDVAR.cond.get(), CONDVAR.mutex.get());
+                        debug_assert_eq!(status, 0);
+                    }
+                    Some(deadline) => {
+                        let remaining = match deadline.checked_duration_since(Instant::now()) {
+                            Some(remaining) => remaining,
+                            None => return false,
+                        };
+
+                        // pthread_cond_timedwait() takes an absolute time on the system clock.
+                        let abs_time = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .saturating_add(remaining);
+                        let timespec = libc::timespec {
+                            tv_sec: abs_time.as_secs().min(libc::time_t::MAX as u64) as _,
+                            tv_nsec: abs_time.subsec_nanos() as _,
+                        };
+
+                        let status = libc::pthread_cond_timedwait(
+                            CONDVAR.cond.get(),
+                            CONDVAR.mutex.get(),
+                            &timespec,
+                        );
+                        debug_assert!(status == 0 || status == libc::ETIMEDOUT);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn duration_to_us(duration: Duration) -> u32 {
+    // Round up so that we don't wake up before the deadline and spin on it.
+    let us = duration
+        .saturating_add(Duration::from_nanos(999))
+        .as_micros();
+    us.clamp(1, u32::MAX as u128) as u32
+}
+
+/// The mutex and condition variable used to block threads when `__ulock_wait` isn't available.
+struct GlobalCondvar {
+    mutex: UnsafeCell<libc::pthread_mutex_t>,
+    cond: UnsafeCell<libc::pthread_cond_t>,
+}
+
+unsafe impl Sync for GlobalCondvar {}
+
+static CONDVAR: GlobalCondvar = GlobalCondvar {
+    mutex: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+    cond: UnsafeCell::new(libc::PTHREAD_COND_INITIALIZER),
+};
+
+impl GlobalCondvar {
+    fn lock(&self) -> GlobalCondvarGuard<'_> {
+        let status = unsafe { libc::pthread_mutex_lock(self.mutex.get()) };
+        debug_assert_eq!(status, 0);
+        GlobalCondvarGuard(self)
+    }
+}
+
+struct GlobalCondvarGuard<'a>(&'a GlobalCondvar);
+
+impl Drop for GlobalCondvarGuard<'_> {
+    fn drop(&mut self) {
+        let status = unsafe { libc::pthread_mutex_unlock(self.0.mutex.get()) };
+        debug_assert_eq!(status, 0);
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Backend {
+    ULock(ULockWaitFn, ULockWakeFn),
+    Condvar,
+}
+
+impl Backend {
+    /// Returns the backend, looking up which one is available on the first call.
+    ///
+    /// This can't use any of the crate's primitives as they'd block on an Event,
+    /// so the lookups may happen concurrently. They always find the same functions.
+    fn get() -> Self {
+        static ULOCK_WAIT: AtomicUsize = AtomicUsize::new(UNKNOWN);
+        static ULOCK_WAKE: AtomicUsize = AtomicUsize::new(UNKNOWN);
+
+        match ULOCK_WAIT.load(Ordering::Acquire) {
+            UNKNOWN => Self::get_slow(&ULOCK_WAIT, &ULOCK_WAKE),
+            UNAVAILABLE => Self::Condvar,
+            wait => unsafe { Self::ulock(wait, ULOCK_WAKE.load(Ordering::Relaxed)) },
+        }
+    }
+
+    unsafe fn ulock(wait: usize, wake: usize) -> Self {
+        Self::ULock(
+            mem::transmute::<usize, ULockWaitFn>(wait),
+            mem::transmute::<usize, ULockWakeFn>(wake),
+        )
+    }
+
+    #[cold]
+    fn get_slow(ulock_wait: &AtomicUsize, ulock_wake: &AtomicUsize) -> Self {
+        unsafe {
+            // The functions are private, so look them up instead of linking them
+            // to keep working on older versions which don't have them.
+            let wait = libc::dlsym(libc::RTLD_DEFAULT, ULOCK_WAIT_NAME.as_ptr().cast()) as usize;
+            let wake = libc::dlsym(libc::RTLD_DEFAULT, ULOCK_WAKE_NAME.as_ptr().cast()) as usize;
+            if wait == 0 || wake == 0 {
+                ulock_wait.store(UNAVAILABLE, Ordering::Relaxed);
+                return Self::Condvar;
+            }
+
+            // Release barrier ensures the wake function is visible once the wait one is.
+            ulock_wake.store(wake, Ordering::Relaxed);
+            ulock_wait.store(wait, Ordering::Release);
+            Self::ulock(wait, wake)
+        }
+    }
+}
+
+/// Sentinel values for the looked up functions, which can't be valid function addresses.
+const UNKNOWN: usize = 0;
+const UNAVAILABLE: usize = 1;
+
+type ULockWaitFn = unsafe extern "C" fn(u32, *mut c_void, u64, u32) -> i32;
+type ULockWakeFn = unsafe extern "C" fn(u32, *mut c_void, u64) -> i32;
+
+const ULOCK_WAIT_NAME: &[u8] = b"__ulock_wait\0";
+const ULOCK_WAKE_NAME: &[u8] = b"__ulock_wake\0";
+
+const UL_COMPARE_AND_WAIT: u32 = 1;
+const ULF_NO_ERRNO: u32 = 0x0100_0000;
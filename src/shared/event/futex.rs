@@ -0,0 +1,98 @@
+use std::{
+    marker::PhantomPinned,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+const EMPTY: u32 = 0;
+const SET: u32 = 1;
+
+/// The primary blocking primitive used by all the synchronization data structures.
+///
+/// Waits with a futex on the event's own state, so unlike the thread based event it
+/// doesn't need a handle to the current thread. This keeps blocking usable from thread
+/// local destructors, where `std::thread::current()` may not be available anymore.
+pub(crate) struct Event {
+    state: AtomicU32,
+    _pinned: PhantomPinned,
+}
+
+impl Event {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(EMPTY),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+        // SAFETY: The event lives on the thread's stack.
+        let event = Self::new();
+        f(unsafe { Pin::new_unchecked(&event) })
+    }
+
+    #[cold]
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            // Returns true when the event is set.
+            // Acquire barrier ensures that the set() happens before we return.
+            if self.state.load(Ordering::Acquire) == SET {
+                return true;
+            }
+
+            // Timeouts too large for a timespec are treated as waiting forever.
+            let timespec = match deadline {
+                None => None,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) => {
+                        remaining
+                            .as_secs()
+                            .try_into()
+                            .ok()
+                            .map(|tv_sec| libc::timespec {
+                                tv_sec,
+                                tv_nsec: remaining.subsec_nanos() as _,
+                            })
+                    }
+                    None => return false,
+                },
+            };
+
+            // This may return spuriously (EINTR, EAGAIN), in which case the state
+            // and the deadline are checked again.
+            unsafe {
+                libc::syscall(
+                    libc::SYS_futex,
+                    &self.state as *const AtomicU32,
+                    libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                    EMPTY,
+                    timespec.as_ref().map_or(ptr::null(), |ts| ts as *const _),
+                );
+            }
+        }
+    }
+
+    #[cold]
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
+        // Try to not leave dangling references when returning (see below)
+        let state_ptr = &self.state as *const AtomicU32;
+        let _ = self;
+
+        // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
+        // `store()` has a potentially dangling ref to `state` once wait() thread sees SET and returns.
+        // Release barrier ensures the set() happens before wait() sees SET and returns.
+        (*state_ptr).store(SET, Ordering::Release);
+
+        // Waking up an address which isn't waited on anymore is harmless,
+        // so it doesn't matter if the waiter already returned.
+        libc::syscall(
+            libc::SYS_futex,
+            state_ptr,
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! The primary blocking primitive used by all the synchronization data structures,
+//! implemented with the most efficient way to block a thread on each platform.
+//!
+//! On WebAssembly with the `atomics` target feature, the standard library parks threads
+//! with `memory.atomic.wait32` and `memory.atomic.notify`, so the thread based event is used.
+//! Only that one depends on thread locals (through `std::thread::current()`), which other
+//! platforms avoid so that blocking keeps working from thread local destructors.
+//! Without the `std` feature, threads are blocked with the parker registered by the application.
+
+#[cfg(all(feature = "std", windows))]
+mod windows;
+#[cfg(all(feature = "std", windows))]
+pub(super) use self::windows::Event;
+
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+mod darwin;
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+pub(super) use self::darwin::Event;
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod futex;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub(super) use self::futex::Event;
+
+#[cfg(all(
+    feature = "std",
+    target_arch = "wasm32",
+    not(target_feature = "atomics")
+))]
+mod wasm;
+#[cfg(all(
+    feature = "std",
+    target_arch = "wasm32",
+    not(target_feature = "atomics")
+))]
+pub(super) use self::wasm::Event;
+
+#[cfg(all(
+    feature = "std",
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "android",
+        all(target_arch = "wasm32", not(target_feature = "atomics"))
+    ))
+))]
+mod thread;
+#[cfg(all(
+    feature = "std",
+    not(any(
+        windows,
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "android",
+        all(target_arch = "wasm32", not(target_feature = "atomics"))
+    ))
+))]
+pub(super) use self::thread::Event;
+
+#[cfg(not(feature = "std"))]
+mod custom;
+#[cfg(not(feature = "std"))]
+pub(crate) use self::custom::{current, yield_now, Event};
@@ -8,14 +8,14 @@ use std::{
 };
 
 /// The primary blocking primitive used by all the synchronization data structures.
-pub(super) struct Event {
+pub(crate) struct Event {
     thread: Cell<Option<thread::Thread>>,
     is_set: AtomicBool,
     _pinned: PhantomPinned,
 }
 
 impl Event {
-    pub(super) const fn new() -> Self {
+    pub(crate) const fn new() -> Self {
         Self {
             thread: Cell::new(None),
             is_set: AtomicBool::new(false),
@@ -23,7 +23,7 @@ impl Event {
         }
     }
 
-    pub(super) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
         // SAFETY: The event lives on the thread's stack.
         let event = Self::new();
         event.thread.set(Some(thread::current()));
@@ -31,7 +31,7 @@ impl Event {
     }
 
     #[cold]
-    pub(super) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
         let mut started = None;
         loop {
             // Returns true when the event is set.
@@ -45,7 +45,7 @@ impl Event {
                 Some(timeout) => {
                     // Get the current time and lazily compute when we started waiting.
                     let now = Instant::now();
-                    let start = started.unwrap_or_else(|| now);
+                    let start = started.unwrap_or(now);
                     started = Some(start);
 
                     // Check if we've been waiting for longer than the timeout
@@ -60,13 +60,13 @@ impl Event {
     }
 
     #[cold]
-    pub(super) unsafe fn set(self: Pin<&Self>) {
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
         let thread = self.thread.take();
         let thread = thread.expect("Event waiting without a thread");
 
         // Try to not leave dangling references when returning (see below)
         let is_set_ptr = &self.is_set as *const AtomicBool;
-        drop(self);
+        let _ = self;
 
         // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
         // `store()` has a potentially dangling ref to `is_set` once wait() thread sees true and returns.
@@ -0,0 +1,47 @@
+use std::{
+    marker::PhantomPinned,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// The primary blocking primitive used by all the synchronization data structures.
+///
+/// WebAssembly without the `atomics` target feature only ever runs a single thread,
+/// so waiting for an event which isn't set would never return. Instead of hanging,
+/// this panics, leaving the non-blocking `try_*` operations of the primitives usable.
+pub(crate) struct Event {
+    is_set: AtomicBool,
+    _pinned: PhantomPinned,
+}
+
+impl Event {
+    pub(crate) const fn new() -> Self {
+        Self {
+            is_set: AtomicBool::new(false),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+        // SAFETY: The event lives on the thread's stack.
+        let event = Self::new();
+        f(unsafe { Pin::new_unchecked(&event) })
+    }
+
+    #[cold]
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+        if self.is_set.load(Ordering::Acquire) {
+            return true;
+        }
+
+        // A timed wait can't return early either, as there is no clock to wait on.
+        let _ = timeout;
+        panic!("cannot block the only thread on WebAssembly without the `atomics` target feature");
+    }
+
+    #[cold]
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
+        self.is_set.store(true, Ordering::Release);
+    }
+}
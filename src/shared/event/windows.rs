@@ -0,0 +1,290 @@
+use std::{
+    ffi::c_void,
+    marker::PhantomPinned,
+    mem,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+const EMPTY: u32 = 0;
+const WAITING: u32 = 1;
+const SET: u32 = 2;
+
+/// The primary blocking primitive used by all the synchronization data structures.
+///
+/// Waits with `WaitOnAddress` on Windows 8 and later, and with NT keyed events
+/// on older versions which don't have it.
+pub(crate) struct Event {
+    state: AtomicU32,
+    _pinned: PhantomPinned,
+}
+
+impl Event {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(EMPTY),
+            _pinned: PhantomPinned,
+        }
+    }
+
+    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+        // SAFETY: The event lives on the thread's stack.
+        let event = Self::new();
+        f(unsafe { Pin::new_unchecked(&event) })
+    }
+
+    #[cold]
+    pub(crate) fn wait(self: Pin<&Self>, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        match Backend::get() {
+            Backend::WaitOnAddress(wait, _) => self.wait_on_address(wait, deadline),
+            Backend::KeyedEvent(handle) => self.wait_keyed_event(handle, deadline),
+        }
+    }
+
+    #[cold]
+    pub(crate) unsafe fn set(self: Pin<&Self>) {
+        // Try to not leave dangling references when returning (see below)
+        let state_ptr = &self.state as *const AtomicU32;
+        let _ = self;
+
+        // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
+        // `swap()` has a potentially dangling ref to `state` once wait() thread sees SET and returns.
+        // Release barrier ensures the set() happens before wait() sees SET and returns.
+        let state = (*state_ptr).swap(SET, Ordering::Release);
+
+        match Backend::get() {
+            // Waking up an address which isn't waited on anymore is harmless,
+            // so it doesn't matter if the waiter already returned.
+            Backend::WaitOnAddress(_, wake) => wake(state_ptr as *const c_void),
+            // Releasing a keyed event blocks until a thread waits on the key, so only
+            // do it if the waiter is (or will be) waiting. It can't return until it is released.
+            Backend::KeyedEvent(handle) => {
+                if state == WAITING {
+                    let status =
+                        NtReleaseKeyedEvent(handle, state_ptr as *const c_void, 0, ptr::null());
+                    debug_assert_eq!(status, STATUS_SUCCESS);
+                }
+            }
+        }
+    }
+
+    fn wait_on_address(&self, wait: WaitOnAddressFn, deadline: Option<Instant>) -> bool {
+        loop {
+            // Acquire barrier ensures that the set() happens before we return.
+            if self.state.load(Ordering::Acquire) == SET {
+                return true;
+            }
+
+            let timeout_ms = match deadline {
+                None => INFINITE,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) => duration_to_ms(remaining),
+                    None => return false,
+                },
+            };
+
+            // This may return spuriously, in which case the state and the deadline are checked again.
+            let compare = EMPTY;
+            unsafe {
+                wait(
+                    &self.state as *const AtomicU32 as *const c_void,
+                    &compare as *const u32 as *const c_void,
+                    mem::size_of::<u32>(),
+                    timeout_ms,
+                );
+            }
+        }
+    }
+
+    fn wait_keyed_event(&self, handle: Handle, deadline: Option<Instant>) -> bool {
+        // Announce that we're about to wait, so that set() releases the keyed event.
+        // Acquire barrier ensures that the set() happens before we return.
+        if let Err(state) =
+            self.state
+                .compare_exchange(EMPTY, WAITING, Ordering::Acquire, Ordering::Acquire)
+        {
+            debug_assert_eq!(state, SET);
+            return true;
+        }
+
+        let key = &self.state as *const AtomicU32 as *const c_void;
+        let timeout = deadline.map(|deadline| {
+            // NT timeouts are in 100ns units, with negative values being relative to now.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let units = remaining.as_nanos() / 100;
+            -(units.min(i64::MAX as u128) as i64)
+        });
+
+        let status = unsafe {
+            NtWaitForKeyedEvent(
+                handle,
+                key,
+                0,
+                timeout
+                    .as_ref()
+                    .map_or(ptr::null(), |timeout| timeout as *const i64),
+            )
+        };
+
+        if status == STATUS_TIMEOUT {
+            // On timeout, stop waiting unless set() already saw us waiting.
+            // In that case it's about to release the keyed event, which blocks
+            // until we wait on it, so do that before returning.
+            match self
+                .state
+                .compare_exchange(WAITING, EMPTY, Ordering::Relaxed, Ordering::Acquire)
+            {
+                Ok(_) => return false,
+                Err(state) => debug_assert_eq!(state, SET),
+            }
+
+            let status = unsafe { NtWaitForKeyedEvent(handle, key, 0, ptr::null()) };
+            debug_assert_eq!(status, STATUS_SUCCESS);
+        }
+
+        debug_assert_eq!(self.state.load(Ordering::Acquire), SET);
+        true
+    }
+}
+
+fn duration_to_ms(duration: Duration) -> u32 {
+    // Round up so that we don't wake up before the deadline and spin on it.
+    let ms = duration
+        .saturating_add(Duration::from_nanos(999_999))
+        .as_millis();
+    ms.min((INFINITE - 1) as u128) as u32
+}
+
+#[derive(Copy, Clone)]
+enum Backend {
+    WaitOnAddress(WaitOnAddressFn, WakeByAddressSingleFn),
+    KeyedEvent(Handle),
+}
+
+impl Backend {
+    /// Returns the backend, looking up which one is available on the first call.
+    ///
+    /// This can't use any of the crate's primitives as they'd block on an Event,
+    /// so the lookups may happen concurrently. They always find the same functions,
+    /// and only one keyed event handle is kept.
+    fn get() -> Self {
+        static WAIT_ON_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+        static WAKE_BY_ADDRESS_SINGLE: AtomicUsize = AtomicUsize::new(0);
+        static KEYED_EVENT: AtomicUsize = AtomicUsize::new(0);
+
+        let wait = WAIT_ON_ADDRESS.load(Ordering::Acquire);
+        if wait != 0 {
+            let wake = WAKE_BY_ADDRESS_SINGLE.load(Ordering::Relaxed);
+            return unsafe { Self::wait_on_address(wait, wake) };
+        }
+
+        let handle = KEYED_EVENT.load(Ordering::Acquire);
+        if handle != 0 {
+            return Self::KeyedEvent(handle as Handle);
+        }
+
+        Self::get_slow(&WAIT_ON_ADDRESS, &WAKE_BY_ADDRESS_SINGLE, &KEYED_EVENT)
+    }
+
+    unsafe fn wait_on_address(wait: usize, wake: usize) -> Self {
+        Self::WaitOnAddress(
+            mem::transmute::<usize, WaitOnAddressFn>(wait),
+            mem::transmute::<usize, WakeByAddressSingleFn>(wake),
+        )
+    }
+
+    #[cold]
+    fn get_slow(
+        wait_on_address: &AtomicUsize,
+        wake_by_address_single: &AtomicUsize,
+        keyed_event: &AtomicUsize,
+    ) -> Self {
+        unsafe {
+            // WaitOnAddress is available from Windows 8 on, through this API set.
+            let synch = GetModuleHandleA(SYNCH_API_SET.as_ptr());
+            if !synch.is_null() {
+                let wait = GetProcAddress(synch, WAIT_ON_ADDRESS.as_ptr()) as usize;
+                let wake = GetProcAddress(synch, WAKE_BY_ADDRESS_SINGLE.as_ptr()) as usize;
+                if wait != 0 && wake != 0 {
+                    // Release barrier ensures the wake function is visible once the wait one is.
+                    wake_by_address_single.store(wake, Ordering::Relaxed);
+                    wait_on_address.store(wait, Ordering::Release);
+                    return Self::wait_on_address(wait, wake);
+                }
+            }
+
+            let mut handle: Handle = ptr::null_mut();
+            let status = NtCreateKeyedEvent(
+                &mut handle,
+                GENERIC_READ | GENERIC_WRITE,
+                ptr::null_mut(),
+                0,
+            );
+            assert_eq!(
+                status, STATUS_SUCCESS,
+                "neither WaitOnAddress nor keyed events are available to block threads"
+            );
+
+            // Keep the first handle created if multiple threads raced to create one.
+            match keyed_event.compare_exchange(
+                0,
+                handle as usize,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => Self::KeyedEvent(handle),
+                Err(existing) => {
+                    CloseHandle(handle);
+                    Self::KeyedEvent(existing as Handle)
+                }
+            }
+        }
+    }
+}
+
+type Handle = *mut c_void;
+type NtStatus = i32;
+type WaitOnAddressFn = unsafe extern "system" fn(*const c_void, *const c_void, usize, u32) -> i32;
+type WakeByAddressSingleFn = unsafe extern "system" fn(*const c_void);
+
+const SYNCH_API_SET: &[u8] = b"api-ms-win-core-synch-l1-2-0.dll\0";
+const WAIT_ON_ADDRESS: &[u8] = b"WaitOnAddress\0";
+const WAKE_BY_ADDRESS_SINGLE: &[u8] = b"WakeByAddressSingle\0";
+
+const INFINITE: u32 = u32::MAX;
+const GENERIC_READ: u32 = 0x8000_0000;
+const GENERIC_WRITE: u32 = 0x4000_0000;
+const STATUS_SUCCESS: NtStatus = 0;
+const STATUS_TIMEOUT: NtStatus = 0x102;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetModuleHandleA(module_name: *const u8) -> Handle;
+    fn GetProcAddress(module: Handle, proc_name: *const u8) -> *mut c_void;
+    fn CloseHandle(handle: Handle) -> i32;
+}
+
+#[link(name = "ntdll")]
+extern "system" {
+    fn NtCreateKeyedEvent(
+        handle: *mut Handle,
+        access: u32,
+        attributes: *mut c_void,
+        flags: u32,
+    ) -> NtStatus;
+    fn NtWaitForKeyedEvent(
+        handle: Handle,
+        key: *const c_void,
+        alertable: u8,
+        timeout: *const i64,
+    ) -> NtStatus;
+    fn NtReleaseKeyedEvent(
+        handle: Handle,
+        key: *const c_void,
+        alertable: u8,
+        timeout: *const i64,
+    ) -> NtStatus;
+}
@@ -0,0 +1,33 @@
+//! Names given to locks by the application, looked up by their address when reporting
+//! about them, e.g. in deadlocks and contention logs.
+
+use crate::{const_spin_lock, Mutex, RwLock, SpinLock};
+
+// A spin lock is used as the labeled locks may report about themselves while it's held.
+static LABELS: SpinLock<Vec<(usize, &'static str)>> = const_spin_lock(Vec::new());
+
+/// Returns the address identifying the mutex in reports.
+pub(crate) fn mutex_addr<T: ?Sized>(mutex: &Mutex<T>) -> usize {
+    let rwlock = unsafe { &mutex.raw().rwlock };
+    rwlock as *const _ as usize
+}
+
+/// Returns the address identifying the rwlock in reports.
+pub(crate) fn rwlock_addr<T: ?Sized>(rwlock: &RwLock<T>) -> usize {
+    let rwlock = unsafe { rwlock.raw() };
+    rwlock as *const _ as usize
+}
+
+pub(crate) fn set(lock: usize, label: &'static str) {
+    let mut labels = LABELS.lock();
+    labels.retain(|&(labeled, _)| labeled != lock);
+    labels.push((lock, label));
+}
+
+pub(crate) fn get(lock: usize) -> Option<&'static str> {
+    LABELS
+        .lock()
+        .iter()
+        .find(|&&(labeled, _)| labeled == lock)
+        .map(|&(_, label)| label)
+}
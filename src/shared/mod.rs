@@ -1,17 +1,36 @@
+pub(crate) mod chaos;
+#[cfg(feature = "std")]
+mod epoch;
 mod event;
+#[cfg(feature = "std")]
+pub(crate) mod label;
+pub(crate) mod numa;
 mod parker;
 mod spin;
 mod strict_provenance;
+pub(crate) mod trace;
 mod waiter;
 
+#[cfg(feature = "std")]
+pub(crate) use self::epoch::Epoch;
 pub(crate) use self::{
-    spin::SpinWait,
+    spin::{CasBackoff, SpinWait},
     strict_provenance::{invalid_mut, AtomicPtrRmw, StrictProvenance},
-    waiter::Waiter,
+    waiter::{Primitive, Waiter},
 };
 
 use std::sync::atomic::{fence, AtomicPtr, Ordering};
 
+#[cfg(not(feature = "std"))]
+pub(crate) use self::event::{current as current_thread, yield_now};
+
+/// Yields the current thread to the OS scheduler.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn yield_now() {
+    std::thread::yield_now();
+}
+
 // Thread-Sanitizer only has partial fence support, so when running under it, we
 // try and avoid false positives by using a discarded acquire load instead.
 #[inline]
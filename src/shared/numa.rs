@@ -0,0 +1,285 @@
+//! Detection of the NUMA node threads are running on, used to prefer waking up
+//! waiters close to the releasing thread on multi-socket machines.
+//!
+//! With cache affinity enabled, the waiters are instead grouped by the last level cache
+//! of the CPU they ran on, which is finer grained than NUMA nodes on most machines.
+//!
+//! Both are opt-in through the [`config`](crate::config) module, which reads the topology
+//! right away so that threads never read it from the file system while blocking.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+const UNKNOWN: u8 = 0;
+const SINGLE_NODE: u8 = 1;
+const MULTI_NODE: u8 = 2;
+
+static TOPOLOGY: AtomicU8 = AtomicU8::new(UNKNOWN);
+static NUMA_AFFINITY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_numa_affinity(enabled: bool) {
+    if enabled && TOPOLOGY.load(Ordering::Relaxed) == UNKNOWN {
+        let topology = match sys::node_count() {
+            1 => SINGLE_NODE,
+            _ => MULTI_NODE,
+        };
+        TOPOLOGY.store(topology, Ordering::Relaxed);
+    }
+
+    NUMA_AFFINITY.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn numa_affinity() -> bool {
+    NUMA_AFFINITY.load(Ordering::Relaxed)
+}
+
+/// Returns true if NUMA affinity is enabled and the machine has more than one NUMA node.
+#[inline]
+pub(crate) fn is_multi_node() -> bool {
+    numa_affinity() && TOPOLOGY.load(Ordering::Relaxed) == MULTI_NODE
+}
+
+static CACHE_AFFINITY: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_cache_affinity(enabled: bool) {
+    if enabled {
+        sys::load_caches();
+    }
+
+    CACHE_AFFINITY.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn cache_affinity() -> bool {
+    CACHE_AFFINITY.load(Ordering::Relaxed)
+}
+
+/// Returns true if threads can run in different locality domains: last level caches
+/// with cache affinity enabled, and NUMA nodes with NUMA affinity enabled.
+#[inline]
+pub(crate) fn is_multi_domain() -> bool {
+    if cache_affinity() && sys::is_multi_cache() {
+        return true;
+    }
+
+    is_multi_node()
+}
+
+/// Returns the locality domain the current thread is running on.
+///
+/// This is a hint as the thread may migrate right after, and is only meaningful
+/// if [`is_multi_domain`] returns true.
+#[inline]
+pub(crate) fn current_domain() -> usize {
+    #[cfg(test)]
+    if let Some(node) = tests::FAKE_NODE.with(|node| node.get()) {
+        return node;
+    }
+
+    if cache_affinity() {
+        if let Some(cache) = sys::current_cache() {
+            return cache;
+        }
+    }
+
+    sys::current_node()
+}
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod sys {
+    use std::{
+        fs, ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    };
+
+    pub(super) fn node_count() -> usize {
+        // The online nodes are listed as ranges, e.g. "0-1" or "0,2-3".
+        let online = match fs::read_to_string("/sys/devices/system/node/online") {
+            Ok(online) => online,
+            Err(_) => return 1,
+        };
+
+        online
+            .trim()
+            .split(',')
+            .filter_map(|range| {
+                let mut bounds = range.splitn(2, '-').map(|bound| bound.parse::<usize>());
+                let start = bounds.next()?.ok()?;
+                let end = bounds.next().unwrap_or(Ok(start)).ok()?;
+                Some(end.saturating_sub(start) + 1)
+            })
+            .sum::<usize>()
+            .max(1)
+    }
+
+    pub(super) fn current_node() -> usize {
+        getcpu().map_or(0, |(_, node)| node)
+    }
+
+    /// Returns the CPU and the NUMA node the current thread is running on.
+    fn getcpu() -> Option<(usize, usize)> {
+        let mut cpu: libc::c_uint = 0;
+        let mut node: libc::c_uint = 0;
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_getcpu,
+                &mut cpu as *mut libc::c_uint,
+                &mut node as *mut libc::c_uint,
+                ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        if rc == 0 {
+            Some((cpu as usize, node as usize))
+        } else {
+            None
+        }
+    }
+
+    /// The last level cache of each CPU, identified by the first CPU sharing it.
+    struct Caches {
+        by_cpu: Vec<usize>,
+        is_multi_cache: bool,
+    }
+
+    static CACHES: AtomicPtr<Caches> = AtomicPtr::new(ptr::null_mut());
+
+    /// Returns the caches, or `None` if they weren't loaded.
+    fn caches() -> Option<&'static Caches> {
+        let caches = CACHES.load(Ordering::Acquire);
+        unsafe { caches.as_ref() }
+    }
+
+    /// Reads the caches of the CPUs unless they were already.
+    pub(super) fn load_caches() {
+        if caches().is_some() {
+            return;
+        }
+
+        let by_cpu = read_caches();
+        let mut domains = by_cpu.clone();
+        domains.sort_unstable();
+        domains.dedup();
+        let new_caches = Box::into_raw(Box::new(Caches {
+            is_multi_cache: domains.len() > 1,
+            by_cpu,
+        }));
+
+        // Release barrier ensures the caches are visible once the pointer is.
+        // Threads racing to load them keep the ones of the first.
+        if CACHES
+            .compare_exchange(
+                ptr::null_mut(),
+                new_caches,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            drop(unsafe { Box::from_raw(new_caches) });
+        }
+    }
+
+    fn read_caches() -> Vec<usize> {
+        let mut by_cpu = Vec::new();
+        for cpu in 0.. {
+            let cache_dir = format!("/sys/devices/system/cpu/cpu{}/cache", cpu);
+            if fs::metadata(&cache_dir).is_err() {
+                break;
+            }
+
+            // Find the highest level cache of the CPU, defaulting to the CPU itself.
+            let mut last_level = (0, cpu);
+            for index in 0.. {
+                let index_dir = format!("{}/index{}", cache_dir, index);
+                let level = match fs::read_to_string(format!("{}/level", index_dir)) {
+                    Ok(level) => level.trim().parse::<usize>().unwrap_or(0),
+                    Err(_) => break,
+                };
+
+                // The shared CPUs are listed as ranges, e.g. "0-7" or "0,8".
+                let first_cpu = fs::read_to_string(format!("{}/shared_cpu_list", index_dir))
+                    .ok()
+                    .and_then(|list| {
+                        let end = list.find(|c: char| !c.is_ascii_digit())?;
+                        list[..end].parse::<usize>().ok()
+                    });
+
+                if let Some(first_cpu) = first_cpu {
+                    if level > last_level.0 {
+                        last_level = (level, first_cpu);
+                    }
+                }
+            }
+
+            by_cpu.push(last_level.1);
+        }
+        by_cpu
+    }
+
+    pub(super) fn is_multi_cache() -> bool {
+        matches!(caches(), Some(caches) if caches.is_multi_cache)
+    }
+
+    pub(super) fn current_cache() -> Option<usize> {
+        let caches = caches()?;
+        let (cpu, _) = getcpu()?;
+        caches.by_cpu.get(cpu).copied()
+    }
+}
+
+#[cfg(not(all(feature = "std", any(target_os = "linux", target_os = "android"))))]
+mod sys {
+    pub(super) fn node_count() -> usize {
+        1
+    }
+
+    pub(super) fn current_node() -> usize {
+        0
+    }
+
+    pub(super) fn load_caches() {}
+
+    pub(super) fn is_multi_cache() -> bool {
+        false
+    }
+
+    pub(super) fn current_cache() -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{cell::Cell, sync::atomic::Ordering};
+
+    std::thread_local! {
+        pub(super) static FAKE_NODE: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Pretends that the machine has multiple NUMA nodes, with the current thread on `node`.
+    pub(crate) fn fake_node(node: usize) {
+        super::TOPOLOGY.store(super::MULTI_NODE, Ordering::Relaxed);
+        super::NUMA_AFFINITY.store(true, Ordering::Relaxed);
+        FAKE_NODE.with(|fake| fake.set(Some(node)));
+    }
+
+    #[test]
+    fn topology() {
+        assert!(super::sys::node_count() >= 1);
+        assert!(super::sys::current_node() < 4096);
+    }
+
+    #[test]
+    fn numa_affinity_loads_topology() {
+        // Other tests fake multiple nodes, which enables NUMA affinity anyway.
+        super::set_numa_affinity(true);
+        assert!(super::numa_affinity());
+        assert_ne!(super::TOPOLOGY.load(Ordering::Relaxed), super::UNKNOWN);
+    }
+
+    #[test]
+    fn caches() {
+        super::sys::load_caches();
+        if super::sys::is_multi_cache() {
+            assert!(super::sys::current_cache().is_some());
+        }
+    }
+}
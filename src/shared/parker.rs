@@ -1,4 +1,4 @@
-use super::{event::Event, SpinWait};
+use super::{chaos, event::Event, trace, SpinWait};
 use std::{
     pin::Pin,
     ptr::{self, NonNull},
@@ -6,6 +6,9 @@ use std::{
     time::Duration,
 };
 
+#[cfg(feature = "std")]
+use {crate::clock, std::time::Instant};
+
 #[derive(Default)]
 pub(crate) struct Parker {
     event: AtomicPtr<Event>,
@@ -29,6 +32,18 @@ impl Parker {
     }
 
     pub(crate) fn park(&self, timeout: Option<Duration>) -> bool {
+        // Timeouts measured with the thread's virtual clock are turned into deadlines on it.
+        #[cfg(feature = "std")]
+        if let Some(timeout) = timeout {
+            if let Some(now) = clock::virtual_now() {
+                return self.park_until(now.checked_add(timeout));
+            }
+        }
+
+        self.park_for(timeout)
+    }
+
+    fn park_for(&self, timeout: Option<Duration>) -> bool {
         // Spin a little bit in hopes that another thread wakes us up.
         let mut spin = SpinWait::default();
         loop {
@@ -43,6 +58,41 @@ impl Parker {
         }
     }
 
+    /// Parks until unparked or until the deadline, returning false if it passed.
+    ///
+    /// Like with `park()`, a waiter which timed out must make sure that no thread is about
+    /// to unpark it before it can be reused, e.g. by unlinking itself from the queue it's in.
+    #[cfg(feature = "std")]
+    pub(crate) fn park_until(&self, deadline: Option<Instant>) -> bool {
+        // A virtual clock moves on without waking up the threads waiting on it,
+        // so park for a little while at a time and check the deadline again.
+        const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+        loop {
+            let (now, is_virtual) = match clock::virtual_now() {
+                Some(now) => (now, true),
+                None => (Instant::now(), false),
+            };
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(now) {
+                    Some(timeout) => Some(timeout),
+                    None => return false,
+                },
+                None => None,
+            };
+
+            match timeout {
+                Some(timeout) if is_virtual => {
+                    if self.park_for(Some(timeout.min(POLL_INTERVAL))) {
+                        return true;
+                    }
+                }
+                timeout => return self.park_for(timeout),
+            }
+        }
+    }
+
     #[cold]
     fn park_slow(&self, timeout: Option<Duration>) -> bool {
         Event::with(|ev| {
@@ -58,8 +108,11 @@ impl Parker {
                 return self.park_complete(event);
             }
 
+            #[cfg(feature = "metrics")]
+            let _parked = crate::metrics::parked();
+
             // Do a wait on the event and check if we timed out.
-            let timed_out = !ev.wait(timeout);
+            let timed_out = !trace::park(self, timeout, || Self::wait(ev, timeout));
             if timed_out {
                 // On timeout, we must remove our event from self.event
                 // before returning to ensure that unpark() doesn't access invalid memory.
@@ -72,7 +125,11 @@ impl Parker {
                     Ordering::Release,
                     Ordering::Relaxed,
                 ) {
-                    Ok(_) => return false,
+                    Ok(_) => {
+                        #[cfg(feature = "metrics")]
+                        crate::metrics::increment(crate::metrics::Counter::Timeouts);
+                        return false;
+                    }
                     Err(_) => assert!(ev.wait(None)),
                 }
             }
@@ -84,11 +141,27 @@ impl Parker {
         })
     }
 
+    /// Waits on the event, letting the watchdog know if it takes too long.
+    fn wait(ev: Pin<&Event>, timeout: Option<Duration>) -> bool {
+        #[cfg(feature = "std")]
+        if crate::watchdog::is_set() {
+            return crate::watchdog::wait(timeout, |timeout| ev.wait(timeout));
+        }
+
+        ev.wait(timeout)
+    }
+
     pub(crate) fn unpark(&self) {
+        chaos::delay_unpark();
+        trace::unpark(self);
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::increment(crate::metrics::Counter::Unparks);
+
         unsafe {
             // Try not to leave a dangling ref to the parker (see below).
             let event_ptr = &self.event as *const AtomicPtr<Event>;
-            drop(self);
+            let _ = self;
 
             // FIXME (maybe): This is a case of https://github.com/rust-lang/rust/issues/55005.
             // `swap()` has a potentially dangling ref to `event_ptr` once park() thread sees notified and returns.
@@ -1,31 +1,68 @@
+use super::numa;
+use crate::config::{self, Backoff};
+use std::hint::spin_loop;
+
+/// How much longer the exponential backoff is on machines with several NUMA nodes, as a shift.
+const REMOTE_NODE_SHIFT: usize = 2;
+
+#[cfg(feature = "std")]
 use std::{
-    hint::spin_loop,
     num::NonZeroUsize,
     sync::atomic::{AtomicUsize, Ordering},
     thread::available_parallelism,
 };
 
-#[derive(Default)]
 pub(crate) struct SpinWait {
     counter: usize,
+    limit: usize,
+    backoff: Backoff,
+}
+
+impl Default for SpinWait {
+    fn default() -> Self {
+        // Spin for at most 100 times unless configured otherwise.
+        // This could be lower but this works as is also the default spin count in musl
+        // as well as glibc PTHREAD_MUTEX_ADAPTIVE_SPIN.
+        let backoff = config::backoff();
+        Self::with_backoff(backoff.get_spin_limit(), backoff)
+    }
 }
 
 impl SpinWait {
+    pub(crate) fn with_limit(limit: usize) -> Self {
+        Self::with_backoff(limit, config::backoff())
+    }
+
+    fn with_backoff(limit: usize, backoff: Backoff) -> Self {
+        Self {
+            counter: 0,
+            limit,
+            backoff,
+        }
+    }
+
+    fn spin(&self) {
+        if self.backoff.get_yield_thread() {
+            super::yield_now();
+        } else {
+            spin_loop();
+        }
+    }
+
     pub(crate) fn try_yield_now(&mut self) -> bool {
-        // Don't spin if we're on a uni-core system (e.g. docker instance or low-end vps/vm)
-        if !is_multi_core() {
+        // Don't spin if we're on a uni-core system (e.g. docker instance or low-end vps/vm),
+        // nor with the deterministic feature, where how long a thread spins would decide
+        // whether it acquires the primitive or queues up.
+        if cfg!(feature = "deterministic") || !is_multi_core() {
             return false;
         }
 
-        // Spin for at most 100 times.
-        // This could be lower but this works as is also the default spin count in musl
-        // as well as glibc PTHREAD_MUTEX_ADAPTIVE_SPIN.
-        if self.counter >= 100 {
+        if self.counter >= self.limit {
             return false;
         }
 
         self.counter += 1;
-        spin_loop();
+        self.spin();
         true
     }
 
@@ -38,28 +75,78 @@ impl SpinWait {
         // Spin using exponential backoff.
         // parking_lot has the spin count capped at (1 << 10) = 1024
         // but we probably don't need to spin that long to avoid cache-line contention
-        // so we cap it at (1 << 5) = 32 instead (this is still fairly arbitrary).
+        // so we cap it at (1 << 5) = 32 by default instead (this is still fairly arbitrary).
         self.counter += 1;
-        for _ in 0..(1 << self.counter.min(5)) {
-            spin_loop();
+        let mut exponent = self.counter.min(self.backoff.get_max_exponent() as usize);
+
+        // Cache lines take longer to move between NUMA nodes, so back off for longer there.
+        if numa::is_multi_node() {
+            exponent += REMOTE_NODE_SHIFT;
+        }
+
+        for _ in 0..(1 << exponent) {
+            self.spin();
+        }
+    }
+}
+
+/// Backoff between the retries of a `compare_exchange_weak` which failed because of a
+/// concurrent update.
+///
+/// On RISC-V without the Zacas extension, compare exchanges are `lr`/`sc` loops which fail
+/// whenever another hart writes to the cache line, so retrying right away under contention
+/// mostly keeps it bouncing between them. The build script enables the backoff there, which
+/// spins exponentially longer after each failure. It does nothing on other targets, where
+/// compare exchanges are single instructions (`amocas` with Zacas) which only fail when the
+/// value actually changed.
+#[derive(Default)]
+pub(crate) struct CasBackoff {
+    #[cfg(usync_cas_backoff)]
+    exponent: u32,
+}
+
+impl CasBackoff {
+    #[cfg(usync_cas_backoff)]
+    const MAX_EXPONENT: u32 = 4;
+
+    #[inline]
+    pub(crate) fn retry(&mut self) {
+        #[cfg(usync_cas_backoff)]
+        {
+            for _ in 0..(1 << self.exponent) {
+                spin_loop();
+            }
+            self.exponent = (self.exponent + 1).min(Self::MAX_EXPONENT);
         }
     }
 }
 
+#[cfg(feature = "std")]
 #[inline]
 fn is_multi_core() -> bool {
     num_cpus().get() > 1
 }
 
+// Without the standard library the amount of cores is unknown, so assume there are
+// several and rely on the spinning being bounded.
+#[cfg(not(feature = "std"))]
+#[inline]
+fn is_multi_core() -> bool {
+    true
+}
+
+#[cfg(feature = "std")]
 static NUM_CPUS: AtomicUsize = AtomicUsize::new(0);
 
+#[cfg(feature = "std")]
 #[inline]
 fn num_cpus() -> NonZeroUsize {
     // fast path to get the num cpus as provided by libstd
     let num_cpus = NUM_CPUS.load(Ordering::Relaxed);
-    NonZeroUsize::new(num_cpus).unwrap_or_else(|| num_cpus_slow())
+    NonZeroUsize::new(num_cpus).unwrap_or_else(num_cpus_slow)
 }
 
+#[cfg(feature = "std")]
 #[cold]
 fn num_cpus_slow() -> NonZeroUsize {
     let num_cpus = available_parallelism()
@@ -11,6 +11,9 @@ pub(crate) const fn invalid_mut<T>(addr: usize) -> *mut T {
     addr as *mut T
 }
 
+/// # Safety
+///
+/// Implementors must ensure that `with_address` preserves the provenance of `self`.
 pub(crate) unsafe trait StrictProvenance: Copy {
     fn address(self) -> usize;
 
@@ -45,10 +48,12 @@ use std::{
 };
 
 pub(crate) trait AtomicPtrRmw<T> {
+    #[allow(dead_code)]
     fn fetch_add(&self, value: T, ordering: Ordering) -> T;
 
     fn fetch_sub(&self, value: T, ordering: Ordering) -> T;
 
+    #[allow(dead_code)]
     fn fetch_ptr_or(&self, value: T, ordering: Ordering) -> T;
 }
 
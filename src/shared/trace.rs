@@ -0,0 +1,177 @@
+//! Structured events for the `tracing` feature, emitted at the `TRACE` level with the `usync`
+//! target: when a thread blocks in a primitive's slow path and is woken up, and when locks are
+//! acquired and released, along with how long they were held by the current thread.
+//!
+//! Hold durations are tracked per thread, so a guard released on another thread than the one
+//! which acquired it (with the `send_guard` feature) reports its release without a duration.
+//! Without the feature, all of this compiles down to nothing.
+//!
+//! The lock events are also where the `deadlock_detection` feature tracks the held locks
+//! and where the `hold_time` feature measures how long they're held.
+
+use std::time::Duration;
+
+/// How a lock is held.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    Exclusive,
+    Shared,
+}
+
+/// Records that the current thread acquired the lock at `lock`.
+#[inline]
+pub(crate) fn acquired<T>(lock: *const T, kind: LockKind) {
+    #[cfg(feature = "deadlock_detection")]
+    crate::deadlock::acquired(lock as usize);
+    #[cfg(any(feature = "tracing", feature = "hold_time"))]
+    hold::start(lock as usize);
+
+    imp::acquired(lock as *const (), kind)
+}
+
+/// Records that the current thread released the lock at `lock`.
+#[inline]
+pub(crate) fn released<T>(lock: *const T, kind: LockKind) {
+    #[cfg(feature = "deadlock_detection")]
+    crate::deadlock::released(lock as usize);
+
+    #[cfg(any(feature = "tracing", feature = "hold_time"))]
+    let held = hold::stop(lock as usize);
+    #[cfg(not(any(feature = "tracing", feature = "hold_time")))]
+    let held = None;
+
+    #[cfg(feature = "hold_time")]
+    if let Some(held) = held {
+        crate::hold_time::record(lock as usize, held);
+    }
+
+    imp::released(lock as *const (), kind, held)
+}
+
+/// Calls `wait`, which blocks the thread on `parker`, recording how long it was blocked
+/// and whether it was woken up or `wait` returned false for a timeout.
+#[inline]
+pub(crate) fn park<T>(
+    parker: *const T,
+    timeout: Option<Duration>,
+    wait: impl FnOnce() -> bool,
+) -> bool {
+    imp::park(parker as *const (), timeout, wait)
+}
+
+/// Records that the current thread is waking up the thread blocked on `parker`.
+#[inline]
+pub(crate) fn unpark<T>(parker: *const T) {
+    imp::unpark(parker as *const ())
+}
+
+#[cfg(any(feature = "tracing", feature = "hold_time"))]
+mod hold {
+    use std::{
+        cell::RefCell,
+        time::{Duration, Instant},
+    };
+
+    std::thread_local! {
+        /// The locks held by the current thread, with when they were acquired.
+        pub(super) static HELD: RefCell<Vec<(usize, Instant)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn start(lock: usize) {
+        // Thread locals may already be destroyed, in which case the hold isn't timed.
+        let _ = HELD.try_with(|held| held.borrow_mut().push((lock, Instant::now())));
+    }
+
+    /// Returns how long the current thread held the lock, if it acquired it.
+    pub(super) fn stop(lock: usize) -> Option<Duration> {
+        // Shared locks can be held more than once, in which case the latest hold is released.
+        HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            let index = held.iter().rposition(|&(held_lock, _)| held_lock == lock)?;
+            Some(held.remove(index).1.elapsed())
+        })
+        .ok()
+        .flatten()
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use super::LockKind;
+    use std::time::{Duration, Instant};
+    use tracing_crate::trace;
+
+    pub(super) fn acquired(lock: *const (), kind: LockKind) {
+        trace!(target: "usync", lock = ?lock, ?kind, "lock acquired");
+    }
+
+    pub(super) fn released(lock: *const (), kind: LockKind, held: Option<Duration>) {
+        match held {
+            Some(held) => trace!(target: "usync", lock = ?lock, ?kind, ?held, "lock released"),
+            None => trace!(target: "usync", lock = ?lock, ?kind, "lock released"),
+        }
+    }
+
+    pub(super) fn park(
+        parker: *const (),
+        timeout: Option<Duration>,
+        wait: impl FnOnce() -> bool,
+    ) -> bool {
+        trace!(target: "usync", parker = ?parker, ?timeout, "parking");
+        let parked_at = Instant::now();
+        let unparked = wait();
+        let parked = parked_at.elapsed();
+        trace!(target: "usync", parker = ?parker, ?parked, timed_out = !unparked, "parked");
+        unparked
+    }
+
+    pub(super) fn unpark(parker: *const ()) {
+        trace!(target: "usync", parker = ?parker, "unparking");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use super::LockKind;
+    use std::time::Duration;
+
+    #[inline(always)]
+    pub(super) fn acquired(_lock: *const (), _kind: LockKind) {}
+
+    #[inline(always)]
+    pub(super) fn released(_lock: *const (), _kind: LockKind, _held: Option<Duration>) {}
+
+    #[inline(always)]
+    pub(super) fn park(
+        _parker: *const (),
+        _timeout: Option<Duration>,
+        wait: impl FnOnce() -> bool,
+    ) -> bool {
+        wait()
+    }
+
+    #[inline(always)]
+    pub(super) fn unpark(_parker: *const ()) {}
+}
+
+#[cfg(all(test, any(feature = "tracing", feature = "hold_time")))]
+mod tests {
+    use super::{acquired, hold::HELD, released, LockKind};
+
+    #[test]
+    fn hold_tracking() {
+        let (a, b) = (1u8, 2u8);
+        acquired(&a, LockKind::Shared);
+        acquired(&b, LockKind::Exclusive);
+        acquired(&a, LockKind::Shared);
+        released(&a, LockKind::Shared);
+        assert_eq!(HELD.with(|held| held.borrow().len()), 2);
+
+        released(&b, LockKind::Exclusive);
+        released(&a, LockKind::Shared);
+        assert!(HELD.with(|held| held.borrow().is_empty()));
+
+        // Releasing a lock acquired on another thread is still fine.
+        released(&b, LockKind::Exclusive);
+    }
+}
@@ -1,7 +1,8 @@
-use super::{parker::Parker, StrictProvenance};
+use super::{numa, parker::Parker, StrictProvenance};
 use std::{
     cell::Cell,
     marker::PhantomPinned,
+    mem,
     pin::Pin,
     ptr::{self, NonNull},
     sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
@@ -13,17 +14,47 @@ pub(crate) struct AtomicWaiterCell(AtomicPtr<Waiter>);
 impl AtomicWaiterCell {
     #[inline]
     pub(crate) fn set(&self, ptr: Option<NonNull<Waiter>>) {
-        let ptr = ptr.map(|p| p.as_ptr()).unwrap_or(ptr::null_mut());
-        self.0.store(ptr, Ordering::Relaxed);
+        self.store(ptr, Ordering::Relaxed)
     }
 
     #[inline]
     pub(crate) fn get(&self) -> Option<NonNull<Waiter>> {
-        let ptr = self.0.load(Ordering::Relaxed);
+        self.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn store(&self, ptr: Option<NonNull<Waiter>>, ordering: Ordering) {
+        let ptr = ptr.map(|p| p.as_ptr()).unwrap_or(ptr::null_mut());
+        self.0.store(ptr, ordering);
+    }
+
+    #[inline]
+    pub(crate) fn load(&self, ordering: Ordering) -> Option<NonNull<Waiter>> {
+        let ptr = self.0.load(ordering);
         NonNull::new(ptr)
     }
 }
 
+/// The type and address of the primitive a thread blocks on, which the watchdog reports.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) struct Primitive {
+    pub(crate) name: &'static str,
+    pub(crate) address: usize,
+}
+
+impl Primitive {
+    #[inline]
+    pub(crate) fn new<T: ?Sized>(name: &'static str, primitive: &T) -> Self {
+        Self::at(name, primitive as *const T as *const () as usize)
+    }
+
+    #[inline]
+    pub(crate) const fn at(name: &'static str, address: usize) -> Self {
+        Self { name, address }
+    }
+}
+
 #[derive(Default)]
 #[repr(align(16))]
 pub(crate) struct Waiter {
@@ -33,17 +64,53 @@ pub(crate) struct Waiter {
     pub(crate) waiting_on: Cell<Option<NonNull<()>>>,
     pub(crate) counter: AtomicUsize,
     pub(crate) flags: Cell<usize>,
+    /// The locality domain the thread was running on when it was queued: its NUMA node,
+    /// or its last level cache with cache affinity enabled.
+    pub(crate) domain: Cell<usize>,
+    /// How many times waiters queued after this one were woken up before it.
+    pub(crate) bypassed: Cell<usize>,
+    /// The priority of the thread, when priorities are in use.
+    pub(crate) priority: Cell<u8>,
+    /// The token a thread waiting on a `Condvar` registered with, for `notify_matching`.
+    pub(crate) token: Cell<usize>,
     pub(crate) parker: Parker,
     _pinned: PhantomPinned,
 }
 
+// Catches the repr and the tag bits getting out of sync at compile time.
+const _: () = assert!(mem::align_of::<Waiter>() == 1 << Waiter::TAG_BITS);
+
 impl Waiter {
-    // The waiter is aligned to 16 from the repr to allow ample tagging bits.
-    pub(crate) const MASK: usize = !0b1111usize;
+    /// The low bits of a waiter's address which are always zero, for the primitives to
+    /// store their state alongside a pointer to their queue.
+    ///
+    /// The alignment comes from the repr, so it holds on every target regardless of the
+    /// natural alignment of pointers there.
+    pub(crate) const TAG_BITS: u32 = 4;
+    pub(crate) const MASK: usize = !((1usize << Self::TAG_BITS) - 1);
 
-    pub(crate) fn with<F>(f: impl FnOnce(Pin<&Self>) -> F) -> F {
+    /// Runs `f` with a waiter for the current thread to block on the `primitive`.
+    pub(crate) fn with<F>(primitive: Primitive, f: impl FnOnce(Pin<&Self>) -> F) -> F {
         // SAFETY: the waiter lives on stack.
         let waiter = Self::default();
+        if numa::is_multi_domain() {
+            waiter.domain.set(numa::current_domain());
+        }
+        #[cfg(feature = "std")]
+        if crate::priority::is_in_use() {
+            waiter.priority.set(crate::priority::thread_priority());
+        }
+
+        // The watchdog reports the primitive a stalled thread is blocked on.
+        #[cfg(feature = "std")]
+        if crate::watchdog::is_set() {
+            return crate::watchdog::waiting_on(primitive.name, primitive.address, || {
+                f(unsafe { Pin::new_unchecked(&waiter) })
+            });
+        }
+        #[cfg(not(feature = "std"))]
+        let _ = primitive;
+
         f(unsafe { Pin::new_unchecked(&waiter) })
     }
 
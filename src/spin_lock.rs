@@ -0,0 +1,176 @@
+use core::{
+    fmt, hint,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Raw spin lock which busy-waits instead of ever parking the thread.
+///
+/// The lock is a single byte and doesn't rely on the thread parking machinery or anything
+/// else from the standard library's threading support. This makes it usable in contexts where
+/// blocking is forbidden, like signal or interrupt handlers and early initialization code,
+/// and for critical sections short enough that parking would cost more than spinning.
+///
+/// Contended threads burn CPU time until the lock is released and a descheduled owner
+/// delays every waiter, so prefer [`RawMutex`](struct.RawMutex.html) everywhere else.
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+impl Default for RawSpinLock {
+    fn default() -> Self {
+        <Self as lock_api::RawMutex>::INIT
+    }
+}
+
+impl fmt::Debug for RawSpinLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("RawSpinLock { .. }")
+    }
+}
+
+unsafe impl lock_api::RawMutex for RawSpinLock {
+    type GuardMarker = crate::GuardMarker;
+
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+    };
+
+    #[inline]
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[inline]
+    fn lock(&self) {
+        if !self.try_lock() {
+            self.lock_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl RawSpinLock {
+    #[cold]
+    fn lock_slow(&self) {
+        let mut backoff = 1;
+        loop {
+            // Wait for the lock to look unlocked before trying to acquire it again,
+            // so waiters only read the cache line instead of bouncing it between cores.
+            // The amount of spins between checks grows exponentially to reduce contention.
+            while self.locked.load(Ordering::Relaxed) {
+                for _ in 0..backoff {
+                    hint::spin_loop();
+                }
+                backoff = (backoff * 2).min(64);
+            }
+
+            if lock_api::RawMutex::try_lock(self) {
+                return;
+            }
+        }
+    }
+}
+
+/// A mutual exclusion primitive which spins instead of blocking while the lock is held.
+///
+/// This type is a [`RawSpinLock`] wrapped in a [`lock_api::Mutex`]. See the documentation
+/// of `RawSpinLock` for when spinning is preferable to [`Mutex`](type.Mutex.html).
+///
+/// # Examples
+///
+/// ```
+/// use usync::SpinLock;
+///
+/// static COUNTER: SpinLock<u32> = usync::const_spin_lock(0);
+///
+/// *COUNTER.lock() += 1;
+/// assert_eq!(*COUNTER.lock(), 1);
+/// ```
+pub type SpinLock<T> = lock_api::Mutex<RawSpinLock, T>;
+
+/// An RAII implementation of a "scoped lock" of a spin lock. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the lock can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+pub type SpinLockGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinLock, T>;
+
+/// An RAII mutex guard returned by `SpinLockGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedSpinLockGuard` and `SpinLockGuard` is that the
+/// former doesn't support temporarily unlocking and re-locking, since that
+/// could introduce soundness issues if the locked object is modified by another
+/// thread.
+pub type MappedSpinLockGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawSpinLock, T>;
+
+/// Creates a new spin lock in an unlocked state ready for use.
+///
+/// This allows creating a spin lock in a constant context on stable Rust.
+pub const fn const_spin_lock<T>(value: T) -> SpinLock<T> {
+    SpinLock::const_new(<RawSpinLock as lock_api::RawMutex>::INIT, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SpinLock;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn smoke() {
+        let m = SpinLock::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 6;
+
+        let m = Arc::new(SpinLock::new(0));
+        let threads: Vec<_> = (0..K)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for _ in 0..J {
+                        *m.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock(), J * K);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = SpinLock::new(());
+        let guard = m.lock();
+        assert!(m.try_lock().is_none());
+        assert!(m.is_locked());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn test_spin_lock_debug() {
+        let lock = SpinLock::new(vec![0u8, 10]);
+        assert_eq!(format!("{:?}", lock), "Mutex { data: [0, 10] }");
+    }
+}
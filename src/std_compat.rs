@@ -0,0 +1,617 @@
+//! Locks with the API of `std::sync`, including poisoning, built on the crate's primitives.
+//!
+//! [`Mutex`], [`RwLock`] and [`Condvar`] have the same methods as their counterparts in
+//! `std::sync` and return the same [`LockResult`]s, so code written against the standard
+//! library can switch to them by only changing its imports. Like in `std`, a lock is poisoned
+//! when a thread panics while holding it exclusively, after which acquiring it returns a
+//! [`PoisonError`] still holding the guard.
+//!
+//! Code which doesn't rely on poisoning should prefer the crate's own locks, which don't
+//! spend the extra checks and whose guards don't need unwrapping.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::thread;
+//! use usync::std_compat::Mutex;
+//!
+//! let counter = Arc::new(Mutex::new(0));
+//! let threads: Vec<_> = (0..4)
+//!     .map(|_| {
+//!         let counter = counter.clone();
+//!         thread::spawn(move || *counter.lock().unwrap() += 1)
+//!     })
+//!     .collect();
+//!
+//! for t in threads {
+//!     t.join().unwrap();
+//! }
+//! assert_eq!(*counter.lock().unwrap(), 4);
+//! ```
+
+use crate::clock;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+pub use crate::WaitTimeoutResult;
+pub use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+/// Whether a lock was poisoned by a thread panicking while holding it.
+struct Flag(AtomicBool);
+
+/// Whether the thread was already panicking when it acquired the lock,
+/// in which case it doesn't poison the lock when releasing it while still panicking.
+struct Poison {
+    panicking: bool,
+}
+
+impl Flag {
+    const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed)
+    }
+
+    /// Starts tracking whether the current thread panics while holding the lock.
+    fn guard(&self) -> Poison {
+        Poison {
+            panicking: thread::panicking(),
+        }
+    }
+
+    /// Poisons the lock if the current thread started panicking while holding it.
+    fn done(&self, poison: &Poison) {
+        if !poison.panicking && thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn result<T>(&self, value: T) -> LockResult<T> {
+        match self.get() {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+
+/// A mutual exclusion primitive with the API of [`std::sync::Mutex`].
+pub struct Mutex<T: ?Sized> {
+    poison: Flag,
+    inner: crate::Mutex<T>,
+}
+
+/// The guard of a locked [`Mutex`], which unlocks it when dropped.
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    lock: &'a Mutex<T>,
+    poison: Poison,
+    guard: crate::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        Self {
+            poison: Flag::new(),
+            inner: crate::const_mutex(value),
+        }
+    }
+
+    /// Consumes the mutex, returning the value, or an error holding it if the mutex is poisoned.
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.into_inner();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the mutex, blocking the current thread until it's able to.
+    ///
+    /// Returns an error holding the guard if the mutex is poisoned.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let guard = self.inner.lock();
+        self.poison.result(MutexGuard::new(self, guard))
+    }
+
+    /// Attempts to acquire the mutex without blocking.
+    ///
+    /// Returns [`TryLockError::WouldBlock`] if it's locked, and
+    /// [`TryLockError::Poisoned`] holding the guard if it's poisoned.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let guard = self.inner.try_lock().ok_or(TryLockError::WouldBlock)?;
+        Ok(self.poison.result(MutexGuard::new(self, guard))?)
+    }
+
+    /// Returns true if a thread panicked while holding the mutex.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the mutex, once the protected value was checked.
+    pub fn clear_poison(&self) {
+        self.poison.clear()
+    }
+
+    /// Returns a mutable reference to the value, or an error holding it if the mutex is poisoned.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.get_mut();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.inner.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.poison.get()).finish()
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    fn new(lock: &'a Mutex<T>, guard: crate::MutexGuard<'a, T>) -> Self {
+        Self {
+            lock,
+            poison: lock.poison.guard(),
+            guard,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// A reader-writer lock with the API of [`std::sync::RwLock`].
+///
+/// Only panics while holding the write lock poison it, like in `std`.
+pub struct RwLock<T: ?Sized> {
+    poison: Flag,
+    inner: crate::RwLock<T>,
+}
+
+/// The guard of a [`RwLock`] locked for reading, which unlocks it when dropped.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    guard: crate::RwLockReadGuard<'a, T>,
+}
+
+/// The guard of a [`RwLock`] locked for writing, which unlocks it when dropped.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    poison: Poison,
+    guard: crate::RwLockWriteGuard<'a, T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new unlocked rwlock.
+    pub const fn new(value: T) -> Self {
+        Self {
+            poison: Flag::new(),
+            inner: crate::const_rwlock(value),
+        }
+    }
+
+    /// Consumes the rwlock, returning the value, or an error holding it if the rwlock is poisoned.
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.into_inner();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks the rwlock for reading, blocking the current thread until it's able to.
+    ///
+    /// Returns an error holding the guard if the rwlock is poisoned.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let guard = self.inner.read();
+        self.poison.result(RwLockReadGuard { guard })
+    }
+
+    /// Attempts to lock the rwlock for reading without blocking.
+    ///
+    /// Returns [`TryLockError::WouldBlock`] if it's locked for writing, and
+    /// [`TryLockError::Poisoned`] holding the guard if it's poisoned.
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        let guard = self.inner.try_read().ok_or(TryLockError::WouldBlock)?;
+        Ok(self.poison.result(RwLockReadGuard { guard })?)
+    }
+
+    /// Locks the rwlock for writing, blocking the current thread until it's able to.
+    ///
+    /// Returns an error holding the guard if the rwlock is poisoned.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let guard = self.inner.write();
+        self.poison.result(RwLockWriteGuard::new(self, guard))
+    }
+
+    /// Attempts to lock the rwlock for writing without blocking.
+    ///
+    /// Returns [`TryLockError::WouldBlock`] if it's locked, and
+    /// [`TryLockError::Poisoned`] holding the guard if it's poisoned.
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        let guard = self.inner.try_write().ok_or(TryLockError::WouldBlock)?;
+        Ok(self.poison.result(RwLockWriteGuard::new(self, guard))?)
+    }
+
+    /// Returns true if a thread panicked while holding the rwlock for writing.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the rwlock, once the protected value was checked.
+    pub fn clear_poison(&self) {
+        self.poison.clear()
+    }
+
+    /// Returns a mutable reference to the value, or an error holding it if the rwlock is poisoned.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let value = self.inner.get_mut();
+        match poisoned {
+            true => Err(PoisonError::new(value)),
+            false => Ok(value),
+        }
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for RwLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.inner.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.poison.get()).finish()
+    }
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    fn new(lock: &'a RwLock<T>, guard: crate::RwLockWriteGuard<'a, T>) -> Self {
+        Self {
+            lock,
+            poison: lock.poison.guard(),
+            guard,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.poison.done(&self.poison);
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// A condition variable with the API of [`std::sync::Condvar`], used with this module's [`Mutex`].
+#[derive(Debug, Default)]
+pub struct Condvar {
+    inner: crate::Condvar,
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    pub const fn new() -> Self {
+        Self {
+            inner: crate::Condvar::new(),
+        }
+    }
+
+    /// Blocks the current thread until the condition variable is notified,
+    /// unlocking the mutex of `guard` while blocked.
+    ///
+    /// Returns an error holding the guard if the mutex is poisoned once it's reacquired.
+    pub fn wait<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        self.inner.wait(&mut guard.guard);
+        guard.lock.poison.result(guard)
+    }
+
+    /// Blocks the current thread until `condition` returns false,
+    /// waiting for notifications in between like [`wait`](Condvar::wait).
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> LockResult<MutexGuard<'a, T>>
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Blocks the current thread until the condition variable is notified or
+    /// `timeout` elapses, unlocking the mutex of `guard` while blocked.
+    ///
+    /// Returns an error holding the guard if the mutex is poisoned once it's reacquired.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
+        let result = self.inner.wait_for(&mut guard.guard, timeout);
+        guard.lock.poison.result((guard, result))
+    }
+
+    /// Blocks the current thread until `condition` returns false or `timeout` elapses,
+    /// waiting for notifications in between like [`wait_timeout`](Condvar::wait_timeout).
+    ///
+    /// The result tells if the timeout elapsed while the condition still held.
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)>
+    where
+        T: ?Sized,
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = clock::now().checked_add(timeout);
+        loop {
+            if !condition(&mut *guard) {
+                return Ok((guard, WaitTimeoutResult(false)));
+            }
+
+            // Waiting too long to represent the deadline means waiting forever.
+            let result = match deadline {
+                Some(deadline) => self.inner.wait_until(&mut guard.guard, deadline),
+                None => {
+                    self.inner.wait(&mut guard.guard);
+                    WaitTimeoutResult(false)
+                }
+            };
+
+            if guard.lock.poison.get() {
+                return Err(PoisonError::new((guard, result)));
+            }
+            if result.timed_out() {
+                let timed_out = condition(&mut *guard);
+                return Ok((guard, WaitTimeoutResult(timed_out)));
+            }
+        }
+    }
+
+    /// Wakes up one thread blocked on the condition variable.
+    pub fn notify_one(&self) {
+        self.inner.notify_one();
+    }
+
+    /// Wakes up all the threads blocked on the condition variable.
+    pub fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condvar, Mutex, RwLock, TryLockError};
+    use std::{panic, sync::Arc, thread, time::Duration};
+
+    #[test]
+    fn poisoning() {
+        let mutex = Arc::new(Mutex::new(0));
+        let _ = {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                panic!("poison the mutex");
+            })
+            .join()
+        };
+
+        assert!(mutex.is_poisoned());
+        let mut guard = mutex.lock().unwrap_err().into_inner();
+        *guard += 1;
+        drop(guard);
+
+        mutex.clear_poison();
+        assert_eq!(*mutex.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn rwlock_poisoning() {
+        let rwlock = Arc::new(RwLock::new(0));
+
+        // Panicking while reading doesn't poison the rwlock.
+        let _ = {
+            let rwlock = rwlock.clone();
+            thread::spawn(move || {
+                let _guard = rwlock.read().unwrap();
+                panic!("don't poison the rwlock");
+            })
+            .join()
+        };
+        assert!(!rwlock.is_poisoned());
+
+        let _ = {
+            let rwlock = rwlock.clone();
+            thread::spawn(move || {
+                let _guard = rwlock.write().unwrap();
+                panic!("poison the rwlock");
+            })
+            .join()
+        };
+        assert!(rwlock.read().is_err());
+        assert!(matches!(rwlock.try_write(), Err(TryLockError::Poisoned(_))));
+    }
+
+    #[test]
+    fn try_lock() {
+        let mutex = Mutex::new(());
+        let _guard = mutex.lock().unwrap();
+        assert!(matches!(mutex.try_lock(), Err(TryLockError::WouldBlock)));
+
+        let rwlock = RwLock::new(());
+        let _read = rwlock.read().unwrap();
+        assert!(rwlock.try_read().is_ok());
+        assert!(matches!(rwlock.try_write(), Err(TryLockError::WouldBlock)));
+    }
+
+    #[test]
+    fn condvar() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let t = {
+            let pair = pair.clone();
+            thread::spawn(move || {
+                *pair.0.lock().unwrap() = true;
+                pair.1.notify_one();
+            })
+        };
+
+        let (lock, cvar) = &*pair;
+        let guard = cvar.wait_while(lock.lock().unwrap(), |ready| !*ready);
+        assert!(*guard.unwrap());
+        t.join().unwrap();
+
+        let guard = lock.lock().unwrap();
+        let (guard, result) = cvar
+            .wait_timeout_while(guard, Duration::from_millis(10), |ready| !*ready)
+            .unwrap();
+        assert!(!result.timed_out());
+        let (_guard, result) = cvar
+            .wait_timeout_while(guard, Duration::from_millis(10), |ready| *ready)
+            .unwrap();
+        assert!(result.timed_out());
+    }
+
+    #[test]
+    fn into_inner() {
+        let mutex = Mutex::new(5);
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("poison the mutex");
+        }));
+        assert_eq!(mutex.into_inner().unwrap_err().into_inner(), 5);
+    }
+}
@@ -7,10 +7,17 @@ pub struct RawThreadId;
 unsafe impl lock_api::GetThreadId for RawThreadId {
     const INIT: Self = Self;
 
+    #[cfg(feature = "std")]
     fn nonzero_thread_id(&self) -> NonZeroUsize {
         // The address of a thread-local is guaranteed to
         // be unique to the current thread and non-zero (null)
-        thread_local!(static ID: bool = false);
+        thread_local!(static ID: bool = const { false });
         ID.with(|id| NonZeroUsize::new(id as *const _ as usize).unwrap())
     }
+
+    #[cfg(not(feature = "std"))]
+    fn nonzero_thread_id(&self) -> NonZeroUsize {
+        // The registered parker already identifies threads with non-zero tokens.
+        NonZeroUsize::new(crate::shared::current_thread()).unwrap()
+    }
 }
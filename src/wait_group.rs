@@ -0,0 +1,259 @@
+use super::shared::{Primitive, Waiter};
+use alloc::sync::Arc;
+use std::{
+    fmt,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// Enables a thread to wait until a dynamic set of other threads finished their work.
+///
+/// Every `WaitGroup` handle counts as one unit of pending work: cloning it adds a unit
+/// and dropping it completes that unit. Like Go's `sync.WaitGroup`, units can also be
+/// added with [`add`](WaitGroup::add) and completed with [`done`](WaitGroup::done)
+/// without creating new handles.
+///
+/// [`wait`](WaitGroup::wait) consumes the caller's handle and blocks until all the
+/// other pending units are completed.
+///
+/// # Examples
+///
+/// ```
+/// use usync::WaitGroup;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let finished = Arc::new(AtomicUsize::new(0));
+/// let wg = WaitGroup::new();
+///
+/// for _ in 0..4 {
+///     let wg = wg.clone();
+///     let finished = finished.clone();
+///     thread::spawn(move || {
+///         finished.fetch_add(1, Ordering::Relaxed);
+///         drop(wg);
+///     });
+/// }
+///
+/// // Block until all the threads dropped their handle.
+/// wg.wait();
+/// assert_eq!(finished.load(Ordering::Relaxed), 4);
+/// ```
+pub struct WaitGroup {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// The amount of pending units of work.
+    count: AtomicUsize,
+    /// A stack of threads blocked in wait(), linked through their `next` fields.
+    waiters: AtomicPtr<Waiter>,
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitGroup")
+            .field("count", &self.inner.count.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl Clone for WaitGroup {
+    /// Creates a new handle to the same group, adding a unit of pending work.
+    fn clone(&self) -> Self {
+        self.add(1);
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for WaitGroup {
+    /// Completes the unit of work held by this handle.
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl WaitGroup {
+    /// Creates a new wait group, with a single unit of pending work held by the returned handle.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                count: AtomicUsize::new(1),
+                waiters: AtomicPtr::new(ptr::null_mut()),
+            }),
+        }
+    }
+
+    /// Adds `n` units of pending work, which must each be completed with [`done`](WaitGroup::done).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the amount of pending work overflows a `usize`.
+    pub fn add(&self, n: usize) {
+        let count = self.inner.count.fetch_add(n, Ordering::Relaxed);
+        assert!(
+            count.checked_add(n).is_some(),
+            "WaitGroup pending work overflowed"
+        );
+    }
+
+    /// Completes a unit of pending work, waking up the waiting threads if it was the last one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there was no pending work left.
+    pub fn done(&self) {
+        // Release barrier ensures the work done by this thread happens before wait() returns.
+        let count = self.inner.count.fetch_sub(1, Ordering::SeqCst);
+        assert_ne!(count, 0, "WaitGroup::done called without pending work");
+
+        if count == 1 {
+            self.inner.wake_all();
+        }
+    }
+
+    /// Completes the unit of work held by this handle and blocks the current thread
+    /// until all other pending units of work were completed.
+    pub fn wait(self) {
+        let inner = self.inner.clone();
+        drop(self);
+
+        // Acquire barrier ensures the completed work happens before we return.
+        if inner.count.load(Ordering::Acquire) != 0 {
+            inner.wait_slow();
+        }
+    }
+}
+
+impl Inner {
+    #[cold]
+    fn wait_slow(&self) {
+        Waiter::with(Primitive::new("WaitGroup", self), |waiter| {
+            let waiter_ptr = NonNull::from(&*waiter).as_ptr();
+            let mut head = self.waiters.load(Ordering::Relaxed);
+            loop {
+                waiter.next.set(NonNull::new(head));
+                match self.waiters.compare_exchange_weak(
+                    head,
+                    waiter_ptr,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => head = e,
+                }
+            }
+
+            // The work may have been completed before we were pushed, so the thread
+            // which completed it could have missed us. Wake up the stack ourselves then.
+            // SeqCst ensures that either we see the completion here or done() sees our push.
+            if self.count.load(Ordering::SeqCst) == 0 {
+                self.wake_all();
+            }
+
+            // Whichever thread took us off the stack will wake us up.
+            assert!(waiter.parker.park(None));
+        })
+    }
+
+    fn wake_all(&self) {
+        // Acquire barrier ensures the waiter writes from wait_slow() happen before we wake them.
+        let mut waiters = NonNull::new(self.waiters.swap(ptr::null_mut(), Ordering::SeqCst));
+
+        // The next field must be read before unpark() as it lets the waiter return.
+        while let Some(waiter) = waiters {
+            unsafe {
+                waiters = waiter.as_ref().next.get();
+                waiter.as_ref().parker.unpark();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::WaitGroup;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::channel,
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn smoke() {
+        let wg = WaitGroup::new();
+        wg.wait();
+
+        let wg = WaitGroup::new();
+        wg.add(2);
+        wg.done();
+        wg.done();
+        wg.wait();
+    }
+
+    #[test]
+    fn wait_for_threads() {
+        const THREADS: usize = 10;
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..100 {
+            finished.store(0, Ordering::Relaxed);
+            let wg = WaitGroup::new();
+            for _ in 0..THREADS {
+                let (wg, finished) = (wg.clone(), finished.clone());
+                thread::spawn(move || {
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    drop(wg);
+                });
+            }
+
+            wg.wait();
+            assert_eq!(finished.load(Ordering::Relaxed), THREADS);
+        }
+    }
+
+    #[test]
+    fn multiple_waiters() {
+        let wg = WaitGroup::new();
+        let (tx, rx) = channel();
+        wg.add(1);
+
+        // Each waiter holds its own handle, which is completed when it starts waiting.
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let (wg, tx) = (wg.clone(), tx.clone());
+                thread::spawn(move || {
+                    wg.wait();
+                    tx.send(()).unwrap();
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        // Completing the manually added work isn't enough while our handle is alive.
+        wg.done();
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        drop(wg);
+        for waiter in waiters {
+            waiter.join().unwrap();
+            rx.recv().unwrap();
+        }
+    }
+}
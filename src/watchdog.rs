@@ -0,0 +1,219 @@
+//! A callback warning about threads blocked on a primitive for too long.
+//!
+//! Once registered with [`set_watchdog`], the callback is called when a thread has been
+//! blocked on any of the crate's primitives for longer than the threshold, with which
+//! primitive and for how long. It's called once per wait, on the blocked thread itself,
+//! which keeps waiting afterwards. Stalls much longer than expected are often the first
+//! sign of a deadlock or of a lock held across a slow operation.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use usync::watchdog;
+//!
+//! let _ = watchdog::set_watchdog(Duration::from_secs(5), |stall| {
+//!     eprintln!(
+//!         "{:?} blocked on {} for {:?}",
+//!         std::thread::current().id(),
+//!         stall.primitive(),
+//!         stall.waited(),
+//!     );
+//! });
+//! ```
+
+use crate::{const_spin_lock, SpinLock};
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// A thread which has been blocked for longer than the watchdog threshold.
+#[derive(Copy, Clone, Debug)]
+pub struct Stall {
+    primitive: &'static str,
+    address: usize,
+    waited: Duration,
+}
+
+impl Stall {
+    /// Returns the type of the primitive the thread is blocked on, e.g. `Mutex` or `Semaphore`,
+    /// which is only `unknown` when it's blocked while its thread locals are being destroyed.
+    ///
+    /// Threads parked directly through the [`parking`](crate::parking) module report `parking`.
+    pub fn primitive(&self) -> &'static str {
+        self.primitive
+    }
+
+    /// Returns the address of the primitive the thread is blocked on, to tell apart stalls on
+    /// different instances of the same type, or 0 when it's unknown.
+    ///
+    /// Threads parked through the [`parking`](crate::parking) module report their key.
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Returns how long the thread has been blocked.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+}
+
+struct Watchdog {
+    threshold: Duration,
+    callback: Box<dyn Fn(&Stall) + Send + Sync>,
+}
+
+// The watchdog is only read when a thread blocks for longer than the threshold,
+// and `IS_SET` keeps the other waits from looking it up at all.
+static WATCHDOG: SpinLock<Option<Arc<Watchdog>>> = const_spin_lock(None);
+static IS_SET: AtomicBool = AtomicBool::new(false);
+
+std::thread_local! {
+    /// The primitive the current thread is blocked on, set while it's in a primitive's slow path.
+    static WAITING_ON: Cell<Option<(&'static str, usize)>> = const { Cell::new(None) };
+}
+
+/// Registers the callback called when a thread has been blocked for longer than `threshold`.
+///
+/// The callback runs on the blocked thread, so it must not use the primitive it's told about.
+/// The watchdog can only be set once, and the callback is returned back if it already was.
+pub fn set_watchdog<F>(threshold: Duration, callback: F) -> Result<(), F>
+where
+    F: Fn(&Stall) + Send + Sync + 'static,
+{
+    let mut watchdog = WATCHDOG.lock();
+    if watchdog.is_some() {
+        return Err(callback);
+    }
+
+    *watchdog = Some(Arc::new(Watchdog {
+        threshold,
+        callback: Box::new(callback),
+    }));
+    IS_SET.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Unregisters the watchdog, for tests not to leave it set for the rest of the process.
+#[cfg(test)]
+pub(crate) fn unset_watchdog() {
+    IS_SET.store(false, Ordering::Relaxed);
+    *WATCHDOG.lock() = None;
+}
+
+/// Returns true if a watchdog was registered.
+#[inline]
+pub(crate) fn is_set() -> bool {
+    IS_SET.load(Ordering::Relaxed)
+}
+
+/// Records that the current thread is blocked on the `primitive` at `address` while `f` runs.
+pub(crate) fn waiting_on<F>(primitive: &'static str, address: usize, f: impl FnOnce() -> F) -> F {
+    struct Restore(Option<(&'static str, usize)>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = WAITING_ON.try_with(|waiting_on| waiting_on.set(self.0));
+        }
+    }
+
+    // Thread locals may already be destroyed, in which case the stall is reported without it.
+    let previous = WAITING_ON
+        .try_with(|waiting_on| waiting_on.replace(Some((primitive, address))))
+        .ok()
+        .flatten();
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Waits with `wait` for up to `timeout`, calling the watchdog once the thread waited
+/// for longer than its threshold. Returns false if the wait timed out.
+pub(crate) fn wait(
+    timeout: Option<Duration>,
+    mut wait: impl FnMut(Option<Duration>) -> bool,
+) -> bool {
+    let watchdog = match WATCHDOG.lock().clone() {
+        Some(watchdog) => watchdog,
+        None => return wait(timeout),
+    };
+
+    if matches!(timeout, Some(timeout) if timeout <= watchdog.threshold) {
+        return wait(timeout);
+    }
+
+    let started = Instant::now();
+    if wait(Some(watchdog.threshold)) {
+        return true;
+    }
+
+    let (primitive, address) = WAITING_ON
+        .try_with(Cell::get)
+        .ok()
+        .flatten()
+        .unwrap_or(("unknown", 0));
+    (watchdog.callback)(&Stall {
+        primitive,
+        address,
+        waited: started.elapsed(),
+    });
+
+    wait(timeout.map(|timeout| timeout.saturating_sub(started.elapsed())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_watchdog, unset_watchdog};
+    use crate::Mutex;
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn reports_stalls() {
+        // Don't leave the watchdog set for the other tests, even if this one fails.
+        struct Unset;
+        impl Drop for Unset {
+            fn drop(&mut self) {
+                unset_watchdog();
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let tx = crate::SpinLock::new(tx);
+        let result = set_watchdog(Duration::from_millis(50), move |stall| {
+            let _ = tx
+                .lock()
+                .send((stall.primitive(), stall.address(), stall.waited()));
+        });
+        assert!(result.is_ok());
+        let _unset = Unset;
+        assert!(set_watchdog(Duration::from_secs(1), |_| {}).is_err());
+
+        let mutex = Arc::new(Mutex::new(()));
+        let guard = mutex.lock();
+        let t = {
+            let mutex = mutex.clone();
+            thread::spawn(move || drop(mutex.lock()))
+        };
+
+        // Other tests may stall too, so look for the one blocked on the mutex.
+        let address = unsafe { mutex.raw() } as *const _ as usize;
+        loop {
+            let (primitive, stalled_on, waited) = rx.recv().unwrap();
+            if stalled_on == address {
+                assert_eq!(primitive, "Mutex");
+                assert!(waited >= Duration::from_millis(50));
+                break;
+            }
+        }
+
+        drop(guard);
+        t.join().unwrap();
+    }
+}